@@ -0,0 +1,79 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use modbusrouter::frame::{
+    encode_frame, parse_all, parse_frame, read_first_message, read_message, DEFAULT_MACS, FRAME_LEN,
+};
+use std::io::Cursor;
+
+// A well formed frame, the same one used by the tests in frame.rs
+const FRAME: [u8; FRAME_LEN] = [
+    0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFE, 0xF2,
+    0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+];
+
+// The number of frames in the stream used by the throughput benchmark
+const FRAME_COUNT: usize = 10_000;
+
+fn read_message_throughput(c: &mut Criterion) {
+    let raw: Vec<u8> = FRAME
+        .iter()
+        .cycle()
+        .take(FRAME_LEN * FRAME_COUNT)
+        .cloned()
+        .collect();
+
+    let mut group = c.benchmark_group("read_message");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("10k frames", |b| {
+        b.iter(|| {
+            let mut stream = Cursor::new(&raw);
+            for _ in 0..FRAME_COUNT {
+                black_box(read_message(&mut stream, &DEFAULT_MACS).unwrap());
+            }
+        })
+    });
+    // the same frames already in memory
+    group.bench_function("10k frames parse_all", |b| {
+        b.iter(|| black_box(parse_all(black_box(&raw), &DEFAULT_MACS)))
+    });
+    group.finish();
+}
+
+fn encode_round_trip(c: &mut Criterion) {
+    c.bench_function("encode round trip", |b| {
+        b.iter(|| {
+            let msg = parse_frame(black_box(&FRAME), &DEFAULT_MACS).unwrap();
+            black_box(encode_frame(&msg))
+        })
+    });
+}
+
+// The worst case for the alignment scan: as much garbage as it is willing to skip before the first frame.
+// The garbage contains partial start sequences so that every position gets a second look
+fn alignment_scan(c: &mut Criterion) {
+    let garbage_len = FRAME_LEN * 8;
+    let mut raw: Vec<u8> = [0x19, 0x00, 0xD0, 0xCF]
+        .iter()
+        .cycle()
+        .take(garbage_len)
+        .cloned()
+        .collect();
+    raw.extend_from_slice(&FRAME);
+
+    let mut group = c.benchmark_group("read_first_message");
+    group.throughput(Throughput::Bytes(raw.len() as u64));
+    group.bench_function("garbage before first frame", |b| {
+        b.iter(|| {
+            let mut stream = Cursor::new(&raw);
+            black_box(read_first_message(&mut stream, &DEFAULT_MACS).unwrap())
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    read_message_throughput,
+    encode_round_trip,
+    alignment_scan
+);
+criterion_main!(benches);
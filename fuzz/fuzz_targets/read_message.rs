@@ -0,0 +1,38 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use modbusrouter::formats::Formats;
+use modbusrouter::frame::{
+    decode_frame, parse_frame, read_first_message, read_message, RouterError, DEFAULT_MACS,
+    FRAME_LEN,
+};
+use std::io::Cursor;
+
+fuzz_target!(|data: &[u8]| {
+    // the frame parser on its own
+    if data.len() >= FRAME_LEN {
+        let mut frame = [0; FRAME_LEN];
+        frame.copy_from_slice(&data[..FRAME_LEN]);
+        let _ = parse_frame(&frame, &DEFAULT_MACS);
+        let _ = decode_frame(&frame, &DEFAULT_MACS);
+    }
+
+    // a standard frame with a crc (see --payload-crc) of any length at all, a short one included.
+    // Anything but a whole frame is an error, never a read past the end of the buffer
+    let formats = Formats::new(Vec::new()).unwrap().with_standard_crc();
+    let _ = formats.decode(data, &DEFAULT_MACS);
+
+    // the reader the way the main loop uses it: find the first frame then read the rest back to back
+    // until the input runs out
+    let mut stream = Cursor::new(data);
+    if read_first_message(&mut stream, &DEFAULT_MACS).is_ok() {
+        loop {
+            match read_message(&mut stream, &DEFAULT_MACS) {
+                // the input has run out, between frames or part way through one
+                Err(RouterError::CleanEof) | Err(RouterError::TruncatedFrame { .. }) => break,
+                // nothing else can come of a stream that has failed
+                Err(RouterError::Io(_)) | Err(RouterError::Timeout(_)) => break,
+                _ => {}
+            }
+        }
+    }
+});
@@ -0,0 +1,138 @@
+use crate::clock::Clock;
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+// How often the thread looks at the clock, the lines can be this late
+const TICK: Duration = Duration::from_secs(1);
+
+// What the router is up to, as far as the alive line is concerned
+struct Liveness {
+    interval: Duration,
+    // the device host while we are connected to it
+    connected: Option<String>,
+    last_message: Option<SystemTime>,
+    // when the quiet period started or the last line was printed, whichever is later
+    quiet_since: SystemTime,
+}
+
+impl Liveness {
+    fn new(interval: Duration, now: SystemTime) -> Liveness {
+        Liveness {
+            interval,
+            connected: None,
+            last_message: None,
+            quiet_since: now,
+        }
+    }
+
+    fn message(&mut self, now: SystemTime) {
+        self.last_message = Some(now);
+        self.quiet_since = now;
+    }
+
+    // The line to print, if it has been quiet for a whole interval since the last message or line
+    fn line(&mut self, now: SystemTime) -> Option<String> {
+        let quiet = now.duration_since(self.quiet_since).unwrap_or_default();
+        if quiet < self.interval {
+            return None;
+        }
+        self.quiet_since = now;
+        let last_message = match self.last_message {
+            Some(at) => format!(
+                "last message {}s ago",
+                now.duration_since(at).unwrap_or_default().as_secs()
+            ),
+            None => "no messages yet".to_string(),
+        };
+        let connection = match &self.connected {
+            Some(host) => format!("connected to {}", host),
+            None => "not connected".to_string(),
+        };
+        Some(format!("Still alive, {}, {}", last_message, connection))
+    }
+}
+
+// Prints a line every interval while no messages arrive, so a quiet router can be told apart from a hung one.
+// It runs on its own thread because the main loop is blocked reading the device exactly when it is needed
+pub struct AliveLog {
+    liveness: Arc<Mutex<Liveness>>,
+}
+
+impl AliveLog {
+    pub fn start<C: Clock + Send + 'static>(interval: Duration, clock: C) -> AliveLog {
+        let liveness = Arc::new(Mutex::new(Liveness::new(interval, clock.now())));
+        {
+            let liveness = liveness.clone();
+            thread::spawn(move || loop {
+                thread::sleep(TICK);
+                if let Some(line) = liveness.lock().unwrap().line(clock.now()) {
+                    info!("{}", line);
+                }
+            });
+        }
+        AliveLog { liveness }
+    }
+
+    pub fn connected(&self, host: &str) {
+        self.liveness.lock().unwrap().connected = Some(host.to_string());
+    }
+
+    pub fn disconnected(&self) {
+        self.liveness.lock().unwrap().connected = None;
+    }
+
+    // Call this for every message received from the device
+    pub fn message(&self, now: SystemTime) {
+        self.liveness.lock().unwrap().message(now);
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn a_line_for_every_quiet_interval() {
+        let mut liveness = Liveness::new(Duration::from_secs(60), at(0));
+        assert_eq!(liveness.line(at(59)), None);
+        assert_eq!(
+            liveness.line(at(60)),
+            Some("Still alive, no messages yet, not connected".to_string())
+        );
+        // the next one is a whole interval after the last line
+        assert_eq!(liveness.line(at(100)), None);
+
+        liveness.connected = Some("192.168.1.87:10001".to_string());
+        liveness.message(at(110));
+        assert_eq!(liveness.line(at(169)), None);
+        assert_eq!(
+            liveness.line(at(170)),
+            Some("Still alive, last message 60s ago, connected to 192.168.1.87:10001".to_string())
+        );
+        assert_eq!(
+            liveness.line(at(230)),
+            Some("Still alive, last message 120s ago, connected to 192.168.1.87:10001".to_string())
+        );
+    }
+
+    #[test]
+    fn nothing_while_messages_keep_arriving() {
+        let mut liveness = Liveness::new(Duration::from_secs(10), at(0));
+        for secs in (5..100).step_by(5) {
+            liveness.message(at(secs));
+            assert_eq!(liveness.line(at(secs + 4)), None);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+// Only built with --features async
+use crate::frame::{
+    check_complete, extra_payload_len, is_frame_header, parse_frame, DeviceMessage, PartialFrame,
+    RouterError, FRAME_LEN, HEADER_LEN, START_SEQ,
+};
+use std::io;
+use std::io::ErrorKind;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// The same as frame::read_message for a tokio stream, so that one thread can wait on many connections.
+// The errors are the same ones too, an EOF between frames is not the same as one part way through
+pub async fn read_message<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    macs: &[[u8; 6]],
+) -> Result<DeviceMessage, RouterError> {
+    let mut buffer = [0; FRAME_LEN];
+    check_complete(read_up_to(stream, &mut buffer).await?, FRAME_LEN)?;
+    let mut extra = vec![0; extra_payload_len(&buffer, START_SEQ)];
+    let got = read_up_to(stream, &mut extra).await?;
+    check_complete(FRAME_LEN + got, FRAME_LEN + extra.len())?;
+    let mut message = parse_frame(&buffer, macs)?;
+    message.received_at = Some(SystemTime::now());
+    Ok(message)
+}
+
+// The same as frame::resync_to_start for a tokio stream
+pub async fn resync_to_start<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    max_skip: usize,
+) -> Result<([u8; FRAME_LEN], usize), io::Error> {
+    let mut buffer = [0; FRAME_LEN];
+    fill_buffer(stream, &mut buffer[..HEADER_LEN]).await?;
+
+    let mut discarded = 0;
+    while !is_frame_header(&buffer[..HEADER_LEN], start_seq, macs) {
+        if discarded == max_skip {
+            let e = io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unable to find the start of a frame after skipping {} bytes",
+                    discarded
+                ),
+            );
+            return Err(e);
+        }
+        buffer.copy_within(1..HEADER_LEN, 0);
+        fill_buffer(stream, &mut buffer[HEADER_LEN - 1..HEADER_LEN]).await?;
+        discarded += 1;
+    }
+
+    fill_buffer(stream, &mut buffer[HEADER_LEN..]).await?;
+    Ok((buffer, discarded))
+}
+
+// The same as frame::read_extra_payload for a tokio stream
+pub async fn read_extra_payload<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    start_seq: [u8; 2],
+    frame: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let mut extra = vec![0; extra_payload_len(frame, start_seq)];
+    fill_buffer(stream, &mut extra)
+        .await
+        .map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, PartialFrame),
+            _ => e,
+        })?;
+    Ok(extra)
+}
+
+async fn fill_buffer<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buffer: &mut [u8],
+) -> Result<(), io::Error> {
+    let num_bytes = read_up_to(stream, buffer).await?;
+    if num_bytes < buffer.len() {
+        let e = if num_bytes > 0 {
+            io::Error::new(ErrorKind::UnexpectedEof, PartialFrame)
+        } else {
+            io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "The connection was closed by the device",
+            )
+        };
+        return Err(e);
+    }
+    Ok(())
+}
+
+async fn read_up_to<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    buffer: &mut [u8],
+) -> Result<usize, io::Error> {
+    let mut num_bytes = 0;
+    while num_bytes < buffer.len() {
+        let read = stream.read(&mut buffer[num_bytes..]).await?;
+        if read == 0 {
+            break;
+        }
+        num_bytes += read;
+    }
+    Ok(num_bytes)
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{encode_frame, is_partial_frame, DEFAULT_MACS};
+    use crate::testing::sample_message;
+    use std::future::Future;
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn read_messages_then_eof() {
+        let mut bytes = encode_frame(&sample_message()).to_vec();
+        bytes.extend_from_slice(&encode_frame(&sample_message()));
+        let mut stream = bytes.as_slice();
+        for _ in 0..2 {
+            let msg = block_on(read_message(&mut stream, &DEFAULT_MACS)).unwrap();
+            assert_eq!(msg.msg_num_value, sample_message().msg_num_value);
+            assert!(msg.received_at.is_some());
+        }
+        let err = block_on(read_message(&mut stream, &DEFAULT_MACS)).unwrap_err();
+        assert!(matches!(err, RouterError::CleanEof));
+        let e = io::Error::from(err);
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(!is_partial_frame(&e));
+
+        let frame = encode_frame(&sample_message());
+        let mut stream = &frame[..10];
+        let err = block_on(read_message(&mut stream, &DEFAULT_MACS)).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::TruncatedFrame {
+                got: 10,
+                expected: FRAME_LEN
+            }
+        ));
+        assert!(is_partial_frame(&io::Error::from(err)));
+    }
+
+    #[test]
+    fn resync_skips_to_the_start_of_a_frame() {
+        let mut bytes = vec![0xAA, 0x19, 0xBB];
+        bytes.extend_from_slice(&encode_frame(&sample_message()));
+        let mut stream = bytes.as_slice();
+        let (frame, discarded) =
+            block_on(resync_to_start(&mut stream, START_SEQ, &DEFAULT_MACS, 8)).unwrap();
+        assert_eq!(frame, encode_frame(&sample_message()));
+        assert_eq!(discarded, 3);
+
+        let rubbish = [0xAA; 40];
+        let mut stream = &rubbish[..];
+        assert!(block_on(resync_to_start(&mut stream, START_SEQ, &DEFAULT_MACS, 8)).is_err());
+    }
+}
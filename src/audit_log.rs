@@ -0,0 +1,203 @@
+use crate::modbus_client::{ModbusClient, RegisterKind};
+use log::error;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// One line of the audit log
+#[derive(Debug, Serialize)]
+struct Entry {
+    at_ms: u64,
+    // single, multiple or coil, as in --write-function
+    function: &'static str,
+    address: u16,
+    // a coil is 1 for on and 0 for off
+    values: Vec<u16>,
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+// The append only record of every write the router made to the modbus (see --audit-log). It is written to
+// directly rather than through the log, so no log level can turn it off, and every line is on disk before
+// the write is reported as done. The same file is kept across modbus reconnects
+#[derive(Clone)]
+pub struct AuditLog {
+    file: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> io::Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn record(
+        &self,
+        function: &'static str,
+        address: u16,
+        values: &[u16],
+        result: &Result<(), modbus::Error>,
+    ) {
+        let entry = Entry {
+            at_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            function,
+            address,
+            values: values.to_vec(),
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        };
+        let written = serde_json::to_string(&entry)
+            .map_err(io::Error::from)
+            .and_then(|line| {
+                let mut file = self.file.lock().unwrap();
+                writeln!(file, "{}", line)?;
+                file.sync_data()
+            });
+        if let Err(e) = written {
+            error!(
+                "Unable to write to the audit log, the write to register {} is not recorded: {:?}",
+                address, e
+            );
+        }
+    }
+}
+
+// Records each write, and whether it worked, in the audit log. Reads aren't recorded
+pub struct AuditingClient<C> {
+    inner: C,
+    log: AuditLog,
+}
+
+impl<C: ModbusClient> AuditingClient<C> {
+    pub fn new(inner: C, log: AuditLog) -> AuditingClient<C> {
+        AuditingClient { inner, log }
+    }
+}
+
+impl<C: ModbusClient> ModbusClient for AuditingClient<C> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        let result = self.inner.write_single_register(address, value);
+        self.log.record("single", address, &[value], &result);
+        result
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        let result = self.inner.write_multiple_registers(address, values);
+        self.log.record("multiple", address, values, &result);
+        result
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        let result = self.inner.write_single_coil(address, value);
+        self.log.record("coil", address, &[value as u16], &result);
+        result
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        self.inner.read_registers(kind, address, count)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fields::FieldSet;
+    use crate::frame::DeviceMessage;
+    use crate::register_map::{send_message_to_modbus, RegisterMap};
+    use crate::testing::{sample_message, FailingClient, RecordingClient, Write};
+    use serde_json::Value;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn each_write_of_each_message_is_recorded_in_order() {
+        let path = env::temp_dir().join(format!("modbusrouter-audit-{}.jsonl", std::process::id()));
+        let log = AuditLog::open(path.to_str().unwrap()).unwrap();
+        let mut client = AuditingClient::new(RecordingClient::default(), log.clone());
+        let second = DeviceMessage {
+            batt_value: 50,
+            ..sample_message()
+        };
+        for msg in [sample_message(), second].iter() {
+            send_message_to_modbus(
+                msg,
+                FieldSet::all(),
+                &RegisterMap::default(),
+                Duration::from_millis(0),
+                &mut client,
+            )
+            .unwrap();
+        }
+        // and a write that the modbus turned down
+        let mut failing = AuditingClient::new(FailingClient::after(0), log);
+        assert!(failing.write_single_register(20, 1).is_err());
+
+        let lines: Vec<Value> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        fs::remove_file(&path).unwrap();
+        let expected: Vec<(&str, u64, Vec<u64>)> = client
+            .inner
+            .writes
+            .iter()
+            .map(|write| match write {
+                Write::Single(address, value) => ("single", *address as u64, vec![*value as u64]),
+                Write::Multiple(address, values) => (
+                    "multiple",
+                    *address as u64,
+                    values.iter().map(|value| *value as u64).collect(),
+                ),
+                Write::Coil(address, value) => ("coil", *address as u64, vec![*value as u64]),
+            })
+            .collect();
+        let audited: Vec<(&str, u64, Vec<u64>)> = lines[..lines.len() - 1]
+            .iter()
+            .map(|line| {
+                assert!(line["at_ms"].as_u64().unwrap() > 0);
+                assert_eq!(line["ok"].as_bool(), Some(true));
+                assert!(line.get("error").is_none());
+                let values = line["values"].as_array().unwrap();
+                (
+                    line["function"].as_str().unwrap(),
+                    line["address"].as_u64().unwrap(),
+                    values.iter().map(|value| value.as_u64().unwrap()).collect(),
+                )
+            })
+            .collect();
+        // the same writes for each message, in the order they were made
+        assert_eq!(audited, expected);
+        assert_eq!(audited[0].2, vec![sample_message().batt_value as u64]);
+        assert_eq!(audited[audited.len() / 2].2, vec![50]);
+
+        let failed = &lines[lines.len() - 1];
+        assert_eq!(failed["address"].as_u64(), Some(20));
+        assert_eq!(failed["ok"].as_bool(), Some(false));
+        assert_eq!(failed["error"].as_str(), Some("InvalidResponse"));
+    }
+}
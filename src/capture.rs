@@ -0,0 +1,204 @@
+use log::{error, warn};
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+// How many frames can be waiting for a slow disk before new ones are dropped
+const PENDING_FRAMES: usize = 1024;
+
+// How long the buffered frames can sit in memory when nothing else is arriving
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Where to capture the frames to and when to start a new file
+#[derive(Debug, Clone, PartialEq)]
+pub struct CaptureConfig {
+    pub path: String,
+    // start a new file once the current one would go over this, None means never
+    pub max_bytes: Option<u64>,
+    // how many of the older files to keep as <path>.1, <path>.2 ... with .1 the newest
+    pub keep: usize,
+}
+
+// Makes a capture file of every valid frame, byte for byte, for building test fixtures from real traffic.
+// The file is written on its own thread so a slow disk never holds up the modbus
+pub struct Capture {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl Capture {
+    // The file is opened straight away so that a bad path is found at startup
+    pub fn start(config: CaptureConfig) -> io::Result<Capture> {
+        let mut writer = Writer::open(config)?;
+        let (sender, receiver) = mpsc::sync_channel(PENDING_FRAMES);
+        thread::spawn(move || run(&mut writer, receiver));
+        Ok(Capture { sender })
+    }
+
+    // Queues the frame and returns straight away, if the queue is full the frame is dropped
+    pub fn send(&self, frame: &[u8]) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(frame.to_vec()) {
+            warn!("Capture is not keeping up, dropping frame");
+        }
+    }
+}
+
+// Runs until the router drops its Capture
+fn run(writer: &mut Writer, receiver: Receiver<Vec<u8>>) {
+    loop {
+        let result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(frame) => writer.write(&frame),
+            Err(mpsc::RecvTimeoutError::Timeout) => writer.flush(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let _ = writer.flush();
+                return;
+            }
+        };
+        if let Err(e) = result {
+            error!(
+                "Error writing to capture file {}: {:?}",
+                writer.config.path, e
+            );
+        }
+    }
+}
+
+// The file being written to and how much is in it
+struct Writer {
+    config: CaptureConfig,
+    file: BufWriter<File>,
+    written: u64,
+}
+
+impl Writer {
+    // Carries on from the end of an existing file
+    fn open(config: CaptureConfig) -> io::Result<Writer> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)?;
+        let written = file.metadata()?.len();
+        Ok(Writer {
+            config,
+            file: BufWriter::new(file),
+            written,
+        })
+    }
+
+    fn write(&mut self, frame: &[u8]) -> io::Result<()> {
+        let full = self
+            .config
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written + frame.len() as u64 > max);
+        if full {
+            self.rotate()?;
+        }
+        self.file.write_all(frame)?;
+        self.written += frame.len() as u64;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    // Moves each file along one, dropping the oldest, and starts a new one. Frames are never split across files
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        let path = &self.config.path;
+        if self.config.keep == 0 {
+            fs::remove_file(path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                let older = format!("{}.{}", path, n);
+                if fs::metadata(&older).is_ok() {
+                    fs::rename(&older, format!("{}.{}", path, n + 1))?;
+                }
+            }
+            fs::rename(path, format!("{}.1", path))?;
+        }
+        let file = File::create(path)?;
+        self.file = BufWriter::new(file);
+        self.written = 0;
+        Ok(())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::process;
+
+    // A path of its own for each test so that they can run side by side
+    fn capture_path(name: &str) -> String {
+        let path = env::temp_dir().join(format!("modbusrouter-{}-{}.bin", name, process::id()));
+        path.to_string_lossy().to_string()
+    }
+
+    fn cleanup(path: &str) {
+        for file in [
+            path.to_string(),
+            format!("{}.1", path),
+            format!("{}.2", path),
+        ] {
+            let _ = fs::remove_file(file);
+        }
+    }
+
+    #[test]
+    fn frames_are_appended_verbatim() {
+        let path = capture_path("append");
+        cleanup(&path);
+        let config = CaptureConfig {
+            path: path.clone(),
+            max_bytes: None,
+            keep: 0,
+        };
+        let mut writer = Writer::open(config.clone()).unwrap();
+        writer.write(&[1, 2, 3]).unwrap();
+        writer.flush().unwrap();
+        // a restarted router carries on where the last one left off
+        let mut writer = Writer::open(config).unwrap();
+        writer.write(&[4, 5]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![1, 2, 3, 4, 5]);
+        cleanup(&path);
+    }
+
+    #[test]
+    fn full_files_are_rotated() {
+        let path = capture_path("rotate");
+        cleanup(&path);
+        let mut writer = Writer::open(CaptureConfig {
+            path: path.clone(),
+            max_bytes: Some(4),
+            keep: 2,
+        })
+        .unwrap();
+        for n in 1..=4 {
+            writer.write(&[n, n]).unwrap();
+        }
+        writer.write(&[5, 5, 5]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![5, 5, 5]);
+        assert_eq!(fs::read(format!("{}.1", path)).unwrap(), vec![3, 3, 4, 4]);
+        assert_eq!(fs::read(format!("{}.2", path)).unwrap(), vec![1, 1, 2, 2]);
+
+        // only two old files are kept
+        writer.write(&[6, 6]).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(fs::read(format!("{}.2", path)).unwrap(), vec![3, 3, 4, 4]);
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        cleanup(&path);
+    }
+}
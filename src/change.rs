@@ -0,0 +1,274 @@
+use crate::fields::{Field, FieldSet};
+use crate::frame::DeviceMessage;
+use crate::register_map::{RegisterMap, Signedness};
+use std::collections::BTreeMap;
+
+// Which fields are only forwarded when they change
+#[derive(Debug, Clone, Default)]
+pub struct ChangeConfig {
+    // the deadband of each watched field: the value must move by more than this to count as a change
+    // fields that are not in the map are forwarded with every message
+    pub deadbands: BTreeMap<Field, u16>,
+    // forward the whole message when any watched field changes rather than just the fields that changed
+    pub whole_message: bool,
+}
+
+type DeviceKey = ([u8; 6], Option<u8>);
+
+// Sits in front of the modbus and decides which fields of a message are worth sending.
+// Values are compared to the last value that was actually forwarded for the same device
+// so that a slow drift still gets through once it has moved further than the deadband
+pub struct ChangeFilter {
+    config: ChangeConfig,
+    last_forwarded: BTreeMap<DeviceKey, BTreeMap<Field, Vec<u16>>>,
+}
+
+impl ChangeFilter {
+    pub fn new(config: ChangeConfig) -> ChangeFilter {
+        ChangeFilter {
+            config,
+            last_forwarded: BTreeMap::new(),
+        }
+    }
+
+    // Returns the fields of the message that should be forwarded. The register map of the device says how its
+    // values are read, so that a signed value going from -1 to 0 is a change of 1 and not of 65535
+    pub fn filter(&self, msg: &DeviceMessage, register_map: &RegisterMap) -> FieldSet {
+        let mut fields = FieldSet::empty();
+        let mut any_changed = false;
+        for field in Field::ALL.iter() {
+            let changed = match self.config.deadbands.get(field) {
+                Some(deadband) => {
+                    let signedness = register_map.entry(*field).signedness;
+                    let changed = self.has_changed(msg, *field, signedness, *deadband);
+                    any_changed |= changed;
+                    changed
+                }
+                None => true,
+            };
+            if changed {
+                fields.insert(*field);
+            }
+        }
+
+        if !self.config.whole_message || self.config.deadbands.is_empty() {
+            fields
+        } else if any_changed {
+            FieldSet::all()
+        } else {
+            FieldSet::empty()
+        }
+    }
+
+    // Call this once the fields have been written so that future messages are compared to them
+    pub fn record_forwarded(&mut self, msg: &DeviceMessage, fields: FieldSet) {
+        let last = self
+            .last_forwarded
+            .entry((msg.mac, msg.sensor_id))
+            .or_default();
+        for field in Field::ALL.iter() {
+            if fields.contains(*field) {
+                last.insert(*field, msg.field_values(*field));
+            }
+        }
+    }
+
+    // Forget everything we have forwarded for every device so far, the next message from each
+    // of them will be sent in full. Called when the device connection or the modbus is started again
+    pub fn reset(&mut self) {
+        self.last_forwarded.clear();
+    }
+
+    fn has_changed(
+        &self,
+        msg: &DeviceMessage,
+        field: Field,
+        signedness: Signedness,
+        deadband: u16,
+    ) -> bool {
+        let last = self
+            .last_forwarded
+            .get(&(msg.mac, msg.sensor_id))
+            .and_then(|last| last.get(&field));
+        match last {
+            Some(last) => msg
+                .field_values(field)
+                .iter()
+                .zip(last.iter())
+                .any(|(value, last)| {
+                    let moved = signedness.reading(*value) - signedness.reading(*last);
+                    moved.abs() > f64::from(deadband)
+                }),
+            None => true,
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(batt_value: u8, temp_value: u8) -> DeviceMessage {
+        DeviceMessage {
+            batt_value,
+            temp_value,
+            ..crate::testing::sample_message()
+        }
+    }
+
+    fn watching(fields: &[(Field, u16)], whole_message: bool) -> ChangeFilter {
+        ChangeFilter::new(ChangeConfig {
+            deadbands: fields.iter().cloned().collect(),
+            whole_message,
+        })
+    }
+
+    #[test]
+    fn unwatched_fields_are_always_forwarded() {
+        let filter = watching(&[], false);
+        assert_eq!(
+            filter.filter(&message(0, 84), &RegisterMap::default()),
+            FieldSet::all()
+        );
+    }
+
+    #[test]
+    fn watched_field_only_forwarded_on_change() {
+        let mut filter = watching(&[(Field::Battery, 0)], false);
+
+        let first = message(10, 84);
+        let fields = filter.filter(&first, &RegisterMap::default());
+        assert!(fields.contains(Field::Battery));
+        filter.record_forwarded(&first, fields);
+
+        let fields = filter.filter(&message(10, 85), &RegisterMap::default());
+        assert!(!fields.contains(Field::Battery));
+        assert!(fields.contains(Field::Temperature));
+
+        assert!(filter
+            .filter(&message(11, 85), &RegisterMap::default())
+            .contains(Field::Battery));
+    }
+
+    #[test]
+    fn deadband_is_relative_to_last_forwarded_value() {
+        let mut filter = watching(&[(Field::Temperature, 2)], false);
+        let first = message(0, 80);
+        filter.record_forwarded(&first, filter.filter(&first, &RegisterMap::default()));
+
+        // within the deadband
+        assert!(!filter
+            .filter(&message(0, 82), &RegisterMap::default())
+            .contains(Field::Temperature));
+        assert!(!filter
+            .filter(&message(0, 78), &RegisterMap::default())
+            .contains(Field::Temperature));
+        // outside of it
+        assert!(filter
+            .filter(&message(0, 83), &RegisterMap::default())
+            .contains(Field::Temperature));
+    }
+
+    #[test]
+    fn whole_message_mode() {
+        let mut filter = watching(&[(Field::Battery, 0), (Field::Version, 0)], true);
+        let first = message(10, 84);
+        filter.record_forwarded(&first, filter.filter(&first, &RegisterMap::default()));
+
+        // the temperature is not watched so on its own it doesn't count as a change
+        assert!(filter
+            .filter(&message(10, 90), &RegisterMap::default())
+            .is_empty());
+        assert_eq!(
+            filter.filter(&message(11, 84), &RegisterMap::default()),
+            FieldSet::all()
+        );
+    }
+
+    #[test]
+    fn each_device_is_compared_to_its_own_last_value() {
+        let mut filter = watching(&[(Field::Battery, 0)], false);
+        let first = message(10, 84);
+        filter.record_forwarded(&first, filter.filter(&first, &RegisterMap::default()));
+        assert!(!filter
+            .filter(&first, &RegisterMap::default())
+            .contains(Field::Battery));
+
+        // the same value from another device, or another sensor on the same device, hasn't been sent yet
+        let other_device = DeviceMessage {
+            mac: [0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F],
+            ..first.clone()
+        };
+        let other_sensor = DeviceMessage {
+            sensor_id: Some(7),
+            ..first.clone()
+        };
+        assert!(filter
+            .filter(&other_device, &RegisterMap::default())
+            .contains(Field::Battery));
+        assert!(filter
+            .filter(&other_sensor, &RegisterMap::default())
+            .contains(Field::Battery));
+
+        filter.record_forwarded(
+            &other_device,
+            filter.filter(&other_device, &RegisterMap::default()),
+        );
+        assert!(!filter
+            .filter(&other_device, &RegisterMap::default())
+            .contains(Field::Battery));
+        assert!(filter
+            .filter(&other_sensor, &RegisterMap::default())
+            .contains(Field::Battery));
+    }
+
+    #[test]
+    fn signed_values_are_compared_as_signed() {
+        let mut filter = watching(&[(Field::Vibration, 10)], false);
+        let mut signed = RegisterMap::default();
+        signed.set_signedness(Field::Vibration, Signedness::Signed);
+        let vibration = |vib_x| DeviceMessage {
+            vib_x,
+            vib_y: 0,
+            vib_z: 0,
+            ..message(0, 84)
+        };
+
+        // -1 to 0 is a change of 1, within the deadband
+        let first = vibration(0xFFFF);
+        filter.record_forwarded(&first, filter.filter(&first, &signed));
+        assert!(!filter
+            .filter(&vibration(0), &signed)
+            .contains(Field::Vibration));
+
+        // from 32767 to -32768 is a long way, not a change of 1
+        let top = vibration(0x7FFF);
+        filter.record_forwarded(&top, filter.filter(&top, &signed));
+        assert!(filter
+            .filter(&vibration(0x8000), &signed)
+            .contains(Field::Vibration));
+        // which unsigned it is
+        assert!(!filter
+            .filter(&vibration(0x8000), &RegisterMap::default())
+            .contains(Field::Vibration));
+    }
+
+    #[test]
+    fn reset_forgets_last_values() {
+        let mut filter = watching(&[(Field::Battery, 0)], false);
+        let first = message(10, 84);
+        filter.record_forwarded(&first, filter.filter(&first, &RegisterMap::default()));
+        assert!(!filter
+            .filter(&first, &RegisterMap::default())
+            .contains(Field::Battery));
+        filter.reset();
+        assert!(filter
+            .filter(&first, &RegisterMap::default())
+            .contains(Field::Battery));
+    }
+}
@@ -0,0 +1,252 @@
+use crate::modbus_client::{ModbusClient, RegisterKind};
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// Every wrapper gets its own stream of random numbers, numbered in the order they were made,
+// so a run with the same seed and the same sequence of connections fails in the same places
+static STREAMS: AtomicU64 = AtomicU64::new(0);
+
+// Failures to inject, for exercising the retry and reconnect paths that are otherwise hard to trigger.
+// Rates are the chance of it happening to each read or write, between 0 and 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    pub delay_rate: f64,
+    pub delay: Duration,
+    pub read_failure_rate: f64,
+    pub write_failure_rate: f64,
+}
+
+impl ChaosConfig {
+    // Comma separated settings, e.g. seed=42,delay=0.1:200,read=0.01,write=0.05
+    // where delay is the rate and how many milliseconds to wait
+    pub fn parse(spec: &str) -> Result<ChaosConfig, String> {
+        let mut config = ChaosConfig {
+            seed: 0,
+            delay_rate: 0.0,
+            delay: Duration::from_millis(0),
+            read_failure_rate: 0.0,
+            write_failure_rate: 0.0,
+        };
+        for setting in spec.split(',') {
+            let mut parts = setting.trim().splitn(2, '=');
+            let key = parts.next().unwrap_or("");
+            let value = parts
+                .next()
+                .ok_or_else(|| format!("Expected key=value but got: {}", setting))?;
+            match key {
+                "seed" => {
+                    config.seed = value
+                        .parse()
+                        .map_err(|_| format!("Invalid chaos seed: {}", value))?
+                }
+                "delay" => {
+                    let mut parts = value.splitn(2, ':');
+                    config.delay_rate = parse_rate(parts.next().unwrap_or(""))?;
+                    let ms = parts
+                        .next()
+                        .ok_or_else(|| format!("Expected delay=<rate>:<ms> but got: {}", value))?;
+                    let ms = ms
+                        .parse()
+                        .map_err(|_| format!("Invalid chaos delay: {}", ms))?;
+                    config.delay = Duration::from_millis(ms);
+                }
+                "read" => config.read_failure_rate = parse_rate(value)?,
+                "write" => config.write_failure_rate = parse_rate(value)?,
+                _ => return Err(format!("Unknown chaos setting: {}", key)),
+            }
+        }
+        Ok(config)
+    }
+
+    fn rng(&self) -> Rng {
+        let stream = STREAMS.fetch_add(1, Ordering::SeqCst);
+        Rng::new(self.seed ^ stream.wrapping_mul(0x9E37_79B9_7F4A_7C15))
+    }
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!(
+            "Expected a rate between 0 and 1 but got: {}",
+            value
+        )),
+    }
+}
+
+// xorshift64*, random enough for deciding when to fail and the same every time for a given seed
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        // the state must never be zero
+        Rng(seed ^ 0x2545_F491_4F6C_DD1D)
+    }
+
+    // true with the given probability
+    fn chance(&mut self, rate: f64) -> bool {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        let value = self.0.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        // the top 53 bits make an evenly spread f64 in [0, 1)
+        ((value >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+// Wraps the device stream, slowing down or failing reads
+pub struct ChaosReader<R> {
+    inner: R,
+    config: ChaosConfig,
+    rng: Rng,
+}
+
+impl<R: Read> ChaosReader<R> {
+    pub fn new(inner: R, config: &ChaosConfig) -> ChaosReader<R> {
+        ChaosReader {
+            inner,
+            config: config.clone(),
+            rng: config.rng(),
+        }
+    }
+}
+
+impl<R: Read> Read for ChaosReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.rng.chance(self.config.delay_rate) {
+            thread::sleep(self.config.delay);
+        }
+        if self.rng.chance(self.config.read_failure_rate) {
+            // the same as a device that has gone away, so that the reconnect path gets a workout
+            return Err(io::Error::new(
+                ErrorKind::ConnectionReset,
+                "Injected read failure",
+            ));
+        }
+        self.inner.read(buf)
+    }
+}
+
+// Wraps the modbus connection, failing writes
+pub struct ChaosClient<C> {
+    inner: C,
+    config: ChaosConfig,
+    rng: Rng,
+}
+
+impl<C: ModbusClient> ChaosClient<C> {
+    pub fn new(inner: C, config: &ChaosConfig) -> ChaosClient<C> {
+        ChaosClient {
+            inner,
+            config: config.clone(),
+            rng: config.rng(),
+        }
+    }
+
+    fn fail(&mut self) -> Result<(), modbus::Error> {
+        if self.rng.chance(self.config.delay_rate) {
+            thread::sleep(self.config.delay);
+        }
+        if self.rng.chance(self.config.write_failure_rate) {
+            let e = io::Error::new(ErrorKind::ConnectionReset, "Injected write failure");
+            return Err(modbus::Error::Io(e));
+        }
+        Ok(())
+    }
+}
+
+impl<C: ModbusClient> ModbusClient for ChaosClient<C> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.fail()?;
+        self.inner.write_single_register(address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.fail()?;
+        self.inner.write_multiple_registers(address, values)
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.fail()?;
+        self.inner.write_single_coil(address, value)
+    }
+
+    // reads are left alone, so that --verify-writes still works on a flaky modbus
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        self.inner.read_registers(kind, address, count)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::RecordingClient;
+    use std::io::Cursor;
+
+    fn config(spec: &str) -> ChaosConfig {
+        ChaosConfig::parse(spec).unwrap()
+    }
+
+    #[test]
+    fn parse_spec() {
+        let parsed = config("seed=42, delay=0.1:200,read=0.01,write=1");
+        assert_eq!(
+            parsed,
+            ChaosConfig {
+                seed: 42,
+                delay_rate: 0.1,
+                delay: Duration::from_millis(200),
+                read_failure_rate: 0.01,
+                write_failure_rate: 1.0,
+            }
+        );
+        assert!(ChaosConfig::parse("read=2").is_err());
+        assert!(ChaosConfig::parse("delay=0.5").is_err());
+        assert!(ChaosConfig::parse("bogus=1").is_err());
+    }
+
+    #[test]
+    fn the_same_seed_fails_in_the_same_places() {
+        let pattern = |seed: u64| -> Vec<bool> {
+            let mut rng = Rng::new(seed);
+            (0..100).map(|_| rng.chance(0.3)).collect()
+        };
+        assert_eq!(pattern(7), pattern(7));
+        assert_ne!(pattern(7), pattern(8));
+        // roughly the rate asked for
+        let failures = pattern(7).iter().filter(|failed| **failed).count();
+        assert!((15..45).contains(&failures));
+    }
+
+    #[test]
+    fn reads_and_writes_fail_at_the_given_rate() {
+        let mut reader = ChaosReader::new(Cursor::new(vec![1, 2, 3]), &config("read=1"));
+        let e = reader.read(&mut [0; 3]).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ConnectionReset);
+
+        let mut reader = ChaosReader::new(Cursor::new(vec![1, 2, 3]), &config("read=0"));
+        assert_eq!(reader.read(&mut [0; 3]).unwrap(), 3);
+
+        let mut client = ChaosClient::new(RecordingClient::default(), &config("write=1"));
+        assert!(client.write_single_register(1, 2).is_err());
+        assert!(client.inner.writes.is_empty());
+    }
+}
@@ -0,0 +1,122 @@
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    // writing as normal, counting the failures in a row
+    Closed { failures: u32 },
+    // not writing until then
+    Open { until: Instant },
+    // the cooldown is over, the next write is the test of whether the modbus is back
+    HalfOpen,
+}
+
+// Stops the router hammering a modbus that is down (see --circuit-breaker). After enough writes in a row have
+// failed it opens, and for the cooldown nothing is written or connected at all, the messages are dropped (or
+// held by the write queue). Then the next message is let through as a test: if it is written the breaker closes
+// again and if not it opens for another cooldown
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    state: State,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            state: State::Closed { failures: 0 },
+        }
+    }
+
+    // Whether to try writing the message. Once the cooldown is over the breaker half opens to let a test through
+    pub fn allow(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Open { until } if now < until => false,
+            State::Open { .. } => {
+                self.state = State::HalfOpen;
+                true
+            }
+            _ => true,
+        }
+    }
+
+    // Call when a write fails, true when that opens the breaker
+    pub fn record_failure(&mut self, now: Instant) -> bool {
+        match self.state {
+            State::Closed { failures } if failures + 1 < self.threshold => {
+                self.state = State::Closed {
+                    failures: failures + 1,
+                };
+                false
+            }
+            State::Open { .. } => false,
+            _ => {
+                self.state = State::Open {
+                    until: now + self.cooldown,
+                };
+                true
+            }
+        }
+    }
+
+    // Call when a message was written, true when that was the test that closes the breaker again
+    pub fn record_success(&mut self) -> bool {
+        let closing = self.state == State::HalfOpen;
+        self.state = State::Closed { failures: 0 };
+        closing
+    }
+
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    pub fn cooldown(&self) -> Duration {
+        self.cooldown
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn closed_open_half_open_and_closed_again() {
+        let cooldown = Duration::from_secs(30);
+        let mut breaker = CircuitBreaker::new(3, cooldown);
+        let started = Instant::now();
+        let at = |secs| started + Duration::from_secs(secs);
+
+        // closed, it takes three failures in a row to open
+        assert!(breaker.allow(at(0)));
+        assert!(!breaker.record_failure(at(0)));
+        assert!(!breaker.record_failure(at(1)));
+        assert!(!breaker.record_success());
+        assert!(!breaker.record_failure(at(2)));
+        assert!(!breaker.record_failure(at(3)));
+        assert!(breaker.record_failure(at(4)));
+
+        // open for the cooldown
+        assert!(!breaker.allow(at(5)));
+        assert!(!breaker.allow(at(33)));
+
+        // half open, the test write fails so it opens again from then
+        assert!(breaker.allow(at(34)));
+        assert!(breaker.record_failure(at(34)));
+        assert!(!breaker.allow(at(63)));
+
+        // and this time it works
+        assert!(breaker.allow(at(64)));
+        assert!(breaker.record_success());
+        assert!(breaker.allow(at(65)));
+        // closed with the count started again
+        assert!(!breaker.record_failure(at(66)));
+        assert!(!breaker.record_failure(at(67)));
+        assert!(breaker.allow(at(67)));
+    }
+}
@@ -0,0 +1,23 @@
+use std::time::{Instant, SystemTime};
+
+// Where the router gets the current time from.
+// The main loop only ever asks the clock, so tests can swap in a clock that returns whatever time they like
+pub trait Clock {
+    // the wall clock, for the times that are shown or sent on
+    fn now(&self) -> SystemTime;
+    // a clock that only ever goes forward, for measuring how long something took whatever NTP does to the wall clock
+    fn monotonic(&self) -> Instant;
+}
+
+// The real wall clock
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn monotonic(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,172 @@
+use crate::fields::{Field, FieldSet};
+use crate::frame::DeviceMessage;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+// A device, or one of the sensors behind it when the gateway sends a sensor id
+type DeviceKey = ([u8; 6], Option<u8>);
+
+// Where one device is up to
+struct Window {
+    // nothing more is written for the device until then
+    ends: SystemTime,
+    // the latest message that arrived since the last write, and every field that changed since then
+    pending: Option<(DeviceMessage, FieldSet)>,
+}
+
+// Writes each device at most once a window (see --coalesce), for sensors that report far more often than the
+// PLC needs. A message that arrives while the device's window is open is held, and a newer one takes its place,
+// so what goes to the modbus is the freshest value rather than every one in between. The fields that changed in
+// the messages that were replaced are written along with it so that no change is lost.
+// There is no timer of its own, the router only gets a look in when a frame arrives, so a held message goes out
+// with the next frame once its window is over: the device's own next message, which is newer and is written
+// instead, or that of another device
+pub struct Coalescer {
+    window: Duration,
+    devices: BTreeMap<DeviceKey, Window>,
+}
+
+impl Coalescer {
+    pub fn new(window: Duration) -> Coalescer {
+        Coalescer {
+            window,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    // Call with every message about to be written. Returns the message to write now, if there is one: this one
+    // when the device's window is over, otherwise a message held for another device whose window is
+    pub fn offer(
+        &mut self,
+        msg: DeviceMessage,
+        fields: FieldSet,
+        now: SystemTime,
+    ) -> Option<(DeviceMessage, FieldSet)> {
+        let window = self.window;
+        let key = (msg.mac, msg.sensor_id);
+        let device = self.devices.entry(key).or_insert(Window {
+            ends: now,
+            pending: None,
+        });
+        let fields = match device.pending.take() {
+            Some((_, held)) => union(held, fields),
+            None => fields,
+        };
+        if now >= device.ends {
+            device.ends = now + window;
+            return Some((msg, fields));
+        }
+        device.pending = Some((msg, fields));
+        self.take_due(now)
+    }
+
+    // A held message whose window is over, taking it starts the device's next window
+    pub fn take_due(&mut self, now: SystemTime) -> Option<(DeviceMessage, FieldSet)> {
+        let window = self.window;
+        self.devices
+            .values_mut()
+            .find(|device| device.pending.is_some() && now >= device.ends)
+            .and_then(|device| {
+                device.ends = now + window;
+                device.pending.take()
+            })
+    }
+}
+
+fn union(a: FieldSet, b: FieldSet) -> FieldSet {
+    let mut fields = a;
+    for field in Field::ALL.iter() {
+        if b.contains(*field) {
+            fields.insert(*field);
+        }
+    }
+    fields
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+    use std::time::UNIX_EPOCH;
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_571_388_795) + Duration::from_millis(ms)
+    }
+
+    fn message(mac: u8, msg_num: u16) -> DeviceMessage {
+        DeviceMessage {
+            mac: [mac; 6],
+            msg_num_value: msg_num,
+            ..sample_message()
+        }
+    }
+
+    fn only(field: Field) -> FieldSet {
+        let mut fields = FieldSet::empty();
+        fields.insert(field);
+        fields
+    }
+
+    #[test]
+    fn only_the_last_message_of_a_window_is_written() {
+        let mut coalescer = Coalescer::new(Duration::from_millis(1000));
+        // the first message of a device goes straight through and opens its window
+        let written = coalescer.offer(message(1, 1), FieldSet::all(), at(0));
+        assert_eq!(written.unwrap().0.msg_num_value, 1);
+
+        // a report every 300ms is held
+        assert!(coalescer
+            .offer(message(1, 2), only(Field::Battery), at(300))
+            .is_none());
+        assert!(coalescer
+            .offer(message(1, 3), only(Field::Temperature), at(600))
+            .is_none());
+        assert!(coalescer
+            .offer(message(1, 4), only(Field::Battery), at(900))
+            .is_none());
+        assert!(coalescer.take_due(at(999)).is_none());
+
+        // and once the window is over it is the last of them that goes, with every field that changed
+        let (msg, fields) = coalescer.take_due(at(1000)).unwrap();
+        assert_eq!(msg.msg_num_value, 4);
+        assert!(fields.contains(Field::Battery) && fields.contains(Field::Temperature));
+        assert!(!fields.contains(Field::Rssi));
+        assert!(coalescer.take_due(at(5000)).is_none());
+
+        // which opened a new window
+        assert!(coalescer
+            .offer(message(1, 5), only(Field::Rssi), at(1900))
+            .is_none());
+        // a newer message from the device once it is over is written instead of the one held
+        let (msg, fields) = coalescer
+            .offer(message(1, 6), only(Field::Battery), at(2000))
+            .unwrap();
+        assert_eq!(msg.msg_num_value, 6);
+        assert!(fields.contains(Field::Rssi) && fields.contains(Field::Battery));
+
+        // another device has a window of its own, and a frame from it lets the held message out too
+        assert!(coalescer
+            .offer(message(1, 7), FieldSet::all(), at(2500))
+            .is_none());
+        assert_eq!(
+            coalescer
+                .offer(message(2, 1), FieldSet::all(), at(2600))
+                .unwrap()
+                .0
+                .mac,
+            [2; 6]
+        );
+        assert!(coalescer
+            .offer(message(2, 2), FieldSet::all(), at(2700))
+            .is_none());
+        let (msg, _) = coalescer
+            .offer(message(2, 3), FieldSet::all(), at(3100))
+            .unwrap();
+        assert_eq!((msg.mac, msg.msg_num_value), ([1; 6], 7));
+    }
+}
@@ -0,0 +1,3285 @@
+// Configuration for the router.
+// Settings come from the command line, the environment and an optional config file, see Config::from_args_and_env.
+// Anything that is not set anywhere falls back to the defaults in Config::default()
+
+use crate::change::ChangeConfig;
+use crate::chaos::ChaosConfig;
+use crate::connections::SilenceAction;
+use crate::device_source::{TcpOptions, UnixSocket};
+use crate::error_log::ErrorLogConfig;
+use crate::fields::Field;
+use crate::formats::{Endian, Formats, FrameFormat};
+use crate::frame::{
+    framing_error_kind, parse_hex, parse_mac, Checksum, DEFAULT_MACS, FRAME_LEN,
+    MAX_ALIGNMENT_SCAN, MAX_PAYLOAD_LEN, PAYLOAD_LEN, START_SEQ,
+};
+use crate::hooks::HookUrl;
+use crate::log_sampling::LogSamplingConfig;
+use crate::monitor::Alert;
+use crate::policy::ErrorPolicy;
+use crate::read_ahead::WhenFull;
+use crate::reconnect::{Escalation, ReconnectConfig};
+use crate::register_map::RegisterMaps;
+use crate::rtu_transport::{Parity, SerialConfig};
+use crate::sentinel::SentinelConfig;
+use crate::status::Thresholds;
+use crate::stream_transport::TransactionIds;
+use crate::units::Units;
+use crate::version_gate::{MismatchAction, VersionConfig};
+use crate::websocket;
+use log::LevelFilter;
+use modbus::tcp;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "Usage: modbusrouter [options] [hostname]
+       modbusrouter selftest [options]
+       modbusrouter monitor [options] [hostname]
+       modbusrouter read-registers --start <addr> --count <n> [--kind <holding|input>] [options]
+       modbusrouter validate <file> [options]
+
+Options:
+  --device-host <host:port>   the source of the data, the same as passing the hostname (default: 192.168.1.87:10001)
+  --device-unix <path>        read from this unix domain socket instead of a tcp host
+  --device-unix-listen <path> create this unix domain socket and read from whatever connects to it
+  --device-listen <host:port> listen on this address and read from the gateway that connects to it, one at a time
+  --device-listen-max <n>     read from up to n gateways connected to --device-listen at once (default: 1)
+  --device-ws <url>           read the frames from the binary messages of this WebSocket, e.g. ws://10.0.0.5:8080/frames
+  --tls                       connect to the device host over TLS (only when built with --features tls)
+  --tls-ca <path>             the PEM file of the CA certificates the device's certificate must be signed by, needed by --tls
+  --tls-server-name <name>    the name the device's certificate must be for, also sent as the SNI
+                              (default: the host of --device-host)
+  --stdin                     read the frames from stdin instead, the router exits at the end of the input
+  --replay <path>             read the frames from this file instead, the router exits at the end of the file
+  --simulate <rate>           make up frames from the --macs devices at this many a second instead, for load
+                              tests and demos
+  --tcp-keepalive-idle <s>    send keepalive probes once the device connection has been idle this long, 0 turns
+                              keepalive off (default: 60)
+  --tcp-keepalive-interval <s> the time between keepalive probes (default: 10)
+  --tcp-keepalive-count <n>   drop the connection after this many unanswered probes (default: 6)
+  --tcp-nodelay               set TCP_NODELAY on the device connection
+  --tcp-read-timeout <s>      drop the device connection when nothing arrives for this long, 0 waits forever
+                              (default: 30)
+  --silence-timeout <s>       warn when the device is connected but hasn't sent a message for this long (default: never)
+  --on-silence <warn|reconnect>
+                              keep waiting after the warning or drop the connection and connect again (default: warn)
+  --modbus-host <host>        the modbus server to write to, repeat it to write everything to each of several servers
+                              such as a primary and a hot standby PLC (default: 127.0.0.1)
+  --modbus-port <port>        the port of the modbus server (default: 502)
+  --modbus-unit <id>          the unit (slave) id to address, 0 to 255 (default: 1)
+  --serial <device>           write to a Modbus RTU PLC on this serial port instead, e.g. /dev/ttyUSB0
+                              (only when built with --features serial)
+  --serial-baud <n>           the baud rate of the serial port (default: 9600)
+  --serial-parity <none|even|odd>
+                              the parity of the serial port (default: even)
+  --serial-stop-bits <1|2>    the stop bits of the serial port (default: 1)
+  --transaction-ids <sequential|<n>>
+                              number the modbus requests ourselves, counting up or always using the id n
+                              (default: left to the modbus crate)
+  --check-transaction-ids     fail a modbus write when the response has a different transaction id to the request
+  --config <file>             read settings from a file of key = value lines, the keys are the option names
+  --log-format <human|json>   format of the summary report printed on exit (default: human)
+  --on-error <class>=<action> what to do when an error is encountered, can be repeated
+                              classes: bad-frame, eof, connection-reset, timeout, device-io, modbus-exception, modbus-io
+                              actions: retry-in-place, reconnect-device, reconnect-modbus, skip-frame, fatal-exit
+  --error-log-window <s>      identical errors within this many seconds are counted rather than logged (default: 10)
+  --error-log-threshold <n>   how many identical errors in a window are logged in full (default: 1)
+  --on-change <field>[=<deadband>]
+                              only forward the field when it changes by more than the deadband (default 0), can be repeated
+                              fields: battery, temperature, vibration, msg-num, version, rssi
+  --on-change-whole-message   forward every field when any --on-change field changes
+  --sentinel <field>=<value>  the value the device sends when it couldn't read the field, e.g. temperature=255,
+                              can be repeated. Any axis counts for vibration
+  --on-sentinel <field>=<action>
+                              what to do when the field holds its sentinel value, can be repeated
+                              actions: forward-anyway, skip-write, hold-last, write-zero (default: forward-anyway)
+  --clamp <field>=<min>:<max>
+                              keep the field's values within the range before they are written, e.g. battery=0:100,
+                              can be repeated. Each axis of the vibration is clamped
+  --log-on-change <field>[=<deadband>]
+                              only log a message when the field has changed by more than the deadband (default 0)
+                              since the last message logged, can be repeated. This doesn't affect what is forwarded
+  --log-interval <s>          log a message at least this often even if nothing has changed (default: never)
+  --min-version <n>           warn about messages with a lower version_value, e.g. after a firmware downgrade
+  --expect-version <n>        warn about messages with any other version_value
+  --on-version-mismatch <warn|drop>
+                              forward those messages anyway or drop them (default: warn)
+  --min-rssi <n>              don't write messages with a lower rssi_value to the modbus, 0 to 255 (default: 0, all of them)
+  --max-msgs-per-sec <n>      write at most this many messages a second to the modbus, dropping the rest (default: no limit)
+  --min-repeat-interval <ms>  warn about a repeated frame that comes sooner than this after the one before it, a
+                              gateway sending frames again by mistake. It is still dropped (default: off)
+  --coalesce <ms>             write each device at most once in this long, the latest of its messages (default: every one)
+  --max-forward-latency <ms>  drop the device connection when writing each message to the modbus keeps taking longer
+                              than this, to skip the frames that queued up meanwhile (default: never)
+  --max-frames-per-connection <n>
+                              close the device connection and connect again after reading this many frames on it,
+                              for gateways that go wrong on connections that have been up for long (default: never)
+  --device-idle-timeout <s>   drop the device connection and connect again when no complete frame, heartbeats
+                              included, has arrived for this long. Caps --tcp-read-timeout to match (default: never)
+  --read-ahead <n>            read the device on a thread of its own, up to n frames ahead of the modbus writes, so
+                              that neither holds the other up (default: off, one after the other)
+  --read-ahead-full <block|drop>
+                              when n frames are waiting stop reading the device or drop the frames (default: block)
+  --circuit-breaker <n>       after n modbus writes in a row have failed stop writing, and reconnecting, for the
+                              cooldown and drop the messages meanwhile, then try again with the next (default: off)
+  --circuit-breaker-cooldown <s>
+                              how long the circuit breaker stays open (default: 30)
+  --max-reconnects <n>        give up after this many consecutive failed attempts to connect to the host (default: unlimited)
+  --reconnect-escalation <exit|park>
+                              what giving up means: exit with code 3 or keep retrying once a minute (default: exit)
+  --write-function [<mac>[#<sensor>]/]<field>=<single|multiple|coil>
+                              write the field with function 0x06 (one request per register), 0x10 or to coils
+                              with 0x05, can be repeated
+                              (default: multiple for vibration, single for everything else)
+  --fields <field,...>        only write these fields to the modbus, e.g. temperature,battery,rssi, the registers
+                              of the others are left alone (default: all of them)
+  --register [<mac>[#<sensor>]/]<field>=<address>
+                              write the field to this register instead of the one given by its PID byte, can be repeated
+                              starting with a MAC address (e.g. D0:CF:5E:82:93:7B/battery=100) only applies to that device
+                              and adding a sensor id (e.g. D0:CF:5E:82:93:7B#2/battery=100) to one sensor behind it
+  --register-base [<mac>[#<sensor>]/]<n>
+                              add n to the register of every field, e.g. D0:CF:5E:82:93:7B/100 to give a device a block
+                              of registers of its own, can be repeated (default: 0)
+  --float [<mac>[#<sensor>]/]<field>=<scale>[:<word-order>]
+                              write the field times the scale as a 32-bit float across two registers, can be repeated
+                              word orders: abcd, badc, cdab, dcba (default: abcd)
+  --scale [<mac>[#<sensor>]/]<field>=<scale>[:<overflow>]
+                              write the field times the scale, rounded, as an integer, can be repeated. What happens to
+                              values that end up outside 0-65535: saturate, wrap, skip (default: saturate)
+  --signedness [<mac>[#<sensor>]/]<field>=<unsigned|signed|offset>
+                              how the 16-bit values of vibration or msg-num are read: unsigned, two's complement written
+                              as is, or two's complement plus 32768 so it is never negative, can be repeated
+                              (default: unsigned)
+  --offset [<mac>[#<sensor>]/]<field>=<offset>
+                              add this to the field after the scale, e.g. temperature=-400, can be repeated (default: 0)
+  --lookup [<mac>[#<sensor>]/]<field>=<path>
+                              read the field's raw values up in the table in this file, one raw = value a line, before
+                              the scale and offset, e.g. rssi=rssi-dbm.txt, can be repeated (default: none)
+  --unit <field>=<unit>[:<scale>]
+                              label the field with this unit in the json outputs, after multiplying it by the scale
+                              (default: raw with a scale of 1), can be repeated. The modbus still gets the raw values
+  --sensor-id-offset <byte>   the byte of the frame that says which sensor behind the gateway sent it (default: none)
+  --sensor-ids <id,...>       the sensor ids to accept, frames from any other are bad frames (default: any)
+  --macs <mac,...>            the MAC addresses to accept standard frames from, frames from any other are bad frames
+                              (default: D0:CF:5E:82:93:7B)
+  --unknown-mac-policy <warn|drop|allow>
+                              what to do with a standard frame from any other MAC address: warn logs it as a bad
+                              frame for the error policy, drop skips it quietly (it is still counted) and allow
+                              reads it like the others (default: warn)
+  --verify-checksum <xor|sum> treat the last byte of each standard frame as a checksum of the bytes before it
+                              and count frames where it doesn't match as bad frames. Only for firmware that sends a
+                              checksum there, the standard frame has the rssi there (default: not checked)
+  --constant-byte <byte>=<hex>
+                              warn when this byte of the standard frame isn't this value, e.g. 10=00, to catch a
+                              firmware change. Can be given more than once (default: none)
+  --strict                    count a frame where a --constant-byte has changed as a bad frame instead
+  --frame-format <name>:start=<hex>:len=<n>:mac=<byte>:<field>=<byte>...
+                              also accept frames with this layout, told apart from the standard frames (and each other)
+                              by their start sequence, can be repeated. Every field needs the offset of its PID byte
+                              with the value straight after it, e.g. model-b:start=1a00:len=31:mac=2:battery=9:...
+                              Add length=<byte> to check a length byte that counts the bytes after it, crc=<byte>
+                              to check a crc of the bytes after it and endian=big for a layout that sends its u16
+                              values big endian
+  --endian <little|big>       the byte order of msg-num and the vibration in the standard frame (default: little)
+  --frame-terminator <hex>    the bytes the device sends after every frame, e.g. 0d0a. Each frame is checked for them
+                              and they are used to find the frames again after losing our place (default: none)
+  --payload-len <hex>         the payload length every standard frame from the device declares, for a model whose
+                              payload is always longer than ours, e.g. 14. A frame declaring any other length is a
+                              bad frame (default: any from 12 to 40, the bytes after the first 12 are skipped)
+  --payload-crc               the standard frames have a crc of the payload after the length byte, for the newer
+                              firmware. A frame where it doesn't match is a bad frame (default: no crc)
+  --start-sequence <hex>      the 2 bytes every standard frame starts with, for the devices of the same family that
+                              send something else, e.g. 1a00 (default: 1900)
+  --max-resync-bytes <n>      how many bytes to skip looking for the start of a frame on a new connection or after
+                              a bad frame before that counts as a bad frame too (default: 216)
+  --write-delay <ms>          wait this long between the register writes of a message (default: 0)
+  --forward-retries <n>       try the writes of a message this many more times, 100ms apart, when the modbus
+                              connection fails before the error policy is told (default: 0)
+  --batch-writes              send each run of consecutive registers in one write multiple registers request
+                              instead of one request per field (default: one request per field)
+  --verify-writes             read every write back from the holding registers and log the ones that didn't land,
+                              twice the modbus traffic (default: off)
+  --audit-log <path>          append a line of json for every register write, and whether it worked, to this file,
+                              each one synced to disk and whatever the log level (default: none)
+  --write-queue <n>           hold up to n register writes while the modbus is down and send them once it is back,
+                              only the latest value of each register is kept and the oldest go first when it is full
+                              (default: off, a lost modbus is fatal)
+  --retry-queue <n>           hold up to n whole messages while the modbus is down and write each of them once it
+                              is back, the oldest go first when it is full (default: off, a lost modbus is fatal)
+  --raw-sink <host:port>      also send every valid frame, byte for byte, to this tcp endpoint
+  --capture <path>            also append every valid frame, byte for byte, to this file (default: none)
+  --capture-max-bytes <n>     start a new capture file once the current one would go over n bytes (default: never)
+  --capture-keep <n>          how many of the older capture files to keep as <path>.1, <path>.2 ... (default: 5)
+  --json-out <path|->         also append every decoded message as a line of json to this file, - is stdout
+                              (default: none)
+  --csv-out <path|->          also append every decoded message as a row of csv to this file, - is stdout
+                              (default: none)
+  --state-file <path>         keep the latest message of each device in this file, so that the gap detection and
+                              the repeat check carry on from there after a restart (default: none)
+  --hook-command <path>       run this on device-connected, device-disconnected, modbus-down and modbus-up
+                              with the event, the peer and the MAC address (once known) as arguments
+  --hook-url <url>            POST the same events as json to this http url
+  --grpc <url>                publish every decoded message to this gRPC service, e.g. http://10.0.0.5:50051
+                              (only when built with --features grpc, see proto/telemetry.proto)
+  --grpc-batch <n>            send up to n messages in each gRPC publish (default: 1)
+  --mqtt <host:port>          publish every field of every decoded message to this MQTT broker, e.g. 10.0.0.5:1883
+                              (only when built with --features mqtt)
+  --mqtt-topic-prefix <prefix>
+                              the topics are <prefix>/<mac>/<field> (default: sensors)
+  --mqtt-client-id <id>       the client id to connect to the broker with (default: modbusrouter)
+  --http <host:port>          serve the diagnostic endpoints (e.g. /debug/frames) on this address
+  --health-addr <host:port>   answer GET /healthz on this address, 200 while connected to the device and the modbus
+                              and 503 otherwise (default: none)
+  --ws-addr <host:port>       send every decoded message as json to the WebSocket clients connected to this address
+                              (default: none)
+  --recent-frames <n>         how many recent frames /debug/frames keeps (default: 100)
+  --fresh-register <addr>     write 1 to this register for every message and 0 once messages stop arriving
+  --fresh-timeout <s>         how long without a message before the fresh register is set to 0 (default: 10)
+  --strobe-register <addr>    write a strobe to this register after each new message reaches the modbus
+  --strobe-modulus <n>        2 toggles the strobe between 0 and 1, more counts from 0 to n-1 and wraps (default: 2)
+  --last-seen-register <high>,<low>
+                              write the unix time in seconds to these two registers, the high word and the low word,
+                              every time a message reaches the modbus (default: off)
+  --vib-magnitude-register <addr> also write sqrt(x^2 + y^2 + z^2) of the vibration to this register (default: off)
+  --status-register <addr>    also write a status word to this register, a bit for each --status-threshold that is
+                              crossed (default: off)
+  --status-threshold <condition>=<n>
+                              low-battery=<n> sets bit 0 below n, weak-rssi=<n> bit 1 below n and high-vibration=<n>
+                              bit 2 above n, can be repeated (default: none, the bit stays 0)
+  --watchdog-register <addr>  a counter the router increments every --watchdog-interval so the PLC can tell it is alive
+  --watchdog-interval <s>     how often the watchdog counter goes up (default: 5)
+  --alive-interval <s>        log that the router is still alive, when it last had a message and what it is
+                              connected to, every time this long passes without a message (default: off)
+  --stats-interval <s>        log the messages decoded and forwarded, errors, msgs/sec and connection uptime
+                              every this long, 0 turns it off (default: 60)
+  --selftest-register <addr>  a scratch register that selftest may write 0 to (default: no test write)
+  --startup-check             read holding register 0 from the modbus at startup and exit if that fails (default: off)
+  --alert <field><<|>><limit> show the field in red in the monitor when it is below or above the limit,
+                              e.g. battery<20, can be repeated
+  --monitor-write             carry on writing to the modbus while monitoring (default: the monitor only reads)
+  --dry-run                   log the register writes instead of sending them, the modbus is never connected to
+  --once                      exit after the first message has been written to the modbus, 0 if it was and 1 if
+                              the write failed
+  -q, --quiet                 only log errors
+  -v, --verbose               log at the info level, e.g. the connection events, even when RUST_LOG asks for less
+  -vv, --very-verbose         log at the debug level, e.g. every message and register write. The three of them pick
+                              the level over RUST_LOG, the levels it gives particular modules still apply
+  --log-raw                   log the bytes of every frame as hex, most of all the ones that fail to decode. Logs at
+                              the debug level like --very-verbose (RUST_LOG wins)
+
+Every option can also be set in the environment, e.g. MODBUSROUTER_DEVICE_HOST or MODBUSROUTER_ON_ERROR
+(repeated options are separated by commas). The command line beats the environment which beats the config file";
+
+// How reports are written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Human,
+    Json,
+}
+
+// What to do with a standard frame from a MAC address that isn't in --macs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnknownMacPolicy {
+    // a bad frame like any other, logged and handled by the error policy
+    Warn,
+    // skipped without a word, for a gateway that forwards the neighbours' sensors as well
+    Drop,
+    // decoded and written like the frames from --macs, for commissioning before the MAC addresses are known
+    Allow,
+}
+
+impl UnknownMacPolicy {
+    pub fn from_name(name: &str) -> Option<UnknownMacPolicy> {
+        match name {
+            "warn" => Some(UnknownMacPolicy::Warn),
+            "drop" => Some(UnknownMacPolicy::Drop),
+            "allow" => Some(UnknownMacPolicy::Allow),
+            _ => None,
+        }
+    }
+
+    // The MAC addresses to check standard frames against, none at all lets every one through
+    pub fn accepted<'a>(&self, macs: &'a [[u8; 6]]) -> &'a [[u8; 6]] {
+        match self {
+            UnknownMacPolicy::Allow => &[],
+            _ => macs,
+        }
+    }
+
+    // Whether the read error is a frame to skip without logging it or going to the error policy. The whole
+    // frame has been read so the next one is read from where it ends
+    pub fn drops(&self, e: &io::Error) -> bool {
+        *self == UnknownMacPolicy::Drop && framing_error_kind(e) == Some("unexpected-mac")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // the source of the data
+    pub device_host: String,
+    // or a unix domain socket, for a gateway daemon on the same host
+    pub device_unix: Option<UnixSocket>,
+    // or a tcp address to listen on, for gateways that connect to us
+    pub device_listen: Option<String>,
+    // how many gateways may be connected to it at once
+    pub device_listen_max: usize,
+    // or a WebSocket, for gateways that don't offer anything else
+    pub device_ws: Option<String>,
+    // the tcp host wrapped in TLS (needs the tls feature), checked against the CA certificates in tls_ca
+    pub tls: bool,
+    pub tls_ca: Option<PathBuf>,
+    pub tls_server_name: String,
+    // or whatever is piped in
+    pub stdin: bool,
+    // or a file of frames played back, e.g. a capture
+    pub replay: Option<PathBuf>,
+    // frames a second
+    pub simulate: Option<f64>,
+    // how long a connected device can go without a message before we say so, None means never
+    pub silence_timeout: Option<Duration>,
+    pub on_silence: SilenceAction,
+    // keepalive and nodelay for the tcp connection to the device
+    pub tcp: TcpOptions,
+    // where the data goes
+    pub modbus_host: String,
+    // the servers written to alongside modbus_host, from repeating --modbus-host
+    pub standby_hosts: Vec<String>,
+    pub modbus_port: u16,
+    // the unit (slave) id in every modbus request
+    pub modbus_unit: u8,
+    // the serial port of a Modbus RTU PLC, used instead of the modbus host (needs the serial feature)
+    pub serial: Option<String>,
+    pub serial_baud: u32,
+    pub serial_parity: Parity,
+    pub serial_stop_bits: u8,
+    // number the modbus requests ourselves rather than leaving it to the modbus crate, if set
+    pub transaction_ids: Option<TransactionIds>,
+    // whether a response with the wrong transaction id fails the write
+    pub check_transaction_ids: bool,
+    pub log_format: LogFormat,
+    // what the main loop does when it runs into each type of error
+    pub error_policy: ErrorPolicy,
+    // how repeated errors are rate limited in the log
+    pub error_log: ErrorLogConfig,
+    // fields that are only forwarded when they change
+    pub change: ChangeConfig,
+    // the values that mean a field couldn't be read and what to do about them
+    pub sentinels: SentinelConfig,
+    // the ranges fields are clamped to before they are written, in the order they were given
+    pub clamps: Vec<(Field, u16, u16)>,
+    // which messages get logged
+    pub log_sampling: LogSamplingConfig,
+    // the firmware versions we expect to see
+    pub version: VersionConfig,
+    // how hard we try to connect to the device
+    pub reconnect: ReconnectConfig,
+    // how each field is written to the modbus, with any per device differences
+    pub register_maps: RegisterMaps,
+    // where the sensor id is in the frame, for gateways with several sensors behind one MAC
+    pub sensor_id_offset: Option<usize>,
+    // the sensor ids we expect, anything else is a bad frame. Empty means any
+    pub sensor_ids: Vec<u8>,
+    // the MAC addresses standard frames are accepted from, anything else is a bad frame
+    pub macs: Vec<[u8; 6]>,
+    pub unknown_mac_policy: UnknownMacPolicy,
+    // the frame layouts that can turn up alongside the standard one
+    pub frame_formats: Vec<FrameFormat>,
+    // what the last byte of a standard frame is checked against, if anything
+    pub checksum: Option<Checksum>,
+    // bytes of the standard frame that should never change, by offset
+    pub constant_bytes: Vec<(usize, u8)>,
+    // a constant byte that has changed makes it a bad frame, rather than a warning
+    pub strict: bool,
+    // the byte order of the u16 values in the standard frame
+    pub endian: Endian,
+    // the bytes the device sends after every frame, if it sends any
+    pub frame_terminator: Option<Vec<u8>>,
+    // the first two bytes of every standard frame, the same for the whole device family
+    pub start_seq: [u8; 2],
+    // the one payload length standard frames may declare, None means any of them we can read
+    pub payload_len: Option<u8>,
+    // the standard frames have a crc in front of the payload, see FrameFormat::with_crc
+    pub payload_crc: bool,
+    // the most bytes thrown away looking for the start of a frame
+    pub max_resync: usize,
+    // what the values mean, for the json outputs
+    pub units: Units,
+    // the gap between register writes within a message
+    pub write_delay: Duration,
+    // how many more times the writes of a message are tried when the connection fails
+    pub forward_retries: u32,
+    // as few requests per message as the register map allows
+    pub batch_writes: bool,
+    // read back every write to check that it landed
+    pub verify_writes: bool,
+    // the file every write is recorded in, if any
+    pub audit_log: Option<String>,
+    // how many register writes to hold while the modbus is down, None means don't queue
+    pub write_queue: Option<usize>,
+    // how many messages to hold while the modbus is down, None means don't queue
+    pub retry_queue: Option<usize>,
+    // where to mirror the raw frames to, if anywhere
+    pub raw_sink: Option<String>,
+    // the file to capture the raw frames to, if any, and when to rotate it
+    pub capture: Option<String>,
+    pub capture_max_bytes: Option<u64>,
+    pub capture_keep: usize,
+    // the file (or - for stdout) to write the decoded messages to as json lines, if any
+    pub json_out: Option<String>,
+    // where the latest message of each device is kept across restarts
+    pub state_file: Option<String>,
+    // - is stdout
+    pub csv_out: Option<String>,
+    // run or tell on connection state changes
+    pub hook_command: Option<String>,
+    pub hook_url: Option<HookUrl>,
+    // the gRPC service to publish decoded messages to, if any (needs the grpc feature)
+    pub grpc: Option<String>,
+    // the most messages in one gRPC publish
+    pub grpc_batch: usize,
+    // the MQTT broker to publish the fields to, if any (needs the mqtt feature)
+    pub mqtt: Option<String>,
+    pub mqtt_topic_prefix: String,
+    pub mqtt_client_id: String,
+    // where to serve the diagnostic http endpoints, if anywhere
+    pub http: Option<String>,
+    // where to answer the health check, if anywhere
+    pub health_addr: Option<String>,
+    // where to serve the WebSocket broadcast of the decoded messages, if anywhere
+    pub ws_addr: Option<String>,
+    // the size of the recent frames ring buffer
+    pub recent_frames: usize,
+    // the register that is 1 while messages keep arriving, if any
+    pub fresh_register: Option<u16>,
+    // how long without a message before the fresh register goes back to 0
+    pub fresh_timeout: Duration,
+    // the register that changes with every new message, if any
+    pub strobe_register: Option<u16>,
+    // the high and low word registers of the time the last message was written, if any
+    pub last_seen_registers: Option<(u16, u16)>,
+    // where the magnitude of the vibration goes, if anywhere
+    pub vib_magnitude_register: Option<u16>,
+    // where the status word goes, if anywhere, and what sets its bits
+    pub status_register: Option<u16>,
+    pub status_thresholds: Thresholds,
+    // messages with a weaker signal than this are not written to the modbus
+    pub min_rssi: u8,
+    pub max_msgs_per_sec: Option<u32>,
+    // a repeat sooner than this after the frame before it is a flood, if that is looked out for
+    pub min_repeat_interval: Option<Duration>,
+    // how long each device's messages are coalesced for, if they are
+    pub coalesce: Option<Duration>,
+    // writing a message to the modbus taking longer than this again and again means we can't keep up
+    pub max_forward_latency: Option<Duration>,
+    // the frames to read on one device connection before connecting again, None means as many as it sends
+    pub max_frames_per_connection: Option<u64>,
+    // how long the device connection can go without a complete frame before connecting again, None means forever
+    pub device_idle_timeout: Option<Duration>,
+    // how many frames can be read ahead of the modbus writes on another thread, None means they aren't
+    pub read_ahead: Option<usize>,
+    pub read_ahead_full: WhenFull,
+    // how many failed writes in a row open the circuit breaker, None means there isn't one
+    pub circuit_breaker: Option<u32>,
+    pub circuit_breaker_cooldown: Duration,
+    // 2 toggles the strobe between 0 and 1, more counts up to one less than this and wraps
+    pub strobe_modulus: u16,
+    // the register the watchdog counter is written to, if any
+    pub watchdog_register: Option<u16>,
+    // how often the watchdog counter goes up
+    pub watchdog_interval: Duration,
+    // how long without a message before the router logs that it is still alive, None means never
+    pub alive_interval: Option<Duration>,
+    // how often the router logs a line of stats, None means never
+    pub stats_interval: Option<Duration>,
+    // the register selftest writes to, if any
+    pub selftest_register: Option<u16>,
+    // read from the modbus before reading any frames, exiting if that fails
+    pub startup_check: bool,
+    // the values the monitor highlights
+    pub alerts: Vec<Alert>,
+    // the failures to inject, for testing the error handling. Never set in production
+    pub chaos: Option<ChaosConfig>,
+    // whether the monitor writes to the modbus as well
+    pub monitor_write: bool,
+    // log the writes rather than making them
+    pub dry_run: bool,
+    // exit once a message has been written to the modbus
+    pub once: bool,
+    // only log errors
+    pub quiet: bool,
+    // log at the info level
+    pub verbose: bool,
+    // log at the debug level, every message and register write
+    pub very_verbose: bool,
+    // log the bytes of every frame as hex
+    pub log_raw: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            // hardcode the IP address if one has not been passed in
+            device_host: "192.168.1.87:10001".to_string(),
+            device_unix: None,
+            device_ws: None,
+            tls: false,
+            tls_ca: None,
+            tls_server_name: String::new(),
+            device_listen: None,
+            device_listen_max: 1,
+            stdin: false,
+            replay: None,
+            simulate: None,
+            tcp: TcpOptions::default(),
+            modbus_host: "127.0.0.1".to_string(),
+            standby_hosts: vec![],
+            modbus_port: tcp::Config::default().tcp_port,
+            modbus_unit: tcp::Config::default().modbus_uid,
+            serial: None,
+            serial_baud: 9600,
+            serial_parity: Parity::Even,
+            serial_stop_bits: 1,
+            transaction_ids: None,
+            check_transaction_ids: false,
+            log_format: LogFormat::Human,
+            error_policy: ErrorPolicy::default(),
+            error_log: ErrorLogConfig::default(),
+            change: ChangeConfig::default(),
+            sentinels: SentinelConfig::default(),
+            clamps: Vec::new(),
+            log_sampling: LogSamplingConfig::default(),
+            version: VersionConfig::default(),
+            reconnect: ReconnectConfig::default(),
+            register_maps: RegisterMaps::default(),
+            sensor_id_offset: None,
+            sensor_ids: Vec::new(),
+            macs: DEFAULT_MACS.to_vec(),
+            unknown_mac_policy: UnknownMacPolicy::Warn,
+            frame_formats: Vec::new(),
+            checksum: None,
+            constant_bytes: Vec::new(),
+            strict: false,
+            endian: Endian::Little,
+            frame_terminator: None,
+            start_seq: START_SEQ,
+            payload_len: None,
+            payload_crc: false,
+            max_resync: MAX_ALIGNMENT_SCAN,
+            units: Units::default(),
+            write_delay: Duration::from_millis(0),
+            forward_retries: 0,
+            batch_writes: false,
+            verify_writes: false,
+            audit_log: None,
+            write_queue: None,
+            retry_queue: None,
+            raw_sink: None,
+            capture: None,
+            capture_max_bytes: None,
+            capture_keep: 5,
+            json_out: None,
+            state_file: None,
+            csv_out: None,
+            hook_command: None,
+            hook_url: None,
+            grpc: None,
+            grpc_batch: 1,
+            mqtt: None,
+            mqtt_topic_prefix: "sensors".to_string(),
+            mqtt_client_id: "modbusrouter".to_string(),
+            http: None,
+            health_addr: None,
+            ws_addr: None,
+            recent_frames: 100,
+            silence_timeout: None,
+            on_silence: SilenceAction::Warn,
+            fresh_register: None,
+            fresh_timeout: Duration::from_secs(10),
+            strobe_register: None,
+            last_seen_registers: None,
+            vib_magnitude_register: None,
+            status_register: None,
+            status_thresholds: Thresholds::default(),
+            min_rssi: 0,
+            max_msgs_per_sec: None,
+            min_repeat_interval: None,
+            coalesce: None,
+            max_forward_latency: None,
+            max_frames_per_connection: None,
+            device_idle_timeout: None,
+            read_ahead: None,
+            read_ahead_full: WhenFull::Block,
+            circuit_breaker: None,
+            circuit_breaker_cooldown: Duration::from_secs(30),
+            strobe_modulus: 2,
+            watchdog_register: None,
+            watchdog_interval: Duration::from_secs(5),
+            alive_interval: None,
+            stats_interval: Some(Duration::from_secs(60)),
+            selftest_register: None,
+            startup_check: false,
+            alerts: Vec::new(),
+            chaos: None,
+            monitor_write: false,
+            dry_run: false,
+            once: false,
+            quiet: false,
+            verbose: false,
+            very_verbose: false,
+            log_raw: false,
+        }
+    }
+}
+
+// Options that are switched on just by being there, they don't take a value
+const FLAGS: [&str; 17] = [
+    "batch-writes",
+    "check-transaction-ids",
+    "dry-run",
+    "log-raw",
+    "on-change-whole-message",
+    "monitor-write",
+    "once",
+    "payload-crc",
+    "quiet",
+    "startup-check",
+    "stdin",
+    "strict",
+    "tcp-nodelay",
+    "tls",
+    "verbose",
+    "verify-writes",
+    "very-verbose",
+];
+
+// The short forms of the flags that say how much is logged
+const SHORT_FLAGS: [(&str, &str); 3] =
+    [("-q", "quiet"), ("-v", "verbose"), ("-vv", "very-verbose")];
+
+// Options that can be given more than once, in the environment the values are separated by commas
+const REPEATABLE: [&str; 20] = [
+    "modbus-host",
+    "on-error",
+    "on-change",
+    "log-on-change",
+    "write-function",
+    "register",
+    "register-base",
+    "float",
+    "scale",
+    "offset",
+    "lookup",
+    "signedness",
+    "unit",
+    "alert",
+    "frame-format",
+    "sentinel",
+    "status-threshold",
+    "on-sentinel",
+    "constant-byte",
+    "clamp",
+];
+
+// Environment variables are the option name in upper case with underscores, e.g. MODBUSROUTER_DEVICE_HOST
+const ENV_PREFIX: &str = "MODBUSROUTER_";
+
+impl Config {
+    // The config of this run of the router: the options given (without the program name or a subcommand) over
+    // the process's environment and the config file, over the defaults
+    pub fn from_args_and_env<A>(args: A) -> Result<Config, String>
+    where
+        A: IntoIterator<Item = String>,
+    {
+        Config::load(args.into_iter(), env::vars())
+    }
+
+    // Builds the config from every source we know about. Each source overrides the ones before it:
+    // the defaults, then the config file, then the environment and finally the command line
+    pub fn load<A, E>(args: A, env: E) -> Result<Config, String>
+    where
+        A: Iterator<Item = String>,
+        E: Iterator<Item = (String, String)>,
+    {
+        let args: Vec<String> = args.collect();
+        let env: Vec<(String, String)> = env
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+
+        // the file can be named on the command line or in the environment
+        let path = args
+            .iter()
+            .position(|arg| arg == "--config")
+            .map(|i| args.get(i + 1).cloned().ok_or("--config requires a path"))
+            .transpose()?
+            .or_else(|| env_value(&env, "config"));
+        let file = match path {
+            Some(path) => Some(
+                fs::read_to_string(&path)
+                    .map_err(|e| format!("Unable to read config file {}: {}", path, e))?,
+            ),
+            None => None,
+        };
+
+        Config::from_sources(args.into_iter(), env.into_iter(), file.as_deref())
+    }
+
+    // The same as load but with the config file contents passed in
+    pub fn from_sources<A, E>(args: A, env: E, file: Option<&str>) -> Result<Config, String>
+    where
+        A: Iterator<Item = String>,
+        E: Iterator<Item = (String, String)>,
+    {
+        let mut config = Config::default();
+        if let Some(file) = file {
+            config.apply_source(|config| config.apply_file(file))?;
+        }
+        config.apply_source(|config| config.apply_env(env))?;
+        config.apply_source(|config| config.apply_args(args))?;
+        config.register_maps.merge()?;
+        // the device host can also come from the plain positional parameter so it is checked here
+        if !config.stdin
+            && config.replay.is_none()
+            && config.simulate.is_none()
+            && config.device_unix.is_none()
+            && config.device_ws.is_none()
+            && config.device_listen.is_none()
+        {
+            check_host_port(&config.device_host)?;
+        }
+        if !config.sensor_ids.is_empty() && config.sensor_id_offset.is_none() {
+            return Err("--sensor-ids needs --sensor-id-offset".to_string());
+        }
+        if config.device_ws.is_some() && config.device_unix.is_some() {
+            return Err("--device-ws can't be used with --device-unix".to_string());
+        }
+        if config.device_listen.is_some()
+            && (config.stdin || config.device_unix.is_some() || config.device_ws.is_some())
+        {
+            return Err(
+                "--device-listen can't be used with --stdin, --device-unix or --device-ws"
+                    .to_string(),
+            );
+        }
+        if config.device_listen_max > 1 {
+            if config.device_listen.is_none() {
+                return Err("--device-listen-max needs --device-listen".to_string());
+            }
+            // the gateways' frames are told apart before the router sees them, which only works for the standard frame
+            if !config.frame_formats.is_empty() || config.frame_terminator.is_some() {
+                return Err(
+                    "--device-listen-max can't be used with --frame-format or --frame-terminator"
+                        .to_string(),
+                );
+            }
+        }
+        if config.replay.is_some()
+            && (config.stdin
+                || config.device_unix.is_some()
+                || config.device_ws.is_some()
+                || config.device_listen.is_some())
+        {
+            return Err(
+                "--replay can't be used with --stdin, --device-unix, --device-ws or --device-listen"
+                    .to_string(),
+            );
+        }
+        if config.simulate.is_some() {
+            if config.stdin
+                || config.replay.is_some()
+                || config.device_unix.is_some()
+                || config.device_ws.is_some()
+                || config.device_listen.is_some()
+                || config.tls
+            {
+                return Err(
+                    "--simulate can't be used with --stdin, --replay, --device-unix, --device-ws, --device-listen or --tls"
+                        .to_string(),
+                );
+            }
+            // the simulator only makes the standard frame
+            if !config.frame_formats.is_empty() || config.frame_terminator.is_some() {
+                return Err(
+                    "--simulate can't be used with --frame-format or --frame-terminator"
+                        .to_string(),
+                );
+            }
+            if config.start_seq != START_SEQ {
+                return Err("--simulate can't be used with --start-sequence".to_string());
+            }
+        }
+        // the other layouts are told apart from the standard one by its start sequence
+        if config.start_seq != START_SEQ && !config.frame_formats.is_empty() {
+            return Err("--start-sequence can't be used with --frame-format".to_string());
+        }
+        Formats::new(config.frame_formats.clone())?;
+        if config.frame_terminator.is_some() && !config.frame_formats.is_empty() {
+            return Err("--frame-terminator can't be used with --frame-format".to_string());
+        }
+        // the other layouts don't say where a checksum would be
+        if config.checksum.is_some() && !config.frame_formats.is_empty() {
+            return Err("--verify-checksum can't be used with --frame-format".to_string());
+        }
+        // the other layouts put their bytes in other places
+        if !config.constant_bytes.is_empty() && !config.frame_formats.is_empty() {
+            return Err("--constant-byte can't be used with --frame-format".to_string());
+        }
+        if config.payload_len.is_some() && !config.frame_formats.is_empty() {
+            return Err("--payload-len can't be used with --frame-format".to_string());
+        }
+        // the frames with a crc are read and decoded the way the other formats are, which is only for those
+        // options that work with --frame-format as well
+        if config.payload_crc
+            && (config.start_seq != START_SEQ
+                || config.frame_terminator.is_some()
+                || config.checksum.is_some()
+                || !config.constant_bytes.is_empty()
+                || config.payload_len.is_some()
+                || config.device_listen_max > 1
+                || config.simulate.is_some()
+                || config.read_ahead.is_some())
+        {
+            return Err(
+                "--payload-crc can't be used with --start-sequence, --frame-terminator, --verify-checksum, --constant-byte, --payload-len, --device-listen-max, --simulate or --read-ahead"
+                    .to_string(),
+            );
+        }
+        if config.quiet && (config.verbose || config.very_verbose || config.log_raw) {
+            return Err(
+                "--quiet can't be used with --verbose, --very-verbose or --log-raw".to_string(),
+            );
+        }
+        if config.strict && config.constant_bytes.is_empty() {
+            return Err("--strict needs --constant-byte".to_string());
+        }
+        if config.status_thresholds != Thresholds::default() && config.status_register.is_none() {
+            return Err("--status-threshold needs --status-register".to_string());
+        }
+        if config.tls {
+            // a flag in the config file is only looked at once everything is in
+            if !cfg!(feature = "tls") {
+                return Err("--tls needs the router to be built with --features tls".to_string());
+            }
+            if config.tls_ca.is_none() {
+                return Err("--tls needs --tls-ca".to_string());
+            }
+            if config.stdin
+                || config.replay.is_some()
+                || config.device_unix.is_some()
+                || config.device_ws.is_some()
+                || config.device_listen.is_some()
+            {
+                return Err(
+                    "--tls is only for --device-host, it can't be used with --stdin, --replay, --device-unix, --device-ws or --device-listen"
+                        .to_string(),
+                );
+            }
+            if config.tls_server_name.is_empty() {
+                config.tls_server_name = host_name(&config.device_host).to_string();
+            }
+        }
+        // there is no connection to drop, stdin and a capture can't be opened again
+        if config.max_forward_latency.is_some() && (config.stdin || config.replay.is_some()) {
+            return Err("--max-forward-latency can't be used with --stdin or --replay".to_string());
+        }
+        // connecting again to the gateways' pool doesn't close their connections
+        if config.max_frames_per_connection.is_some()
+            && (config.stdin || config.replay.is_some() || config.device_listen_max > 1)
+        {
+            return Err(
+                "--max-frames-per-connection can't be used with --stdin, --replay or --device-listen-max"
+                    .to_string(),
+            );
+        }
+        if config.device_idle_timeout.is_some()
+            && (config.stdin || config.replay.is_some() || config.device_listen_max > 1)
+        {
+            return Err(
+                "--device-idle-timeout can't be used with --stdin, --replay or --device-listen-max"
+                    .to_string(),
+            );
+        }
+        // a read has to come back for the router to notice a gateway that sends nothing at all
+        if let Some(idle) = config.device_idle_timeout {
+            config.tcp.read_timeout = Some(
+                config
+                    .tcp
+                    .read_timeout
+                    .map_or(idle, |timeout| timeout.min(idle)),
+            );
+        }
+        // the reader only knows the standard frames, and the gateways' pool has threads of its own already
+        if config.read_ahead.is_some()
+            && (!config.frame_formats.is_empty()
+                || config.frame_terminator.is_some()
+                || config.device_listen_max > 1)
+        {
+            return Err(
+                "--read-ahead can't be used with --frame-format, --frame-terminator or --device-listen-max"
+                    .to_string(),
+            );
+        }
+        if config.serial.is_some() {
+            if !cfg!(feature = "serial") {
+                return Err(
+                    "--serial needs the router to be built with --features serial".to_string(),
+                );
+            }
+            // RTU has no transaction ids
+            if config.transaction_ids.is_some() || config.check_transaction_ids {
+                return Err(
+                    "--serial can't be used with --transaction-ids or --check-transaction-ids"
+                        .to_string(),
+                );
+            }
+            // they make connections of their own, and only one thing at a time can have the serial port
+            if config.fresh_register.is_some() || config.watchdog_register.is_some() {
+                return Err(
+                    "--serial can't be used with --fresh-register or --watchdog-register"
+                        .to_string(),
+                );
+            }
+            if !config.standby_hosts.is_empty() {
+                return Err("--serial can't be used with more than one --modbus-host".to_string());
+            }
+        }
+        // only one of them holds on to what is written while the modbus is down
+        if config.write_queue.is_some() && config.retry_queue.is_some() {
+            return Err("--retry-queue can't be used with --write-queue".to_string());
+        }
+        Ok(config)
+    }
+
+    // Repeating --modbus-host adds to the servers, but only within the one place the settings come from.
+    // A later place that has any replaces them all, the same as it would a single host
+    fn apply_source<F>(&mut self, apply: F) -> Result<(), String>
+    where
+        F: FnOnce(&mut Config) -> Result<(), String>,
+    {
+        let before = self.standby_hosts.len();
+        apply(self)?;
+        let mut given = self.standby_hosts.split_off(before);
+        if !given.is_empty() {
+            self.modbus_host = given.remove(0);
+            self.standby_hosts = given;
+        }
+        Ok(())
+    }
+
+    // The level the logging flags ask for, which wins over the one RUST_LOG gives everything, the levels it
+    // gives particular modules still apply. None leaves it to RUST_LOG
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        if self.quiet {
+            Some(LevelFilter::Error)
+        } else if self.very_verbose {
+            Some(LevelFilter::Debug)
+        } else if self.verbose {
+            Some(LevelFilter::Info)
+        } else {
+            None
+        }
+    }
+
+    // What gets logged when neither RUST_LOG nor the logging flags say
+    pub fn default_log_level(&self) -> &'static str {
+        if self.log_raw {
+            "debug"
+        } else {
+            "info"
+        }
+    }
+
+    // The serial port to write to, if there is one
+    #[cfg_attr(not(feature = "serial"), allow(dead_code))]
+    pub fn serial_config(&self) -> Option<SerialConfig> {
+        self.serial.as_ref().map(|device| SerialConfig {
+            device: device.clone(),
+            baud: self.serial_baud,
+            parity: self.serial_parity,
+            stop_bits: self.serial_stop_bits,
+        })
+    }
+
+    // Lines of key = value, e.g. device-host = 192.168.1.1:5000
+    // Blank lines and lines starting with # are ignored, flags take true or false
+    fn apply_file(&mut self, file: &str) -> Result<(), String> {
+        for (number, line) in file.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts
+                .next()
+                .ok_or_else(|| {
+                    format!(
+                        "Expected key = value on line {} of the config file",
+                        number + 1
+                    )
+                })?
+                .trim();
+            self.apply_setting(key, value)
+                .map_err(|e| format!("{} (line {} of the config file)", e, number + 1))?;
+        }
+        Ok(())
+    }
+
+    fn apply_env<E: Iterator<Item = (String, String)>>(&mut self, env: E) -> Result<(), String> {
+        for (name, value) in env {
+            if !name.starts_with(ENV_PREFIX) {
+                continue;
+            }
+            let key = name[ENV_PREFIX.len()..]
+                .to_ascii_lowercase()
+                .replace('_', "-");
+            // the config file has already been read by now
+            if key == "config" {
+                continue;
+            }
+            let values: Vec<&str> = if REPEATABLE.contains(&key.as_str()) {
+                value.split(',').map(str::trim).collect()
+            } else {
+                vec![value.as_str()]
+            };
+            for value in values {
+                self.apply_setting(&key, value)
+                    .map_err(|e| format!("{} (from {})", e, name))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Parses the console application parameters (excluding the program name)
+    // The hostname remains a plain positional parameter so existing scripts keep working
+    fn apply_args<I: Iterator<Item = String>>(&mut self, mut args: I) -> Result<(), String> {
+        while let Some(arg) = args.next() {
+            let short = SHORT_FLAGS.iter().find(|(short, _)| *short == arg);
+            if let Some((_, key)) = short {
+                self.apply(key, None)?;
+            } else if let Some(key) = arg.strip_prefix("--") {
+                if FLAGS.contains(&key) {
+                    self.apply(key, None)?;
+                } else if key == "config" {
+                    // read by load
+                    args.next();
+                } else {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("{} requires a value", arg))?;
+                    self.apply(key, Some(&value))?;
+                }
+            } else {
+                self.device_host = arg;
+            }
+        }
+        Ok(())
+    }
+
+    // Settings from the file and the environment always have a value, for flags it is true or false
+    fn apply_setting(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if FLAGS.contains(&key) {
+            match value {
+                "true" => self.apply(key, None),
+                "false" => self.clear_flag(key),
+                _ => Err(format!(
+                    "Expected true or false for {} but got: {}",
+                    key, value
+                )),
+            }
+        } else {
+            self.apply(key, Some(value))
+        }
+    }
+
+    fn clear_flag(&mut self, key: &str) -> Result<(), String> {
+        match key {
+            "on-change-whole-message" => self.change.whole_message = false,
+            "tcp-nodelay" => self.tcp.nodelay = false,
+            "stdin" => self.stdin = false,
+            "monitor-write" => self.monitor_write = false,
+            "dry-run" => self.dry_run = false,
+            "once" => self.once = false,
+            "payload-crc" => self.payload_crc = false,
+            "strict" => self.strict = false,
+            "batch-writes" => self.batch_writes = false,
+            "verify-writes" => self.verify_writes = false,
+            "tls" => self.tls = false,
+            "quiet" => self.quiet = false,
+            "verbose" => self.verbose = false,
+            "very-verbose" => self.very_verbose = false,
+            "log-raw" => self.log_raw = false,
+            "startup-check" => self.startup_check = false,
+            _ => return Err(format!("Unknown option: {}", key)),
+        }
+        Ok(())
+    }
+
+    // Applies a single option, the value is only None for flags
+    fn apply(&mut self, key: &str, value: Option<&str>) -> Result<(), String> {
+        let value = value.unwrap_or("");
+        match key {
+            "device-host" => self.device_host = value.to_string(),
+            "device-unix" => self.device_unix = Some(UnixSocket::Connect(value.into())),
+            "device-unix-listen" => self.device_unix = Some(UnixSocket::Listen(value.into())),
+            "device-ws" => {
+                websocket::host_of(value)?;
+                self.device_ws = Some(value.to_string());
+            }
+            "replay" => self.replay = Some(value.into()),
+            "simulate" => {
+                let rate: f64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid simulated frame rate: {}", value))?;
+                if !rate.is_finite() || rate <= 0.0 {
+                    return Err("The simulated frame rate must be more than 0".to_string());
+                }
+                self.simulate = Some(rate);
+            }
+            "tls-ca" => self.tls_ca = Some(value.into()),
+            "tls-server-name" => {
+                if value.is_empty() {
+                    return Err("The TLS server name can't be empty".to_string());
+                }
+                self.tls_server_name = value.to_string();
+            }
+            "device-listen" => {
+                check_host_port(value)?;
+                self.device_listen = Some(value.to_string());
+            }
+            "device-listen-max" => {
+                let max: usize = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of gateways: {}", value))?;
+                if max == 0 {
+                    return Err("--device-listen-max must be at least 1".to_string());
+                }
+                self.device_listen_max = max;
+            }
+            "tcp-keepalive-idle" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid keepalive idle time: {}", value))?;
+                self.tcp.keepalive_idle = match secs {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                };
+            }
+            "tcp-keepalive-interval" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid keepalive interval: {}", value))?;
+                if secs == 0 {
+                    return Err("The keepalive interval must be at least 1s".to_string());
+                }
+                self.tcp.keepalive_interval = Duration::from_secs(secs);
+            }
+            "tcp-keepalive-count" => {
+                let count: u32 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid keepalive count: {}", value))?;
+                if count == 0 {
+                    return Err("The keepalive count must be at least 1".to_string());
+                }
+                self.tcp.keepalive_count = count;
+            }
+            "tcp-nodelay" => self.tcp.nodelay = true,
+            "tcp-read-timeout" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid read timeout: {}", value))?;
+                self.tcp.read_timeout = match secs {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                };
+            }
+            "stdin" => self.stdin = true,
+            "modbus-host" => {
+                if value.is_empty() {
+                    return Err("The modbus host can't be empty".to_string());
+                }
+                // sorted out by apply_source once the rest of the settings from the same place are in
+                self.standby_hosts.push(value.to_string());
+            }
+            "modbus-port" => self.modbus_port = parse_port(value)?,
+            "modbus-unit" => {
+                self.modbus_unit = value
+                    .parse()
+                    .map_err(|_| format!("Invalid modbus unit id: {}", value))?
+            }
+            "serial" => {
+                if value.is_empty() {
+                    return Err("--serial needs a device, e.g. /dev/ttyUSB0".to_string());
+                }
+                self.serial = Some(value.to_string());
+            }
+            "serial-baud" => {
+                self.serial_baud = value
+                    .parse()
+                    .ok()
+                    .filter(|baud| *baud > 0)
+                    .ok_or_else(|| format!("Invalid baud rate: {}", value))?
+            }
+            "serial-parity" => {
+                self.serial_parity = Parity::from_name(value).ok_or_else(|| {
+                    format!("Unknown parity, expected none, even or odd: {}", value)
+                })?
+            }
+            "serial-stop-bits" => {
+                self.serial_stop_bits = match value {
+                    "1" => 1,
+                    "2" => 2,
+                    _ => return Err(format!("Expected 1 or 2 stop bits but got: {}", value)),
+                }
+            }
+            "transaction-ids" => self.transaction_ids = Some(TransactionIds::parse(value)?),
+            "check-transaction-ids" => self.check_transaction_ids = true,
+            "log-format" => {
+                self.log_format = match value {
+                    "human" => LogFormat::Human,
+                    "json" => LogFormat::Json,
+                    _ => return Err(format!("Unknown log format: {}", value)),
+                };
+            }
+            "on-error" => self.error_policy.parse_rule(value)?,
+            "error-log-window" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid error log window: {}", value))?;
+                self.error_log.window = Duration::from_secs(secs);
+            }
+            "error-log-threshold" => {
+                let threshold: u32 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid error log threshold: {}", value))?;
+                if threshold == 0 {
+                    return Err("The error log threshold must be at least 1".to_string());
+                }
+                self.error_log.threshold = threshold;
+            }
+            "on-change" => {
+                let (field, deadband) = parse_on_change(value)?;
+                self.change.deadbands.insert(field, deadband);
+            }
+            "on-change-whole-message" => self.change.whole_message = true,
+            "sentinel" => self.sentinels.parse_value(value)?,
+            "on-sentinel" => self.sentinels.parse_action(value)?,
+            "clamp" => self.clamps.push(parse_clamp(value)?),
+            "log-on-change" => {
+                let (field, deadband) = parse_on_change(value)?;
+                self.log_sampling.deadbands.insert(field, deadband);
+            }
+            "min-version" => {
+                let version = value
+                    .parse()
+                    .map_err(|_| format!("Invalid version: {}", value))?;
+                self.version.min = Some(version);
+            }
+            "expect-version" => {
+                let version = value
+                    .parse()
+                    .map_err(|_| format!("Invalid version: {}", value))?;
+                self.version.expected = Some(version);
+            }
+            "min-rssi" => {
+                self.min_rssi = value
+                    .parse()
+                    .map_err(|_| format!("Invalid rssi, expected 0 to 255: {}", value))?;
+            }
+            "max-msgs-per-sec" => match value.parse() {
+                Ok(max) if max > 0 => self.max_msgs_per_sec = Some(max),
+                _ => return Err(format!("Invalid number of messages a second: {}", value)),
+            },
+            "min-repeat-interval" => match value.parse() {
+                Ok(ms) if ms > 0 => self.min_repeat_interval = Some(Duration::from_millis(ms)),
+                _ => return Err(format!("Invalid repeat interval: {}", value)),
+            },
+            "coalesce" => match value.parse() {
+                Ok(ms) if ms > 0 => self.coalesce = Some(Duration::from_millis(ms)),
+                _ => return Err(format!("Invalid coalescing window: {}", value)),
+            },
+            "max-forward-latency" => match value.parse() {
+                Ok(ms) if ms > 0 => self.max_forward_latency = Some(Duration::from_millis(ms)),
+                _ => return Err(format!("Invalid forward latency: {}", value)),
+            },
+            "max-frames-per-connection" => match value.parse() {
+                Ok(frames) if frames > 0 => self.max_frames_per_connection = Some(frames),
+                _ => return Err(format!("Invalid number of frames: {}", value)),
+            },
+            "device-idle-timeout" => match value.parse() {
+                Ok(secs) if secs > 0 => self.device_idle_timeout = Some(Duration::from_secs(secs)),
+                _ => return Err(format!("Invalid idle timeout: {}", value)),
+            },
+            "read-ahead" => match value.parse() {
+                Ok(frames) if frames > 0 => self.read_ahead = Some(frames),
+                _ => return Err(format!("Invalid number of frames: {}", value)),
+            },
+            "read-ahead-full" => {
+                self.read_ahead_full = WhenFull::from_name(value)
+                    .ok_or_else(|| format!("Invalid read ahead action: {}", value))?
+            }
+            "circuit-breaker" => match value.parse() {
+                Ok(failures) if failures > 0 => self.circuit_breaker = Some(failures),
+                _ => return Err(format!("Invalid number of failed writes: {}", value)),
+            },
+            "circuit-breaker-cooldown" => match value.parse() {
+                Ok(secs) if secs > 0 => self.circuit_breaker_cooldown = Duration::from_secs(secs),
+                _ => return Err(format!("Invalid circuit breaker cooldown: {}", value)),
+            },
+            "on-version-mismatch" => {
+                self.version.action = MismatchAction::from_name(value)
+                    .ok_or_else(|| format!("Unknown version mismatch action: {}", value))?;
+            }
+            "log-interval" => {
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("Invalid log interval: {}", value))?;
+                self.log_sampling.interval = Some(Duration::from_secs(secs));
+            }
+            "max-reconnects" => {
+                let max = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of reconnects: {}", value))?;
+                self.reconnect.max_attempts = Some(max);
+            }
+            "reconnect-escalation" => {
+                self.reconnect.escalation = Escalation::from_name(value)
+                    .ok_or_else(|| format!("Unknown reconnect escalation: {}", value))?;
+            }
+            "write-function" => self.register_maps.parse_write_function(value)?,
+            "register" => self.register_maps.parse_address(value)?,
+            "register-base" => self.register_maps.parse_base(value)?,
+            "fields" => self.register_maps.parse_enabled(value)?,
+            "frame-format" => self.frame_formats.push(FrameFormat::parse(value)?),
+            "constant-byte" => {
+                let invalid = || format!("Expected <byte>=<hex value> but got: {}", value);
+                let (offset, expected) = value.split_once('=').ok_or_else(invalid)?;
+                let offset: usize = offset.trim().parse().map_err(|_| invalid())?;
+                if offset >= FRAME_LEN {
+                    return Err(format!(
+                        "The constant byte must be between 0 and {}",
+                        FRAME_LEN - 1
+                    ));
+                }
+                let expected = match parse_hex(expected.trim())?.as_slice() {
+                    [expected] => *expected,
+                    _ => return Err(invalid()),
+                };
+                self.constant_bytes.push((offset, expected));
+            }
+            "verify-checksum" => {
+                let checksum = Checksum::from_name(value)
+                    .ok_or_else(|| format!("Expected xor or sum but got: {}", value))?;
+                self.checksum = Some(checksum);
+            }
+            "endian" => {
+                self.endian = Endian::from_name(value)
+                    .ok_or_else(|| format!("Invalid endian: {}, expected little or big", value))?;
+            }
+            "frame-terminator" => self.frame_terminator = Some(parse_hex(value)?),
+            "payload-len" => {
+                let len = match parse_hex(value)?.as_slice() {
+                    [len] => *len,
+                    _ => return Err(format!("The payload length must be 1 byte: {}", value)),
+                };
+                if !(PAYLOAD_LEN..=MAX_PAYLOAD_LEN).contains(&len) {
+                    return Err(format!(
+                        "The payload length must be between {:02x} and {:02x}: {}",
+                        PAYLOAD_LEN, MAX_PAYLOAD_LEN, value
+                    ));
+                }
+                self.payload_len = Some(len);
+            }
+            "start-sequence" => {
+                let bytes = parse_hex(value)?;
+                if bytes.len() != START_SEQ.len() {
+                    return Err(format!("The start sequence must be 2 bytes: {}", value));
+                }
+                self.start_seq.copy_from_slice(&bytes);
+            }
+            "max-resync-bytes" => {
+                self.max_resync = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of bytes: {}", value))?;
+            }
+            "sensor-id-offset" => {
+                let offset: usize = value
+                    .parse()
+                    .map_err(|_| format!("Invalid sensor id offset: {}", value))?;
+                // the header (start sequence, MAC and length) can't hold a sensor id
+                if !(9..FRAME_LEN).contains(&offset) {
+                    return Err(format!(
+                        "The sensor id offset must be between 9 and {}",
+                        FRAME_LEN - 1
+                    ));
+                }
+                self.sensor_id_offset = Some(offset);
+            }
+            "sensor-ids" => {
+                self.sensor_ids = value
+                    .split(',')
+                    .map(|id| {
+                        id.trim()
+                            .parse()
+                            .map_err(|_| format!("Invalid sensor id: {}", id))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "macs" => {
+                self.macs = value
+                    .split(',')
+                    .map(|mac| {
+                        parse_mac(mac.trim()).ok_or_else(|| format!("Invalid MAC address: {}", mac))
+                    })
+                    .collect::<Result<_, _>>()?;
+            }
+            "unknown-mac-policy" => {
+                self.unknown_mac_policy = UnknownMacPolicy::from_name(value)
+                    .ok_or_else(|| format!("Invalid unknown MAC policy: {}", value))?;
+            }
+            "float" => self.register_maps.parse_float(value)?,
+            "scale" => self.register_maps.parse_scale(value)?,
+            "offset" => self.register_maps.parse_offset(value)?,
+            "lookup" => self.register_maps.parse_lookup(value)?,
+            "signedness" => self.register_maps.parse_signedness(value)?,
+            "unit" => self.units.parse(value)?,
+            "forward-retries" => {
+                self.forward_retries = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of retries: {}", value))?;
+            }
+            "write-delay" => {
+                let ms = value
+                    .parse()
+                    .map_err(|_| format!("Invalid write delay: {}", value))?;
+                self.write_delay = Duration::from_millis(ms);
+            }
+            "write-queue" => {
+                let capacity = value
+                    .parse()
+                    .map_err(|_| format!("Invalid write queue size: {}", value))?;
+                self.write_queue = Some(capacity);
+            }
+            "retry-queue" => {
+                let capacity = value
+                    .parse()
+                    .map_err(|_| format!("Invalid retry queue size: {}", value))?;
+                self.retry_queue = Some(capacity);
+            }
+            "raw-sink" => self.raw_sink = Some(value.to_string()),
+            "capture" => self.capture = Some(value.to_string()),
+            "json-out" => {
+                if value.is_empty() {
+                    return Err("--json-out needs a path, or - for stdout".to_string());
+                }
+                self.json_out = Some(value.to_string());
+            }
+            "state-file" => {
+                if value.is_empty() {
+                    return Err("--state-file needs a path".to_string());
+                }
+                self.state_file = Some(value.to_string());
+            }
+            "audit-log" => {
+                if value.is_empty() {
+                    return Err("--audit-log needs a path".to_string());
+                }
+                self.audit_log = Some(value.to_string());
+            }
+            "csv-out" => {
+                if value.is_empty() {
+                    return Err("--csv-out needs a path, or - for stdout".to_string());
+                }
+                self.csv_out = Some(value.to_string());
+            }
+            "capture-max-bytes" => {
+                let bytes: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid capture file size: {}", value))?;
+                if bytes == 0 {
+                    return Err("The capture file size must be at least 1 byte".to_string());
+                }
+                self.capture_max_bytes = Some(bytes);
+            }
+            "capture-keep" => {
+                self.capture_keep = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of capture files: {}", value))?;
+            }
+            // deliberately left out of the usage, it is only for testing the router itself
+            "chaos" => self.chaos = Some(ChaosConfig::parse(value)?),
+            "hook-command" => self.hook_command = Some(value.to_string()),
+            "hook-url" => self.hook_url = Some(HookUrl::parse(value)?),
+            "grpc" => {
+                if !cfg!(feature = "grpc") {
+                    return Err(
+                        "--grpc needs the router to be built with --features grpc".to_string()
+                    );
+                }
+                self.grpc = Some(value.to_string());
+            }
+            "grpc-batch" => {
+                let batch = value
+                    .parse()
+                    .map_err(|_| format!("Invalid gRPC batch size: {}", value))?;
+                if batch == 0 {
+                    return Err("The gRPC batch size must be at least 1".to_string());
+                }
+                self.grpc_batch = batch;
+            }
+            "mqtt" => {
+                if !cfg!(feature = "mqtt") {
+                    return Err(
+                        "--mqtt needs the router to be built with --features mqtt".to_string()
+                    );
+                }
+                check_host_port(value)?;
+                self.mqtt = Some(value.to_string());
+            }
+            "mqtt-topic-prefix" => {
+                // the topics are built from the prefix so it can't hold the wildcards
+                let prefix = value.trim_end_matches('/');
+                if prefix.is_empty() || prefix.contains(['+', '#']) {
+                    return Err(format!("Invalid MQTT topic prefix: {}", value));
+                }
+                self.mqtt_topic_prefix = prefix.to_string();
+            }
+            "mqtt-client-id" => {
+                if value.is_empty() {
+                    return Err("The MQTT client id can't be empty".to_string());
+                }
+                self.mqtt_client_id = value.to_string();
+            }
+            "http" => self.http = Some(value.to_string()),
+            "health-addr" => self.health_addr = Some(value.to_string()),
+            "ws-addr" => self.ws_addr = Some(value.to_string()),
+            "recent-frames" => {
+                self.recent_frames = value
+                    .parse()
+                    .map_err(|_| format!("Invalid number of recent frames: {}", value))?;
+            }
+            "fresh-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.fresh_register = Some(address);
+            }
+            "silence-timeout" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid silence timeout: {}", value))?;
+                if secs == 0 {
+                    return Err("The silence timeout must be at least 1s".to_string());
+                }
+                self.silence_timeout = Some(Duration::from_secs(secs));
+            }
+            "on-silence" => {
+                self.on_silence = SilenceAction::from_name(value)
+                    .ok_or_else(|| format!("Unknown silence action: {}", value))?;
+            }
+            "fresh-timeout" => {
+                let secs = value
+                    .parse()
+                    .map_err(|_| format!("Invalid fresh timeout: {}", value))?;
+                self.fresh_timeout = Duration::from_secs(secs);
+            }
+            "strobe-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.strobe_register = Some(address);
+            }
+            "last-seen-register" => {
+                let invalid = || format!("Expected <high>,<low> registers but got: {}", value);
+                let (high, low) = value.split_once(',').ok_or_else(invalid)?;
+                let high = high.trim().parse().map_err(|_| invalid())?;
+                let low = low.trim().parse().map_err(|_| invalid())?;
+                if high == low {
+                    return Err(format!(
+                        "The high and low words of --last-seen-register can't both go in register {}",
+                        high
+                    ));
+                }
+                self.last_seen_registers = Some((high, low));
+            }
+            "vib-magnitude-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.vib_magnitude_register = Some(address);
+            }
+            "status-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.status_register = Some(address);
+            }
+            "status-threshold" => self.status_thresholds.parse(value)?,
+            "strobe-modulus" => {
+                let modulus: u16 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid strobe modulus: {}", value))?;
+                if modulus < 2 {
+                    return Err("The strobe modulus must be at least 2".to_string());
+                }
+                self.strobe_modulus = modulus;
+            }
+            "watchdog-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.watchdog_register = Some(address);
+            }
+            "watchdog-interval" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid watchdog interval: {}", value))?;
+                if secs == 0 {
+                    return Err("The watchdog interval must be at least 1s".to_string());
+                }
+                self.watchdog_interval = Duration::from_secs(secs);
+            }
+            "alive-interval" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid alive interval: {}", value))?;
+                if secs == 0 {
+                    return Err("The alive interval must be at least 1s".to_string());
+                }
+                self.alive_interval = Some(Duration::from_secs(secs));
+            }
+            "stats-interval" => {
+                let secs: u64 = value
+                    .parse()
+                    .map_err(|_| format!("Invalid stats interval: {}", value))?;
+                self.stats_interval = match secs {
+                    0 => None,
+                    secs => Some(Duration::from_secs(secs)),
+                };
+            }
+            "selftest-register" => {
+                let address = value
+                    .parse()
+                    .map_err(|_| format!("Invalid register address: {}", value))?;
+                self.selftest_register = Some(address);
+            }
+            "alert" => self.alerts.push(Alert::parse(value)?),
+            "monitor-write" => self.monitor_write = true,
+            "dry-run" => self.dry_run = true,
+            "once" => self.once = true,
+            "payload-crc" => self.payload_crc = true,
+            "strict" => self.strict = true,
+            "batch-writes" => self.batch_writes = true,
+            "verify-writes" => self.verify_writes = true,
+            "tls" => self.tls = true,
+            "quiet" => self.quiet = true,
+            "verbose" => self.verbose = true,
+            "very-verbose" => self.very_verbose = true,
+            "log-raw" => self.log_raw = true,
+            "startup-check" => self.startup_check = true,
+            _ => return Err(format!("Unknown option: --{}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn env_value(env: &[(String, String)], key: &str) -> Option<String> {
+    let name = format!(
+        "{}{}",
+        ENV_PREFIX,
+        key.to_ascii_uppercase().replace('-', "_")
+    );
+    env.iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, value)| value.clone())
+}
+
+// Parses field[=deadband], e.g. temperature=2
+fn parse_on_change(value: &str) -> Result<(Field, u16), String> {
+    let mut parts = value.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+    let deadband = match parts.next() {
+        Some(deadband) => deadband
+            .parse()
+            .map_err(|_| format!("Invalid deadband: {}", deadband))?,
+        None => 0,
+    };
+    Ok((field, deadband))
+}
+
+// e.g. battery=0:100
+fn parse_clamp(value: &str) -> Result<(Field, u16, u16), String> {
+    let invalid = || format!("Expected <field>=<min>:<max> but got: {}", value);
+    let (name, range) = value.split_once('=').ok_or_else(invalid)?;
+    let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+    let (min, max) = range.split_once(':').ok_or_else(invalid)?;
+    match (min.parse(), max.parse()) {
+        (Ok(min), Ok(max)) if min <= max => Ok((field, min, max)),
+        _ => Err(invalid()),
+    }
+}
+
+fn parse_port(value: &str) -> Result<u16, String> {
+    match value.parse() {
+        Ok(port) if port > 0 => Ok(port),
+        _ => Err(format!("Invalid port: {}", value)),
+    }
+}
+
+// A host name or address followed by its port, e.g. 192.168.1.87:10001 or [::1]:10001
+fn check_host_port(value: &str) -> Result<(), String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() => parse_port(port).map(|_| ()),
+        _ => Err(format!(
+            "Expected the device host as <host>:<port> but got: {}",
+            value
+        )),
+    }
+}
+
+// The host of a <host>:<port> without the port, e.g. gateway.local:10001 is gateway.local and [::1]:10001 is ::1
+fn host_name(value: &str) -> &str {
+    let host = value.rsplit_once(':').map_or(value, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // just the command line on top of the defaults
+    fn from_args<I: Iterator<Item = String>>(args: I) -> Result<Config, String> {
+        Config::from_sources(args, std::iter::empty(), None)
+    }
+
+    fn args(list: &[&str]) -> impl Iterator<Item = String> {
+        list.iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn from_args_defaults() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.device_host, "192.168.1.87:10001");
+        assert_eq!(config.modbus_host, "127.0.0.1");
+        assert_eq!(config.log_format, LogFormat::Human);
+        assert_eq!(config.reconnect.max_attempts, None);
+        assert_eq!(config.write_delay, Duration::from_millis(0));
+        assert_eq!(config.raw_sink, None);
+        assert_eq!(config.device_unix, None);
+    }
+
+    #[test]
+    fn from_args_and_env_is_the_one_place_settings_come_from() {
+        // the test process has no MODBUSROUTER_ variables of its own
+        let config =
+            Config::from_args_and_env(args(&["192.168.1.90:10001", "--modbus-port", "1502"]))
+                .unwrap();
+        assert_eq!(config.device_host, "192.168.1.90:10001");
+        assert_eq!(config.modbus_port, 1502);
+        // and what used to be constants in frame.rs are its defaults
+        assert_eq!(config.start_seq, START_SEQ);
+        assert!(Config::from_args_and_env(args(&["--modbus-port", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_device_ws() {
+        let config = from_args(args(&["--device-ws", "ws://10.0.0.5:8080/frames"])).unwrap();
+        assert_eq!(
+            config.device_ws,
+            Some("ws://10.0.0.5:8080/frames".to_string())
+        );
+        assert!(from_args(args(&["--device-ws", "wss://10.0.0.5/frames"])).is_err());
+        assert!(from_args(args(&[
+            "--device-ws",
+            "ws://10.0.0.5/frames",
+            "--device-unix",
+            "/run/gateway.sock"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_device_listen() {
+        assert_eq!(from_args(args(&[])).unwrap().device_listen, None);
+        let config = from_args(args(&["--device-listen", "0.0.0.0:10001"])).unwrap();
+        assert_eq!(config.device_listen, Some("0.0.0.0:10001".to_string()));
+        assert!(from_args(args(&["--device-listen", "0.0.0.0"])).is_err());
+        assert!(from_args(args(&["--device-listen", "0.0.0.0:10001", "--stdin"])).is_err());
+
+        assert_eq!(from_args(args(&[])).unwrap().device_listen_max, 1);
+        let config = from_args(args(&[
+            "--device-listen",
+            "0.0.0.0:10001",
+            "--device-listen-max",
+            "4",
+        ]))
+        .unwrap();
+        assert_eq!(config.device_listen_max, 4);
+        assert!(from_args(args(&["--device-listen-max", "4"])).is_err());
+        assert!(from_args(args(&[
+            "--device-listen",
+            "0.0.0.0:10001",
+            "--device-listen-max",
+            "0"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_replay() {
+        let config = from_args(args(&["--replay", "capture.bin"])).unwrap();
+        assert_eq!(config.replay, Some("capture.bin".into()));
+        assert_eq!(from_args(args(&[])).unwrap().replay, None);
+        assert!(from_args(args(&["--replay", "capture.bin", "--stdin"])).is_err());
+        assert!(from_args(args(&[
+            "--replay",
+            "capture.bin",
+            "--device-ws",
+            "ws://10.0.0.5/frames"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_simulate() {
+        assert_eq!(from_args(args(&[])).unwrap().simulate, None);
+        let config = from_args(args(&["--simulate", "0.5"])).unwrap();
+        assert_eq!(config.simulate, Some(0.5));
+        // there is no device to connect to
+        let config = from_args(args(&["--simulate", "100", "--device-host", "gateway"])).unwrap();
+        assert_eq!(config.simulate, Some(100.0));
+        assert!(from_args(args(&["--simulate", "0"])).is_err());
+        assert!(from_args(args(&["--simulate", "-1"])).is_err());
+        assert!(from_args(args(&["--simulate", "fast"])).is_err());
+        assert!(from_args(args(&["--simulate", "10", "--stdin"])).is_err());
+        assert!(from_args(args(&["--simulate", "10", "--frame-terminator", "0d0a"])).is_err());
+    }
+
+    #[test]
+    fn from_args_device_unix() {
+        let config = from_args(args(&["--device-unix", "/run/gateway.sock"])).unwrap();
+        assert_eq!(
+            config.device_unix,
+            Some(UnixSocket::Connect("/run/gateway.sock".into()))
+        );
+        let config = from_args(args(&["--device-unix-listen", "/run/router.sock"])).unwrap();
+        assert_eq!(
+            config.device_unix,
+            Some(UnixSocket::Listen("/run/router.sock".into()))
+        );
+    }
+
+    #[test]
+    fn from_args_transaction_ids() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.transaction_ids, None);
+        assert!(!config.check_transaction_ids);
+        let config =
+            from_args(args(&["--check-transaction-ids", "--transaction-ids", "0"])).unwrap();
+        assert_eq!(config.transaction_ids, Some(TransactionIds::Fixed(0)));
+        assert!(config.check_transaction_ids);
+        assert!(from_args(args(&["--transaction-ids", "random"])).is_err());
+    }
+
+    #[test]
+    fn from_args_host_and_log_format() {
+        let config = from_args(args(&[
+            "--log-format",
+            "json",
+            "192.168.1.1:5000",
+            "--raw-sink",
+            "10.0.0.1:9000",
+        ]))
+        .unwrap();
+        assert_eq!(config.device_host, "192.168.1.1:5000");
+        assert_eq!(config.raw_sink, Some("10.0.0.1:9000".to_string()));
+        assert_eq!(config.http, None);
+        assert_eq!(config.recent_frames, 100);
+        assert_eq!(config.log_format, LogFormat::Json);
+    }
+
+    #[test]
+    fn from_args_hooks() {
+        let config = from_args(args(&[
+            "--hook-command",
+            "/usr/local/bin/alert.sh",
+            "--hook-url",
+            "http://10.0.0.5:8080/alerts",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.hook_command,
+            Some("/usr/local/bin/alert.sh".to_string())
+        );
+        assert_eq!(
+            config.hook_url.map(|url| url.addr),
+            Some("10.0.0.5:8080".to_string())
+        );
+        assert!(from_args(args(&["--hook-url", "https://10.0.0.5"])).is_err());
+    }
+
+    #[test]
+    fn from_args_stdin() {
+        assert!(!from_args(args(&[])).unwrap().stdin);
+        assert!(from_args(args(&["--stdin"])).unwrap().stdin);
+    }
+
+    #[test]
+    fn from_args_version() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.version, VersionConfig::default());
+
+        let config = from_args(args(&[
+            "--min-version",
+            "2",
+            "--expect-version",
+            "3",
+            "--on-version-mismatch",
+            "drop",
+        ]))
+        .unwrap();
+        assert_eq!(config.version.min, Some(2));
+        assert_eq!(config.version.expected, Some(3));
+        assert_eq!(config.version.action, MismatchAction::Drop);
+        assert!(from_args(args(&["--on-version-mismatch", "ignore"])).is_err());
+        assert!(from_args(args(&["--min-version", "256"])).is_err());
+    }
+
+    #[test]
+    fn from_args_frame_format() {
+        let format = "model-b:start=1a00:len=31:mac=2:rssi=10:battery=12:temperature=14:vibration=16:msg-num=23:version=26";
+        let config = from_args(args(&["--frame-format", format])).unwrap();
+        assert_eq!(config.frame_formats.len(), 1);
+        assert_eq!(config.frame_formats[0].name, "model-b");
+        // the standard frames already start with 1900
+        let clash = format.replace("start=1a00", "start=1900");
+        assert!(from_args(args(&["--frame-format", &clash])).is_err());
+        assert!(from_args(args(&["--frame-format", "model-b:start=1a00"])).is_err());
+    }
+
+    #[test]
+    fn from_args_frame_terminator() {
+        assert_eq!(from_args(args(&[])).unwrap().frame_terminator, None);
+        let config = from_args(args(&["--frame-terminator", "0d0a"])).unwrap();
+        assert_eq!(config.frame_terminator, Some(vec![0x0D, 0x0A]));
+        assert!(from_args(args(&["--frame-terminator", "0d0"])).is_err());
+        assert!(from_args(args(&["--frame-terminator", "zz"])).is_err());
+    }
+
+    #[test]
+    fn from_args_start_sequence() {
+        assert_eq!(from_args(args(&[])).unwrap().start_seq, [0x19, 0x00]);
+        let config = from_args(args(&["--start-sequence", "1a00"])).unwrap();
+        assert_eq!(config.start_seq, [0x1A, 0x00]);
+        assert!(from_args(args(&["--start-sequence", "1a"])).is_err());
+        assert!(from_args(args(&["--start-sequence", "1a0000"])).is_err());
+        assert!(from_args(args(&["--start-sequence", "zz00"])).is_err());
+        assert!(from_args(args(&["--start-sequence", "1a00", "--simulate", "10"])).is_err());
+        // the default is fine with anything
+        assert!(from_args(args(&["--start-sequence", "1900", "--simulate", "10"])).is_ok());
+    }
+
+    #[test]
+    fn from_args_payload_crc() {
+        assert!(!from_args(args(&[])).unwrap().payload_crc);
+        assert!(from_args(args(&["--payload-crc"])).unwrap().payload_crc);
+        assert!(from_args(args(&[
+            "--payload-crc",
+            "--frame-format",
+            "model-b:start=1a00:len=31:mac=2:rssi=10:battery=12:temperature=14:vibration=16:msg-num=23:version=26"
+        ]))
+        .is_ok());
+        assert!(from_args(args(&["--payload-crc", "--verify-checksum", "xor"])).is_err());
+        assert!(from_args(args(&["--payload-crc", "--read-ahead", "4"])).is_err());
+    }
+
+    #[test]
+    fn from_args_payload_len() {
+        assert_eq!(from_args(args(&[])).unwrap().payload_len, None);
+        let config = from_args(args(&["--payload-len", "14"])).unwrap();
+        assert_eq!(config.payload_len, Some(0x14));
+        assert!(from_args(args(&["--payload-len", "11"])).is_err());
+        assert!(from_args(args(&["--payload-len", "41"])).is_err());
+        assert!(from_args(args(&["--payload-len", "0014"])).is_err());
+        assert!(from_args(args(&[
+            "--payload-len",
+            "14",
+            "--frame-format",
+            "model-b:start=1a00:len=31:mac=2:rssi=10:battery=12:temperature=14:vibration=16:msg-num=23:version=26"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_max_resync_bytes() {
+        assert_eq!(from_args(args(&[])).unwrap().max_resync, 216);
+        let config = from_args(args(&["--max-resync-bytes", "1000"])).unwrap();
+        assert_eq!(config.max_resync, 1000);
+        assert!(from_args(args(&["--max-resync-bytes", "-1"])).is_err());
+    }
+
+    #[test]
+    fn from_args_chaos() {
+        assert_eq!(from_args(args(&[])).unwrap().chaos, None);
+        let config = from_args(args(&["--chaos", "seed=1,write=0.5"])).unwrap();
+        assert_eq!(config.chaos.unwrap().write_failure_rate, 0.5);
+        assert!(from_args(args(&["--chaos", "write=lots"])).is_err());
+    }
+
+    #[test]
+    fn from_args_tcp_options() {
+        let config = from_args(args(&[
+            "--tcp-keepalive-idle",
+            "30",
+            "--tcp-keepalive-interval",
+            "5",
+            "--tcp-keepalive-count",
+            "3",
+            "--tcp-nodelay",
+            "--tcp-read-timeout",
+            "120",
+        ]))
+        .unwrap();
+        assert_eq!(config.tcp.keepalive_idle, Some(Duration::from_secs(30)));
+        assert_eq!(config.tcp.keepalive_interval, Duration::from_secs(5));
+        assert_eq!(config.tcp.keepalive_count, 3);
+        assert!(config.tcp.nodelay);
+        assert_eq!(config.tcp.read_timeout, Some(Duration::from_secs(120)));
+
+        let config = from_args(args(&[
+            "--tcp-keepalive-idle",
+            "0",
+            "--tcp-read-timeout",
+            "0",
+        ]))
+        .unwrap();
+        assert_eq!(config.tcp.keepalive_idle, None);
+        assert_eq!(config.tcp.read_timeout, None);
+        assert_eq!(
+            from_args(args(&[])).unwrap().tcp.read_timeout,
+            Some(Duration::from_secs(30))
+        );
+        assert!(from_args(args(&["--tcp-keepalive-count", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_error_policy() {
+        use crate::policy::{Action, ErrorClass};
+        let config = from_args(args(&["--on-error", "bad-frame=skip-frame"])).unwrap();
+        assert_eq!(
+            config.error_policy.action_for(ErrorClass::BadFrame),
+            Action::SkipFrame
+        );
+    }
+
+    #[test]
+    fn from_args_on_change() {
+        let config = from_args(args(&[
+            "--on-change",
+            "battery",
+            "--on-change",
+            "temperature=2",
+            "--on-change-whole-message",
+        ]))
+        .unwrap();
+        assert_eq!(config.change.deadbands[&Field::Battery], 0);
+        assert_eq!(config.change.deadbands[&Field::Temperature], 2);
+        assert!(config.change.whole_message);
+        assert!(from_args(args(&["--on-change", "temperature=x"])).is_err());
+    }
+
+    #[test]
+    fn from_args_clamp() {
+        assert!(from_args(args(&[])).unwrap().clamps.is_empty());
+        let config = from_args(args(&[
+            "--clamp",
+            "battery=0:100",
+            "--clamp",
+            "temperature=10:90",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config.clamps,
+            vec![(Field::Battery, 0, 100), (Field::Temperature, 10, 90)]
+        );
+        assert!(from_args(args(&["--clamp", "battery"])).is_err());
+        assert!(from_args(args(&["--clamp", "battery=0"])).is_err());
+        assert!(from_args(args(&["--clamp", "battery=100:0"])).is_err());
+        assert!(from_args(args(&["--clamp", "humidity=0:100"])).is_err());
+    }
+
+    #[test]
+    fn from_args_error_log() {
+        let config = from_args(args(&[
+            "--error-log-window",
+            "30",
+            "--error-log-threshold",
+            "5",
+        ]))
+        .unwrap();
+        assert_eq!(config.error_log.window, Duration::from_secs(30));
+        assert_eq!(config.error_log.threshold, 5);
+        assert!(from_args(args(&["--error-log-threshold", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_log_sampling() {
+        let config = from_args(args(&[
+            "--log-on-change",
+            "temperature=2",
+            "--log-on-change",
+            "battery",
+            "--log-interval",
+            "60",
+        ]))
+        .unwrap();
+        assert_eq!(config.log_sampling.deadbands[&Field::Temperature], 2);
+        assert_eq!(config.log_sampling.deadbands[&Field::Battery], 0);
+        assert_eq!(config.log_sampling.interval, Some(Duration::from_secs(60)));
+        // logging is separate from forwarding
+        assert!(config.change.deadbands.is_empty());
+        assert!(from_args(args(&["--log-interval", "often"])).is_err());
+    }
+
+    #[test]
+    fn from_args_reconnect() {
+        let config = from_args(args(&[
+            "--max-reconnects",
+            "5",
+            "--reconnect-escalation",
+            "park",
+        ]))
+        .unwrap();
+        assert_eq!(config.reconnect.max_attempts, Some(5));
+        assert_eq!(config.reconnect.escalation, Escalation::Park);
+        assert!(from_args(args(&["--max-reconnects", "-1"])).is_err());
+        assert!(from_args(args(&["--reconnect-escalation", "sleep"])).is_err());
+    }
+
+    #[test]
+    fn from_args_forward_retries() {
+        assert_eq!(from_args(args(&[])).unwrap().forward_retries, 0);
+        let config = from_args(args(&["--forward-retries", "3"])).unwrap();
+        assert_eq!(config.forward_retries, 3);
+        assert!(from_args(args(&["--forward-retries", "-1"])).is_err());
+    }
+
+    #[test]
+    fn from_args_write_function() {
+        use crate::register_map::WriteFunction;
+        let config = from_args(args(&[
+            "--write-function",
+            "temperature=multiple",
+            "--write-delay",
+            "50",
+            "--verbose",
+        ]))
+        .unwrap();
+        assert_eq!(config.write_delay, Duration::from_millis(50));
+        assert_eq!(config.write_queue, None);
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Temperature)
+                .function,
+            WriteFunction::Multiple
+        );
+        assert!(config.verbose);
+    }
+
+    #[test]
+    fn from_args_fields() {
+        let enabled = from_args(args(&[]))
+            .unwrap()
+            .register_maps
+            .default
+            .enabled();
+        assert!(Field::ALL.iter().all(|field| enabled.contains(*field)));
+
+        // vibration isn't written so its registers can be used by the battery
+        let config = from_args(args(&[
+            "--fields",
+            "temperature,battery",
+            "--register",
+            "battery=3",
+            "--register",
+            "vibration=3",
+        ]))
+        .unwrap();
+        let enabled = config.register_maps.default.enabled();
+        assert!(enabled.contains(Field::Temperature));
+        assert!(!enabled.contains(Field::Vibration));
+        assert!(from_args(args(&["--fields", "temperature,wind"])).is_err());
+    }
+
+    #[test]
+    fn from_args_write_queue() {
+        let config = from_args(args(&["--write-queue", "64"])).unwrap();
+        assert_eq!(config.write_queue, Some(64));
+        assert!(from_args(args(&["--write-queue", "-1"])).is_err());
+    }
+
+    #[test]
+    fn from_args_retry_queue() {
+        let config = from_args(args(&["--retry-queue", "100"])).unwrap();
+        assert_eq!(config.retry_queue, Some(100));
+        assert!(from_args(args(&["--retry-queue", "lots"])).is_err());
+        assert!(from_args(args(&["--retry-queue", "100", "--write-queue", "64"])).is_err());
+    }
+
+    #[test]
+    fn from_args_grpc() {
+        let config = from_args(args(&["--grpc-batch", "20"])).unwrap();
+        assert_eq!(config.grpc_batch, 20);
+        assert!(from_args(args(&["--grpc-batch", "0"])).is_err());
+        // the option is only there when the feature is
+        let result = from_args(args(&["--grpc", "http://10.0.0.5:50051"]));
+        assert_eq!(result.is_ok(), cfg!(feature = "grpc"));
+    }
+
+    #[test]
+    fn from_args_http() {
+        let config = from_args(args(&["--http", "0.0.0.0:8080", "--recent-frames", "10"])).unwrap();
+        assert_eq!(config.http, Some("0.0.0.0:8080".to_string()));
+        assert_eq!(config.recent_frames, 10);
+    }
+
+    #[test]
+    fn from_args_health_addr() {
+        assert_eq!(from_args(args(&[])).unwrap().health_addr, None);
+        let config = from_args(args(&["--health-addr", "0.0.0.0:8081"])).unwrap();
+        assert_eq!(config.health_addr, Some("0.0.0.0:8081".to_string()));
+    }
+
+    #[test]
+    fn from_args_ws_addr() {
+        assert_eq!(from_args(args(&[])).unwrap().ws_addr, None);
+        let config = from_args(args(&["--ws-addr", "0.0.0.0:8081"])).unwrap();
+        assert_eq!(config.ws_addr, Some("0.0.0.0:8081".to_string()));
+    }
+
+    #[test]
+    fn from_args_fresh_register() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.fresh_register, None);
+        assert_eq!(config.fresh_timeout, Duration::from_secs(10));
+        let config = from_args(args(&["--fresh-register", "90", "--fresh-timeout", "30"])).unwrap();
+        assert_eq!(config.fresh_register, Some(90));
+        assert_eq!(config.fresh_timeout, Duration::from_secs(30));
+        assert!(from_args(args(&["--fresh-timeout", "soon"])).is_err());
+    }
+
+    #[test]
+    fn from_args_strobe() {
+        let config = from_args(args(&["--strobe-register", "80"])).unwrap();
+        assert_eq!(config.strobe_register, Some(80));
+        assert_eq!(config.strobe_modulus, 2);
+        let config = from_args(args(&["--strobe-modulus", "16"])).unwrap();
+        assert_eq!(config.strobe_modulus, 16);
+        assert!(from_args(args(&["--strobe-modulus", "1"])).is_err());
+    }
+
+    #[test]
+    fn from_args_last_seen_register() {
+        assert_eq!(from_args(args(&[])).unwrap().last_seen_registers, None);
+        let config = from_args(args(&["--last-seen-register", "300,301"])).unwrap();
+        assert_eq!(config.last_seen_registers, Some((300, 301)));
+        assert!(from_args(args(&["--last-seen-register", "300"])).is_err());
+        assert!(from_args(args(&["--last-seen-register", "300,300"])).is_err());
+        assert!(from_args(args(&["--last-seen-register", "300,70000"])).is_err());
+    }
+
+    #[test]
+    fn from_args_status_register() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.status_register, None);
+        assert_eq!(config.status_thresholds, Thresholds::default());
+
+        let config = from_args(args(&[
+            "--status-register",
+            "30",
+            "--status-threshold",
+            "low-battery=20",
+            "--status-threshold",
+            "high-vibration=3000",
+        ]))
+        .unwrap();
+        assert_eq!(config.status_register, Some(30));
+        assert_eq!(config.status_thresholds.low_battery, Some(20));
+        assert_eq!(config.status_thresholds.weak_rssi, None);
+        assert_eq!(config.status_thresholds.high_vibration, Some(3000));
+
+        assert!(from_args(args(&["--status-threshold", "weak-rssi=60"])).is_err());
+        assert!(from_args(args(&[
+            "--status-register",
+            "30",
+            "--status-threshold",
+            "hot=1"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_vib_magnitude_register() {
+        assert_eq!(from_args(args(&[])).unwrap().vib_magnitude_register, None);
+        let config = from_args(args(&["--vib-magnitude-register", "20"])).unwrap();
+        assert_eq!(config.vib_magnitude_register, Some(20));
+        assert!(from_args(args(&["--vib-magnitude-register", "loud"])).is_err());
+    }
+
+    #[test]
+    fn from_args_min_rssi() {
+        assert_eq!(from_args(args(&[])).unwrap().min_rssi, 0);
+        let config = from_args(args(&["--min-rssi", "120"])).unwrap();
+        assert_eq!(config.min_rssi, 120);
+        assert!(from_args(args(&["--min-rssi", "256"])).is_err());
+        assert!(from_args(args(&["--min-rssi", "-1"])).is_err());
+    }
+
+    #[test]
+    fn from_args_circuit_breaker() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.circuit_breaker, None);
+        assert_eq!(config.circuit_breaker_cooldown, Duration::from_secs(30));
+        let config = from_args(args(&[
+            "--circuit-breaker",
+            "5",
+            "--circuit-breaker-cooldown",
+            "60",
+        ]))
+        .unwrap();
+        assert_eq!(config.circuit_breaker, Some(5));
+        assert_eq!(config.circuit_breaker_cooldown, Duration::from_secs(60));
+        assert!(from_args(args(&["--circuit-breaker", "0"])).is_err());
+        assert!(from_args(args(&["--circuit-breaker-cooldown", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_coalesce() {
+        assert_eq!(from_args(args(&[])).unwrap().coalesce, None);
+        let config = from_args(args(&["--coalesce", "1000"])).unwrap();
+        assert_eq!(config.coalesce, Some(Duration::from_millis(1000)));
+        assert!(from_args(args(&["--coalesce", "0"])).is_err());
+        assert!(from_args(args(&["--coalesce", "1s"])).is_err());
+    }
+
+    #[test]
+    fn from_args_min_repeat_interval() {
+        assert_eq!(from_args(args(&[])).unwrap().min_repeat_interval, None);
+        let config = from_args(args(&["--min-repeat-interval", "10"])).unwrap();
+        assert_eq!(config.min_repeat_interval, Some(Duration::from_millis(10)));
+        assert!(from_args(args(&["--min-repeat-interval", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_max_forward_latency() {
+        assert_eq!(from_args(args(&[])).unwrap().max_forward_latency, None);
+        let config = from_args(args(&["--max-forward-latency", "250"])).unwrap();
+        assert_eq!(config.max_forward_latency, Some(Duration::from_millis(250)));
+        assert!(from_args(args(&["--max-forward-latency", "0"])).is_err());
+        assert!(from_args(args(&["--max-forward-latency", "slow"])).is_err());
+        assert!(from_args(args(&["--max-forward-latency", "250", "--stdin"])).is_err());
+    }
+
+    #[test]
+    fn from_args_device_idle_timeout() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.device_idle_timeout, None);
+        assert_eq!(config.tcp.read_timeout, Some(Duration::from_secs(30)));
+
+        // the read timeout comes down to the idle timeout, never up
+        let config = from_args(args(&["--device-idle-timeout", "10"])).unwrap();
+        assert_eq!(config.device_idle_timeout, Some(Duration::from_secs(10)));
+        assert_eq!(config.tcp.read_timeout, Some(Duration::from_secs(10)));
+        let config = from_args(args(&["--device-idle-timeout", "60"])).unwrap();
+        assert_eq!(config.tcp.read_timeout, Some(Duration::from_secs(30)));
+        let config = from_args(args(&[
+            "--device-idle-timeout",
+            "60",
+            "--tcp-read-timeout",
+            "0",
+        ]))
+        .unwrap();
+        assert_eq!(config.tcp.read_timeout, Some(Duration::from_secs(60)));
+
+        assert!(from_args(args(&["--device-idle-timeout", "0"])).is_err());
+        assert!(from_args(args(&["--device-idle-timeout", "soon"])).is_err());
+        assert!(from_args(args(&["--device-idle-timeout", "10", "--stdin"])).is_err());
+    }
+
+    #[test]
+    fn from_args_max_frames_per_connection() {
+        assert_eq!(
+            from_args(args(&[])).unwrap().max_frames_per_connection,
+            None
+        );
+        let config = from_args(args(&["--max-frames-per-connection", "10000"])).unwrap();
+        assert_eq!(config.max_frames_per_connection, Some(10000));
+        assert!(from_args(args(&["--max-frames-per-connection", "0"])).is_err());
+        assert!(from_args(args(&["--max-frames-per-connection", "many"])).is_err());
+        assert!(from_args(args(&[
+            "--max-frames-per-connection",
+            "10",
+            "--replay",
+            "a.bin"
+        ]))
+        .is_err());
+        assert!(from_args(args(&[
+            "--max-frames-per-connection",
+            "10",
+            "--device-listen",
+            "0.0.0.0:10001",
+            "--device-listen-max",
+            "4"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_read_ahead() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.read_ahead, None);
+        assert_eq!(config.read_ahead_full, WhenFull::Block);
+        let config = from_args(args(&["--read-ahead", "64", "--read-ahead-full", "drop"])).unwrap();
+        assert_eq!(config.read_ahead, Some(64));
+        assert_eq!(config.read_ahead_full, WhenFull::Drop);
+        assert!(from_args(args(&["--read-ahead", "0"])).is_err());
+        assert!(from_args(args(&["--read-ahead-full", "wait"])).is_err());
+        assert!(from_args(args(&["--read-ahead", "8", "--frame-terminator", "0d0a"])).is_err());
+    }
+
+    #[test]
+    fn from_args_max_msgs_per_sec() {
+        assert_eq!(from_args(args(&[])).unwrap().max_msgs_per_sec, None);
+        let config = from_args(args(&["--max-msgs-per-sec", "50"])).unwrap();
+        assert_eq!(config.max_msgs_per_sec, Some(50));
+        assert!(from_args(args(&["--max-msgs-per-sec", "0"])).is_err());
+        assert!(from_args(args(&["--max-msgs-per-sec", "fast"])).is_err());
+    }
+
+    #[test]
+    fn from_args_watchdog() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.watchdog_register, None);
+        assert_eq!(config.watchdog_interval, Duration::from_secs(5));
+        let config = from_args(args(&[
+            "--watchdog-register",
+            "70",
+            "--watchdog-interval",
+            "2",
+        ]))
+        .unwrap();
+        assert_eq!(config.watchdog_register, Some(70));
+        assert_eq!(config.watchdog_interval, Duration::from_secs(2));
+        assert!(from_args(args(&["--watchdog-interval", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_capture() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.capture, None);
+        assert_eq!(config.capture_keep, 5);
+        let config = from_args(args(&[
+            "--capture",
+            "out.bin",
+            "--capture-max-bytes",
+            "1000000",
+            "--capture-keep",
+            "2",
+        ]))
+        .unwrap();
+        assert_eq!(config.capture, Some("out.bin".to_string()));
+        assert_eq!(config.capture_max_bytes, Some(1000000));
+        assert_eq!(config.capture_keep, 2);
+        assert!(from_args(args(&["--capture-max-bytes", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_state_file() {
+        assert_eq!(from_args(args(&[])).unwrap().state_file, None);
+        let config =
+            from_args(args(&["--state-file", "/var/lib/modbusrouter/state.json"])).unwrap();
+        assert_eq!(
+            config.state_file,
+            Some("/var/lib/modbusrouter/state.json".to_string())
+        );
+        assert!(from_args(args(&["--state-file", ""])).is_err());
+    }
+
+    #[test]
+    fn from_args_json_out() {
+        assert_eq!(from_args(args(&[])).unwrap().json_out, None);
+        let config = from_args(args(&["--json-out", "-"])).unwrap();
+        assert_eq!(config.json_out, Some("-".to_string()));
+        let config = from_args(args(&["--json-out", "messages.jsonl"])).unwrap();
+        assert_eq!(config.json_out, Some("messages.jsonl".to_string()));
+    }
+
+    #[test]
+    fn from_args_csv_out() {
+        assert_eq!(from_args(args(&[])).unwrap().csv_out, None);
+        let config = from_args(args(&["--csv-out", "messages.csv"])).unwrap();
+        assert_eq!(config.csv_out, Some("messages.csv".to_string()));
+        assert!(from_args(args(&["--csv-out", ""])).is_err());
+    }
+
+    #[test]
+    fn from_args_alive_interval() {
+        assert_eq!(from_args(args(&[])).unwrap().alive_interval, None);
+        let config = from_args(args(&["--alive-interval", "300"])).unwrap();
+        assert_eq!(config.alive_interval, Some(Duration::from_secs(300)));
+        assert!(from_args(args(&["--alive-interval", "0"])).is_err());
+    }
+
+    #[test]
+    fn from_args_endian() {
+        assert_eq!(from_args(args(&[])).unwrap().endian, Endian::Little);
+        let config = from_args(args(&["--endian", "big"])).unwrap();
+        assert_eq!(config.endian, Endian::Big);
+        assert!(from_args(args(&["--endian", "middle"])).is_err());
+    }
+
+    #[test]
+    fn from_args_stats_interval() {
+        assert_eq!(
+            from_args(args(&[])).unwrap().stats_interval,
+            Some(Duration::from_secs(60))
+        );
+        let config = from_args(args(&["--stats-interval", "10"])).unwrap();
+        assert_eq!(config.stats_interval, Some(Duration::from_secs(10)));
+        let config = from_args(args(&["--stats-interval", "0"])).unwrap();
+        assert_eq!(config.stats_interval, None);
+        assert!(from_args(args(&["--stats-interval", "soon"])).is_err());
+    }
+
+    #[test]
+    fn from_args_monitor() {
+        let config = from_args(args(&[
+            "--alert",
+            "battery<20",
+            "--alert",
+            "temperature>60",
+            "--monitor-write",
+        ]))
+        .unwrap();
+        assert_eq!(config.alerts.len(), 2);
+        assert!(config.monitor_write);
+        assert!(from_args(args(&["--alert", "battery"])).is_err());
+    }
+
+    #[test]
+    fn from_args_dry_run() {
+        assert!(!from_args(args(&[])).unwrap().dry_run);
+        assert!(from_args(args(&["--dry-run"])).unwrap().dry_run);
+    }
+
+    #[test]
+    fn from_args_once() {
+        assert!(!from_args(args(&[])).unwrap().once);
+        let config = from_args(args(&["--once", "10.0.0.1:5000"])).unwrap();
+        assert!(config.once);
+        assert_eq!(config.device_host, "10.0.0.1:5000");
+        let config = Config::from_sources(args(&[]), env(&[]), Some("once = true\n")).unwrap();
+        assert!(config.once);
+    }
+
+    #[test]
+    fn from_args_constant_bytes() {
+        let config = from_args(args(&[])).unwrap();
+        assert!(config.constant_bytes.is_empty());
+        assert!(!config.strict);
+        let config = from_args(args(&[
+            "--constant-byte",
+            "9=01",
+            "--constant-byte",
+            "10=00",
+            "--strict",
+        ]))
+        .unwrap();
+        assert_eq!(config.constant_bytes, vec![(9, 0x01), (10, 0x00)]);
+        assert!(config.strict);
+        assert!(from_args(args(&["--constant-byte", "27=00"])).is_err());
+        assert!(from_args(args(&["--constant-byte", "10=0000"])).is_err());
+        assert!(from_args(args(&["--constant-byte", "10"])).is_err());
+        assert!(from_args(args(&["--strict"])).is_err());
+    }
+
+    #[test]
+    fn from_args_verify_checksum() {
+        assert_eq!(from_args(args(&[])).unwrap().checksum, None);
+        let config = from_args(args(&["--verify-checksum", "xor"])).unwrap();
+        assert_eq!(config.checksum, Some(Checksum::Xor));
+        let config = from_args(args(&["--verify-checksum", "sum"])).unwrap();
+        assert_eq!(config.checksum, Some(Checksum::Sum));
+        assert!(from_args(args(&["--verify-checksum", "crc16"])).is_err());
+        let format = "model-b:start=1a00:len=31:mac=2:rssi=10:battery=12:temperature=14:vibration=16:msg-num=23:version=26";
+        assert!(from_args(args(&[
+            "--verify-checksum",
+            "xor",
+            "--frame-format",
+            format
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_mqtt() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.mqtt, None);
+        assert_eq!(config.mqtt_topic_prefix, "sensors");
+        assert_eq!(config.mqtt_client_id, "modbusrouter");
+        let config = from_args(args(&[
+            "--mqtt-topic-prefix",
+            "plant/line1/",
+            "--mqtt-client-id",
+            "router-2",
+        ]))
+        .unwrap();
+        assert_eq!(config.mqtt_topic_prefix, "plant/line1");
+        assert_eq!(config.mqtt_client_id, "router-2");
+        assert!(from_args(args(&["--mqtt-topic-prefix", "plant/#"])).is_err());
+
+        let result = from_args(args(&["--mqtt", "10.0.0.5:1883"]));
+        assert_eq!(result.is_ok(), cfg!(feature = "mqtt"));
+        assert!(from_args(args(&["--mqtt", "10.0.0.5"])).is_err());
+    }
+
+    #[test]
+    fn from_args_tls() {
+        let config = from_args(args(&[])).unwrap();
+        assert!(!config.tls);
+        assert_eq!(config.tls_ca, None);
+
+        let result = from_args(args(&[
+            "--tls",
+            "--tls-ca",
+            "ca.pem",
+            "gateway.local:10001",
+        ]));
+        assert_eq!(result.is_ok(), cfg!(feature = "tls"));
+        if let Ok(config) = result {
+            assert_eq!(config.tls_ca, Some(PathBuf::from("ca.pem")));
+            // the certificate is checked against the host we connect to unless told otherwise
+            assert_eq!(config.tls_server_name, "gateway.local");
+        }
+        let result = from_args(args(&[
+            "--tls",
+            "--tls-ca",
+            "ca.pem",
+            "--tls-server-name",
+            "gateway-7",
+            "10.0.0.5:10001",
+        ]));
+        if let Ok(config) = result {
+            assert_eq!(config.tls_server_name, "gateway-7");
+        }
+        assert!(from_args(args(&["--tls", "10.0.0.5:10001"])).is_err());
+        assert!(from_args(args(&["--tls", "--tls-ca", "ca.pem", "--stdin"])).is_err());
+        assert!(from_args(args(&["--tls-server-name", ""])).is_err());
+        assert_eq!(host_name("[::1]:10001"), "::1");
+    }
+
+    #[test]
+    fn from_args_silence_timeout() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.silence_timeout, None);
+        assert_eq!(config.on_silence, SilenceAction::Warn);
+        let config = from_args(args(&[
+            "--silence-timeout",
+            "120",
+            "--on-silence",
+            "reconnect",
+        ]))
+        .unwrap();
+        assert_eq!(config.silence_timeout, Some(Duration::from_secs(120)));
+        assert_eq!(config.on_silence, SilenceAction::Reconnect);
+        assert!(from_args(args(&["--silence-timeout", "0"])).is_err());
+        assert!(from_args(args(&["--on-silence", "exit"])).is_err());
+    }
+
+    #[test]
+    fn from_args_batch_writes() {
+        assert!(!from_args(args(&[])).unwrap().batch_writes);
+        assert!(from_args(args(&["--batch-writes"])).unwrap().batch_writes);
+    }
+
+    #[test]
+    fn from_args_verify_writes() {
+        assert!(!from_args(args(&[])).unwrap().verify_writes);
+        assert!(from_args(args(&["--verify-writes"])).unwrap().verify_writes);
+    }
+
+    #[test]
+    fn from_args_audit_log() {
+        assert_eq!(from_args(args(&[])).unwrap().audit_log, None);
+        let config =
+            from_args(args(&["--audit-log", "/var/log/modbusrouter-audit.jsonl"])).unwrap();
+        assert_eq!(
+            config.audit_log,
+            Some("/var/log/modbusrouter-audit.jsonl".to_string())
+        );
+        assert!(from_args(args(&["--audit-log", ""])).is_err());
+    }
+
+    #[test]
+    fn the_logging_flags_pick_the_level() {
+        // RUST_LOG decides, or info when it isn't set
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.log_level(), None);
+        assert_eq!(config.default_log_level(), "info");
+        // and the flags over it
+        for (flag, level) in [
+            ("-q", LevelFilter::Error),
+            ("--quiet", LevelFilter::Error),
+            ("-v", LevelFilter::Info),
+            ("--verbose", LevelFilter::Info),
+            ("-vv", LevelFilter::Debug),
+            ("--very-verbose", LevelFilter::Debug),
+        ]
+        .iter()
+        {
+            let config = from_args(args(&[flag])).unwrap();
+            assert_eq!(config.log_level(), Some(*level), "{}", flag);
+        }
+        // the most detail of the two
+        let config = from_args(args(&["-v", "-vv"])).unwrap();
+        assert_eq!(config.log_level(), Some(LevelFilter::Debug));
+        // --log-raw only changes the default, RUST_LOG still wins over it
+        let config = from_args(args(&["--log-raw"])).unwrap();
+        assert_eq!(config.log_level(), None);
+        assert_eq!(config.default_log_level(), "debug");
+
+        assert!(from_args(args(&["-q", "-v"])).is_err());
+        assert!(from_args(args(&["--quiet", "--log-raw"])).is_err());
+        assert!(from_args(args(&["-vvv"])).is_err());
+        let config =
+            Config::from_sources(args(&[]), env(&[("MODBUSROUTER_QUIET", "true")]), None).unwrap();
+        assert_eq!(config.log_level(), Some(LevelFilter::Error));
+    }
+
+    #[test]
+    fn from_args_log_raw() {
+        let config = from_args(args(&[])).unwrap();
+        assert!(!config.log_raw);
+        let config = from_args(args(&["--log-raw"])).unwrap();
+        assert!(config.log_raw);
+        assert!(!config.verbose);
+    }
+
+    #[test]
+    fn from_args_startup_check() {
+        assert!(!from_args(args(&[])).unwrap().startup_check);
+        assert!(from_args(args(&["--startup-check"])).unwrap().startup_check);
+    }
+
+    #[test]
+    fn from_args_selftest_register() {
+        let config = from_args(args(&["--selftest-register", "900"])).unwrap();
+        assert_eq!(config.selftest_register, Some(900));
+        assert!(from_args(args(&["--selftest-register", "70000"])).is_err());
+    }
+
+    fn env(list: &[(&str, &str)]) -> impl Iterator<Item = (String, String)> {
+        list.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<Vec<_>>()
+            .into_iter()
+    }
+
+    #[test]
+    fn env_overrides_file_but_loses_to_cli() {
+        let file = "
+            # where the data comes from
+            device-host = 10.0.0.1:5000
+            modbus-host = 10.0.0.2
+            log-format = json
+        ";
+        let config = Config::from_sources(
+            args(&["--modbus-host", "10.0.0.4"]),
+            env(&[
+                ("MODBUSROUTER_DEVICE_HOST", "10.0.0.3:5000"),
+                ("MODBUSROUTER_MODBUS_HOST", "10.0.0.3"),
+            ]),
+            Some(file),
+        )
+        .unwrap();
+        // only set in the file
+        assert_eq!(config.log_format, LogFormat::Json);
+        // the environment beats the file
+        assert_eq!(config.device_host, "10.0.0.3:5000");
+        // the command line beats the environment
+        assert_eq!(config.modbus_host, "10.0.0.4");
+    }
+
+    #[test]
+    fn repeatable_options_and_flags_from_env_and_file() {
+        use crate::policy::{Action, ErrorClass};
+        let config = Config::from_sources(
+            args(&[]),
+            env(&[
+                (
+                    "MODBUSROUTER_ON_ERROR",
+                    "bad-frame=skip-frame, eof=fatal-exit",
+                ),
+                ("MODBUSROUTER_VERBOSE", "true"),
+                ("PATH", "/usr/bin"),
+            ]),
+            Some("on-change = battery\non-change-whole-message = true"),
+        )
+        .unwrap();
+        assert_eq!(
+            config.error_policy.action_for(ErrorClass::BadFrame),
+            Action::SkipFrame
+        );
+        assert_eq!(
+            config.error_policy.action_for(ErrorClass::Eof),
+            Action::FatalExit
+        );
+        assert!(config.verbose);
+        assert!(config.change.whole_message);
+        assert_eq!(config.change.deadbands[&Field::Battery], 0);
+    }
+
+    #[test]
+    fn invalid_env_and_file_settings() {
+        assert!(
+            Config::from_sources(args(&[]), env(&[("MODBUSROUTER_BOGUS", "1")]), None).is_err()
+        );
+        assert!(Config::from_sources(args(&[]), env(&[]), Some("verbose = yes")).is_err());
+        assert!(Config::from_sources(args(&[]), env(&[]), Some("device-host")).is_err());
+    }
+
+    #[test]
+    fn from_args_register_overrides() {
+        let config = from_args(args(&[
+            "--register",
+            "battery=100",
+            "--register",
+            "01:02:03:04:05:06/battery=200",
+        ]))
+        .unwrap();
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        assert_eq!(
+            config.register_maps.default.entry(Field::Battery).address,
+            Some(100)
+        );
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&mac, None)
+                .entry(Field::Battery)
+                .address,
+            Some(200)
+        );
+
+        // a device can't write two fields to the same register
+        assert!(from_args(args(&[
+            "--register",
+            "battery=100",
+            "--register",
+            "01:02:03:04:05:06/temperature=100",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_unit() {
+        let config = from_args(args(&[
+            "--unit",
+            "temperature=C:0.1",
+            "--unit",
+            "vibration=counts",
+        ]))
+        .unwrap();
+        assert_eq!(config.units.get(Field::Temperature).name, "C");
+        assert_eq!(config.units.get(Field::Temperature).scale, 0.1);
+        assert_eq!(config.units.get(Field::Vibration).scale, 1.0);
+        assert_eq!(config.units.get(Field::Battery).name, "raw");
+        assert!(from_args(args(&["--unit", "temperature"])).is_err());
+    }
+
+    #[test]
+    fn from_args_serial() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.serial_config(), None);
+        let result = from_args(args(&[
+            "--serial",
+            "/dev/ttyUSB0",
+            "--serial-baud",
+            "19200",
+            "--serial-parity",
+            "none",
+            "--serial-stop-bits",
+            "2",
+        ]));
+        assert_eq!(result.is_ok(), cfg!(feature = "serial"));
+        if let Ok(config) = result {
+            assert_eq!(
+                config.serial_config(),
+                Some(SerialConfig {
+                    device: "/dev/ttyUSB0".to_string(),
+                    baud: 19200,
+                    parity: Parity::None,
+                    stop_bits: 2,
+                })
+            );
+            let serial = |extra: &[&str]| {
+                let mut list = vec!["--serial", "/dev/ttyUSB0"];
+                list.extend_from_slice(extra);
+                from_args(args(&list))
+            };
+            assert!(serial(&["--check-transaction-ids"]).is_err());
+            assert!(serial(&["--fresh-register", "20"]).is_err());
+            assert!(serial(&["--modbus-host", "10.0.0.2", "--modbus-host", "10.0.0.3"]).is_err());
+        }
+
+        assert!(from_args(args(&["--serial-baud", "0"])).is_err());
+        assert!(from_args(args(&["--serial-parity", "mark"])).is_err());
+        assert!(from_args(args(&["--serial-stop-bits", "1.5"])).is_err());
+    }
+
+    #[test]
+    fn from_args_standby_hosts() {
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(config.modbus_host, "127.0.0.1");
+        assert!(config.standby_hosts.is_empty());
+        let config = from_args(args(&["--modbus-host", "10.0.0.2"])).unwrap();
+        assert_eq!(config.modbus_host, "10.0.0.2");
+        assert!(config.standby_hosts.is_empty());
+        let config = from_args(args(&[
+            "--modbus-host",
+            "10.0.0.2",
+            "--modbus-host",
+            "10.0.0.3",
+        ]))
+        .unwrap();
+        assert_eq!(config.modbus_host, "10.0.0.2");
+        assert_eq!(config.standby_hosts, vec!["10.0.0.3".to_string()]);
+
+        // the hosts from the environment replace the ones in the file rather than adding to them
+        let file = "
+            modbus-host = 10.0.0.2
+            modbus-host = 10.0.0.3
+        ";
+        let config = Config::from_sources(
+            args(&[]),
+            env(&[("MODBUSROUTER_MODBUS_HOST", "10.0.0.4, 10.0.0.5")]),
+            Some(file),
+        )
+        .unwrap();
+        assert_eq!(config.modbus_host, "10.0.0.4");
+        assert_eq!(config.standby_hosts, vec!["10.0.0.5".to_string()]);
+        let config = Config::from_sources(args(&[]), std::iter::empty(), Some(file)).unwrap();
+        assert_eq!(config.modbus_host, "10.0.0.2");
+        assert_eq!(config.standby_hosts, vec!["10.0.0.3".to_string()]);
+    }
+
+    #[test]
+    fn from_args_modbus_port() {
+        assert_eq!(from_args(args(&[])).unwrap().modbus_port, 502);
+        let config = from_args(args(&[
+            "--device-host",
+            "10.0.0.1:5000",
+            "--modbus-host",
+            "10.0.0.2",
+            "--modbus-port",
+            "1502",
+        ]))
+        .unwrap();
+        assert_eq!(config.device_host, "10.0.0.1:5000");
+        assert_eq!(config.modbus_host, "10.0.0.2");
+        assert_eq!(config.modbus_port, 1502);
+        assert!(from_args(args(&["--modbus-port", "0"])).is_err());
+        assert!(from_args(args(&["--modbus-port", "65536"])).is_err());
+        assert!(from_args(args(&["--modbus-host", ""])).is_err());
+        assert!(from_args(args(&["--modbus-port"])).is_err());
+
+        assert_eq!(from_args(args(&[])).unwrap().modbus_unit, 1);
+        let config = from_args(args(&["--modbus-unit", "17"])).unwrap();
+        assert_eq!(config.modbus_unit, 17);
+        assert!(from_args(args(&["--modbus-unit", "256"])).is_err());
+        assert!(from_args(args(&["--modbus-unit", "-1"])).is_err());
+        // the device needs a port, unless it isn't a tcp host at all
+        assert!(from_args(args(&["10.0.0.1"])).is_err());
+        assert!(from_args(args(&["--device-host", ":5000"])).is_err());
+        assert!(from_args(args(&["[::1]:5000"])).is_ok());
+        assert!(from_args(args(&["--stdin", "--device-host", "10.0.0.1"])).is_ok());
+    }
+
+    #[test]
+    fn from_args_sensor_ids() {
+        let config = from_args(args(&[
+            "--sensor-id-offset",
+            "23",
+            "--sensor-ids",
+            "1, 2,3",
+        ]))
+        .unwrap();
+        assert_eq!(config.sensor_id_offset, Some(23));
+        assert_eq!(config.sensor_ids, vec![1, 2, 3]);
+        assert!(from_args(args(&["--sensor-id-offset", "8"])).is_err());
+        assert!(from_args(args(&["--sensor-id-offset", "27"])).is_err());
+        assert!(from_args(args(&["--sensor-ids", "1,2"])).is_err());
+    }
+
+    #[test]
+    fn from_args_macs() {
+        assert_eq!(from_args(args(&[])).unwrap().macs, DEFAULT_MACS.to_vec());
+        let config = from_args(args(&["--macs", "D0:CF:5E:82:93:7B, 01:02:03:04:05:06"])).unwrap();
+        assert_eq!(
+            config.macs,
+            vec![DEFAULT_MACS[0], [0x01, 0x02, 0x03, 0x04, 0x05, 0x06]]
+        );
+        assert!(from_args(args(&["--macs", "D0:CF:5E:82:93"])).is_err());
+    }
+
+    #[test]
+    fn from_args_unknown_mac_policy() {
+        use crate::frame::{decode_frame, Frame, RouterError};
+        let policy = |name| {
+            from_args(args(&["--unknown-mac-policy", name]))
+                .unwrap()
+                .unknown_mac_policy
+        };
+        assert_eq!(
+            from_args(args(&[])).unwrap().unknown_mac_policy,
+            UnknownMacPolicy::Warn
+        );
+        assert!(from_args(args(&["--unknown-mac-policy", "ignore"])).is_err());
+
+        // a frame from a neighbour's sensor
+        let other_mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let mut frame = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        frame[2..8].copy_from_slice(&other_mac);
+        let unexpected = || io::Error::from(RouterError::UnexpectedMac(other_mac));
+        let other = io::Error::from(RouterError::BadStartSequence);
+
+        // warn, a bad frame like any other
+        let warn = policy("warn");
+        assert!(decode_frame(&frame, warn.accepted(&DEFAULT_MACS)).is_err());
+        assert!(!warn.drops(&unexpected()));
+
+        // drop, still not read but skipped without going to the error policy
+        let drop = policy("drop");
+        assert!(decode_frame(&frame, drop.accepted(&DEFAULT_MACS)).is_err());
+        assert!(drop.drops(&unexpected()));
+        assert!(!drop.drops(&other));
+
+        // allow, read like the frames from the MAC addresses we know
+        let allow = policy("allow");
+        match decode_frame(&frame, allow.accepted(&DEFAULT_MACS)).unwrap() {
+            Frame::Message(msg) => assert_eq!(msg.mac, other_mac),
+            other => panic!("Expected a message, got {:?}", other),
+        }
+        assert!(!allow.drops(&unexpected()));
+    }
+
+    #[test]
+    fn from_args_scale() {
+        use crate::register_map::{Encoding, Overflow};
+        let config = from_args(args(&[
+            "--scale",
+            "temperature=10",
+            "--scale",
+            "01:02:03:04:05:06/battery=100:wrap",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Temperature)
+                .encoding,
+            Encoding::Scaled {
+                scale: 10.0,
+                offset: 0.0,
+                overflow: Overflow::Saturate
+            }
+        );
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06], None)
+                .entry(Field::Battery)
+                .encoding,
+            Encoding::Scaled {
+                scale: 100.0,
+                offset: 0.0,
+                overflow: Overflow::Wrap
+            }
+        );
+        assert!(from_args(args(&["--scale", "temperature=10:bogus"])).is_err());
+    }
+
+    #[test]
+    fn from_args_offset() {
+        use crate::register_map::{Encoding, Overflow};
+        let config = from_args(args(&[
+            "--offset",
+            "temperature=-400",
+            "--scale",
+            "temperature=10",
+            "--offset",
+            "01:02:03:04:05:06/battery=5",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Temperature)
+                .encoding,
+            Encoding::Scaled {
+                scale: 10.0,
+                offset: -400.0,
+                overflow: Overflow::Saturate
+            }
+        );
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06], None)
+                .entry(Field::Battery)
+                .encoding
+                .offset(),
+            5.0
+        );
+        assert!(from_args(args(&["--offset", "temperature"])).is_err());
+    }
+
+    #[test]
+    fn from_args_register_base() {
+        let config = from_args(args(&[
+            "--register-base",
+            "D0:CF:5E:82:93:7B/100",
+            "--register-base",
+            "01:02:03:04:05:06#2/200",
+        ]))
+        .unwrap();
+        assert_eq!(config.register_maps.default.base(), 0);
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&[0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B], None)
+                .base(),
+            100
+        );
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06], Some(2))
+                .base(),
+            200
+        );
+        let config = from_args(args(&["--register-base", "10"])).unwrap();
+        assert_eq!(config.register_maps.default.base(), 10);
+        assert!(from_args(args(&["--register-base", "x"])).is_err());
+        // the registers of a field can't be moved past the last one
+        assert!(from_args(args(&[
+            "--register-base",
+            "65535",
+            "--register",
+            "battery=1"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn from_args_lookup() {
+        let path =
+            std::env::temp_dir().join(format!("modbusrouter-rssi-{}.txt", std::process::id()));
+        fs::write(&path, "180 = -70\n189 = -60\n").unwrap();
+        let rule = format!("rssi={}", path.display());
+        let config = from_args(args(&["--lookup", &rule, "--register", "rssi=40"])).unwrap();
+        let msg = crate::testing::sample_message();
+        let mut fields = crate::fields::FieldSet::empty();
+        fields.insert(Field::Rssi);
+        let writes = config.register_maps.default.registers(&msg, fields);
+        assert_eq!(writes[0].values, vec![-60i16 as u16]);
+        let device_rule = format!("01:02:03:04:05:06/{}", rule);
+        assert!(from_args(args(&["--lookup", &device_rule])).is_ok());
+        fs::remove_file(&path).unwrap();
+
+        assert!(from_args(args(&["--lookup", &rule])).is_err());
+        assert!(from_args(args(&["--lookup", "rssi"])).is_err());
+    }
+
+    #[test]
+    fn from_args_signedness() {
+        use crate::register_map::Signedness;
+        let config = from_args(args(&[])).unwrap();
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Vibration)
+                .signedness,
+            Signedness::Unsigned
+        );
+        let config = from_args(args(&[
+            "--signedness",
+            "vibration=signed",
+            "--signedness",
+            "01:02:03:04:05:06/vibration=offset",
+        ]))
+        .unwrap();
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Vibration)
+                .signedness,
+            Signedness::Signed
+        );
+        assert_eq!(
+            config
+                .register_maps
+                .for_device(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06], None)
+                .entry(Field::Vibration)
+                .signedness,
+            Signedness::Offset
+        );
+        assert!(from_args(args(&["--signedness", "temperature=signed"])).is_err());
+    }
+
+    #[test]
+    fn from_args_float() {
+        use crate::register_map::Encoding;
+        use crate::word_order::WordOrder;
+        let config = from_args(args(&["--float", "temperature=0.1:cdab"])).unwrap();
+        assert_eq!(
+            config
+                .register_maps
+                .default
+                .entry(Field::Temperature)
+                .encoding,
+            Encoding::Float {
+                scale: 0.1,
+                offset: 0.0,
+                word_order: WordOrder::Cdab
+            }
+        );
+        assert!(from_args(args(&["--float", "temperature=0.1:bogus"])).is_err());
+    }
+
+    #[test]
+    fn from_args_unknown_option() {
+        assert!(from_args(args(&["--bogus"])).is_err());
+        assert!(from_args(args(&["--log-format", "xml"])).is_err());
+    }
+}
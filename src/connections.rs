@@ -0,0 +1,296 @@
+use crate::frame::format_mac;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+// What to do about a device that is connected but hasn't sent a message for --silence-timeout
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SilenceAction {
+    // log it and keep waiting
+    Warn,
+    // log it and connect again, for gateways that stop forwarding without closing the connection
+    Reconnect,
+}
+
+impl SilenceAction {
+    pub fn from_name(name: &str) -> Option<SilenceAction> {
+        match name {
+            "warn" => Some(SilenceAction::Warn),
+            "reconnect" => Some(SilenceAction::Reconnect),
+            _ => None,
+        }
+    }
+}
+
+// How stable the connection to each device is.
+// Devices are known by the address we connect to until their first frame tells us their MAC address
+#[derive(Default)]
+pub struct Connections {
+    peers: BTreeMap<String, Peer>,
+}
+
+#[derive(Default)]
+struct Peer {
+    mac: Option<[u8; 6]>,
+    // None while we are not connected
+    connected_since: Option<Instant>,
+    // the number of times we have connected after the first time
+    reconnects: u64,
+    // read errors by class, e.g. eof or connection-reset
+    read_errors: BTreeMap<&'static str, u64>,
+    // when the last message was decoded, heartbeats and bad frames don't count
+    last_message: Option<Instant>,
+    // the current silence has already been reported by silent
+    silence_reported: bool,
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections::default()
+    }
+
+    pub fn connected(&mut self, peer: &str, now: Instant) {
+        match self.peers.get_mut(peer) {
+            Some(existing) => {
+                existing.reconnects += 1;
+                existing.connected_since = Some(now);
+                existing.silence_reported = false;
+            }
+            None => {
+                let new = Peer {
+                    connected_since: Some(now),
+                    ..Peer::default()
+                };
+                self.peers.insert(peer.to_string(), new);
+            }
+        }
+    }
+
+    pub fn disconnected(&mut self, peer: &str) {
+        if let Some(existing) = self.peers.get_mut(peer) {
+            existing.connected_since = None;
+        }
+    }
+
+    // Call this with the MAC address from each frame, from then on the device is reported by MAC
+    pub fn identified(&mut self, peer: &str, mac: [u8; 6]) {
+        if let Some(existing) = self.peers.get_mut(peer) {
+            existing.mac = Some(mac);
+        }
+    }
+
+    pub fn message(&mut self, peer: &str, now: Instant) {
+        if let Some(existing) = self.peers.get_mut(peer) {
+            existing.last_message = Some(now);
+            existing.silence_reported = false;
+        }
+    }
+
+    // How long the device has gone without a message (or since we connected, if that is later), but only the
+    // first time it is longer than the threshold. After that it is None until the next message or connection
+    // so that each silence is only reported once
+    pub fn silent(&mut self, peer: &str, threshold: Duration, now: Instant) -> Option<Duration> {
+        let existing = self.peers.get_mut(peer)?;
+        let since = existing.last_message.max(existing.connected_since)?;
+        let silence = now.duration_since(since);
+        if silence < threshold || existing.silence_reported {
+            return None;
+        }
+        existing.silence_reported = true;
+        Some(silence)
+    }
+
+    // Counts a read error, e.g. a clean EOF (device restart) or a reset (device crash)
+    pub fn read_error(&mut self, peer: &str, class: &'static str) {
+        if let Some(existing) = self.peers.get_mut(peer) {
+            *existing.read_errors.entry(class).or_insert(0) += 1;
+        }
+    }
+
+    // How long the oldest of the current connections has been up, None while nothing is connected
+    pub fn uptime(&self, now: Instant) -> Option<Duration> {
+        self.peers
+            .values()
+            .filter_map(|peer| peer.connected_since)
+            .min()
+            .map(|since| now.duration_since(since))
+    }
+
+    // The connection metrics in the Prometheus text format
+    pub fn metrics(&self, now: Instant) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_connection_uptime_seconds How long the current connection to the device has been up"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_connection_uptime_seconds gauge");
+        for (device, peer) in self.devices() {
+            let uptime = peer
+                .connected_since
+                .map_or(0.0, |since| now.duration_since(since).as_secs_f64());
+            let _ = writeln!(
+                out,
+                "modbusrouter_connection_uptime_seconds{{device=\"{}\"}} {:.3}",
+                device, uptime
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_reconnects_total The number of times the connection to the device was made again"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_reconnects_total counter");
+        for (device, peer) in self.devices() {
+            let _ = writeln!(
+                out,
+                "modbusrouter_reconnects_total{{device=\"{}\"}} {}",
+                device, peer.reconnects
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_read_errors_total Errors reading from the device by class"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_read_errors_total counter");
+        for (device, peer) in self.devices() {
+            for (class, count) in &peer.read_errors {
+                let _ = writeln!(
+                    out,
+                    "modbusrouter_read_errors_total{{device=\"{}\",class=\"{}\"}} {}",
+                    device, class, count
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_last_message_age_seconds How long ago the last message was decoded from the device"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_last_message_age_seconds gauge");
+        for (device, peer) in self.devices() {
+            if let Some(last) = peer.last_message {
+                let _ = writeln!(
+                    out,
+                    "modbusrouter_last_message_age_seconds{{device=\"{}\"}} {:.3}",
+                    device,
+                    now.duration_since(last).as_secs_f64()
+                );
+            }
+        }
+        out
+    }
+
+    // each peer labelled by MAC address if we know it
+    fn devices(&self) -> impl Iterator<Item = (String, &Peer)> {
+        self.peers.iter().map(|(addr, peer)| {
+            let device = peer.mac.as_ref().map_or_else(|| addr.clone(), format_mac);
+            (device, peer)
+        })
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const PEER: &str = "192.168.1.87:10001";
+
+    #[test]
+    fn uptime_and_reconnects() {
+        let start = Instant::now();
+        let mut connections = Connections::new();
+        connections.connected(PEER, start);
+
+        // known by address until the first frame arrives
+        let metrics = connections.metrics(start + Duration::from_secs(5));
+        assert!(metrics.contains(
+            "modbusrouter_connection_uptime_seconds{device=\"192.168.1.87:10001\"} 5.000"
+        ));
+        assert!(metrics.contains("modbusrouter_reconnects_total{device=\"192.168.1.87:10001\"} 0"));
+        assert_eq!(
+            connections.uptime(start + Duration::from_secs(5)),
+            Some(Duration::from_secs(5))
+        );
+
+        connections.identified(PEER, [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]);
+        connections.disconnected(PEER);
+        let metrics = connections.metrics(start + Duration::from_secs(6));
+        assert_eq!(connections.uptime(start + Duration::from_secs(6)), None);
+        assert!(metrics.contains(
+            "modbusrouter_connection_uptime_seconds{device=\"D0:CF:5E:82:93:7B\"} 0.000"
+        ));
+
+        // uptime starts again from zero after a reconnect
+        connections.connected(PEER, start + Duration::from_secs(10));
+        let metrics = connections.metrics(start + Duration::from_secs(12));
+        assert!(metrics.contains(
+            "modbusrouter_connection_uptime_seconds{device=\"D0:CF:5E:82:93:7B\"} 2.000"
+        ));
+        assert!(metrics.contains("modbusrouter_reconnects_total{device=\"D0:CF:5E:82:93:7B\"} 1"));
+    }
+
+    #[test]
+    fn read_errors_are_counted_by_class() {
+        let mut connections = Connections::new();
+        connections.connected(PEER, Instant::now());
+        connections.read_error(PEER, "eof");
+        connections.read_error(PEER, "connection-reset");
+        connections.read_error(PEER, "eof");
+        let metrics = connections.metrics(Instant::now());
+        assert!(metrics.contains(
+            "modbusrouter_read_errors_total{device=\"192.168.1.87:10001\",class=\"eof\"} 2"
+        ));
+        assert!(metrics.contains("modbusrouter_read_errors_total{device=\"192.168.1.87:10001\",class=\"connection-reset\"} 1"));
+    }
+
+    #[test]
+    fn a_gap_longer_than_the_threshold_is_reported_once() {
+        let start = Instant::now();
+        let threshold = Duration::from_secs(60);
+        let mut connections = Connections::new();
+        connections.connected(PEER, start);
+        // nothing at all since connecting counts too
+        assert_eq!(
+            connections.silent(PEER, threshold, start + Duration::from_secs(59)),
+            None
+        );
+        assert_eq!(
+            connections.silent(PEER, threshold, start + Duration::from_secs(61)),
+            Some(Duration::from_secs(61))
+        );
+
+        connections.message(PEER, start + Duration::from_secs(70));
+        let metrics = connections.metrics(start + Duration::from_secs(75));
+        assert!(metrics.contains(
+            "modbusrouter_last_message_age_seconds{device=\"192.168.1.87:10001\"} 5.000"
+        ));
+        assert_eq!(
+            connections.silent(PEER, threshold, start + Duration::from_secs(100)),
+            None
+        );
+
+        // the device goes quiet
+        let gap = start + Duration::from_secs(70 + 90);
+        assert_eq!(
+            connections.silent(PEER, threshold, gap),
+            Some(Duration::from_secs(90))
+        );
+        assert_eq!(
+            connections.silent(PEER, threshold, gap + Duration::from_secs(1)),
+            None
+        );
+
+        // a new connection starts the clock again
+        connections.connected(PEER, gap);
+        assert_eq!(
+            connections.silent(PEER, threshold, gap + Duration::from_secs(1)),
+            None
+        );
+        assert!(connections
+            .silent(PEER, threshold, gap + Duration::from_secs(60))
+            .is_some());
+    }
+}
@@ -0,0 +1,205 @@
+use crate::fields::Field;
+use crate::frame::{format_mac, DeviceMessage};
+use crate::json_out::received_at_ms;
+use crate::sink::{self, MessageSink, SinkError};
+use log::error;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many rows can be waiting for a slow disk (or a slow reader of stdout) before new ones are dropped
+const PENDING_ROWS: usize = 1024;
+
+// The most rows that can be lost if the router dies, however busy it is
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Writes every decoded message as a row of csv to a file or stdout, for spreadsheets and pandas.
+// Like --json-out the writing happens on its own thread so a slow disk never holds up the modbus
+pub struct CsvOut {
+    sender: SyncSender<Vec<String>>,
+}
+
+impl CsvOut {
+    // - is stdout, anything else is a file that is added to. The header row is only written to a file that
+    // is empty, so that restarting the router doesn't put another one in the middle of the rows
+    pub fn start(path: &str) -> io::Result<CsvOut> {
+        let (writer, needs_header): (Box<dyn Write + Send>, bool) = if path == "-" {
+            (Box::new(io::stdout()), true)
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let empty = file.metadata()?.len() == 0;
+            (Box::new(BufWriter::new(file)), empty)
+        };
+        let mut writer = csv::Writer::from_writer(writer);
+        if needs_header {
+            writer.write_record(header())?;
+        }
+        let (sender, receiver) = mpsc::sync_channel(PENDING_ROWS);
+        thread::spawn(move || run(&mut writer, receiver));
+        Ok(CsvOut { sender })
+    }
+}
+
+impl MessageSink for CsvOut {
+    fn name(&self) -> &str {
+        "csv output"
+    }
+
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        sink::queue(&self.sender, row(msg))
+    }
+}
+
+// received_at_ms,mac,sensor_id then a column for each field, the vibration gets one for each axis
+fn header() -> Vec<String> {
+    let mut header: Vec<String> = ["received_at_ms", "mac", "sensor_id"]
+        .iter()
+        .map(|column| column.to_string())
+        .collect();
+    for field in Field::ALL.iter() {
+        match field {
+            Field::Vibration => {
+                for axis in ["x", "y", "z"].iter() {
+                    header.push(format!("{}-{}", field.name(), axis));
+                }
+            }
+            _ => header.push(field.name().to_string()),
+        }
+    }
+    header
+}
+
+// The columns of the header, a message that isn't from a sensor behind a gateway has an empty sensor_id
+fn row(msg: &DeviceMessage) -> Vec<String> {
+    let mut row = vec![
+        received_at_ms(msg).to_string(),
+        format_mac(&msg.mac),
+        msg.sensor_id.map(|id| id.to_string()).unwrap_or_default(),
+    ];
+    for field in Field::ALL.iter() {
+        row.extend(
+            msg.field_values(*field)
+                .iter()
+                .map(|value| value.to_string()),
+        );
+    }
+    row
+}
+
+// Runs until the router drops its CsvOut. The rows are flushed at least once a second, even when they
+// never stop arriving
+fn run<W: Write>(writer: &mut csv::Writer<W>, receiver: Receiver<Vec<String>>) {
+    let mut flushed = Instant::now();
+    loop {
+        let result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(row) => writer.write_record(&row).map_err(io::Error::from),
+            Err(mpsc::RecvTimeoutError::Timeout) => Ok(()),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let _ = writer.flush();
+                return;
+            }
+        };
+        let result = result.and_then(|_| {
+            if flushed.elapsed() < FLUSH_INTERVAL {
+                return Ok(());
+            }
+            flushed = Instant::now();
+            writer.flush()
+        });
+        if let Err(e) = result {
+            error!("Error writing to the csv output: {:?}", e);
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn csv_rows_read_back() {
+        let first = DeviceMessage {
+            received_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            ..sample_message()
+        };
+        let second = DeviceMessage {
+            received_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_001_000)),
+            sensor_id: Some(2),
+            temp_value: 90,
+            ..sample_message()
+        };
+
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.write_record(header()).unwrap();
+        let (sender, receiver) = mpsc::sync_channel(PENDING_ROWS);
+        sender.send(row(&first)).unwrap();
+        sender.send(row(&second)).unwrap();
+        drop(sender);
+        run(&mut writer, receiver);
+
+        let bytes = writer.into_inner().unwrap();
+        let mut reader = csv::Reader::from_reader(bytes.as_slice());
+        assert_eq!(
+            reader.headers().unwrap().iter().collect::<Vec<&str>>(),
+            vec![
+                "received_at_ms",
+                "mac",
+                "sensor_id",
+                "battery",
+                "temperature",
+                "vibration-x",
+                "vibration-y",
+                "vibration-z",
+                "msg-num",
+                "version",
+                "rssi"
+            ]
+        );
+        let rows: Vec<Vec<String>> = reader
+            .records()
+            .map(|record| record.unwrap().iter().map(String::from).collect())
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                vec![
+                    "1700000000123",
+                    "D0:CF:5E:82:93:7B",
+                    "",
+                    "0",
+                    "84",
+                    "62206",
+                    "602",
+                    "1914",
+                    "33850",
+                    "2",
+                    "189"
+                ],
+                vec![
+                    "1700000001000",
+                    "D0:CF:5E:82:93:7B",
+                    "2",
+                    "0",
+                    "90",
+                    "62206",
+                    "602",
+                    "1914",
+                    "33850",
+                    "2",
+                    "189"
+                ],
+            ]
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use crate::frame::DeviceMessage;
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+// What the deduplicator made of a message
+#[derive(Debug, PartialEq)]
+pub enum Seen {
+    New,
+    Repeat,
+    // a repeat that came this soon after the frame before it from the device, sooner than --min-repeat-interval.
+    // That is faster than a sensor sends anything, so it is a gateway sending the frame again by mistake
+    Flood(Duration),
+}
+
+// The last frame from a device
+struct Last {
+    msg_num: u16,
+    received_at: Option<SystemTime>,
+}
+
+// Drops the frames the device sends more than once, which would otherwise write the same registers again.
+// A repeat has the same msg_num as the message before it from the same device (and sensor). Any other
+// msg_num is a new message, including the wrap from 65535 back to 0 and a restarted device counting from 0
+#[derive(Default)]
+pub struct Deduplicator {
+    last: BTreeMap<([u8; 6], Option<u8>), Last>,
+    // a repeat sooner than this after the frame before it is a flood, if that is looked out for
+    min_repeat_interval: Option<Duration>,
+}
+
+impl Deduplicator {
+    pub fn new() -> Deduplicator {
+        Deduplicator::default()
+    }
+
+    pub fn with_min_repeat_interval(self, min_repeat_interval: Option<Duration>) -> Deduplicator {
+        Deduplicator {
+            min_repeat_interval,
+            ..self
+        }
+    }
+
+    // Whether the message is a new one, call this once for every message
+    pub fn should_forward(&mut self, msg: &DeviceMessage) -> bool {
+        self.check(msg) == Seen::New
+    }
+
+    // The same as should_forward but says when a repeat came too soon. The time between them is the time
+    // the two frames were received, a message that wasn't stamped is never a flood
+    pub fn check(&mut self, msg: &DeviceMessage) -> Seen {
+        let last = self.last.insert(
+            (msg.mac, msg.sensor_id),
+            Last {
+                msg_num: msg.msg_num_value,
+                received_at: msg.received_at,
+            },
+        );
+        let last = match last {
+            Some(last) if last.msg_num == msg.msg_num_value => last,
+            _ => return Seen::New,
+        };
+        let after = match (last.received_at, msg.received_at) {
+            (Some(last), Some(now)) => now.duration_since(last).unwrap_or_default(),
+            _ => return Seen::Repeat,
+        };
+        match self.min_repeat_interval {
+            Some(min) if after < min => Seen::Flood(after),
+            _ => Seen::Repeat,
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+    use std::time::UNIX_EPOCH;
+
+    fn message(msg_num: u16) -> DeviceMessage {
+        DeviceMessage {
+            msg_num_value: msg_num,
+            ..sample_message()
+        }
+    }
+
+    #[test]
+    fn repeats_are_dropped() {
+        let mut dedup = Deduplicator::new();
+        assert!(dedup.should_forward(&message(10)));
+        assert!(!dedup.should_forward(&message(10)));
+        assert!(!dedup.should_forward(&message(10)));
+        assert!(dedup.should_forward(&message(11)));
+        // only the message straight before counts
+        assert!(dedup.should_forward(&message(10)));
+    }
+
+    #[test]
+    fn a_repeat_too_soon_is_a_flood() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_571_388_795);
+        let at = |ms: u64, msg_num: u16| DeviceMessage {
+            received_at: Some(start + Duration::from_millis(ms)),
+            ..message(msg_num)
+        };
+        let mut dedup =
+            Deduplicator::new().with_min_repeat_interval(Some(Duration::from_millis(10)));
+        assert_eq!(dedup.check(&at(0, 10)), Seen::New);
+        // two identical frames 3ms apart, the second is still dropped
+        assert_eq!(
+            dedup.check(&at(3, 10)),
+            Seen::Flood(Duration::from_millis(3))
+        );
+        // timed from the frame straight before it
+        assert_eq!(
+            dedup.check(&at(9, 10)),
+            Seen::Flood(Duration::from_millis(6))
+        );
+        assert_eq!(dedup.check(&at(50, 10)), Seen::Repeat);
+        // a new message that soon is fine
+        assert_eq!(dedup.check(&at(51, 11)), Seen::New);
+        assert!(!dedup.should_forward(&message(11)));
+
+        // without an interval nothing is a flood
+        let mut dedup = Deduplicator::new();
+        assert_eq!(dedup.check(&at(0, 10)), Seen::New);
+        assert_eq!(dedup.check(&at(1, 10)), Seen::Repeat);
+    }
+
+    #[test]
+    fn wrapping_is_a_new_message() {
+        let mut dedup = Deduplicator::new();
+        assert!(dedup.should_forward(&message(65535)));
+        assert!(dedup.should_forward(&message(0)));
+        assert!(!dedup.should_forward(&message(0)));
+    }
+
+    #[test]
+    fn each_device_and_sensor_has_its_own_count() {
+        let mut dedup = Deduplicator::new();
+        assert!(dedup.should_forward(&message(10)));
+        let other_device = DeviceMessage {
+            mac: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            ..message(10)
+        };
+        assert!(dedup.should_forward(&other_device));
+        let other_sensor = DeviceMessage {
+            sensor_id: Some(2),
+            ..message(10)
+        };
+        assert!(dedup.should_forward(&other_sensor));
+        assert!(!dedup.should_forward(&other_sensor));
+    }
+}
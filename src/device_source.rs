@@ -0,0 +1,496 @@
+use crate::listen_pool::ListenPool;
+use crate::reconnect::{retry_with_backoff, Backoff};
+use crate::simulator::Simulator;
+#[cfg(feature = "tls")]
+use crate::tls::TlsConnector;
+use crate::websocket;
+use log::{info, warn};
+use socket2::{SockRef, TcpKeepalive};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, Read};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How long to wait after a gateway connection that couldn't be accepted, doubling up to the maximum while
+// they keep failing
+const ACCEPT_BACKOFF_START: Duration = Duration::from_millis(100);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+#[cfg(unix)]
+use std::fs;
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+// A Unix domain socket to read the frames from, for a gateway daemon on the same host
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnixSocket {
+    // connect to the daemon's socket
+    Connect(PathBuf),
+    // create the socket and wait for the daemon to connect to us
+    Listen(PathBuf),
+}
+
+// How the tcp connection to the device is set up.
+// Keepalive lets the OS notice a device that has gone away without closing the connection, which otherwise
+// looks just like a device with nothing to say
+#[derive(Debug, Clone, PartialEq)]
+pub struct TcpOptions {
+    // how long the connection is idle before the first probe, None turns keepalive off
+    pub keepalive_idle: Option<Duration>,
+    // the time between probes
+    pub keepalive_interval: Duration,
+    // how many probes go unanswered before the connection is dropped
+    pub keepalive_count: u32,
+    pub nodelay: bool,
+    // how long a read waits before giving up on the device, None waits forever
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for TcpOptions {
+    // a dead device is noticed after about two minutes
+    fn default() -> TcpOptions {
+        TcpOptions {
+            keepalive_idle: Some(Duration::from_secs(60)),
+            keepalive_interval: Duration::from_secs(10),
+            keepalive_count: 6,
+            nodelay: false,
+            read_timeout: Some(Duration::from_secs(30)),
+        }
+    }
+}
+
+impl TcpOptions {
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        stream.set_read_timeout(self.read_timeout)?;
+        if let Some(idle) = self.keepalive_idle {
+            let keepalive = TcpKeepalive::new().with_time(idle);
+            // not every platform lets us choose these, the rest use the OS defaults
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "freebsd"
+            ))]
+            let keepalive = keepalive
+                .with_interval(self.keepalive_interval)
+                .with_retries(self.keepalive_count);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
+
+// Where the frames come from. The read loop only needs something it can Read from,
+// this takes care of setting that up
+pub enum DeviceSource {
+    Tcp(String, TcpOptions),
+    // the same wrapped in TLS, for gateways that insist on it
+    #[cfg(feature = "tls")]
+    Tls(String, TcpOptions, TlsConnector),
+    // for gateways that connect to us and push the frames, by the address we are listening on
+    TcpListen(TcpListener, String, TcpOptions),
+    // the same for several gateways at once, by the address we are listening on
+    TcpListenPool(ListenPool, String),
+    UnixConnect(PathBuf),
+    #[cfg(unix)]
+    UnixListen(UnixListener, PathBuf),
+    // the binary messages of a WebSocket, by url
+    WebSocket(String, TcpOptions),
+    // frames piped in, e.g. cat capture.bin | modbusrouter --stdin. Unlike the others it comes to an end
+    Stdin,
+    // a capture played back from a file, e.g. --replay capture.bin. It comes to an end like stdin does
+    Replay(SharedReader<BufReader<File>>, PathBuf),
+    // made up frames at this many a second, e.g. --simulate 10
+    Simulate(SharedReader<Simulator>, f64),
+}
+
+impl DeviceSource {
+    // Listening sockets are created straight away so that a bad path is reported at startup
+    pub fn new(
+        host: &str,
+        tcp_options: &TcpOptions,
+        unix_socket: Option<&UnixSocket>,
+        websocket: Option<&String>,
+    ) -> io::Result<DeviceSource> {
+        if let Some(url) = websocket {
+            return Ok(DeviceSource::WebSocket(url.clone(), tcp_options.clone()));
+        }
+        match unix_socket {
+            None => Ok(DeviceSource::Tcp(host.to_string(), tcp_options.clone())),
+            Some(UnixSocket::Connect(path)) => Ok(DeviceSource::UnixConnect(path.clone())),
+            Some(UnixSocket::Listen(path)) => listen(path),
+        }
+    }
+
+    // Binds straight away so that an address that is in use is reported at startup.
+    // With more than one connection allowed the gateways are read from side by side, which needs their MAC addresses
+    pub fn tcp_listen(
+        addr: &str,
+        tcp_options: &TcpOptions,
+        max_connections: usize,
+        start_seq: [u8; 2],
+        macs: &[[u8; 6]],
+    ) -> io::Result<DeviceSource> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?.to_string();
+        if max_connections > 1 {
+            let pool = ListenPool::start(
+                listener,
+                tcp_options.clone(),
+                start_seq,
+                macs.to_vec(),
+                max_connections,
+            );
+            return Ok(DeviceSource::TcpListenPool(pool, local_addr));
+        }
+        Ok(DeviceSource::TcpListen(
+            listener,
+            local_addr,
+            tcp_options.clone(),
+        ))
+    }
+
+    // The CA file is read straight away so that a bad one is reported at startup
+    #[cfg(feature = "tls")]
+    pub fn tls(
+        host: &str,
+        tcp_options: &TcpOptions,
+        ca_file: &Path,
+        server_name: &str,
+    ) -> io::Result<DeviceSource> {
+        let connector = TlsConnector::new(ca_file, server_name)?;
+        Ok(DeviceSource::Tls(
+            host.to_string(),
+            tcp_options.clone(),
+            connector,
+        ))
+    }
+
+    // the config doesn't allow --tls without the feature
+    #[cfg(not(feature = "tls"))]
+    pub fn tls(
+        _host: &str,
+        _tcp_options: &TcpOptions,
+        _ca_file: &Path,
+        _server_name: &str,
+    ) -> io::Result<DeviceSource> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "TLS needs the router to be built with --features tls",
+        ))
+    }
+
+    // The file is opened straight away so that a bad path is reported at startup
+    pub fn replay(path: &Path) -> io::Result<DeviceSource> {
+        let file = BufReader::new(File::open(path)?);
+        Ok(DeviceSource::Replay(
+            SharedReader(Arc::new(Mutex::new(file))),
+            path.to_path_buf(),
+        ))
+    }
+
+    // The frames of every device in macs, taking turns
+    pub fn simulate(rate: f64, macs: &[[u8; 6]]) -> DeviceSource {
+        let simulator = Simulator::new(rate, macs);
+        DeviceSource::Simulate(SharedReader(Arc::new(Mutex::new(simulator))), rate)
+    }
+
+    // Whether the frames run out rather than the connection dropping, at the end there is nothing to reconnect to
+    pub fn comes_to_an_end(&self) -> bool {
+        matches!(self, DeviceSource::Stdin | DeviceSource::Replay(..))
+    }
+
+    // What we are reading from, for the logs and the metrics
+    pub fn name(&self) -> String {
+        match self {
+            DeviceSource::Tcp(host, _) => host.clone(),
+            #[cfg(feature = "tls")]
+            DeviceSource::Tls(host, _, _) => format!("tls:{}", host),
+            DeviceSource::TcpListen(_, addr, _) | DeviceSource::TcpListenPool(_, addr) => {
+                format!("listen:{}", addr)
+            }
+            DeviceSource::UnixConnect(path) => format!("unix:{}", path.display()),
+            #[cfg(unix)]
+            DeviceSource::UnixListen(_, path) => format!("unix:{}", path.display()),
+            DeviceSource::WebSocket(url, _) => url.clone(),
+            DeviceSource::Stdin => "stdin".to_string(),
+            DeviceSource::Replay(_, path) => format!("replay:{}", path.display()),
+            DeviceSource::Simulate(_, rate) => format!("simulate:{}/s", rate),
+        }
+    }
+
+    // Makes a new connection to the device, when listening this waits for the next one to come in
+    pub fn connect(&self) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            DeviceSource::Tcp(host, options) => {
+                let stream = TcpStream::connect(host)?;
+                options.apply(&stream)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(feature = "tls")]
+            DeviceSource::Tls(host, options, connector) => {
+                let stream = TcpStream::connect(host)?;
+                options.apply(&stream)?;
+                Ok(Box::new(connector.connect(stream)?))
+            }
+            // one gateway at a time, the next one is only accepted once this one has gone
+            DeviceSource::TcpListen(listener, _, options) => {
+                let stream = accept_retrying(|| {
+                    let (stream, peer) = listener.accept()?;
+                    options.apply(&stream)?;
+                    info!("Accepted a connection from {}", peer);
+                    Ok(stream)
+                });
+                Ok(Box::new(stream))
+            }
+            DeviceSource::TcpListenPool(pool, _) => Ok(Box::new(pool.reader())),
+            DeviceSource::UnixConnect(path) => connect_unix(path),
+            #[cfg(unix)]
+            DeviceSource::UnixListen(listener, _) => {
+                let stream = accept_retrying(|| Ok(listener.accept()?.0));
+                Ok(Box::new(stream))
+            }
+            DeviceSource::WebSocket(url, options) => websocket::connect(url, options),
+            // there is only one stdin, connecting again carries on from wherever it has got to
+            DeviceSource::Stdin => Ok(Box::new(io::stdin())),
+            // the same goes for the file, nothing that has been buffered is lost
+            DeviceSource::Replay(reader, _) => Ok(Box::new(reader.clone())),
+            // and the devices carry on counting
+            DeviceSource::Simulate(simulator, _) => Ok(Box::new(simulator.clone())),
+        }
+    }
+}
+
+// Waits for the next gateway connection that can be set up. A connection that fails part way through being
+// accepted (the gateway gave up, we are out of file handles for a moment) says nothing about the ones after
+// it, so rather than counting against --max-reconnects it is logged and we wait for the next one
+fn accept_retrying<T, F>(accept: F) -> T
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut backoff = Backoff::new(ACCEPT_BACKOFF_START, ACCEPT_BACKOFF_MAX);
+    retry_with_backoff(&mut backoff, accept, |e, delay| {
+        warn!(
+            "Unable to accept a gateway connection: {:?}, waiting for the next one in {}ms",
+            e,
+            delay.as_millis()
+        );
+        thread::sleep(delay);
+    })
+}
+
+// The one reader of a replayed file (or the simulator), shared by every connection made to it
+pub struct SharedReader<R>(Arc<Mutex<R>>);
+
+// not derived, that would need R to be Clone
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> SharedReader<R> {
+        SharedReader(self.0.clone())
+    }
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.lock() {
+            Ok(mut reader) => reader.read(buf),
+            Err(poisoned) => poisoned.into_inner().read(buf),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    Ok(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(unix)]
+fn listen(path: &Path) -> io::Result<DeviceSource> {
+    // a socket left behind by an earlier run would stop us binding, anything else at the path is left alone
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    Ok(DeviceSource::UnixListen(listener, path.to_path_buf()))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &Path) -> io::Result<Box<dyn Read + Send>> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &Path) -> io::Result<DeviceSource> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        "Unix domain sockets are not supported on this platform",
+    )
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(all(test, unix))]
+mod tests {
+
+    use super::*;
+    use crate::frame::{read_message, DEFAULT_MACS, START_SEQ};
+    use std::env;
+    use std::io::Write;
+    use std::process;
+    use std::thread;
+
+    const FRAME: [u8; 27] = [
+        0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFE,
+        0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+    ];
+
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("modbusrouter-{}-{}.sock", name, process::id()))
+    }
+
+    #[test]
+    fn reads_frames_from_a_listening_socket() {
+        let path = socket_path("listen");
+        let source = DeviceSource::new(
+            "",
+            &TcpOptions::default(),
+            Some(&UnixSocket::Listen(path.clone())),
+            None,
+        )
+        .unwrap();
+        assert_eq!(source.name(), format!("unix:{}", path.display()));
+
+        let daemon = {
+            let path = path.clone();
+            thread::spawn(move || {
+                let mut stream = UnixStream::connect(path).unwrap();
+                stream.write_all(&FRAME).unwrap();
+            })
+        };
+        let mut stream = source.connect().unwrap();
+        assert_eq!(
+            read_message(&mut stream, &DEFAULT_MACS)
+                .unwrap()
+                .msg_num_value,
+            33850
+        );
+        daemon.join().unwrap();
+
+        // a second run can bind to the socket the first one left behind
+        drop(source);
+        assert!(DeviceSource::new(
+            "",
+            &TcpOptions::default(),
+            Some(&UnixSocket::Listen(path.clone())),
+            None
+        )
+        .is_ok());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn a_connection_that_cant_be_accepted_waits_for_the_next() {
+        let mut attempts = 0;
+        let accepted = accept_retrying(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(io::Error::new(io::ErrorKind::ConnectionAborted, "gone"))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(accepted, 3);
+    }
+
+    #[test]
+    fn tcp_options_are_applied_to_the_stream() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let options = TcpOptions {
+            nodelay: true,
+            read_timeout: Some(Duration::from_secs(5)),
+            ..TcpOptions::default()
+        };
+        options.apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        assert_eq!(stream.read_timeout().unwrap(), Some(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn reads_frames_from_a_connected_socket() {
+        let path = socket_path("connect");
+        let _ = fs::remove_file(&path);
+        let daemon = UnixListener::bind(&path).unwrap();
+        let source = DeviceSource::new(
+            "",
+            &TcpOptions::default(),
+            Some(&UnixSocket::Connect(path.clone())),
+            None,
+        )
+        .unwrap();
+        let mut stream = source.connect().unwrap();
+        daemon.accept().unwrap().0.write_all(&FRAME).unwrap();
+        assert!(read_message(&mut stream, &DEFAULT_MACS).is_ok());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn reads_frames_from_a_gateway_that_connects_to_us() {
+        let source = DeviceSource::tcp_listen(
+            "127.0.0.1:0",
+            &TcpOptions::default(),
+            1,
+            START_SEQ,
+            &DEFAULT_MACS,
+        )
+        .unwrap();
+        let name = source.name();
+        let addr = name.strip_prefix("listen:").unwrap().to_string();
+        let gateway = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(&FRAME).unwrap();
+        });
+        let mut stream = source.connect().unwrap();
+        assert_eq!(
+            read_message(&mut stream, &DEFAULT_MACS)
+                .unwrap()
+                .msg_num_value,
+            33850
+        );
+        gateway.join().unwrap();
+    }
+
+    #[test]
+    fn replays_frames_from_a_file() {
+        let path = env::temp_dir().join(format!("modbusrouter-replay-{}.bin", process::id()));
+        fs::write(&path, [FRAME, FRAME].concat()).unwrap();
+        let source = DeviceSource::replay(&path).unwrap();
+        assert_eq!(source.name(), format!("replay:{}", path.display()));
+        assert!(source.comes_to_an_end());
+
+        assert!(read_message(&mut source.connect().unwrap(), &DEFAULT_MACS).is_ok());
+        // connecting again carries on with the next frame rather than starting over
+        let mut stream = source.connect().unwrap();
+        assert!(read_message(&mut stream, &DEFAULT_MACS).is_ok());
+        let mut buf = [0; 1];
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+        let _ = fs::remove_file(&path);
+
+        assert!(DeviceSource::replay(&path).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use log::{error, warn};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// How errors are rate limited in the log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorLogConfig {
+    // identical errors are counted over this long
+    pub window: Duration,
+    // how many of them are logged in full within a window before the rest are only counted
+    pub threshold: u32,
+}
+
+impl Default for ErrorLogConfig {
+    fn default() -> ErrorLogConfig {
+        ErrorLogConfig {
+            window: Duration::from_secs(10),
+            threshold: 1,
+        }
+    }
+}
+
+// A run of errors of the same kind
+struct Burst {
+    started: Instant,
+    logged: u32,
+    suppressed: u64,
+}
+
+// Keeps a device that streams garbage from flooding the log.
+// The first few errors of each kind in a window are logged in full so there is something to diagnose,
+// the rest are counted and summed up once the window is over, e.g. "1423 Unrecognised start sequence errors in the last 10s"
+pub struct ErrorLog {
+    config: ErrorLogConfig,
+    bursts: BTreeMap<String, Burst>,
+}
+
+impl ErrorLog {
+    pub fn new(config: ErrorLogConfig) -> ErrorLog {
+        ErrorLog {
+            config,
+            bursts: BTreeMap::new(),
+        }
+    }
+
+    // Logs the error unless there have already been too many of this kind lately
+    pub fn error(&mut self, kind: &str, line: &str) {
+        for line in self.lines_for_error(kind, line, Instant::now()) {
+            log(&line);
+        }
+    }
+
+    // Prints the summaries of any windows that have finished, call this every now and then
+    // so that the summary of a burst doesn't have to wait for the next error
+    pub fn flush(&mut self) {
+        for line in self.finished_windows(Instant::now()) {
+            log(&line);
+        }
+    }
+
+    fn lines_for_error(&mut self, kind: &str, line: &str, now: Instant) -> Vec<String> {
+        let mut lines = self.finished_windows(now);
+        let burst = self.bursts.entry(kind.to_string()).or_insert(Burst {
+            started: now,
+            logged: 0,
+            suppressed: 0,
+        });
+        if burst.logged < self.config.threshold {
+            burst.logged += 1;
+            lines.push(line.to_string());
+        } else {
+            burst.suppressed += 1;
+        }
+        lines
+    }
+
+    fn finished_windows(&mut self, now: Instant) -> Vec<String> {
+        let window = self.config.window;
+        let mut lines = Vec::new();
+        self.bursts.retain(|kind, burst| {
+            if now.duration_since(burst.started) < window {
+                return true;
+            }
+            if burst.suppressed > 0 {
+                lines.push(format!(
+                    "{} more {} errors in the last {}s",
+                    burst.suppressed,
+                    kind,
+                    window.as_secs()
+                ));
+            }
+            false
+        });
+        lines
+    }
+}
+
+// The lines that start with WARNING: are logged as warnings, everything else is an error
+fn log(line: &str) {
+    match line.strip_prefix("WARNING: ") {
+        Some(warning) => warn!("{}", warning),
+        None => error!("{}", line),
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn repeats_are_counted_and_summed_up() {
+        let mut log = ErrorLog::new(ErrorLogConfig::default());
+        let start = Instant::now();
+        let bad = "Unrecognised start sequence";
+
+        assert_eq!(
+            log.lines_for_error(bad, "Error reading message: bad", start),
+            vec!["Error reading message: bad".to_string()]
+        );
+        for _ in 0..1423 {
+            assert!(log.lines_for_error(bad, "again", start).is_empty());
+        }
+        // another kind of error is logged straight away
+        assert_eq!(log.lines_for_error("ModbusIo", "modbus", start).len(), 1);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(
+            log.finished_windows(later),
+            vec!["1423 more Unrecognised start sequence errors in the last 10s".to_string()]
+        );
+        // a new window logs the first one in full again
+        assert_eq!(log.lines_for_error(bad, "back", later).len(), 1);
+    }
+
+    #[test]
+    fn threshold_logs_the_first_few() {
+        let mut log = ErrorLog::new(ErrorLogConfig {
+            window: Duration::from_secs(60),
+            threshold: 3,
+        });
+        let now = Instant::now();
+        let logged: usize = (0..10)
+            .map(|_| log.lines_for_error("Eof", "eof", now).len())
+            .sum();
+        assert_eq!(logged, 3);
+        // nothing was suppressed for a kind that stayed under the threshold
+        let mut quiet = ErrorLog::new(ErrorLogConfig::default());
+        quiet.lines_for_error("Eof", "eof", now);
+        assert!(quiet
+            .finished_windows(now + Duration::from_secs(10))
+            .is_empty());
+    }
+}
@@ -0,0 +1,130 @@
+// The logical fields of a DeviceMessage that get written to the modbus.
+// The vibration field covers all three axes because they are written together
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Field {
+    Battery,
+    Temperature,
+    Vibration,
+    MsgNum,
+    Version,
+    Rssi,
+}
+
+impl Field {
+    // in the order they are written to the modbus
+    pub const ALL: [Field; 6] = [
+        Field::Battery,
+        Field::Temperature,
+        Field::Vibration,
+        Field::MsgNum,
+        Field::Version,
+        Field::Rssi,
+    ];
+
+    // The name used for the field on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Battery => "battery",
+            Field::Temperature => "temperature",
+            Field::Vibration => "vibration",
+            Field::MsgNum => "msg-num",
+            Field::Version => "version",
+            Field::Rssi => "rssi",
+        }
+    }
+
+    // The PID byte the device puts in front of the field's value in a standard frame
+    pub fn pid(self) -> u8 {
+        match self {
+            Field::Battery => 1,
+            Field::Temperature => 2,
+            Field::Vibration => 3,
+            Field::MsgNum => 5,
+            Field::Version => 11,
+            Field::Rssi => 6,
+        }
+    }
+
+    // How many consecutive registers the field takes up
+    pub fn register_count(self) -> u16 {
+        match self {
+            Field::Vibration => 3,
+            _ => 1,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Field> {
+        Field::ALL
+            .iter()
+            .cloned()
+            .find(|field| field.name() == name)
+    }
+
+    fn bit(self) -> u8 {
+        1 << (self as u8)
+    }
+}
+
+// A small set of fields, stored as one bit per field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldSet(u8);
+
+impl FieldSet {
+    pub fn empty() -> FieldSet {
+        FieldSet(0)
+    }
+
+    pub fn all() -> FieldSet {
+        let mut set = FieldSet::empty();
+        for field in Field::ALL.iter() {
+            set.insert(*field);
+        }
+        set
+    }
+
+    pub fn insert(&mut self, field: Field) {
+        self.0 |= field.bit();
+    }
+
+    pub fn remove(&mut self, field: Field) {
+        self.0 &= !field.bit();
+    }
+
+    pub fn contains(self, field: Field) -> bool {
+        self.0 & field.bit() != 0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn field_set_insert_and_contains() {
+        let mut set = FieldSet::empty();
+        assert!(set.is_empty());
+        set.insert(Field::Temperature);
+        assert!(set.contains(Field::Temperature));
+        assert!(!set.contains(Field::Battery));
+        set.remove(Field::Temperature);
+        assert!(set.is_empty());
+        assert!(Field::ALL.iter().all(|f| FieldSet::all().contains(*f)));
+    }
+
+    #[test]
+    fn field_names_round_trip() {
+        for field in Field::ALL.iter() {
+            assert_eq!(Field::from_name(field.name()), Some(*field));
+        }
+        assert_eq!(Field::from_name("bogus"), None);
+    }
+}
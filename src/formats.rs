@@ -0,0 +1,806 @@
+use crate::fields::Field;
+use crate::frame::{
+    decode_frame_as, extra_payload_len, fill_buffer, parse_hex, read_extra_payload, DeviceMessage,
+    Frame, PartialFrame, RouterError, FRAME_LEN, HEARTBEAT_MARKER, MAX_PAYLOAD_LEN, START_SEQ,
+};
+use crate::rtu_transport::crc16;
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::sync::OnceLock;
+
+// The name the built in frame layout goes by
+pub const STANDARD: &str = "standard";
+
+// The byte order of the u16 values in a frame (msg-num and the vibration axes). The sensors the router was
+// written for are little endian, there is a variant that sends them big endian
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Endian {
+    #[default]
+    Little,
+    Big,
+}
+
+impl Endian {
+    pub fn from_name(name: &str) -> Option<Endian> {
+        match name.to_ascii_lowercase().as_str() {
+            "little" => Some(Endian::Little),
+            "big" => Some(Endian::Big),
+            _ => None,
+        }
+    }
+
+    fn read_u16(self, mut bytes: &[u8]) -> io::Result<u16> {
+        match self {
+            Endian::Little => bytes.read_u16::<LittleEndian>(),
+            Endian::Big => bytes.read_u16::<BigEndian>(),
+        }
+    }
+
+    fn write_u16(self, mut bytes: &mut [u8], value: u16) -> io::Result<()> {
+        match self {
+            Endian::Little => bytes.write_u16::<LittleEndian>(value),
+            Endian::Big => bytes.write_u16::<BigEndian>(value),
+        }
+    }
+}
+
+// Where everything is in a frame. The standard frame is one of these (see FrameFormat::standard) and the others
+// are for gateways that put several sensor models on one stream.
+// Each field is found by the offset of its PID byte and the value comes straight after it, the same as the
+// standard frame: one byte for battery, temperature, version and rssi, a u16 for msg-num and three of them
+// for vibration, little endian unless the format says otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameFormat {
+    pub name: String,
+    pub start_seq: Vec<u8>,
+    pub len: usize,
+    pub mac: usize,
+    // the offset of a byte that holds how many bytes of the frame come after it, if there is one.
+    // A frame where it is anything else is a bad frame
+    pub length: Option<usize>,
+    // the offset of a CRC-16 of every byte after it to the end of the frame, if there is one, sent low byte
+    // first like the modbus RTU one. Firmware that sends it puts it in front of the payload, so the fields
+    // after it are two bytes further on. A frame where it doesn't match is a bad frame
+    pub crc: Option<usize>,
+    pub fields: BTreeMap<Field, usize>,
+    pub endian: Endian,
+    // the length byte can count more than the fields take up, whatever comes after them is ignored
+    // (see frame::PAYLOAD_LEN). Only the standard frame, the other formats are always len long
+    pub extra_payload: bool,
+}
+
+impl FrameFormat {
+    // The layout of the frames the router was first written for, 27 bytes:
+    // 19 00 | MAC (6) | 12 | 01 battery | 02 temperature | 03 vibration x, y, z | 05 msg-num | 0B version | 06 rssi
+    pub fn standard() -> &'static FrameFormat {
+        static STANDARD_FORMAT: OnceLock<FrameFormat> = OnceLock::new();
+        STANDARD_FORMAT.get_or_init(|| {
+            let fields = [
+                (Field::Battery, 9),
+                (Field::Temperature, 11),
+                (Field::Vibration, 13),
+                (Field::MsgNum, 20),
+                (Field::Version, 23),
+                (Field::Rssi, 25),
+            ];
+            FrameFormat {
+                name: STANDARD.to_string(),
+                start_seq: START_SEQ.to_vec(),
+                len: FRAME_LEN,
+                mac: 2,
+                length: Some(8),
+                crc: None,
+                fields: fields.iter().cloned().collect(),
+                endian: Endian::Little,
+                extra_payload: true,
+            }
+        })
+    }
+
+    // e.g. model-b:start=1a00:len=31:mac=2:battery=9:temperature=11:vibration=13:msg-num=20:version=23:rssi=25.
+    // length=<byte>, crc=<byte> and endian=<little|big> are optional
+    pub fn parse(spec: &str) -> Result<FrameFormat, String> {
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or("").trim();
+        if name.is_empty() || name == STANDARD {
+            return Err(format!("Invalid frame format name: {}", name));
+        }
+        let mut start_seq = None;
+        let mut len = None;
+        let mut mac = None;
+        let mut length = None;
+        let mut crc = None;
+        let mut fields = BTreeMap::new();
+        let mut endian = Endian::Little;
+        for part in parts {
+            let mut setting = part.splitn(2, '=');
+            let key = setting.next().unwrap_or("");
+            let value = setting
+                .next()
+                .ok_or_else(|| format!("Expected key=value but got: {}", part))?;
+            let offset = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid {} of frame format {}: {}", key, name, value))
+            };
+            match key {
+                "start" => start_seq = Some(parse_hex(value)?),
+                "len" => len = Some(offset()?),
+                "mac" => mac = Some(offset()?),
+                "length" => length = Some(offset()?),
+                "crc" => crc = Some(offset()?),
+                "endian" => {
+                    endian = Endian::from_name(value).ok_or_else(|| {
+                        format!("Invalid endian of frame format {}: {}", name, value)
+                    })?
+                }
+                _ => {
+                    let field =
+                        Field::from_name(key).ok_or_else(|| format!("Unknown field: {}", key))?;
+                    fields.insert(field, offset()?);
+                }
+            }
+        }
+
+        let missing = |what: &str| format!("Frame format {} needs {}=", name, what);
+        let format = FrameFormat {
+            name: name.to_string(),
+            start_seq: start_seq.ok_or_else(|| missing("start"))?,
+            len: len.ok_or_else(|| missing("len"))?,
+            mac: mac.ok_or_else(|| missing("mac"))?,
+            length,
+            crc,
+            fields,
+            endian,
+            extra_payload: false,
+        };
+        for field in Field::ALL.iter() {
+            if !format.fields.contains_key(field) {
+                return Err(missing(field.name()));
+            }
+        }
+        // everything has to fit inside the frame
+        let ends = format
+            .fields
+            .iter()
+            .map(|(field, offset)| offset + 1 + value_len(*field))
+            .chain(vec![format.start_seq.len(), format.mac + 6])
+            .chain(format.length.map(|at| at + 1))
+            .chain(format.crc.map(|at| at + 2));
+        if ends.max().unwrap_or(0) > format.len {
+            return Err(format!(
+                "Frame format {} reads past the end of its {} byte frame",
+                name, format.len
+            ));
+        }
+        Ok(format)
+    }
+
+    // The same layout with a crc straight after its length byte and everything after that two bytes further
+    // on, for firmware that added one in front of the payload. The length byte counts the crc too. A layout
+    // without a length byte is returned as it is
+    pub fn with_crc(&self) -> FrameFormat {
+        let at = match self.length {
+            Some(at) => at + 1,
+            None => return self.clone(),
+        };
+        let moved = |offset: usize| if offset >= at { offset + 2 } else { offset };
+        FrameFormat {
+            len: self.len + 2,
+            mac: moved(self.mac),
+            crc: Some(at),
+            fields: self
+                .fields
+                .iter()
+                .map(|(field, offset)| (*field, moved(*offset)))
+                .collect(),
+            extra_payload: false,
+            ..self.clone()
+        }
+    }
+
+    // What the length byte should hold, the number of bytes after it
+    pub fn expected_length(&self) -> Option<u8> {
+        self.length.map(|at| (self.len - at - 1) as u8)
+    }
+
+    // Extracts the DeviceMessage from a frame that is known to be in this format
+    pub fn decode(&self, buffer: &[u8]) -> Result<DeviceMessage, io::Error> {
+        if buffer.len() != self.len || !buffer.starts_with(&self.start_seq) {
+            let e = io::Error::new(ErrorKind::InvalidData, format!("Not a {} frame", self.name));
+            return Err(e);
+        }
+        self.extract(buffer).map_err(|e| match e {
+            // the message of BadPayloadLength is about the standard frame
+            RouterError::BadPayloadLength(actual) => io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The length byte of a {} frame must be 0x{:02X} but was 0x{:02X}",
+                    self.name,
+                    self.expected_length().unwrap_or(0),
+                    actual
+                ),
+            ),
+            e => io::Error::from(e),
+        })
+    }
+
+    // Reads the fields out of a frame of exactly this format's length, checking the start sequence and the
+    // length byte on the way. Where the MAC address is allowed to come from and what the PIDs should be is
+    // up to the caller
+    pub fn extract(&self, buffer: &[u8]) -> Result<DeviceMessage, RouterError> {
+        self.extract_as(buffer, self.endian)
+    }
+
+    // Like extract but with the u16 values read in the given byte order rather than the format's own, for
+    // the standard frames of a sensor that sends them big endian (see --endian)
+    pub fn extract_as(&self, buffer: &[u8], endian: Endian) -> Result<DeviceMessage, RouterError> {
+        if !buffer.starts_with(&self.start_seq) {
+            return Err(RouterError::BadStartSequence);
+        }
+        // everything below reads the buffer where the layout says the bytes are
+        if buffer.len() < self.len {
+            return Err(RouterError::TruncatedFrame {
+                got: buffer.len(),
+                expected: self.len,
+            });
+        }
+        if let (Some(at), Some(expected)) = (self.length, self.expected_length()) {
+            let fits = match self.extra_payload {
+                true => buffer[at] >= expected,
+                false => buffer[at] == expected,
+            };
+            if !fits {
+                return Err(RouterError::BadPayloadLength(buffer[at]));
+            }
+            if self.extra_payload && buffer[at] > MAX_PAYLOAD_LEN {
+                return Err(RouterError::PayloadTooLong(buffer[at]));
+            }
+        }
+        if let Some(at) = self.crc {
+            let expected = crc16(&buffer[at + 2..self.len]);
+            let actual = u16::from_le_bytes([buffer[at], buffer[at + 1]]);
+            if expected != actual {
+                return Err(RouterError::CrcMismatch { expected, actual });
+            }
+        }
+        let mut mac = [0; 6];
+        mac.copy_from_slice(&buffer[self.mac..self.mac + 6]);
+        // parse checked that every field is there
+        // we use the byteorder crate with ReadBytesExt extensions to borrowed slices to extract
+        // primitive data types out of byte streams. In this case a u16 in the frame's byte order
+        let pid = |field: Field| buffer[self.fields[&field]];
+        let byte = |field: Field| buffer[self.fields[&field] + 1];
+        let word = |field: Field, n: usize| {
+            let at = self.fields[&field] + 1 + n * 2;
+            endian.read_u16(&buffer[at..at + 2])
+        };
+        Ok(DeviceMessage {
+            mac,
+            batt_pid1: pid(Field::Battery),
+            batt_value: byte(Field::Battery),
+            temp_pid2: pid(Field::Temperature),
+            temp_value: byte(Field::Temperature),
+            vib_pid3: pid(Field::Vibration),
+            vib_x: word(Field::Vibration, 0)?,
+            vib_y: word(Field::Vibration, 1)?,
+            vib_z: word(Field::Vibration, 2)?,
+            msg_num_pid5: pid(Field::MsgNum),
+            msg_num_value: word(Field::MsgNum, 0)?,
+            version_pid11: pid(Field::Version),
+            version_value: byte(Field::Version),
+            rssi_pid6: pid(Field::Rssi),
+            rssi_value: byte(Field::Rssi),
+            sensor_id: None,
+            // the parser doesn't know when the bytes arrived, the caller fills this in
+            received_at: None,
+        })
+    }
+
+    // The reverse of extract, the bytes the device would have sent. Anything the layout doesn't say is 0
+    pub fn encode(&self, msg: &DeviceMessage) -> Vec<u8> {
+        let mut buffer = vec![0; self.len];
+        buffer[..self.start_seq.len()].copy_from_slice(&self.start_seq);
+        buffer[self.mac..self.mac + 6].copy_from_slice(&msg.mac);
+        if let (Some(at), Some(length)) = (self.length, self.expected_length()) {
+            buffer[at] = length;
+        }
+        let pids = [
+            (Field::Battery, msg.batt_pid1),
+            (Field::Temperature, msg.temp_pid2),
+            (Field::Vibration, msg.vib_pid3),
+            (Field::MsgNum, msg.msg_num_pid5),
+            (Field::Version, msg.version_pid11),
+            (Field::Rssi, msg.rssi_pid6),
+        ];
+        for (field, pid) in pids.iter() {
+            let at = self.fields[field];
+            buffer[at] = *pid;
+            match field {
+                Field::Vibration => {
+                    for (n, value) in [msg.vib_x, msg.vib_y, msg.vib_z].iter().enumerate() {
+                        let at = at + 1 + n * 2;
+                        // writing to a slice of the right size can't fail
+                        let _ = self.endian.write_u16(&mut buffer[at..at + 2], *value);
+                    }
+                }
+                Field::MsgNum => {
+                    let _ = self
+                        .endian
+                        .write_u16(&mut buffer[at + 1..at + 3], msg.msg_num_value);
+                }
+                _ => buffer[at + 1] = msg.field_values(*field)[0] as u8,
+            }
+        }
+        if let Some(at) = self.crc {
+            let crc = crc16(&buffer[at + 2..]);
+            buffer[at..at + 2].copy_from_slice(&crc.to_le_bytes());
+        }
+        buffer
+    }
+}
+
+// A standard frame with a crc in front of the payload, checked against macs the same as one without
+fn decode_standard_crc(
+    layout: &FrameFormat,
+    buffer: &[u8],
+    macs: &[[u8; 6]],
+    endian: Endian,
+) -> Result<Decoded<'static>, io::Error> {
+    let format = STANDARD;
+    let mut mac = [0; 6];
+    if let Some(bytes) = buffer.get(layout.mac..layout.mac + 6) {
+        mac.copy_from_slice(bytes);
+    }
+    if !macs.is_empty() && !macs.contains(&mac) {
+        return Err(RouterError::UnexpectedMac(mac).into());
+    }
+    let heartbeat = layout
+        .length
+        .is_some_and(|at| buffer.get(at) == Some(&HEARTBEAT_MARKER));
+    if heartbeat {
+        let frame = Frame::Heartbeat { mac };
+        return Ok(Decoded { format, frame });
+    }
+    // a frame that is too short is a TruncatedFrame from extract_as
+    let frame = Frame::Message(layout.extract_as(buffer, endian)?);
+    Ok(Decoded { format, frame })
+}
+
+// How many bytes follow the PID byte of a field
+fn value_len(field: Field) -> usize {
+    match field {
+        Field::Vibration => 6,
+        Field::MsgNum => 2,
+        _ => 1,
+    }
+}
+
+// A frame along with the name of the format it matched
+#[derive(Debug)]
+pub struct Decoded<'a> {
+    pub format: &'a str,
+    pub frame: Frame,
+}
+
+// Every frame format that can turn up on a connection. The standard one is always there and the leading bytes
+// of each frame say which format it is in
+#[derive(Debug, Clone)]
+pub struct Formats {
+    formats: Vec<FrameFormat>,
+    // the byte order of the standard frames, the other formats say their own
+    standard_endian: Endian,
+    // the layout of the standard frames when they have a crc in front of the payload (see --payload-crc)
+    standard_crc: Option<FrameFormat>,
+}
+
+impl Formats {
+    // The start sequences have to tell the formats apart, so none of them can be the start of another
+    pub fn new(formats: Vec<FrameFormat>) -> Result<Formats, String> {
+        let formats = Formats {
+            formats,
+            standard_endian: Endian::Little,
+            standard_crc: None,
+        };
+        let starts: Vec<(&str, &[u8], usize)> = formats.starts().collect();
+        let longest = starts.iter().map(|(_, start, _)| start.len()).max();
+        for (i, (name, start, len)) in starts.iter().enumerate() {
+            for (j, (other, other_start, _)) in starts.iter().enumerate() {
+                if i != j && other_start.starts_with(start) {
+                    return Err(format!(
+                        "The start sequences of frame formats {} and {} can't be told apart",
+                        name, other
+                    ));
+                }
+            }
+            // the whole of the longest start sequence may have been read before we know which format it is
+            if longest.is_some_and(|longest| *len < longest) {
+                return Err(format!("Frame format {} is too short", name));
+            }
+        }
+        Ok(formats)
+    }
+
+    pub fn with_standard_endian(self, standard_endian: Endian) -> Formats {
+        Formats {
+            standard_endian,
+            ..self
+        }
+    }
+
+    // For firmware that sends the standard frames with a crc after the length byte (see FrameFormat::with_crc)
+    pub fn with_standard_crc(self) -> Formats {
+        Formats {
+            standard_crc: Some(FrameFormat::standard().with_crc()),
+            ..self
+        }
+    }
+
+    // the name, start sequence and length of each format, the standard one first
+    fn starts(&self) -> impl Iterator<Item = (&str, &[u8], usize)> {
+        let standard = (STANDARD, &START_SEQ[..], self.standard_len());
+        vec![standard].into_iter().chain(
+            self.formats
+                .iter()
+                .map(|format| (format.name.as_str(), &format.start_seq[..], format.len)),
+        )
+    }
+
+    fn standard_len(&self) -> usize {
+        self.standard_crc
+            .as_ref()
+            .map_or(FRAME_LEN, |layout| layout.len)
+    }
+
+    // Reads one whole frame in whichever format its start sequence says it is.
+    // Up to max_skip bytes are thrown away looking for a start sequence first (see read_first_frame), pass 0
+    // once the stream is aligned. Returns the frame and the number of bytes skipped
+    pub fn read_frame<T: Read>(
+        &self,
+        stream: &mut T,
+        max_skip: usize,
+    ) -> Result<(Vec<u8>, usize), io::Error> {
+        let mut buffer = vec![];
+        let mut discarded = 0;
+        loop {
+            let matched = self
+                .starts()
+                .find(|(_, start, _)| buffer.starts_with(start))
+                .map(|(name, _, len)| (name == STANDARD, len));
+            if let Some((standard, len)) = matched {
+                let read = buffer.len();
+                buffer.resize(len, 0);
+                fill_buffer(stream, &mut buffer[read..])?;
+                // a standard frame with a longer payload is kept whole, one with a crc is always the same length
+                if standard && self.standard_crc.is_none() {
+                    let extra = read_extra_payload(stream, START_SEQ, &buffer)?;
+                    buffer.extend_from_slice(&extra);
+                }
+                return Ok((buffer, discarded));
+            }
+
+            // keep reading while the bytes so far could still be the start of a frame
+            let possible = self
+                .starts()
+                .any(|(_, start, _)| start.starts_with(&buffer));
+            if possible {
+                let mut byte = [0; 1];
+                match fill_buffer(stream, &mut byte) {
+                    // some of a start sequence is still part of a frame
+                    Err(e) if e.kind() == ErrorKind::UnexpectedEof && !buffer.is_empty() => {
+                        return Err(io::Error::new(ErrorKind::UnexpectedEof, PartialFrame))
+                    }
+                    result => result?,
+                }
+                buffer.push(byte[0]);
+            } else {
+                if discarded == max_skip {
+                    let e = io::Error::new(
+                        ErrorKind::InvalidData,
+                        "No frame format matches the start sequence",
+                    );
+                    return Err(e);
+                }
+                buffer.remove(0);
+                discarded += 1;
+            }
+        }
+    }
+
+    // Decodes a frame read by read_frame with the format that matches its start sequence.
+    // Only the standard frames are checked against macs, the other formats can come from any MAC address
+    pub fn decode(&self, buffer: &[u8], macs: &[[u8; 6]]) -> Result<Decoded<'_>, io::Error> {
+        if let (true, Some(layout)) = (buffer.starts_with(&START_SEQ), &self.standard_crc) {
+            return decode_standard_crc(layout, buffer, macs, self.standard_endian);
+        }
+        if buffer.starts_with(&START_SEQ) {
+            let mut standard = [0; FRAME_LEN];
+            // anything after the first FRAME_LEN bytes is the part of a longer payload that is ignored
+            if buffer.len() == FRAME_LEN + extra_payload_len(buffer, START_SEQ) {
+                standard.copy_from_slice(&buffer[..FRAME_LEN]);
+                let frame = decode_frame_as(&standard, START_SEQ, macs, self.standard_endian)?;
+                return Ok(Decoded {
+                    format: STANDARD,
+                    frame,
+                });
+            }
+        }
+        match self
+            .formats
+            .iter()
+            .find(|format| buffer.starts_with(&format.start_seq))
+        {
+            Some(format) => Ok(Decoded {
+                format: &format.name,
+                frame: Frame::Message(format.decode(buffer)?),
+            }),
+            None => {
+                let e = io::Error::new(
+                    ErrorKind::InvalidData,
+                    "No frame format matches the start sequence",
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{encode_frame, parse_frame, DEFAULT_MACS};
+    use std::io::Cursor;
+
+    // A longer frame with a two byte header after the MAC address and the fields in a different order
+    const MODEL_B: &str =
+        "model-b:start=1a00:len=31:mac=2:rssi=10:battery=12:temperature=14:vibration=16:msg-num=23:version=26";
+
+    // Model b with a length byte and a crc of the payload after it
+    const MODEL_B_CRC: &str =
+        "model-b:start=1a00:len=33:mac=2:length=9:crc=10:rssi=12:battery=14:temperature=16:vibration=18:msg-num=25:version=28";
+
+    fn standard_frame() -> [u8; FRAME_LEN] {
+        [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ]
+    }
+
+    fn model_b_frame() -> Vec<u8> {
+        vec![
+            0x1A, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0xAA, 0xBB, 0x06, 0xC0, 0x01, 0x50,
+            0x02, 0x20, 0x03, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00, 0x05, 0x10, 0x00, 0x0B, 0x04,
+            0x00, 0x00, 0x00,
+        ]
+    }
+
+    #[test]
+    fn the_same_bytes_in_either_byte_order() {
+        let standard = FrameFormat::standard();
+        let little = standard.extract(&standard_frame()).unwrap();
+        let big = standard.extract_as(&standard_frame(), Endian::Big).unwrap();
+        // FE F2
+        assert_eq!(little.vib_x, 0xF2FE);
+        assert_eq!(big.vib_x, 0xFEF2);
+        assert_eq!(big.msg_num_value, 0x3A84);
+        // the single byte values don't change
+        assert_eq!(big.temp_value, little.temp_value);
+
+        let format = FrameFormat::parse(&format!("{}:endian=big", MODEL_B)).unwrap();
+        assert_eq!(format.endian, Endian::Big);
+        let msg = format.decode(&model_b_frame()).unwrap();
+        assert_eq!(msg.vib_x, 0x0100);
+        // and written back the same way
+        assert_eq!(format.decode(&format.encode(&msg)).unwrap(), msg);
+        assert_eq!(format.encode(&msg)[17..19], [0x01, 0x00]);
+        assert!(FrameFormat::parse(&format!("{}:endian=middle", MODEL_B)).is_err());
+    }
+
+    #[test]
+    fn parse_format() {
+        let format = FrameFormat::parse(MODEL_B).unwrap();
+        assert_eq!(format.name, "model-b");
+        assert_eq!(format.start_seq, vec![0x1A, 0x00]);
+        assert_eq!(format.len, 31);
+        assert_eq!(format.fields[&Field::Vibration], 16);
+
+        // no rssi
+        assert!(FrameFormat::parse(
+            "b:start=1a00:len=31:mac=2:battery=12:temperature=14:vibration=16:msg-num=23:version=26"
+        )
+        .is_err());
+        // the version value would be past the end
+        assert!(FrameFormat::parse(&MODEL_B.replace("len=31", "len=27")).is_err());
+        assert!(FrameFormat::parse(&MODEL_B.replace("start=1a00", "start=1a0")).is_err());
+        assert!(FrameFormat::parse(&MODEL_B.replace("model-b", "standard")).is_err());
+    }
+
+    #[test]
+    fn the_standard_format_is_a_layout_like_any_other() {
+        let standard = FrameFormat::standard();
+        assert_eq!(standard.expected_length(), Some(0x12));
+        // the same offsets given on the command line decode the same message
+        let spec = "copy:start=1900:len=27:mac=2:length=8:battery=9:temperature=11:vibration=13:msg-num=20:version=23:rssi=25";
+        let copy = FrameFormat::parse(spec).unwrap();
+        assert_eq!(copy.fields, standard.fields);
+        assert_eq!(
+            copy.decode(&standard_frame()).unwrap(),
+            parse_frame(&standard_frame(), &DEFAULT_MACS).unwrap()
+        );
+        assert_eq!(
+            standard.encode(&standard.extract(&standard_frame()).unwrap()),
+            standard_frame().to_vec()
+        );
+
+        // a layout with a length byte checks it
+        let with_length = FrameFormat::parse(&format!("{}:length=9", MODEL_B)).unwrap();
+        assert_eq!(with_length.expected_length(), Some(21));
+        let e = with_length.decode(&model_b_frame()).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "The length byte of a model-b frame must be 0x15 but was 0xBB"
+        );
+        let mut frame = model_b_frame();
+        frame[9] = 21;
+        assert!(with_length.decode(&frame).is_ok());
+        assert!(FrameFormat::parse(&format!("{}:length=31", MODEL_B)).is_err());
+    }
+
+    // The standard frame with the crc of its payload after the length byte, which counts the crc too
+    fn standard_frame_with_crc() -> Vec<u8> {
+        let standard = standard_frame();
+        let mut frame = standard[..9].to_vec();
+        frame[8] = 0x14;
+        frame.extend_from_slice(&crc16(&standard[9..]).to_le_bytes());
+        frame.extend_from_slice(&standard[9..]);
+        frame
+    }
+
+    #[test]
+    fn a_crc_in_front_of_the_payload_moves_the_fields_along() {
+        let legacy = FrameFormat::standard();
+        let with_crc = legacy.with_crc();
+        assert_eq!(with_crc.len, FRAME_LEN + 2);
+        assert_eq!(with_crc.crc, Some(9));
+        assert_eq!(with_crc.fields[&Field::Battery], 11);
+        assert_eq!(with_crc.expected_length(), Some(0x14));
+
+        // both layouts decode to the same values
+        let frame = standard_frame_with_crc();
+        let msg = with_crc.decode(&frame).unwrap();
+        assert_eq!(msg, legacy.decode(&standard_frame()).unwrap());
+        assert_eq!(with_crc.encode(&msg), frame);
+
+        // a payload that doesn't match its crc
+        let mut corrupt = frame.clone();
+        corrupt[12] ^= 0x01;
+        let e = with_crc.extract(&corrupt).unwrap_err();
+        let expected = crc16(&corrupt[11..]);
+        assert!(matches!(e, RouterError::CrcMismatch { expected: e, .. } if e == expected));
+        assert_eq!(e.kind(), "checksum-mismatch");
+
+        // the other layouts can have one too
+        let model_b = FrameFormat::parse(MODEL_B_CRC).unwrap();
+        let length = FrameFormat::parse(&format!("{}:length=9", MODEL_B)).unwrap();
+        assert_eq!(model_b, length.with_crc());
+        assert!(FrameFormat::parse(&MODEL_B_CRC.replace("crc=10", "crc=32")).is_err());
+    }
+
+    #[test]
+    fn standard_frames_with_a_crc() {
+        let formats = Formats::new(vec![FrameFormat::parse(MODEL_B).unwrap()])
+            .unwrap()
+            .with_standard_crc();
+        let frame = standard_frame_with_crc();
+        let mut bytes = frame.clone();
+        bytes.extend_from_slice(&model_b_frame());
+        bytes.extend_from_slice(&frame);
+        let mut stream = Cursor::new(bytes);
+        let mut names = Vec::new();
+        for _ in 0..3 {
+            let (raw, _) = formats.read_frame(&mut stream, 0).unwrap();
+            names.push(formats.decode(&raw, &DEFAULT_MACS).unwrap().format);
+        }
+        assert_eq!(names, vec![STANDARD, "model-b", STANDARD]);
+        let decoded = formats.decode(&frame, &DEFAULT_MACS).unwrap();
+        assert_eq!(
+            decoded.frame,
+            Frame::Message(parse_frame(&standard_frame(), &DEFAULT_MACS).unwrap())
+        );
+
+        // the MAC address is still checked, and a frame without the crc is no longer a standard one
+        assert!(formats.decode(&frame, &[[0x01; 6]]).is_err());
+        assert!(formats.decode(&standard_frame(), &DEFAULT_MACS).is_err());
+
+        // a frame cut short is an error rather than a read past the end of it
+        let short = &frame[..frame.len() - 3];
+        let e = formats.decode(short, &DEFAULT_MACS).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof, "{}", e);
+        let layout = FrameFormat::standard().with_crc();
+        assert!(matches!(
+            layout.extract(short),
+            Err(RouterError::TruncatedFrame { got, expected }) if got == short.len() && expected == frame.len()
+        ));
+    }
+
+    #[test]
+    fn start_sequences_must_be_distinct() {
+        let clash = FrameFormat::parse(&MODEL_B.replace("start=1a00", "start=1900ff")).unwrap();
+        assert!(Formats::new(vec![clash]).is_err());
+        assert!(Formats::new(vec![FrameFormat::parse(MODEL_B).unwrap()]).is_ok());
+    }
+
+    #[test]
+    fn interleaved_formats_are_each_decoded_with_their_own_layout() {
+        let formats = Formats::new(vec![FrameFormat::parse(MODEL_B).unwrap()]).unwrap();
+        // starts part way through a frame, then alternates between the two models
+        let mut bytes = vec![0x02, 0x06, 0xBD];
+        bytes.extend_from_slice(&standard_frame());
+        bytes.extend_from_slice(&model_b_frame());
+        bytes.extend_from_slice(&standard_frame());
+        bytes.extend_from_slice(&model_b_frame());
+        let mut stream = Cursor::new(bytes);
+
+        let (raw, discarded) = formats.read_frame(&mut stream, FRAME_LEN).unwrap();
+        assert_eq!(discarded, 3);
+        let mut names = vec![];
+        let mut messages = vec![];
+        let mut raw = Some(raw);
+        for _ in 0..4 {
+            let frame = match raw.take() {
+                Some(raw) => raw,
+                None => formats.read_frame(&mut stream, 0).unwrap().0,
+            };
+            let decoded = formats.decode(&frame, &DEFAULT_MACS).unwrap();
+            names.push(decoded.format.to_string());
+            match decoded.frame {
+                Frame::Message(msg) => messages.push(msg),
+                other => panic!("Expected a message, got {:?}", other),
+            }
+        }
+        assert_eq!(names, vec!["standard", "model-b", "standard", "model-b"]);
+        assert_eq!(
+            messages[0],
+            parse_frame(&standard_frame(), &DEFAULT_MACS).unwrap()
+        );
+        assert_eq!(encode_frame(&messages[2]), standard_frame());
+
+        let model_b = &messages[1];
+        assert_eq!(model_b.mac, [0x11, 0x22, 0x33, 0x44, 0x55, 0x66]);
+        assert_eq!((model_b.batt_pid1, model_b.batt_value), (0x01, 0x50));
+        assert_eq!((model_b.temp_pid2, model_b.temp_value), (0x02, 0x20));
+        assert_eq!(
+            (
+                model_b.vib_pid3,
+                model_b.vib_x,
+                model_b.vib_y,
+                model_b.vib_z
+            ),
+            (0x03, 1, 2, 3)
+        );
+        assert_eq!((model_b.msg_num_pid5, model_b.msg_num_value), (0x05, 0x10));
+        assert_eq!((model_b.version_pid11, model_b.version_value), (0x0B, 0x04));
+        assert_eq!((model_b.rssi_pid6, model_b.rssi_value), (0x06, 0xC0));
+        assert_eq!(&messages[3], model_b);
+
+        // nothing left, a clean end of the stream
+        let e = formats.read_frame(&mut stream, 0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn an_unknown_start_sequence_is_an_error() {
+        let formats = Formats::new(vec![FrameFormat::parse(MODEL_B).unwrap()]).unwrap();
+        let mut stream = Cursor::new(vec![0x1B; 40]);
+        let e = formats.read_frame(&mut stream, 0).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert!(formats.decode(&[0x1B; 31], &DEFAULT_MACS).is_err());
+    }
+}
@@ -0,0 +1,2052 @@
+use crate::fields::{Field, FieldSet};
+use crate::formats::{Endian, FrameFormat};
+use crate::register_map::RegisterMap;
+use crate::status::{Thresholds, HIGH_VIBRATION, LOW_BATTERY, WEAK_RSSI};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::cmp::PartialEq;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, SystemTime};
+
+// Every frame is at least this many bytes long, a standard frame is longer when its payload is (see PAYLOAD_LEN)
+pub const FRAME_LEN: usize = 27;
+
+// The bytes of payload that hold the fields we know about. The length byte can count more than this, firmware
+// that adds a field puts it after them, so a longer payload is read to the end and the rest of it ignored
+pub const PAYLOAD_LEN: u8 = 0x12;
+
+// The longest payload we read to the end of. Anything claiming more is taken to be garbage rather than read,
+// otherwise one bad length byte could swallow the frames after it
+pub const MAX_PAYLOAD_LEN: u8 = 0x40;
+
+// check that the start sequence is 0x1900
+pub const START_SEQ: [u8; 2] = [0x19, 0x00];
+
+// the mac address of the one sensor the router was first written for, 0xD0CF5E82937B
+pub const MAC_ADDRESS: [u8; 6] = [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B];
+
+// The MAC addresses accepted when none are configured (see --macs)
+pub const DEFAULT_MACS: [[u8; 6]; 1] = [MAC_ADDRESS];
+
+// Gateways send keepalive frames that carry no sensor readings, they have this byte where the payload length
+// would normally be. The frame is still FRAME_LEN bytes long
+pub const HEARTBEAT_MARKER: u8 = 0x00;
+
+// The start sequence, the MAC address and the length byte, which between them are what says a frame starts here
+pub const HEADER_LEN: usize = START_SEQ.len() + MAC_ADDRESS.len() + 1;
+
+// How far into a fresh connection we look for the start of a frame before giving up
+pub const MAX_ALIGNMENT_SCAN: usize = FRAME_LEN * 8;
+
+// What can go wrong reading a message. All but Io are a bad frame, the stream is still there
+// and we can find our place in it again. Io is the stream itself failing or the device going away
+#[derive(Debug)]
+pub enum RouterError {
+    BadStartSequence,
+    // a MAC address that isn't one of the ones we accept
+    UnexpectedMac([u8; 6]),
+    // the payload length byte, which should be at least 0x12
+    BadPayloadLength(u8),
+    // a payload length byte over MAX_PAYLOAD_LEN
+    PayloadTooLong(u8),
+    // a payload length byte other than the one the device always sends (see --payload-len)
+    UnexpectedPayloadLength {
+        expected: u8,
+        actual: u8,
+    },
+    // the PID byte in front of a field isn't the one for that field, the payload is corrupt
+    BadPid {
+        field: Field,
+        pid: u8,
+    },
+    // the last byte isn't the checksum of the others (see --verify-checksum)
+    ChecksumMismatch {
+        expected: u8,
+        actual: u8,
+    },
+    // the crc in front of the payload isn't the crc of the bytes after it (see FrameFormat::crc)
+    CrcMismatch {
+        expected: u16,
+        actual: u16,
+    },
+    // a byte that should never change has (see --constant-byte)
+    UnexpectedByte {
+        offset: usize,
+        expected: u8,
+        actual: u8,
+    },
+    // the stream ended between frames, e.g. a device that restarted, there is nothing wrong with the frames
+    CleanEof,
+    // the stream ended part way through a frame, after got bytes of the expected ones
+    TruncatedFrame {
+        got: usize,
+        expected: usize,
+    },
+    // nothing arrived within the read timeout, e.g. a device that stalled part way through a frame
+    Timeout(io::Error),
+    Io(io::Error),
+}
+
+impl RouterError {
+    // Whether the frame was bad rather than the stream
+    pub fn is_bad_frame(&self) -> bool {
+        !matches!(
+            self,
+            RouterError::CleanEof
+                | RouterError::TruncatedFrame { .. }
+                | RouterError::Io(_)
+                | RouterError::Timeout(_)
+        )
+    }
+
+    // A short name for what was wrong with the frame, for the metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RouterError::BadStartSequence => "bad-start-sequence",
+            RouterError::UnexpectedMac(_) => "unexpected-mac",
+            RouterError::BadPayloadLength(_) => "bad-payload-length",
+            RouterError::PayloadTooLong(_) => "payload-too-long",
+            RouterError::UnexpectedPayloadLength { .. } => "bad-payload-length",
+            RouterError::BadPid { .. } => "bad-pid",
+            RouterError::ChecksumMismatch { .. } | RouterError::CrcMismatch { .. } => {
+                "checksum-mismatch"
+            }
+            RouterError::UnexpectedByte { .. } => "unexpected-byte",
+            RouterError::CleanEof => "eof",
+            RouterError::TruncatedFrame { .. } => "truncated-frame",
+            RouterError::Timeout(_) => "timeout",
+            RouterError::Io(_) => "io",
+        }
+    }
+}
+
+// The kind of a bad frame that has been turned into an io::Error, None if the stream failed rather than the frame.
+// Bad frames that don't come from read_message, e.g. a missing terminator, are all "other"
+pub fn framing_error_kind(e: &io::Error) -> Option<&'static str> {
+    if e.kind() != ErrorKind::InvalidData {
+        return None;
+    }
+    let kind = e
+        .get_ref()
+        .and_then(|inner| inner.downcast_ref::<RouterError>())
+        .map_or("other", RouterError::kind);
+    Some(kind)
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RouterError::BadStartSequence => write!(f, "Unrecognised start sequence"),
+            RouterError::UnexpectedMac(mac) => {
+                write!(f, "Unexpected MAC address {}", format_mac(mac))
+            }
+            RouterError::BadPayloadLength(len) => write!(
+                f,
+                "Length of payload must be at least 0x12 (18 bytes) but was 0x{:02X}",
+                len
+            ),
+            RouterError::PayloadTooLong(len) => write!(
+                f,
+                "Length of payload can be at most 0x{:02X} ({} bytes) but was 0x{:02X}",
+                MAX_PAYLOAD_LEN, MAX_PAYLOAD_LEN, len
+            ),
+            RouterError::UnexpectedPayloadLength { expected, actual } => write!(
+                f,
+                "Length of payload should be 0x{:02X} ({} bytes) but was 0x{:02X}",
+                expected, expected, actual
+            ),
+            RouterError::BadPid { field, pid } => write!(
+                f,
+                "The PID of the {} must be 0x{:02X} but was 0x{:02X}",
+                field.name(),
+                field.pid(),
+                pid
+            ),
+            RouterError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "The checksum of the frame should be 0x{:02X} but was 0x{:02X}",
+                expected, actual
+            ),
+            RouterError::CrcMismatch { expected, actual } => write!(
+                f,
+                "The crc of the frame should be 0x{:04X} but was 0x{:04X}",
+                expected, actual
+            ),
+            RouterError::UnexpectedByte {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Byte {} of the frame should always be 0x{:02X} but was 0x{:02X}",
+                offset, expected, actual
+            ),
+            RouterError::CleanEof => write!(f, "The connection was closed by the device"),
+            RouterError::TruncatedFrame { got, expected } => write!(
+                f,
+                "The stream ended part way through a frame, after {} of {} bytes",
+                got, expected
+            ),
+            RouterError::Timeout(e) => write!(f, "Timed out waiting for the device: {}", e),
+            RouterError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for RouterError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RouterError::Io(e) | RouterError::Timeout(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// A read timeout shows up as WouldBlock on some platforms and TimedOut on others
+impl From<io::Error> for RouterError {
+    fn from(e: io::Error) -> RouterError {
+        match e.kind() {
+            ErrorKind::WouldBlock | ErrorKind::TimedOut => RouterError::Timeout(e),
+            _ => RouterError::Io(e),
+        }
+    }
+}
+
+// For the parts of the router that read any kind of frame and only deal in io::Error.
+// A bad frame is InvalidData, with the RouterError inside it. The end of the stream is UnexpectedEof either way,
+// is_partial_frame tells them apart
+impl From<RouterError> for io::Error {
+    fn from(e: RouterError) -> io::Error {
+        match e {
+            RouterError::Io(e) | RouterError::Timeout(e) => e,
+            RouterError::CleanEof => io::Error::new(ErrorKind::UnexpectedEof, e.to_string()),
+            e @ RouterError::TruncatedFrame { .. } => io::Error::new(ErrorKind::UnexpectedEof, e),
+            e => io::Error::new(ErrorKind::InvalidData, e),
+        }
+    }
+}
+
+// How the last byte of a frame is worked out from the ones before it, for firmware that sends a checksum there.
+// The standard frame has the rssi there instead, so nothing is checked unless --verify-checksum asks for it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checksum {
+    // every byte xor'd together
+    Xor,
+    // the bytes added up, keeping the lowest 8 bits
+    Sum,
+}
+
+impl Checksum {
+    pub fn from_name(name: &str) -> Option<Checksum> {
+        match name {
+            "xor" => Some(Checksum::Xor),
+            "sum" => Some(Checksum::Sum),
+            _ => None,
+        }
+    }
+
+    pub fn of(self, bytes: &[u8]) -> u8 {
+        match self {
+            Checksum::Xor => bytes.iter().fold(0, |acc, byte| acc ^ byte),
+            Checksum::Sum => bytes
+                .iter()
+                .fold(0, |acc: u8, byte| acc.wrapping_add(*byte)),
+        }
+    }
+}
+
+// Checks the last byte of the frame against the checksum of bytes 0 to 25
+pub fn verify_checksum(buffer: &[u8; FRAME_LEN], checksum: Checksum) -> Result<(), RouterError> {
+    let expected = checksum.of(&buffer[..FRAME_LEN - 1]);
+    let actual = buffer[FRAME_LEN - 1];
+    if expected != actual {
+        return Err(RouterError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+// Checks the bytes at the given offsets of the frame against the values they should always have, to catch a
+// firmware change that moves things around without upsetting any of the other checks. The first one that
+// is different is the error
+pub fn check_constant_bytes(buffer: &[u8], constants: &[(usize, u8)]) -> Result<(), RouterError> {
+    for (offset, expected) in constants {
+        let actual = buffer.get(*offset).copied().unwrap_or_default();
+        if actual != *expected {
+            return Err(RouterError::UnexpectedByte {
+                offset: *offset,
+                expected: *expected,
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
+
+// Checks that the frame declares the payload length the device always sends, for a model whose payload is a
+// fixed number of bytes longer than ours and where any other length means the frame is corrupt. Heartbeats have
+// no payload and are let through. Without this every length from PAYLOAD_LEN to MAX_PAYLOAD_LEN is read
+pub fn check_payload_len(buffer: &[u8], expected: u8) -> Result<(), RouterError> {
+    let actual = FrameFormat::standard()
+        .length
+        .and_then(|at| buffer.get(at).copied())
+        .unwrap_or_default();
+    if actual != expected && actual != HEARTBEAT_MARKER {
+        return Err(RouterError::UnexpectedPayloadLength { expected, actual });
+    }
+    Ok(())
+}
+
+// This function takes a mutable reference to the stream which implements the Read trait.
+// If the read is successful the function will return a populated DeviceMessage struct, otherwise a RouterError.
+// Frames from a MAC address that isn't in macs are rejected, the error says which MAC address it was. An empty
+// macs accepts every MAC address.
+// The stream ending before the frame starts is CleanEof, ending once it has started is TruncatedFrame.
+// The message is stamped with the time the whole frame had arrived
+pub fn read_message<T: Read>(
+    stream: &mut T,
+    macs: &[[u8; 6]],
+) -> Result<DeviceMessage, RouterError> {
+    read_frame(stream, macs).map(|frame| frame.message)
+}
+
+// A message along with the bytes it was decoded from, for tools that keep exactly what was received
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedFrame {
+    pub message: DeviceMessage,
+    // the whole frame, a longer payload (see PAYLOAD_LEN) and all
+    pub raw: Vec<u8>,
+}
+
+// The same as read_message but the frame's bytes come with the message
+pub fn read_frame<T: Read>(stream: &mut T, macs: &[[u8; 6]]) -> Result<ParsedFrame, RouterError> {
+    let mut buffer = [0; FRAME_LEN];
+    check_complete(read_up_to(stream, &mut buffer)?, FRAME_LEN)?;
+    let mut extra = vec![0; extra_payload_len(&buffer, START_SEQ)];
+    let got = read_up_to(stream, &mut extra)?;
+    check_complete(FRAME_LEN + got, FRAME_LEN + extra.len())?;
+    let mut message = parse_frame(&buffer, macs).map_err(|e| {
+        // the error alone doesn't say much when the device is somewhere else
+        debug!("Unable to parse frame {}: {}", format_hex(&buffer), e);
+        e
+    })?;
+    message.received_at = Some(SystemTime::now());
+    let mut raw = buffer.to_vec();
+    raw.extend_from_slice(&extra);
+    Ok(ParsedFrame { message, raw })
+}
+
+// Use this instead of read_message for the first frame on a new connection.
+// Gateways don't always start on a frame boundary (for example they may still be sending the rest of a frame
+// from a previous session) so we skip bytes until we find the start sequence followed by the MAC address.
+// Returns the message and the number of bytes that were thrown away to get there
+pub fn read_first_message<T: Read>(
+    stream: &mut T,
+    macs: &[[u8; 6]],
+) -> Result<(DeviceMessage, usize), RouterError> {
+    let (buffer, discarded) = read_first_frame(stream, macs)?;
+    read_extra_payload(stream, START_SEQ, &buffer)?;
+    let mut message = parse_frame(&buffer, macs)?;
+    message.received_at = Some(SystemTime::now());
+    Ok((message, discarded))
+}
+
+// The messages of a stream one after the other, for callers that don't need to look for the start of a frame
+// or deal with heartbeats, e.g. for msg in MessageReader::new(stream) { ... }.
+// A bad frame is an Err and the next frame is read after it, the end of the stream is the end of the messages.
+// An error from the stream itself, including one that ends part way through a frame, is the last item,
+// apart from a timeout which can be tried again
+pub struct MessageReader<R> {
+    stream: R,
+    macs: Vec<[u8; 6]>,
+    finished: bool,
+}
+
+impl<R: Read> MessageReader<R> {
+    // Only accepts frames from the default MAC address
+    pub fn new(stream: R) -> MessageReader<R> {
+        MessageReader::with_macs(stream, &DEFAULT_MACS)
+    }
+
+    pub fn with_macs(stream: R, macs: &[[u8; 6]]) -> MessageReader<R> {
+        MessageReader {
+            stream,
+            macs: macs.to_vec(),
+            finished: false,
+        }
+    }
+}
+
+impl<R: Read> Iterator for MessageReader<R> {
+    type Item = Result<DeviceMessage, RouterError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        match read_message(&mut self.stream, &self.macs) {
+            // the stream ended on a frame boundary, there is nothing wrong with that
+            Err(RouterError::CleanEof) => {
+                self.finished = true;
+                None
+            }
+            Err(e @ RouterError::TruncatedFrame { .. }) | Err(e @ RouterError::Io(_)) => {
+                self.finished = true;
+                Some(Err(e))
+            }
+            result => Some(result),
+        }
+    }
+}
+
+// How much is asked of the stream at a time by FramedReader
+const READ_CHUNK: usize = 1024;
+
+// Cuts whole frames out of a stream while reading from it as much as it has to give, for streams where a read
+// can end part way through one frame or carry on into the next. The bytes after the last whole frame are kept
+// for the next one, so unlike read_raw_frame a read that times out part way through a frame loses nothing:
+// asking again carries on where it left off. Frames don't have to be the same length (see next_frame_by)
+pub struct FramedReader<R> {
+    stream: R,
+    // read from the stream but not handed out yet, the front of it is the start of the next frame
+    pending: Vec<u8>,
+}
+
+impl<R: Read> FramedReader<R> {
+    pub fn new(stream: R) -> FramedReader<R> {
+        FramedReader {
+            stream,
+            pending: Vec::new(),
+        }
+    }
+
+    // The next standard frame, undecoded
+    pub fn next_frame(&mut self) -> Result<[u8; FRAME_LEN], io::Error> {
+        let bytes = self.next_frame_by(|_| Some(FRAME_LEN))?;
+        let mut frame = [0; FRAME_LEN];
+        frame.copy_from_slice(&bytes);
+        Ok(frame)
+    }
+
+    // The next frame, however long frame_len says it is. It is given the bytes of the frame that have arrived
+    // so far and returns its length once it can tell, e.g. from a length byte, or None while it needs more
+    pub fn next_frame_by<F: Fn(&[u8]) -> Option<usize>>(
+        &mut self,
+        frame_len: F,
+    ) -> Result<Vec<u8>, io::Error> {
+        loop {
+            if let Some(len) = frame_len(&self.pending) {
+                if self.pending.len() >= len {
+                    let rest = self.pending.split_off(len);
+                    return Ok(std::mem::replace(&mut self.pending, rest));
+                }
+            }
+            let mut chunk = [0; READ_CHUNK];
+            let read = match self.stream.read(&mut chunk) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                read => read?,
+            };
+            // the same errors as fill_buffer, so that the router can tell the two kinds of end apart
+            if read == 0 {
+                let e = if self.pending.is_empty() {
+                    io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "The connection was closed by the device",
+                    )
+                } else {
+                    io::Error::new(ErrorKind::UnexpectedEof, PartialFrame)
+                };
+                return Err(e);
+            }
+            self.pending.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    // What has been read beyond the last frame handed out
+    pub fn buffered(&self) -> &[u8] {
+        &self.pending
+    }
+}
+
+// The raw version of read_first_message, returns the undecoded frame and the number of bytes skipped
+pub fn read_first_frame<T: Read>(
+    stream: &mut T,
+    macs: &[[u8; 6]],
+) -> Result<([u8; FRAME_LEN], usize), io::Error> {
+    resync_to_start(stream, START_SEQ, macs, MAX_ALIGNMENT_SCAN)
+}
+
+// read_first_frame with a limit of our own, for frames that start with start_seq (START_SEQ unless the device
+// is one that has another, see --start-sequence). Also used after a bad frame to find our place again
+// without dropping the connection, giving up once more than max_skip bytes have gone by
+pub fn resync_to_start<T: Read>(
+    stream: &mut T,
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    max_skip: usize,
+) -> Result<([u8; FRAME_LEN], usize), io::Error> {
+    let mut buffer = [0; FRAME_LEN];
+    fill_buffer(stream, &mut buffer[..HEADER_LEN])?;
+
+    // slide the header window along one byte at a time until it lines up with the start of a frame
+    let mut discarded = 0;
+    while !is_frame_header(&buffer[..HEADER_LEN], start_seq, macs) {
+        if discarded == max_skip {
+            let e = io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Unable to find the start of a frame after skipping {} bytes",
+                    discarded
+                ),
+            );
+            return Err(e);
+        }
+        buffer.copy_within(1..HEADER_LEN, 0);
+        fill_buffer(stream, &mut buffer[HEADER_LEN - 1..HEADER_LEN])?;
+        discarded += 1;
+    }
+
+    fill_buffer(stream, &mut buffer[HEADER_LEN..])?;
+    Ok((buffer, discarded))
+}
+
+// Whether the bytes start with the header of a standard frame from one of the MAC addresses, or any when there
+// are none. The start sequence alone turns up inside payloads often enough to lose our place on, so the length
+// byte has to be one a frame could have as well: a heartbeat or a payload we would read
+pub fn is_frame_header(bytes: &[u8], start_seq: [u8; 2], macs: &[[u8; 6]]) -> bool {
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(&start_seq) {
+        return false;
+    }
+    let length = bytes[HEADER_LEN - 1];
+    (macs.is_empty()
+        || macs
+            .iter()
+            .any(|mac| bytes[START_SEQ.len()..HEADER_LEN - 1].eq(mac)))
+        && (length == HEARTBEAT_MARKER || (PAYLOAD_LEN..=MAX_PAYLOAD_LEN).contains(&length))
+}
+
+// Where in the bytes the next frame starts (see is_frame_header), for bytes that are already in memory.
+// None when there is no whole header in them
+pub fn find_next_frame(bytes: &[u8], macs: &[[u8; 6]]) -> Option<usize> {
+    (0..bytes.len()).find(|at| is_frame_header(&bytes[*at..], START_SEQ, macs))
+}
+
+// How many more bytes there are after the first FRAME_LEN of a standard frame, going by its length byte.
+// Nothing for anything that doesn't start with start_seq like a standard frame, and for heartbeats and bad
+// lengths which are FRAME_LEN long. A length over MAX_PAYLOAD_LEN is a bad length too
+pub fn extra_payload_len(frame: &[u8], start_seq: [u8; 2]) -> usize {
+    let length = FrameFormat::standard().length.and_then(|at| frame.get(at));
+    match length {
+        Some(length) if frame.starts_with(&start_seq) && *length <= MAX_PAYLOAD_LEN => {
+            length.saturating_sub(PAYLOAD_LEN) as usize
+        }
+        _ => 0,
+    }
+}
+
+// Reads the rest of a standard frame whose payload is longer than PAYLOAD_LEN, once the first FRAME_LEN bytes
+// of it have been read. The bytes are returned for callers that keep the frame as it arrived
+pub fn read_extra_payload<T: Read>(
+    stream: &mut T,
+    start_seq: [u8; 2],
+    frame: &[u8],
+) -> Result<Vec<u8>, io::Error> {
+    let mut extra = vec![0; extra_payload_len(frame, start_seq)];
+    fill_buffer(stream, &mut extra).map_err(|e| match e.kind() {
+        // the frame has already started, so this is never a clean end
+        ErrorKind::UnexpectedEof => io::Error::new(ErrorKind::UnexpectedEof, PartialFrame),
+        _ => e,
+    })?;
+    Ok(extra)
+}
+
+// Reads exactly one frame worth of bytes from the stream
+pub fn read_raw_frame<T: Read>(stream: &mut T) -> Result<[u8; FRAME_LEN], io::Error> {
+    // the buffer used to contain a frame of data from the stream
+    let mut buffer = [0; FRAME_LEN];
+    fill_buffer(stream, &mut buffer)?;
+    Ok(buffer)
+}
+
+// For devices that send a delimiter after every frame. Checks that the delimiter follows the frame,
+// a frame without one means we have lost our place in the stream (see read_first_delimited_frame)
+pub fn read_delimited_frame<T: Read>(
+    stream: &mut T,
+    terminator: &[u8],
+) -> Result<[u8; FRAME_LEN], io::Error> {
+    let mut buffer = vec![0; FRAME_LEN + terminator.len()];
+    fill_buffer(stream, &mut buffer)?;
+    if buffer[FRAME_LEN..].ne(terminator) {
+        let e = io::Error::new(ErrorKind::InvalidData, "Missing frame terminator");
+        return Err(e);
+    }
+    let mut frame = [0; FRAME_LEN];
+    frame.copy_from_slice(&buffer[..FRAME_LEN]);
+    Ok(frame)
+}
+
+// The delimited version of read_first_frame, for a new connection or after losing our place.
+// A frame boundary is where the start sequence is followed a frame later by the terminator, which is
+// a lot harder to hit by chance than the start sequence on its own
+pub fn read_first_delimited_frame<T: Read>(
+    stream: &mut T,
+    start_seq: [u8; 2],
+    terminator: &[u8],
+    max_skip: usize,
+) -> Result<([u8; FRAME_LEN], usize), io::Error> {
+    let mut window = vec![0; FRAME_LEN + terminator.len()];
+    fill_buffer(stream, &mut window)?;
+
+    let mut discarded = 0;
+    while window[..2].ne(&start_seq) || window[FRAME_LEN..].ne(terminator) {
+        if discarded == max_skip {
+            let e = io::Error::new(ErrorKind::InvalidData, "Unable to find a frame terminator");
+            return Err(e);
+        }
+        let last = window.len() - 1;
+        window.copy_within(1.., 0);
+        fill_buffer(stream, &mut window[last..])?;
+        discarded += 1;
+    }
+
+    let mut frame = [0; FRAME_LEN];
+    frame.copy_from_slice(&window[..FRAME_LEN]);
+    Ok((frame, discarded))
+}
+
+// The error inside an UnexpectedEof when the stream stopped with only some of a frame read.
+// Without it the stream ended cleanly between frames (see is_partial_frame)
+#[derive(Debug)]
+pub struct PartialFrame;
+
+impl fmt::Display for PartialFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The stream ended part way through a frame")
+    }
+}
+
+impl Error for PartialFrame {}
+
+// Whether the stream ended part way through a frame rather than on a frame boundary
+pub fn is_partial_frame(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| {
+        inner.is::<PartialFrame>()
+            || matches!(
+                inner.downcast_ref::<RouterError>(),
+                Some(RouterError::TruncatedFrame { .. })
+            )
+    })
+}
+
+// read until we fill up the buffer
+pub(crate) fn fill_buffer<T: Read>(stream: &mut T, buffer: &mut [u8]) -> Result<(), io::Error> {
+    let num_bytes = read_up_to(stream, buffer)?;
+    // the other end has closed the connection before all of it arrived, there is no more to come
+    if num_bytes < buffer.len() {
+        let e = if num_bytes > 0 {
+            io::Error::new(ErrorKind::UnexpectedEof, PartialFrame)
+        } else {
+            io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "The connection was closed by the device",
+            )
+        };
+        return Err(e);
+    }
+    Ok(())
+}
+
+// Reads until the buffer is full or the stream ends, returns how many bytes were read
+pub(crate) fn read_up_to<T: Read>(stream: &mut T, buffer: &mut [u8]) -> Result<usize, io::Error> {
+    let mut num_bytes = 0;
+    while num_bytes < buffer.len() {
+        // pass in a slice of our buffer (we don't want to overwrite what has already been read)
+        // the ? is there to propogate OK results or to catch IO errors and exit the function if they are encountered.
+        // A read interrupted by a signal before anything arrived didn't fail, it is just tried again
+        let read = match stream.read(&mut buffer[num_bytes..]) {
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            read => read?,
+        };
+        // a read of zero bytes means the other end has closed the connection
+        if read == 0 {
+            break;
+        }
+        num_bytes += read;
+    }
+    Ok(num_bytes)
+}
+
+// Whether all of a frame arrived for read_message, got of the expected bytes before the stream ended.
+// None of it at all is the stream ending between frames
+pub(crate) fn check_complete(got: usize, expected: usize) -> Result<(), RouterError> {
+    match got {
+        0 => Err(RouterError::CleanEof),
+        got if got < expected => Err(RouterError::TruncatedFrame { got, expected }),
+        _ => Ok(()),
+    }
+}
+
+// What a frame from the gateway can hold
+#[derive(Debug, Clone, PartialEq)]
+pub enum Frame {
+    Message(DeviceMessage),
+    // a keepalive, the device is still there but has nothing new to say
+    Heartbeat { mac: [u8; 6] },
+}
+
+// Like parse_frame but also understands heartbeat frames
+pub fn decode_frame(buffer: &[u8; FRAME_LEN], macs: &[[u8; 6]]) -> Result<Frame, RouterError> {
+    decode_frame_as(buffer, START_SEQ, macs, Endian::Little)
+}
+
+// decode_frame for a sensor that starts its frames with start_seq and sends its u16 values in the given byte order
+pub fn decode_frame_as(
+    buffer: &[u8; FRAME_LEN],
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    endian: Endian,
+) -> Result<Frame, RouterError> {
+    let mac = check_header(buffer, start_seq, macs)?;
+    let heartbeat = FrameFormat::standard()
+        .length
+        .is_some_and(|at| buffer[at] == HEARTBEAT_MARKER);
+    if heartbeat {
+        return Ok(Frame::Heartbeat { mac });
+    }
+    parse_frame_as(buffer, start_seq, macs, endian).map(Frame::Message)
+}
+
+// The start sequence and MAC address come first in every frame, returns the MAC address. Empty macs means any
+fn check_header(
+    buffer: &[u8; FRAME_LEN],
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+) -> Result<[u8; 6], RouterError> {
+    let layout = FrameFormat::standard();
+    // slices implement the PartialEq trait so we can call ne function on them (not equal)
+    if buffer[..start_seq.len()].ne(&start_seq[..]) {
+        return Err(RouterError::BadStartSequence);
+    }
+
+    let mut mac = [0; 6];
+    mac.copy_from_slice(&buffer[layout.mac..layout.mac + 6]);
+    if !macs.is_empty() && !macs.contains(&mac) {
+        return Err(RouterError::UnexpectedMac(mac));
+    }
+    Ok(mac)
+}
+
+// Checks the frame and extracts the DeviceMessage from it, heartbeat frames are rejected (see decode_frame).
+// Where everything is comes from FrameFormat::standard
+pub fn parse_frame(
+    buffer: &[u8; FRAME_LEN],
+    macs: &[[u8; 6]],
+) -> Result<DeviceMessage, RouterError> {
+    parse_frame_as(buffer, START_SEQ, macs, Endian::Little)
+}
+
+// parse_frame for a sensor that starts its frames with start_seq and sends its u16 values in the given byte order
+pub fn parse_frame_as(
+    buffer: &[u8; FRAME_LEN],
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    endian: Endian,
+) -> Result<DeviceMessage, RouterError> {
+    check_header(buffer, start_seq, macs)?;
+
+    // read the payload into the DeviceMessage struct, checking the length byte on the way
+    // the start sequence has been checked, after it the layout is the standard one whatever the start was
+    let mut standard = *buffer;
+    standard[..START_SEQ.len()].copy_from_slice(&START_SEQ);
+    let message = FrameFormat::standard().extract_as(&standard, endian)?;
+
+    // the PIDs are the register addresses by default so a corrupt one would write to the wrong register
+    for field in Field::ALL.iter() {
+        let pid = message.field_address(*field) as u8;
+        if pid != field.pid() {
+            return Err(RouterError::BadPid { field: *field, pid });
+        }
+    }
+
+    // return the message
+    Ok(message)
+}
+
+// Parses every complete frame in the buffer, for when the bytes are already in memory (e.g. a capture being replayed).
+// The buffer is taken to start on a frame boundary and a bad frame doesn't stop the ones after it from being parsed.
+// Returns a result for each frame and the number of bytes left over at the end that don't make up a whole frame
+pub fn parse_all(
+    bytes: &[u8],
+    macs: &[[u8; 6]],
+) -> (Vec<Result<DeviceMessage, RouterError>>, usize) {
+    let mut results = Vec::new();
+    let mut rest = bytes;
+    while rest.len() >= FRAME_LEN {
+        // a longer payload makes for a longer frame
+        let len = FRAME_LEN + extra_payload_len(rest, START_SEQ);
+        if rest.len() < len {
+            break;
+        }
+        let mut buffer = [0; FRAME_LEN];
+        buffer.copy_from_slice(&rest[..FRAME_LEN]);
+        results.push(parse_frame(&buffer, macs));
+        rest = &rest[len..];
+    }
+    (results, rest.len())
+}
+
+// The reverse of parse_frame, turns a DeviceMessage back into the bytes the device would have sent
+pub fn encode_frame(msg: &DeviceMessage) -> [u8; FRAME_LEN] {
+    let mut buffer = [0; FRAME_LEN];
+    buffer.copy_from_slice(&FrameFormat::standard().encode(msg));
+    buffer
+}
+
+// All the useful information extracted from the tcp stream frame.
+// Deriving Debug allows us to print this struct to std out easily and Serialize lets us turn it into json
+// (and Deserialize back again, for --state-file)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeviceMessage {
+    pub mac: [u8; 6],
+    pub batt_pid1: u8,
+    pub batt_value: u8,
+    pub temp_pid2: u8,
+    pub temp_value: u8,
+    pub vib_pid3: u8,
+    pub vib_x: u16,
+    pub vib_y: u16,
+    pub vib_z: u16,
+    pub msg_num_pid5: u8,
+    pub msg_num_value: u16,
+    pub version_pid11: u8,
+    pub version_value: u8,
+    pub rssi_pid6: u8,
+    pub rssi_value: u8,
+    // which sensor behind the gateway sent the values, only for gateways that say (see --sensor-id-offset).
+    // The parser doesn't know where to find it, the caller fills this in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sensor_id: Option<u8>,
+    // when the router received the frame (not part of the frame itself)
+    #[serde(skip)]
+    pub received_at: Option<SystemTime>,
+}
+
+impl DeviceMessage {
+    // The register a field is written to, which is the PID byte that comes before it in the frame
+    pub fn field_address(&self, field: Field) -> u16 {
+        let pid = match field {
+            Field::Battery => self.batt_pid1,
+            Field::Temperature => self.temp_pid2,
+            Field::Vibration => self.vib_pid3,
+            Field::MsgNum => self.msg_num_pid5,
+            Field::Version => self.version_pid11,
+            Field::Rssi => self.rssi_pid6,
+        };
+        pid as u16
+    }
+
+    // Each register write the router would make for this message with the default register map, as the register
+    // it starts at and the values, in order. See RegisterMap::registers for any other map
+    pub fn to_registers(&self) -> Vec<(u16, Vec<u16>)> {
+        RegisterMap::default()
+            .registers(self, FieldSet::all())
+            .into_iter()
+            .map(|write| (write.address, write.values))
+            .collect()
+    }
+
+    // The register values of a field in the order they are written to the modbus
+    pub fn field_values(&self, field: Field) -> Vec<u16> {
+        match field {
+            Field::Battery => vec![self.batt_value as u16],
+            Field::Temperature => vec![self.temp_value as u16],
+            Field::Vibration => vec![self.vib_x, self.vib_y, self.vib_z],
+            Field::MsgNum => vec![self.msg_num_value],
+            Field::Version => vec![self.version_value as u16],
+            Field::Rssi => vec![self.rssi_value as u16],
+        }
+    }
+
+    // How long ago the message was received, for spotting messages that sat in a buffer before being forwarded.
+    // Zero if we don't know when it was received (or the clock has since gone backwards)
+    pub fn age(&self) -> Duration {
+        self.received_at
+            .and_then(|received_at| received_at.elapsed().ok())
+            .unwrap_or_default()
+    }
+
+    // The length of the vibration vector, sqrt(x^2 + y^2 + z^2) rounded to the nearest whole number.
+    // It can be up to about 113500 so anything that doesn't fit in a register is 65535
+    pub fn vib_magnitude(&self) -> u16 {
+        magnitude(self.vib_x as f64, self.vib_y as f64, self.vib_z as f64)
+    }
+
+    // The vibration axes as the newer firmware sends them, two's complement
+    pub fn vib_signed(&self) -> (i16, i16, i16) {
+        (self.vib_x as i16, self.vib_y as i16, self.vib_z as i16)
+    }
+
+    // vib_magnitude of the signed axes
+    pub fn vib_signed_magnitude(&self) -> u16 {
+        let (x, y, z) = self.vib_signed();
+        magnitude(x as f64, y as f64, z as f64)
+    }
+
+    // The conditions that hold for this message as bits (see the status module), e.g. 0b011 for a low battery
+    // and a weak signal. Only the conditions with a threshold are ever set
+    pub fn status_word(&self, thresholds: &Thresholds) -> u16 {
+        let vibration = match thresholds.signed_vibration {
+            true => self.vib_signed_magnitude(),
+            false => self.vib_magnitude(),
+        };
+        let conditions = [
+            (
+                LOW_BATTERY,
+                thresholds
+                    .low_battery
+                    .is_some_and(|low| self.batt_value < low),
+            ),
+            (
+                WEAK_RSSI,
+                thresholds
+                    .weak_rssi
+                    .is_some_and(|weak| self.rssi_value < weak),
+            ),
+            (
+                HIGH_VIBRATION,
+                thresholds
+                    .high_vibration
+                    .is_some_and(|high| vibration > high),
+            ),
+        ];
+        conditions
+            .iter()
+            .filter(|(_, set)| *set)
+            .fold(0, |word, (bit, _)| word | bit)
+    }
+
+    // The reverse of field_values, the single byte fields keep the low byte of the value
+    pub fn set_field_values(&mut self, field: Field, values: &[u16]) {
+        let value = |i: usize| values.get(i).cloned().unwrap_or(0);
+        match field {
+            Field::Battery => self.batt_value = value(0) as u8,
+            Field::Temperature => self.temp_value = value(0) as u8,
+            Field::Vibration => {
+                self.vib_x = value(0);
+                self.vib_y = value(1);
+                self.vib_z = value(2);
+            }
+            Field::MsgNum => self.msg_num_value = value(0),
+            Field::Version => self.version_value = value(0) as u8,
+            Field::Rssi => self.rssi_value = value(0) as u8,
+        }
+    }
+}
+
+fn magnitude(x: f64, y: f64, z: f64) -> u16 {
+    (x * x + y * y + z * z).sqrt().round().min(65535.0) as u16
+}
+
+// Formats bytes as space separated hex, e.g. 19 00 D0
+pub fn format_hex(bytes: &[u8]) -> String {
+    let parts: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    parts.join(" ")
+}
+
+// Hex bytes without the spaces, e.g. 1900
+pub fn parse_hex(value: &str) -> Result<Vec<u8>, String> {
+    let invalid = || format!("Expected hex bytes but got: {}", value);
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    value
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| {
+            let byte = std::str::from_utf8(pair).map_err(|_| invalid())?;
+            match (byte.len(), u8::from_str_radix(byte, 16)) {
+                (2, Ok(byte)) => Ok(byte),
+                _ => Err(invalid()),
+            }
+        })
+        .collect()
+}
+
+// The reverse of format_mac
+pub fn parse_mac(mac: &str) -> Option<[u8; 6]> {
+    let parts: Vec<&str> = mac.split(':').collect();
+    if parts.len() != 6 {
+        return None;
+    }
+    let mut bytes = [0; 6];
+    for (byte, part) in bytes.iter_mut().zip(parts) {
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+// Formats a MAC address the usual way, e.g. D0:CF:5E:82:93:7B
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    let parts: Vec<String> = mac.iter().map(|b| format!("{:02X}", b)).collect();
+    parts.join(":")
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Cursor;
+
+    // 7 correctly formed messages, one after the other
+    fn seven_messages() -> Vec<u8> {
+        vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD, 0x19,
+            0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFF,
+            0xF2, 0x77, 0x02, 0x74, 0x07, 0x05, 0x3B, 0x84, 0x0B, 0x02, 0x06, 0xCB, 0x19, 0x00,
+            0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFF, 0xF2,
+            0x63, 0x02, 0x76, 0x07, 0x05, 0x3C, 0x84, 0x0B, 0x02, 0x06, 0xC9, 0x19, 0x00, 0xD0,
+            0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x15, 0xF3, 0x78,
+            0x02, 0x66, 0x07, 0x05, 0x3D, 0x84, 0x0B, 0x02, 0x06, 0xBE, 0x19, 0x00, 0xD0, 0xCF,
+            0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x0E, 0xF3, 0x75, 0x02,
+            0x38, 0x07, 0x05, 0x3E, 0x84, 0x0B, 0x02, 0x06, 0xCB, 0x19, 0x00, 0xD0, 0xCF, 0x5E,
+            0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x07, 0xF3, 0x7B, 0x02, 0x65,
+            0x07, 0x05, 0x3F, 0x84, 0x0B, 0x02, 0x06, 0xC9, 0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82,
+            0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x20, 0xF3, 0x6F, 0x02, 0x5B, 0x07,
+            0x05, 0x40, 0x84, 0x0B, 0x02, 0x06, 0xBE,
+        ]
+    }
+
+    #[test]
+    fn message_reader_yields_every_message() {
+        let messages: Vec<_> = MessageReader::new(Cursor::new(seven_messages())).collect();
+        assert_eq!(messages.len(), 7);
+        assert!(messages.iter().all(|msg| msg.is_ok()));
+
+        // a bad frame doesn't stop the ones after it, a partial one at the end is the last item
+        let mut raw = seven_messages();
+        raw[0] = 0xFF;
+        raw.truncate(FRAME_LEN * 2 + 5);
+        let messages: Vec<_> = MessageReader::new(Cursor::new(raw)).collect();
+        assert_eq!(messages.len(), 3);
+        assert!(matches!(messages[0], Err(RouterError::BadStartSequence)));
+        assert!(messages[1].is_ok());
+        assert!(matches!(
+            messages[2],
+            Err(RouterError::TruncatedFrame {
+                got: 5,
+                expected: FRAME_LEN
+            })
+        ));
+    }
+
+    #[test]
+    fn messages_are_stamped_when_read() {
+        let before = SystemTime::now();
+        let msg = read_message(&mut Cursor::new(seven_messages()), &[MAC_ADDRESS]).unwrap();
+        let received_at = msg.received_at.unwrap();
+        assert!(received_at >= before && received_at <= SystemTime::now());
+
+        let msg = DeviceMessage {
+            received_at: Some(SystemTime::now() - Duration::from_secs(5)),
+            ..msg
+        };
+        assert!(msg.age() >= Duration::from_secs(5));
+        // parsing on its own doesn't know when the bytes arrived
+        let msg = parse_frame(&encode_frame(&msg), &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg.received_at, None);
+        assert_eq!(msg.age(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn read_message_multiple_messages() {
+        // this byte stream consists of 7 correctly formed messages.
+        // this test will decode all of them and explicitly check the first two
+        let raw = seven_messages();
+        let mut buff = Cursor::new(raw);
+
+        // unwrap will panic if read_message returns an Err
+        let msg1 = read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg1.mac, [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]);
+        assert_eq!(msg1.batt_pid1, 1);
+        assert_eq!(msg1.batt_value, 0);
+        assert_eq!(msg1.temp_pid2, 2);
+        assert_eq!(msg1.temp_value, 84);
+        assert_eq!(msg1.vib_pid3, 3);
+        assert_eq!(msg1.vib_x, 62206);
+        assert_eq!(msg1.vib_y, 602);
+        assert_eq!(msg1.vib_z, 1914);
+        assert_eq!(msg1.msg_num_pid5, 5);
+        assert_eq!(msg1.msg_num_value, 33850);
+        assert_eq!(msg1.version_pid11, 11);
+        assert_eq!(msg1.version_value, 2);
+        assert_eq!(msg1.rssi_pid6, 6);
+        assert_eq!(msg1.rssi_value, 189);
+
+        let msg2 = read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg2.batt_pid1, 1);
+        assert_eq!(msg2.batt_value, 0);
+        assert_eq!(msg2.temp_pid2, 2);
+        assert_eq!(msg2.temp_value, 84);
+        assert_eq!(msg2.vib_pid3, 3);
+        assert_eq!(msg2.vib_x, 62207);
+        assert_eq!(msg2.vib_y, 631);
+        assert_eq!(msg2.vib_z, 1908);
+        assert_eq!(msg2.msg_num_pid5, 5);
+        assert_eq!(msg2.msg_num_value, 33851);
+        assert_eq!(msg2.version_pid11, 11);
+        assert_eq!(msg2.version_value, 2);
+        assert_eq!(msg2.rssi_pid6, 6);
+        assert_eq!(msg2.rssi_value, 203);
+
+        // read the next 5 messages and ignore the contents
+        for _ in 0..5 {
+            read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        }
+    }
+
+    #[test]
+    fn read_first_message_skips_leading_garbage() {
+        // the tail end of a frame from a previous session followed by a complete frame
+        let raw = vec![
+            0x84, 0x0B, 0x02, 0x19, 0x00, 0x06, 0xBD, 0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93,
+            0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05,
+            0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        let (msg, discarded) = read_first_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(discarded, 7);
+        assert_eq!(msg.msg_num_value, 33850);
+        assert_eq!(msg.rssi_value, 189);
+    }
+
+    #[test]
+    fn read_first_message_already_aligned() {
+        let raw = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        let (_, discarded) = read_first_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(discarded, 0);
+    }
+
+    #[test]
+    fn read_first_message_gives_up() {
+        let mut buff = Cursor::new(vec![0xFF; MAX_ALIGNMENT_SCAN + FRAME_LEN]);
+        let err = read_first_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_message_from_allowed_macs() {
+        let first = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let other_mac = [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7C];
+        let mut second = first;
+        second[2..8].copy_from_slice(&other_mac);
+        let mut raw = first.to_vec();
+        raw.extend_from_slice(&second);
+        let macs = [MAC_ADDRESS, other_mac];
+
+        let mut buff = Cursor::new(raw.clone());
+        assert_eq!(read_message(&mut buff, &macs).unwrap().mac, MAC_ADDRESS);
+        assert_eq!(read_message(&mut buff, &macs).unwrap().mac, other_mac);
+
+        // the second one isn't on the list this time
+        let mut buff = Cursor::new(raw);
+        assert!(read_message(&mut buff, &[MAC_ADDRESS]).is_ok());
+        match read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err() {
+            RouterError::UnexpectedMac(mac) => assert_eq!(mac, other_mac),
+            other => panic!("Expected an unexpected MAC address, got {:?}", other),
+        }
+
+        // and with no list at all anybody's frame is read
+        let mut buff = Cursor::new(second.to_vec());
+        assert_eq!(read_message(&mut buff, &[]).unwrap().mac, other_mac);
+        assert!(is_frame_header(&second, START_SEQ, &[]));
+    }
+
+    #[test]
+    fn resync_after_noise_mid_stream() {
+        // a good frame, a few bytes of noise and then the frames carry on
+        let mut raw = sample_frame();
+        raw.extend_from_slice(&[0x00, 0x19, 0x55]);
+        raw.extend_from_slice(&sample_frame());
+        raw.extend_from_slice(&sample_frame());
+        let mut buff = Cursor::new(raw);
+        read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+
+        // the noise shifts the next read out of step
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(err.is_bad_frame());
+        // which took the start of the second frame with it, so the resync finds the third
+        let (frame, discarded) =
+            resync_to_start(&mut buff, START_SEQ, &[MAC_ADDRESS], 100).unwrap();
+        assert_eq!(discarded, 3);
+        assert_eq!(
+            parse_frame(&frame, &[MAC_ADDRESS]).unwrap().msg_num_value,
+            33850
+        );
+    }
+
+    #[test]
+    fn resync_gives_up_after_max_skip() {
+        let mut raw = vec![0xFF; 10];
+        raw.extend_from_slice(&sample_frame());
+        let e = resync_to_start(&mut Cursor::new(raw.clone()), START_SEQ, &[MAC_ADDRESS], 9)
+            .unwrap_err();
+        // the log says how much was thrown away
+        assert!(e.to_string().contains("after skipping 9 bytes"));
+        let (frame, discarded) =
+            resync_to_start(&mut Cursor::new(raw), START_SEQ, &[MAC_ADDRESS], 10).unwrap();
+        assert_eq!(discarded, 10);
+        assert_eq!(frame.to_vec(), sample_frame());
+    }
+
+    #[test]
+    fn resync_skips_a_start_sequence_inside_a_payload() {
+        // the rest of a frame whose payload happens to hold the start sequence and the MAC address,
+        // but not followed by a length a frame could have
+        let mut noise = vec![0x54, 0x03];
+        noise.extend_from_slice(&START_SEQ);
+        noise.extend_from_slice(&MAC_ADDRESS);
+        noise.extend_from_slice(&[0x02, 0x7A, 0x07]);
+        let mut raw = noise.clone();
+        raw.extend_from_slice(&sample_frame());
+        assert_eq!(find_next_frame(&raw, &[MAC_ADDRESS]), Some(noise.len()));
+        assert_eq!(find_next_frame(&noise, &[MAC_ADDRESS]), None);
+
+        let (frame, discarded) =
+            resync_to_start(&mut Cursor::new(raw), START_SEQ, &[MAC_ADDRESS], 100).unwrap();
+        assert_eq!(discarded, noise.len());
+        assert_eq!(frame.to_vec(), sample_frame());
+
+        // a heartbeat is somewhere to start from too
+        let mut heartbeat = sample_frame();
+        heartbeat[HEADER_LEN - 1] = HEARTBEAT_MARKER;
+        assert!(is_frame_header(&heartbeat, START_SEQ, &[MAC_ADDRESS]));
+        assert!(!is_frame_header(
+            &heartbeat[..HEADER_LEN - 1],
+            START_SEQ,
+            &[MAC_ADDRESS]
+        ));
+    }
+
+    #[test]
+    fn parse_a_frame_with_another_start_sequence() {
+        let start_seq = [0x1A, 0x00];
+        let mut raw = sample_frame();
+        raw[..2].copy_from_slice(&start_seq);
+        let mut buffer = [0; FRAME_LEN];
+        buffer.copy_from_slice(&raw);
+
+        let msg = parse_frame_as(&buffer, start_seq, &[MAC_ADDRESS], Endian::Little).unwrap();
+        let standard = parse_frame(&buffer_of(&sample_frame()), &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg, standard);
+        // to a standard device it's rubbish
+        match parse_frame(&buffer, &[MAC_ADDRESS]) {
+            Err(RouterError::BadStartSequence) => (),
+            other => panic!("Expected a bad start sequence, got {:?}", other),
+        }
+
+        // and the other way round, as well as finding it in the stream
+        assert!(decode_frame_as(
+            &buffer_of(&sample_frame()),
+            start_seq,
+            &[MAC_ADDRESS],
+            Endian::Little
+        )
+        .is_err());
+        let mut stream = vec![0x19, 0x00, 0xAA];
+        stream.extend_from_slice(&raw);
+        let (frame, discarded) =
+            resync_to_start(&mut Cursor::new(stream), start_seq, &[MAC_ADDRESS], 100).unwrap();
+        assert_eq!((frame, discarded), (buffer, 3));
+    }
+
+    fn buffer_of(raw: &[u8]) -> [u8; FRAME_LEN] {
+        let mut buffer = [0; FRAME_LEN];
+        buffer.copy_from_slice(raw);
+        buffer
+    }
+
+    // a frame from the tests above
+    fn sample_frame() -> Vec<u8> {
+        vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ]
+    }
+
+    const TERMINATOR: [u8; 2] = [0x0D, 0x0A];
+
+    #[test]
+    fn read_delimited_frame_checks_the_terminator() {
+        let mut raw = sample_frame();
+        raw.extend_from_slice(&TERMINATOR);
+        raw.extend_from_slice(&sample_frame());
+        raw.extend_from_slice(&[0x0D, 0x00]);
+        let mut buff = Cursor::new(raw);
+        let frame = read_delimited_frame(&mut buff, &TERMINATOR).unwrap();
+        assert_eq!(frame.to_vec(), sample_frame());
+        let err = read_delimited_frame(&mut buff, &TERMINATOR).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "Missing frame terminator");
+    }
+
+    #[test]
+    fn resync_on_the_terminator() {
+        // a frame cut short so the reader loses its place, then a whole one. The start sequence turns up in the
+        // middle of the broken frame but isn't followed by a terminator a frame later so it can't fool the resync
+        let mut raw = sample_frame()[..20].to_vec();
+        raw.extend_from_slice(&[0x19, 0x00, 0x01]);
+        raw.extend_from_slice(&TERMINATOR);
+        for _ in 0..3 {
+            raw.extend_from_slice(&sample_frame());
+            raw.extend_from_slice(&TERMINATOR);
+        }
+        let mut buff = Cursor::new(raw);
+
+        // the broken frame runs into the next one, so its terminator isn't where it should be
+        assert!(read_delimited_frame(&mut buff, &TERMINATOR).is_err());
+        let (frame, discarded) =
+            read_first_delimited_frame(&mut buff, START_SEQ, &TERMINATOR, MAX_ALIGNMENT_SCAN)
+                .unwrap();
+        assert_eq!(frame.to_vec(), sample_frame());
+        // the failed read took the start of the next frame with it, so the rest of that one goes too
+        assert_eq!(discarded, FRAME_LEN - 4 + TERMINATOR.len());
+        // and back in step
+        let frame = read_delimited_frame(&mut buff, &TERMINATOR).unwrap();
+        assert_eq!(
+            parse_frame(&frame, &[MAC_ADDRESS]).unwrap().msg_num_value,
+            33850
+        );
+    }
+
+    #[test]
+    fn read_first_delimited_frame_gives_up() {
+        let mut buff = Cursor::new(vec![0x19; MAX_ALIGNMENT_SCAN + FRAME_LEN + 2]);
+        let err = read_first_delimited_frame(&mut buff, START_SEQ, &TERMINATOR, MAX_ALIGNMENT_SCAN)
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_message_no_start_seq() {
+        // this byte strem does not start with the correct start seq (0x19, 0x00)
+        let raw = vec![
+            0xFF, 0x00, 0xFF, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(err, RouterError::BadStartSequence));
+        let e = io::Error::from(err);
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+        assert_eq!(framing_error_kind(&e), Some("bad-start-sequence"));
+    }
+
+    #[test]
+    fn checksums() {
+        let mut frame = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xE9,
+        ];
+        assert!(verify_checksum(&frame, Checksum::Xor).is_ok());
+        // the same bytes add up to 0xB5
+        assert!(matches!(
+            verify_checksum(&frame, Checksum::Sum),
+            Err(RouterError::ChecksumMismatch {
+                expected: 0xB5,
+                actual: 0xE9
+            })
+        ));
+        frame[26] = 0xB5;
+        assert!(verify_checksum(&frame, Checksum::Sum).is_ok());
+
+        // a corrupted byte anywhere before the checksum is found
+        frame[13] = 0x04;
+        let err = verify_checksum(&frame, Checksum::Sum).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::ChecksumMismatch {
+                expected: 0xB6,
+                actual: 0xB5
+            }
+        ));
+        let e = io::Error::from(err);
+        assert_eq!(framing_error_kind(&e), Some("checksum-mismatch"));
+    }
+
+    #[test]
+    fn constant_bytes() {
+        let mut frame = encode_frame(&crate::testing::sample_message());
+        // the battery pid and the battery level of all the sample frames
+        let constants = [(9, 0x01), (10, 0x00)];
+        assert!(check_constant_bytes(&frame, &constants).is_ok());
+        assert!(check_constant_bytes(&frame, &[]).is_ok());
+
+        // new firmware that sends the real level
+        frame[10] = 0x5A;
+        let err = check_constant_bytes(&frame, &constants).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::UnexpectedByte {
+                offset: 10,
+                expected: 0x00,
+                actual: 0x5A
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Byte 10 of the frame should always be 0x00 but was 0x5A"
+        );
+        assert!(err.is_bad_frame());
+        let e = io::Error::from(err);
+        assert_eq!(framing_error_kind(&e), Some("unexpected-byte"));
+    }
+
+    #[test]
+    fn framing_error_kinds() {
+        let e = io::Error::from(RouterError::BadPayloadLength(0xFF));
+        assert_eq!(framing_error_kind(&e), Some("bad-payload-length"));
+        let e = io::Error::new(ErrorKind::InvalidData, "Missing frame terminator");
+        assert_eq!(framing_error_kind(&e), Some("other"));
+        // the device going away isn't the frame's fault
+        let e = io::Error::from(ErrorKind::UnexpectedEof);
+        assert_eq!(framing_error_kind(&e), None);
+    }
+
+    #[test]
+    fn read_message_unexpected_mac_address() {
+        // this byte strem does not start with the correct MAC address (0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B)
+        let raw = vec![
+            0x19, 0x00, 0xFF, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        match read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err() {
+            RouterError::UnexpectedMac(mac) => {
+                assert_eq!(mac, [0xFF, 0xCF, 0x5E, 0x82, 0x93, 0x7B])
+            }
+            other => panic!("Expected an unexpected MAC address, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn read_message_invalid_payload_length() {
+        // this byte strem has a payload length (0x11) too short for the fields
+        let raw = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x11, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(err, RouterError::BadPayloadLength(0x11)));
+        assert_eq!(
+            err.to_string(),
+            "Length of payload must be at least 0x12 (18 bytes) but was 0x11"
+        );
+    }
+
+    #[test]
+    fn read_message_payload_too_long() {
+        // a length byte of 0xFF, followed by a good frame
+        let mut raw = sample_frame();
+        raw[8] = 0xFF;
+        raw.extend_from_slice(&sample_frame());
+        let mut buff = Cursor::new(raw);
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(err, RouterError::PayloadTooLong(0xFF)));
+        assert_eq!(
+            err.to_string(),
+            "Length of payload can be at most 0x40 (64 bytes) but was 0xFF"
+        );
+        assert!(err.is_bad_frame());
+        assert_eq!(
+            framing_error_kind(&io::Error::from(err)),
+            Some("payload-too-long")
+        );
+        // nothing after the frame was read for it, so the next one is still there
+        assert_eq!(
+            read_message(&mut buff, &[MAC_ADDRESS])
+                .unwrap()
+                .msg_num_value,
+            33850
+        );
+    }
+
+    #[test]
+    fn read_frame_keeps_the_bytes_it_read() {
+        let mut longer = sample_frame();
+        longer[8] = 0x15;
+        longer.extend_from_slice(&[0x0C, 0x01, 0x02]);
+        let mut raw = sample_frame();
+        raw.extend_from_slice(&longer);
+        let mut buff = Cursor::new(raw);
+
+        let frame = read_frame(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(frame.raw, sample_frame());
+        assert_eq!(frame.message.msg_num_value, 33850);
+        assert!(frame.message.received_at.is_some());
+        // the extra payload is part of the frame
+        let frame = read_frame(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(frame.raw, longer);
+        assert_eq!(
+            read_frame(&mut buff, &[MAC_ADDRESS]).unwrap_err().kind(),
+            "eof"
+        );
+    }
+
+    #[test]
+    fn read_message_with_a_longer_payload() {
+        let frame = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        // newer firmware with three more bytes of payload, followed by a frame of the usual 18 bytes
+        let mut longer = frame.clone();
+        longer[8] = 0x15;
+        longer[22] = 0x85;
+        longer.extend_from_slice(&[0x0C, 0x01, 0x02]);
+        let mut raw = longer;
+        raw.extend_from_slice(&frame);
+        let mut buff = Cursor::new(raw);
+
+        let msg = read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg.msg_num_value, 34106);
+        assert_eq!(msg.rssi_value, 189);
+        // the extra bytes were skipped over, so the next frame is found where it starts
+        let msg = read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg.msg_num_value, 33850);
+
+        // a longer payload that ends before all of it has arrived
+        let mut partial = frame;
+        partial[8] = 0x15;
+        partial.push(0x0C);
+        let err = read_message(&mut Cursor::new(partial), &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::TruncatedFrame {
+                got: 28,
+                expected: 30
+            }
+        ));
+    }
+
+    #[test]
+    fn check_payload_len_of_a_model_with_a_longer_payload() {
+        let frame = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        // a payload of 0x14 bytes, two more than ours
+        let mut longer = frame.clone();
+        longer[8] = 0x14;
+        longer.extend_from_slice(&[0x0C, 0x01]);
+        let mut buff = Cursor::new(longer);
+        let (raw, _) = resync_to_start(&mut buff, START_SEQ, &[MAC_ADDRESS], 0).unwrap();
+        let mut raw = raw.to_vec();
+        raw.extend(read_extra_payload(&mut buff, START_SEQ, &raw).unwrap());
+        assert_eq!(raw.len(), FRAME_LEN + 2);
+
+        // it is what that model always sends
+        check_payload_len(&raw, 0x14).unwrap();
+        let mut buffer = [0; FRAME_LEN];
+        buffer.copy_from_slice(&raw[..FRAME_LEN]);
+        assert!(matches!(
+            decode_frame(&buffer, &[MAC_ADDRESS]).unwrap(),
+            Frame::Message(msg) if msg.rssi_value == 189
+        ));
+
+        // but not what ours sends
+        let err = check_payload_len(&raw, PAYLOAD_LEN).unwrap_err();
+        assert_eq!(err.kind(), "bad-payload-length");
+        assert_eq!(
+            err.to_string(),
+            "Length of payload should be 0x12 (18 bytes) but was 0x14"
+        );
+        assert!(check_payload_len(&frame, 0x14).is_err());
+
+        // heartbeats have no payload to check
+        let mut heartbeat = frame;
+        heartbeat[8] = HEARTBEAT_MARKER;
+        check_payload_len(&heartbeat, 0x14).unwrap();
+    }
+
+    #[test]
+    fn read_message_invalid_pid() {
+        // the PID in front of the vibration (0x03) is corrupt
+        let raw = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x04,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut buff = Cursor::new(raw);
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::BadPid {
+                field: Field::Vibration,
+                pid: 0x04
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "The PID of the vibration must be 0x03 but was 0x04"
+        );
+        assert!(err.is_bad_frame());
+    }
+
+    #[test]
+    fn read_message_timeout() {
+        // a device that sends the start of a frame and then goes quiet
+        struct Stalled(Cursor<Vec<u8>>);
+
+        impl Read for Stalled {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                match self.0.read(buf)? {
+                    0 => Err(io::Error::new(ErrorKind::WouldBlock, "no data")),
+                    n => Ok(n),
+                }
+            }
+        }
+
+        let mut stream = Stalled(Cursor::new(vec![0x19, 0x00, 0xD0, 0xCF]));
+        let err = read_message(&mut stream, &DEFAULT_MACS).unwrap_err();
+        assert!(matches!(err, RouterError::Timeout(_)));
+        assert!(!err.is_bad_frame());
+        // the rest of the router still sees the error it came from
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::WouldBlock);
+    }
+
+    // Hands out the bytes in reads of the given sizes, round and round, where a size of 0 is a read that times out
+    struct Chunked {
+        bytes: Cursor<Vec<u8>>,
+        sizes: Vec<usize>,
+        reads: usize,
+    }
+
+    impl Read for Chunked {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let size = self.sizes[self.reads % self.sizes.len()];
+            self.reads += 1;
+            if size == 0 {
+                return Err(io::Error::new(ErrorKind::WouldBlock, "no data"));
+            }
+            let n = size.min(buf.len());
+            self.bytes.read(&mut buf[..n])
+        }
+    }
+
+    #[test]
+    fn framed_reader_across_read_boundaries() {
+        let raw = seven_messages();
+        let mut reader = FramedReader::new(Chunked {
+            bytes: Cursor::new(raw.clone()),
+            sizes: vec![1, 5, 0, 30, 2, 60, 0, 13],
+            reads: 0,
+        });
+        let mut frames = Vec::new();
+        while frames.len() < 7 {
+            match reader.next_frame() {
+                Ok(frame) => frames.push(frame),
+                // a timeout part way through a frame keeps what has arrived of it
+                Err(e) => assert_eq!(e.kind(), ErrorKind::WouldBlock),
+            }
+        }
+        assert_eq!(frames.concat(), raw);
+        for frame in &frames {
+            assert!(parse_frame(frame, &DEFAULT_MACS).is_ok());
+        }
+        assert!(reader.buffered().is_empty());
+        let e = reader.next_frame().unwrap_err();
+        assert!(!is_partial_frame(&e));
+
+        // frames of different lengths, here a length byte after a one byte start
+        let mut bytes = vec![0xAA, 2, 1, 2, 0xAA, 0, 0xAA, 5, 1, 2, 3, 4, 5, 0xAA, 9];
+        let mut reader = FramedReader::new(Chunked {
+            bytes: Cursor::new(bytes.clone()),
+            sizes: vec![3, 0, 1, 7],
+            reads: 0,
+        });
+        let length_byte = |bytes: &[u8]| bytes.get(1).map(|len| 2 + *len as usize);
+        let mut frames = Vec::new();
+        let e = loop {
+            match reader.next_frame_by(length_byte) {
+                Ok(frame) => frames.push(frame),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[2], vec![0xAA, 5, 1, 2, 3, 4, 5]);
+        // the last frame never finished
+        assert!(is_partial_frame(&e));
+        assert_eq!(reader.buffered(), &bytes.split_off(13)[..]);
+    }
+
+    #[test]
+    fn read_message_clean_eof() {
+        // the connection closes part way through a frame
+        let raw = vec![0x19, 0x00, 0xD0, 0xCF];
+        let mut buff = Cursor::new(raw);
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(
+            err,
+            RouterError::TruncatedFrame {
+                got: 4,
+                expected: FRAME_LEN
+            }
+        ));
+        assert!(!err.is_bad_frame());
+        assert_eq!(
+            err.to_string(),
+            "The stream ended part way through a frame, after 4 of 27 bytes"
+        );
+        let e = io::Error::from(err);
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(is_partial_frame(&e));
+
+        // or between frames, after a whole one
+        let mut raw = seven_messages();
+        raw.truncate(FRAME_LEN);
+        let mut buff = Cursor::new(raw);
+        read_message(&mut buff, &[MAC_ADDRESS]).unwrap();
+        let err = read_message(&mut buff, &[MAC_ADDRESS]).unwrap_err();
+        assert!(matches!(err, RouterError::CleanEof));
+        assert!(!err.is_bad_frame());
+        let e = io::Error::from(err);
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(!is_partial_frame(&e));
+    }
+
+    // Hands out its bytes and then returns Ok(0) for ever, like a peer that has half closed the connection
+    struct HalfClosed {
+        bytes: Cursor<Vec<u8>>,
+        empty_reads: usize,
+    }
+
+    impl Read for HalfClosed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let read = self.bytes.read(buf)?;
+            if read == 0 {
+                self.empty_reads += 1;
+            }
+            Ok(read)
+        }
+    }
+
+    #[test]
+    fn read_message_retries_an_interrupted_read() {
+        // a signal arrives before the first read and again part way through the frame
+        struct Interrupted {
+            bytes: Cursor<Vec<u8>>,
+            reads: usize,
+        }
+
+        impl Read for Interrupted {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.reads += 1;
+                match self.reads {
+                    1 | 3 => Err(io::Error::new(ErrorKind::Interrupted, "interrupted")),
+                    _ => {
+                        let n = buf.len().min(10);
+                        self.bytes.read(&mut buf[..n])
+                    }
+                }
+            }
+        }
+
+        let raw = seven_messages();
+        let mut stream = Interrupted {
+            bytes: Cursor::new(raw.clone()),
+            reads: 0,
+        };
+        let msg = read_message(&mut stream, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(msg.msg_num_value, 33850);
+        assert!(stream.reads > 3);
+
+        let mut reader = FramedReader::new(Interrupted {
+            bytes: Cursor::new(raw.clone()),
+            reads: 0,
+        });
+        assert_eq!(reader.next_frame().unwrap(), raw[..FRAME_LEN]);
+
+        // anything else still ends the read
+        struct Failing;
+
+        impl Read for Failing {
+            fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::new(ErrorKind::ConnectionReset, "reset"))
+            }
+        }
+        let err = read_message(&mut Failing, &[MAC_ADDRESS]).unwrap_err();
+        assert_eq!(io::Error::from(err).kind(), ErrorKind::ConnectionReset);
+    }
+
+    #[test]
+    fn read_message_gives_up_on_a_half_closed_stream() {
+        let mut stream = HalfClosed {
+            bytes: Cursor::new(vec![0x19, 0x00, 0xD0, 0xCF, 0x5E]),
+            empty_reads: 0,
+        };
+        assert!(matches!(
+            read_message(&mut stream, &[MAC_ADDRESS]).unwrap_err(),
+            RouterError::TruncatedFrame { got: 5, .. }
+        ));
+        // the first empty read is enough, it doesn't keep asking
+        assert_eq!(stream.empty_reads, 1);
+
+        let mut reader = FramedReader::new(HalfClosed {
+            bytes: Cursor::new(vec![0x19, 0x00, 0xD0]),
+            empty_reads: 0,
+        });
+        assert!(is_partial_frame(&reader.next_frame().unwrap_err()));
+        assert_eq!(reader.stream.empty_reads, 1);
+    }
+
+    #[test]
+    fn encode_frame_round_trip() {
+        let raw = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let msg = parse_frame(&raw, &[MAC_ADDRESS]).unwrap();
+        assert_eq!(encode_frame(&msg), raw);
+    }
+
+    #[test]
+    fn parse_all_with_trailing_bytes() {
+        let frame = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let mut bad = frame;
+        bad[8] = 0x11;
+        let mut longer = frame.to_vec();
+        longer[8] = 0x14;
+        longer.extend_from_slice(&[0x0C, 0x01]);
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&frame);
+        raw.extend_from_slice(&bad);
+        raw.extend_from_slice(&longer);
+        raw.extend_from_slice(&frame);
+        // the start of a fifth frame that hasn't finished arriving
+        raw.extend_from_slice(&frame[..10]);
+
+        let (results, remaining) = parse_all(&raw, &[MAC_ADDRESS]);
+        assert_eq!(results.len(), 4);
+        assert_eq!(remaining, 10);
+        assert_eq!(results[0].as_ref().unwrap().msg_num_value, 33850);
+        assert!(matches!(
+            results[1],
+            Err(RouterError::BadPayloadLength(0x11))
+        ));
+        assert!(results[2].is_ok());
+        assert!(results[3].is_ok());
+        // a longer frame is only parsed once all of it is there
+        assert_eq!(parse_all(&longer[..28], &[MAC_ADDRESS]).1, 28);
+
+        assert_eq!(parse_all(&frame[..26], &[MAC_ADDRESS]).1, 26);
+        assert!(parse_all(&[], &[MAC_ADDRESS]).0.is_empty());
+    }
+
+    #[test]
+    fn decode_frame_heartbeat() {
+        let mut raw = [0; FRAME_LEN];
+        raw[..2].copy_from_slice(&START_SEQ);
+        raw[2..8].copy_from_slice(&MAC_ADDRESS);
+        raw[8] = HEARTBEAT_MARKER;
+        assert_eq!(
+            decode_frame(&raw, &[MAC_ADDRESS]).unwrap(),
+            Frame::Heartbeat { mac: MAC_ADDRESS }
+        );
+        // it has no readings in it so it is not a message
+        assert!(parse_frame(&raw, &[MAC_ADDRESS]).is_err());
+
+        // a heartbeat still needs a valid header
+        raw[0] = 0x18;
+        assert!(decode_frame(&raw, &[MAC_ADDRESS]).is_err());
+
+        let raw = [
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        assert_eq!(
+            decode_frame(&raw, &[MAC_ADDRESS]).unwrap(),
+            Frame::Message(parse_frame(&raw, &[MAC_ADDRESS]).unwrap())
+        );
+    }
+
+    // A cheap soak test so that the property is checked on every cargo test, see fuzz/ for the real thing.
+    // Random bytes with the odd valid header mixed in must never make the reader panic
+    #[test]
+    fn random_input_never_panics() {
+        // xorshift, good enough for making up bytes and it keeps the test repeatable
+        let mut state: u32 = 0x1234_5678;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state
+        };
+
+        for _ in 0..200 {
+            let mut raw: Vec<u8> = (0..MAX_ALIGNMENT_SCAN + FRAME_LEN * 4)
+                .map(|_| next() as u8)
+                .collect();
+            let at = next() as usize % (raw.len() - FRAME_LEN);
+            raw[at..at + 2].copy_from_slice(&START_SEQ);
+            raw[at + 2..at + 8].copy_from_slice(&MAC_ADDRESS);
+
+            // read until the bytes run out, which must end in the end of the stream rather than hanging
+            let mut buff = Cursor::new(raw);
+            if read_first_message(&mut buff, &[MAC_ADDRESS]).is_ok() {
+                loop {
+                    match read_message(&mut buff, &[MAC_ADDRESS]) {
+                        Err(RouterError::CleanEof) | Err(RouterError::TruncatedFrame { .. }) => {
+                            break
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn vib_magnitude() {
+        let vibration = |vib_x, vib_y, vib_z| DeviceMessage {
+            vib_x,
+            vib_y,
+            vib_z,
+            ..crate::testing::sample_message()
+        };
+        assert_eq!(vibration(3, 4, 0).vib_magnitude(), 5);
+        assert_eq!(vibration(2, 3, 6).vib_magnitude(), 7);
+        assert_eq!(vibration(0, 0, 0).vib_magnitude(), 0);
+        // 1.414 rounds down and 1.732 rounds up
+        assert_eq!(vibration(1, 1, 0).vib_magnitude(), 1);
+        assert_eq!(vibration(1, 1, 1).vib_magnitude(), 2);
+        // a single axis fits exactly, two big ones don't
+        assert_eq!(vibration(65535, 0, 0).vib_magnitude(), 65535);
+        assert_eq!(vibration(65535, 65535, 0).vib_magnitude(), 65535);
+        assert_eq!(vibration(65535, 65535, 65535).vib_magnitude(), 65535);
+    }
+
+    #[test]
+    fn vib_signed() {
+        // the x axis is 0xFEFF, -2 as a little endian i16
+        let raw = vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xFF, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+        ];
+        let msg = read_message(&mut Cursor::new(raw), &DEFAULT_MACS).unwrap();
+        assert_eq!(msg.vib_x, 65534);
+        assert_eq!(msg.vib_signed(), (-2, 602, 1914));
+        assert_eq!(msg.vib_magnitude(), 65535);
+        // sqrt(4 + 602^2 + 1914^2) is 2006.4
+        assert_eq!(msg.vib_signed_magnitude(), 2006);
+    }
+
+    #[test]
+    fn status_word() {
+        // a full battery, a strong signal and hardly any vibration
+        let msg = DeviceMessage {
+            batt_value: 90,
+            rssi_value: 200,
+            vib_x: 3,
+            vib_y: 4,
+            vib_z: 0,
+            ..crate::testing::sample_message()
+        };
+        let thresholds = Thresholds {
+            low_battery: Some(20),
+            weak_rssi: Some(60),
+            high_vibration: Some(1000),
+            signed_vibration: false,
+        };
+        assert_eq!(msg.status_word(&thresholds), 0);
+
+        let low_battery = DeviceMessage {
+            batt_value: 19,
+            ..msg.clone()
+        };
+        assert_eq!(low_battery.status_word(&thresholds), LOW_BATTERY);
+        assert_eq!(low_battery.status_word(&thresholds), 0b001);
+        let weak_rssi = DeviceMessage {
+            rssi_value: 59,
+            ..msg.clone()
+        };
+        assert_eq!(weak_rssi.status_word(&thresholds), 0b010);
+        let high_vibration = DeviceMessage {
+            vib_x: 1001,
+            ..msg.clone()
+        };
+        assert_eq!(high_vibration.status_word(&thresholds), 0b100);
+        // right on a threshold isn't past it
+        let on_the_line = DeviceMessage {
+            batt_value: 20,
+            rssi_value: 60,
+            vib_x: 1000,
+            vib_y: 0,
+            ..msg.clone()
+        };
+        assert_eq!(on_the_line.status_word(&thresholds), 0);
+
+        // the sample message has a flat battery and is shaking hard, and that's all three with a weak signal
+        let sample = crate::testing::sample_message();
+        assert_eq!(sample.status_word(&thresholds), 0b001 | 0b100);
+        let sample = DeviceMessage {
+            rssi_value: 10,
+            ..sample
+        };
+        assert_eq!(sample.status_word(&thresholds), 0b111);
+        // a condition without a threshold is never set
+        assert_eq!(sample.status_word(&Thresholds::default()), 0);
+        // signed, the x axis of the sample is -3330 and the magnitude only 3888
+        let signed = Thresholds {
+            high_vibration: Some(4000),
+            signed_vibration: true,
+            ..Thresholds::default()
+        };
+        assert_eq!(sample.status_word(&signed), 0);
+        let unsigned = Thresholds {
+            signed_vibration: false,
+            ..signed
+        };
+        assert_eq!(sample.status_word(&unsigned), HIGH_VIBRATION);
+    }
+
+    #[test]
+    fn format_hex_of_a_frame() {
+        let frame = encode_frame(&crate::testing::sample_message());
+        assert_eq!(
+            format_hex(&frame),
+            "19 00 D0 CF 5E 82 93 7B 12 01 00 02 54 03 FE F2 5A 02 7A 07 05 3A 84 0B 02 06 BD"
+        );
+        assert_eq!(format_hex(&[]), "");
+        assert_eq!(format_hex(&[0x0A]), "0A");
+    }
+
+    #[test]
+    fn mac_round_trip() {
+        let mac = [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B];
+        assert_eq!(parse_mac(&format_mac(&mac)), Some(mac));
+        assert_eq!(parse_mac("d0:cf:5e:82:93:7b"), Some(mac));
+        assert_eq!(parse_mac("D0:CF:5E:82:93"), None);
+        assert_eq!(parse_mac("D0:CF:5E:82:93:7BB"), None);
+    }
+}
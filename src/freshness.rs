@@ -0,0 +1,185 @@
+use crate::modbus_client::{LazyClient, ModbusClient, ModbusConnector};
+use log::{error, info};
+use std::io;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::time::Duration;
+
+// Tells the PLC whether the register values are still current.
+// The flag register is set to 1 for every message and cleared to 0 once no message has arrived for the timeout,
+// so the PLC can stop acting on old values when the device goes quiet.
+// It runs on its own thread with its own modbus connection because the main loop is blocked reading the
+// device exactly when the flag needs clearing
+pub struct FreshnessFlag {
+    sender: SyncSender<()>,
+}
+
+impl FreshnessFlag {
+    pub fn start(
+        modbus_host: String,
+        modbus_port: u16,
+        modbus_unit: u8,
+        register: u16,
+        timeout: Duration,
+    ) -> FreshnessFlag {
+        // one pending update is as good as any number of them
+        let (sender, receiver) = mpsc::sync_channel(1);
+        thread::spawn(move || {
+            let connector = ModbusConnector::Direct {
+                host: modbus_host,
+                port: modbus_port,
+                unit: modbus_unit,
+            };
+            let mut flag = Flag::new(|| connector.connect(), register);
+            run(&mut flag, receiver, timeout);
+        });
+        FreshnessFlag { sender }
+    }
+
+    // Call this for every message received from the device, it never blocks
+    pub fn update(&self) {
+        let _ = self.sender.try_send(());
+    }
+}
+
+// Runs until the router drops its FreshnessFlag
+fn run<F, C>(flag: &mut Flag<F, C>, receiver: Receiver<()>, timeout: Duration)
+where
+    F: FnMut() -> io::Result<C>,
+    C: ModbusClient,
+{
+    loop {
+        match receiver.recv_timeout(timeout) {
+            Ok(()) => flag.fresh(),
+            Err(mpsc::RecvTimeoutError::Timeout) => flag.stale(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+// The last value we managed to write to the flag register
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Unknown,
+    Fresh,
+    Stale,
+}
+
+struct Flag<F, C> {
+    client: LazyClient<F, C>,
+    register: u16,
+    state: State,
+}
+
+impl<F, C> Flag<F, C>
+where
+    F: FnMut() -> io::Result<C>,
+    C: ModbusClient,
+{
+    fn new(connect: F, register: u16) -> Flag<F, C> {
+        Flag {
+            client: LazyClient::new(connect),
+            register,
+            state: State::Unknown,
+        }
+    }
+
+    // A message has arrived
+    fn fresh(&mut self) {
+        if self.write(1) {
+            self.state = State::Fresh;
+        }
+    }
+
+    // Nothing has arrived for the timeout, only clear the flag once rather than every timeout
+    fn stale(&mut self) {
+        if self.state == State::Stale {
+            return;
+        }
+        if self.state == State::Fresh {
+            info!("No messages for a while, clearing the freshness flag");
+        }
+        if self.write(0) {
+            self.state = State::Stale;
+        }
+    }
+
+    fn write(&mut self, value: u16) -> bool {
+        if let Err(e) = self.client.write_single_register(self.register, value) {
+            error!("Error writing the freshness flag: {:?}", e);
+            self.state = State::Unknown;
+            return false;
+        }
+        true
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{RecordingClient, Write};
+
+    fn writes<F>(flag: &Flag<F, RecordingClient>) -> &[Write] {
+        &flag.client.connected().unwrap().writes
+    }
+
+    #[test]
+    fn set_on_update_and_cleared_once_when_stale() {
+        let mut flag = Flag::new(|| Ok(RecordingClient::default()), 50);
+        flag.fresh();
+        flag.fresh();
+        flag.stale();
+        flag.stale();
+        flag.fresh();
+        assert_eq!(
+            writes(&flag),
+            &[
+                Write::Single(50, 1),
+                Write::Single(50, 1),
+                Write::Single(50, 0),
+                Write::Single(50, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_is_retried_until_the_modbus_is_up() {
+        let mut up = false;
+        let mut flag = Flag::new(
+            || {
+                if up {
+                    Ok(RecordingClient::default())
+                } else {
+                    up = true;
+                    Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+                }
+            },
+            50,
+        );
+        flag.stale();
+        assert!(flag.client.connected().is_none());
+        flag.stale();
+        assert_eq!(writes(&flag), &[Write::Single(50, 0)]);
+    }
+
+    #[test]
+    fn run_clears_the_flag_after_the_timeout() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let mut flag = Flag::new(|| Ok(RecordingClient::default()), 50);
+        sender.send(()).unwrap();
+        // the timeout passes before the sender is dropped
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            drop(sender);
+        });
+        run(&mut flag, receiver, Duration::from_millis(20));
+        handle.join().unwrap();
+        assert_eq!(writes(&flag), &[Write::Single(50, 1), Write::Single(50, 0)]);
+    }
+}
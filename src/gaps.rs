@@ -0,0 +1,95 @@
+use crate::frame::DeviceMessage;
+use std::collections::BTreeMap;
+
+// Notices the messages that never arrived. The device counts its messages in msg_num, so going from 10 to 13
+// means 11 and 12 were lost somewhere between the sensor and us. The count wraps from 65535 back to 0, which
+// is the next message like any other. A msg_num that goes backwards is a device that has restarted (they
+// count from 0 again) or a frame sent again late, neither of which are lost messages
+#[derive(Default)]
+pub struct GapDetector {
+    last_msg_num: BTreeMap<([u8; 6], Option<u8>), u16>,
+}
+
+impl GapDetector {
+    pub fn new() -> GapDetector {
+        GapDetector::default()
+    }
+
+    // How many messages are missing between this one and the one before it from the same device (and
+    // sensor), if any. Call this once for every message, repeats included
+    pub fn missing(&mut self, msg: &DeviceMessage) -> Option<u16> {
+        let msg_num = msg.msg_num_value;
+        let last = self
+            .last_msg_num
+            .insert((msg.mac, msg.sensor_id), msg_num)?;
+        let missing = msg_num.wrapping_sub(last).wrapping_sub(1);
+        // the same message again, or the counter has gone backwards
+        let backwards = msg_num == last || missing >= 0x8000 || (msg_num == 0 && last != u16::MAX);
+        if missing == 0 || backwards {
+            return None;
+        }
+        Some(missing)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn message(msg_num: u16) -> DeviceMessage {
+        DeviceMessage {
+            msg_num_value: msg_num,
+            ..sample_message()
+        }
+    }
+
+    #[test]
+    fn counting_up_one_at_a_time_has_no_gaps() {
+        let mut gaps = GapDetector::new();
+        assert_eq!(gaps.missing(&message(10)), None);
+        assert_eq!(gaps.missing(&message(11)), None);
+        // a repeat isn't lost or new
+        assert_eq!(gaps.missing(&message(11)), None);
+        assert_eq!(gaps.missing(&message(12)), None);
+    }
+
+    #[test]
+    fn a_jump_is_the_messages_in_between() {
+        let mut gaps = GapDetector::new();
+        assert_eq!(gaps.missing(&message(10)), None);
+        assert_eq!(gaps.missing(&message(13)), Some(2));
+        assert_eq!(gaps.missing(&message(14)), None);
+
+        // each device and sensor has its own count
+        let other_device = DeviceMessage {
+            mac: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            ..message(100)
+        };
+        assert_eq!(gaps.missing(&other_device), None);
+        assert_eq!(gaps.missing(&message(16)), Some(1));
+    }
+
+    #[test]
+    fn wrapping_is_the_next_message() {
+        let mut gaps = GapDetector::new();
+        assert_eq!(gaps.missing(&message(65535)), None);
+        assert_eq!(gaps.missing(&message(0)), None);
+        // a gap across the wrap
+        assert_eq!(gaps.missing(&message(65534)), None);
+        assert_eq!(gaps.missing(&message(2)), Some(3));
+
+        // a restarted device counts from 0 again
+        assert_eq!(gaps.missing(&message(40000)), None);
+        assert_eq!(gaps.missing(&message(0)), None);
+        assert_eq!(gaps.missing(&message(1)), None);
+        // and a late frame goes backwards
+        assert_eq!(gaps.missing(&message(5)), Some(3));
+        assert_eq!(gaps.missing(&message(4)), None);
+    }
+}
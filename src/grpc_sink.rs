@@ -0,0 +1,212 @@
+// Only built with --features grpc
+use crate::frame::{format_mac, DeviceMessage};
+use crate::sink::{self, MessageSink, SinkError};
+use log::error;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::transport::Channel;
+
+// How many messages can be waiting for the gRPC service before new ones are dropped
+const PENDING_MESSAGES: usize = 1024;
+
+// Don't hammer a service that is down, wait this long between attempts
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// The types in proto/telemetry.proto, written out by hand so that building doesn't need protoc
+pub mod proto {
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct TelemetryBatch {
+        #[prost(message, repeated, tag = "1")]
+        pub messages: Vec<DeviceMessage>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct DeviceMessage {
+        #[prost(string, tag = "1")]
+        pub mac: String,
+        #[prost(uint64, tag = "2")]
+        pub received_at_ms: u64,
+        #[prost(uint32, tag = "3")]
+        pub battery: u32,
+        #[prost(uint32, tag = "4")]
+        pub temperature: u32,
+        #[prost(uint32, tag = "5")]
+        pub vib_x: u32,
+        #[prost(uint32, tag = "6")]
+        pub vib_y: u32,
+        #[prost(uint32, tag = "7")]
+        pub vib_z: u32,
+        #[prost(uint32, tag = "8")]
+        pub msg_num: u32,
+        #[prost(uint32, tag = "9")]
+        pub version: u32,
+        #[prost(uint32, tag = "10")]
+        pub rssi: u32,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct PublishReply {}
+}
+
+pub fn to_proto(msg: &DeviceMessage) -> proto::DeviceMessage {
+    let received_at_ms = msg
+        .received_at
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+    proto::DeviceMessage {
+        mac: format_mac(&msg.mac),
+        received_at_ms,
+        battery: msg.batt_value as u32,
+        temperature: msg.temp_value as u32,
+        vib_x: msg.vib_x as u32,
+        vib_y: msg.vib_y as u32,
+        vib_z: msg.vib_z as u32,
+        msg_num: msg.msg_num_value as u32,
+        version: msg.version_value as u32,
+        rssi: msg.rssi_value as u32,
+    }
+}
+
+// Publishes decoded messages to a gRPC service.
+// Like the raw sink the network side runs on its own thread, if the service can't keep up messages are
+// dropped rather than holding up the modbus
+pub struct GrpcSink {
+    sender: SyncSender<proto::DeviceMessage>,
+}
+
+impl GrpcSink {
+    // batch_size is the most messages sent in one Publish call, a batch goes out as soon as
+    // that many are waiting or nothing more is waiting
+    pub fn start(endpoint: String, batch_size: usize) -> GrpcSink {
+        let (sender, receiver) = mpsc::sync_channel(PENDING_MESSAGES);
+        thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!("Unable to start the gRPC sink: {:?}", e);
+                    return;
+                }
+            };
+            runtime.block_on(run(endpoint, batch_size.max(1), receiver));
+        });
+        GrpcSink { sender }
+    }
+}
+
+impl MessageSink for GrpcSink {
+    fn name(&self) -> &str {
+        "gRPC sink"
+    }
+
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        sink::queue(&self.sender, to_proto(msg))
+    }
+}
+
+// Runs until the router drops its GrpcSink
+async fn run(endpoint: String, batch_size: usize, receiver: Receiver<proto::DeviceMessage>) {
+    let mut client: Option<tonic::client::Grpc<Channel>> = None;
+    loop {
+        // the blocking wait is fine, this thread has nothing else to do
+        let first = match receiver.recv() {
+            Ok(msg) => msg,
+            Err(_) => return,
+        };
+        let mut batch = proto::TelemetryBatch {
+            messages: vec![first],
+        };
+        while batch.messages.len() < batch_size {
+            match receiver.try_recv() {
+                Ok(msg) => batch.messages.push(msg),
+                Err(_) => break,
+            }
+        }
+
+        // keep trying with the same batch, new messages wait in the channel meanwhile
+        loop {
+            if client.is_none() {
+                match connect(&endpoint).await {
+                    Ok(connected) => client = Some(connected),
+                    Err(e) => {
+                        error!("Unable to connect to gRPC service {}: {}", endpoint, e);
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                        continue;
+                    }
+                }
+            }
+            if let Some(grpc) = client.as_mut() {
+                match publish(grpc, batch.clone()).await {
+                    Ok(()) => break,
+                    Err(e) => {
+                        error!("Error publishing to gRPC service: {}", e);
+                        client = None;
+                        tokio::time::sleep(RECONNECT_INTERVAL).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn connect(endpoint: &str) -> Result<tonic::client::Grpc<Channel>, String> {
+    let channel = Channel::from_shared(endpoint.to_string())
+        .map_err(|e| e.to_string())?
+        .connect()
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(tonic::client::Grpc::new(channel))
+}
+
+// What the generated client for the Telemetry service would do
+async fn publish(
+    grpc: &mut tonic::client::Grpc<Channel>,
+    batch: proto::TelemetryBatch,
+) -> Result<(), String> {
+    grpc.ready().await.map_err(|e| e.to_string())?;
+    let path = PathAndQuery::from_static("/modbusrouter.Telemetry/Publish");
+    let codec: tonic::codec::ProstCodec<proto::TelemetryBatch, proto::PublishReply> =
+        tonic::codec::ProstCodec::default();
+    grpc.unary(tonic::Request::new(batch), path, codec)
+        .await
+        .map_err(|status| status.to_string())?;
+    Ok(())
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use prost::Message;
+
+    #[test]
+    fn maps_the_message_fields() {
+        let msg = DeviceMessage {
+            received_at: Some(UNIX_EPOCH + Duration::from_millis(1_571_388_795_123)),
+            ..crate::testing::sample_message()
+        };
+        let proto = to_proto(&msg);
+        assert_eq!(proto.mac, "D0:CF:5E:82:93:7B");
+        assert_eq!(proto.received_at_ms, 1_571_388_795_123);
+        assert_eq!(proto.temperature, 84);
+        assert_eq!(proto.vib_x, 62206);
+        assert_eq!(proto.msg_num, 33850);
+
+        // and it survives the trip through the wire format
+        let batch = proto::TelemetryBatch {
+            messages: vec![proto],
+        };
+        let decoded = proto::TelemetryBatch::decode(&batch.encode_to_vec()[..]).unwrap();
+        assert_eq!(decoded, batch);
+    }
+}
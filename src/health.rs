@@ -0,0 +1,118 @@
+use crate::hooks::Event;
+use crate::http;
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// The body of a /healthz response, e.g. {"device_connected":true,"modbus_up":true}
+#[derive(Debug, Serialize)]
+struct Body {
+    device_connected: bool,
+    modbus_up: bool,
+}
+
+// Whether the router can do its job right now, for load balancer and Kubernetes probes (see --health-addr).
+// It is kept up to date from the connection events the hooks are told about and read by the health check's
+// own thread, so answering a probe never waits on the main loop
+#[derive(Debug, Clone)]
+pub struct Health {
+    device: Arc<AtomicBool>,
+    modbus: Arc<AtomicBool>,
+}
+
+impl Health {
+    // The modbus is up until connecting to it at startup fails, the device is connected to after it
+    pub fn new() -> Health {
+        Health {
+            device: Arc::new(AtomicBool::new(false)),
+            modbus: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn record(&self, event: Event) {
+        match event {
+            Event::DeviceConnected => self.device.store(true, Ordering::Relaxed),
+            Event::DeviceDisconnected => self.device.store(false, Ordering::Relaxed),
+            Event::ModbusUp => self.modbus.store(true, Ordering::Relaxed),
+            Event::ModbusDown => self.modbus.store(false, Ordering::Relaxed),
+        }
+    }
+
+    // Connected to the device with a modbus that is taking the writes
+    pub fn healthy(&self) -> bool {
+        self.device.load(Ordering::Relaxed) && self.modbus.load(Ordering::Relaxed)
+    }
+
+    // Answers GET /healthz on the address with 200 when healthy and 503 when not. Binds straight away so that
+    // an address that is in use is reported at startup
+    pub fn serve(&self, addr: &str) -> io::Result<()> {
+        let health = self.clone();
+        http::start(addr, move |path| match path {
+            "/healthz" => Some(health.response()),
+            _ => None,
+        })
+    }
+
+    fn response(&self) -> http::Response {
+        let body = Body {
+            device_connected: self.device.load(Ordering::Relaxed),
+            modbus_up: self.modbus.load(Ordering::Relaxed),
+        };
+        let response = http::Response::json(serde_json::to_string(&body).unwrap_or_default());
+        if self.healthy() {
+            response
+        } else {
+            response.with_status("503 Service Unavailable")
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    fn get(addr: &str, path: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.1\r\nHost: test\r\n\r\n", path).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn healthy_only_with_the_device_and_the_modbus_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let health = Health::new();
+        health.serve(&addr).unwrap();
+
+        // no device yet
+        let response = get(&addr, "/healthz");
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(response.ends_with("{\"device_connected\":false,\"modbus_up\":true}"));
+
+        health.record(Event::DeviceConnected);
+        let response = get(&addr, "/healthz");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("{\"device_connected\":true,\"modbus_up\":true}"));
+
+        health.record(Event::ModbusDown);
+        assert!(get(&addr, "/healthz").starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        health.record(Event::ModbusUp);
+        assert!(get(&addr, "/healthz").starts_with("HTTP/1.1 200 OK\r\n"));
+        health.record(Event::DeviceDisconnected);
+        assert!(!health.healthy());
+
+        // nothing else is served
+        assert!(get(&addr, "/metrics").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
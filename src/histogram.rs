@@ -0,0 +1,167 @@
+use std::time::Duration;
+
+// Each power of two is split into this many buckets, so a percentile is never more than about 6% out
+const SUB_BUCKETS: u64 = 16;
+
+// How long things take, kept as counts in buckets rather than every value so that it stays small however
+// long the router runs. The buckets are exact up to 32us and get wider after that the same way the
+// hdrhistogram ones do, in steps of 1/16 of a power of two
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Histogram {
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram::default()
+    }
+
+    pub fn record(&mut self, took: Duration) {
+        // far longer than anything the router times, but the top bucket has to end somewhere
+        let bucket = bucket_of(took.as_micros().min(u128::from(u64::MAX >> 1)) as u64);
+        if self.counts.len() <= bucket {
+            self.counts.resize(bucket + 1, 0);
+        }
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += took;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    // The value that q of the values are at or below, e.g. 0.95 for the 95th percentile. It is the top of the
+    // bucket the value fell in, so never less than the real one. None until something has been recorded
+    pub fn percentile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut seen = 0;
+        self.counts
+            .iter()
+            .position(|count| {
+                seen += count;
+                seen >= rank
+            })
+            .map(|bucket| Duration::from_micros(highest_in(bucket)))
+    }
+
+    // What was recorded after earlier was copied, for the values of one interval out of running totals
+    pub fn since(&self, earlier: &Histogram) -> Histogram {
+        let counts = self
+            .counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                count.saturating_sub(earlier.counts.get(bucket).cloned().unwrap_or(0))
+            })
+            .collect();
+        Histogram {
+            counts,
+            count: self.count.saturating_sub(earlier.count),
+            sum: self.sum.checked_sub(earlier.sum).unwrap_or_default(),
+        }
+    }
+}
+
+// The values below 2 * SUB_BUCKETS have a bucket each, after that each power of two has SUB_BUCKETS of them
+fn bucket_of(micros: u64) -> usize {
+    if micros < 2 * SUB_BUCKETS {
+        return micros as usize;
+    }
+    let shift = 63 - micros.leading_zeros() - SUB_BUCKETS.trailing_zeros();
+    ((shift as u64 + 1) * SUB_BUCKETS + (micros >> shift) - SUB_BUCKETS) as usize
+}
+
+fn highest_in(bucket: usize) -> u64 {
+    let bucket = bucket as u64;
+    if bucket < 2 * SUB_BUCKETS {
+        return bucket;
+    }
+    let shift = bucket / SUB_BUCKETS - 1;
+    ((SUB_BUCKETS + bucket % SUB_BUCKETS + 1) << shift) - 1
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn percentiles_of_synthetic_latencies() {
+        let mut histogram = Histogram::new();
+        assert_eq!(histogram.percentile(0.5), None);
+
+        // 1ms to 100ms, one of each
+        for took in 1..=100 {
+            histogram.record(ms(took));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.sum(), ms(5050));
+        for (q, expected) in [(0.5, 50), (0.95, 95), (0.99, 99), (1.0, 100)].iter() {
+            let p = histogram.percentile(*q).unwrap();
+            // never below the real value and no more than a bucket above it
+            assert!(p >= ms(*expected), "p{} was {:?}", q, p);
+            assert!(
+                p.as_secs_f64() <= ms(*expected).as_secs_f64() * 1.07,
+                "p{} was {:?}",
+                q,
+                p
+            );
+        }
+
+        // the small values are exact
+        let mut histogram = Histogram::new();
+        for took in [3, 3, 3, 7, 20].iter() {
+            histogram.record(Duration::from_micros(*took));
+        }
+        assert_eq!(histogram.percentile(0.5), Some(Duration::from_micros(3)));
+        assert_eq!(histogram.percentile(0.8), Some(Duration::from_micros(7)));
+        assert_eq!(histogram.percentile(0.99), Some(Duration::from_micros(20)));
+    }
+
+    #[test]
+    fn since_is_one_interval_of_the_totals() {
+        let mut histogram = Histogram::new();
+        for _ in 0..99 {
+            histogram.record(ms(2));
+        }
+        let earlier = histogram.clone();
+        histogram.record(ms(500));
+        let interval = histogram.since(&earlier);
+        assert_eq!(interval.count(), 1);
+        assert_eq!(interval.sum(), ms(500));
+        assert!(interval.percentile(0.5).unwrap() >= ms(500));
+        // while over all of them the slow one is only at the very top
+        assert!(histogram.percentile(0.99).unwrap() < ms(3));
+        assert!(histogram.since(&histogram).percentile(0.5).is_none());
+    }
+
+    #[test]
+    fn every_value_is_in_a_bucket_that_holds_it() {
+        let mut last = 0;
+        for micros in (0..100_000).chain([u32::MAX as u64, u64::MAX / 2].iter().cloned()) {
+            let bucket = bucket_of(micros);
+            assert!(highest_in(bucket) >= micros, "{}", micros);
+            assert!(bucket == 0 || highest_in(bucket - 1) < micros, "{}", micros);
+            assert!(bucket >= last);
+            last = bucket;
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use crate::frame::format_mac;
+use crate::health::Health;
+use log::{error, warn};
+use serde::Serialize;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many events can be waiting for a slow hook before new ones are dropped
+const PENDING_EVENTS: usize = 64;
+
+// How long a POST may take before we give up on it
+const POST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a hook command may run before it is killed, so that one hung script doesn't hold up every hook after it
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(10);
+
+// How often a running hook command is checked on
+const COMMAND_POLL: Duration = Duration::from_millis(50);
+
+// The connection state changes a hook is told about
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    DeviceConnected,
+    DeviceDisconnected,
+    ModbusDown,
+    ModbusUp,
+}
+
+impl Event {
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::DeviceConnected => "device-connected",
+            Event::DeviceDisconnected => "device-disconnected",
+            Event::ModbusDown => "modbus-down",
+            Event::ModbusUp => "modbus-up",
+        }
+    }
+}
+
+// What is sent to the url, e.g. {"event":"device-connected","peer":"192.168.1.87:10001"}
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Payload {
+    event: &'static str,
+    // the device host, or the modbus host for the modbus events
+    peer: String,
+    // once the device has identified itself
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mac: Option<String>,
+}
+
+// Where to POST the events to. Only plain http is supported
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookUrl {
+    // host:port
+    pub addr: String,
+    pub path: String,
+}
+
+impl HookUrl {
+    // e.g. http://10.0.0.5:8080/alerts, the port defaults to 80
+    pub fn parse(url: &str) -> Result<HookUrl, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("Only http:// urls are supported: {}", url))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(format!("Missing host in url: {}", url));
+        }
+        let addr = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:80", host)
+        };
+        Ok(HookUrl {
+            addr,
+            path: path.to_string(),
+        })
+    }
+}
+
+// Tells the outside world about connection state changes by running a command and/or POSTing to a url.
+// The hooks run one at a time on their own thread so a slow script or a dead url never holds up the data.
+// The health check (see --health-addr) is kept up to date from the same events
+pub struct Hooks {
+    sender: Option<SyncSender<Payload>>,
+    health: Option<Health>,
+}
+
+impl Hooks {
+    // Nothing is started if neither hook is set
+    pub fn start(command: Option<String>, url: Option<HookUrl>) -> Hooks {
+        if command.is_none() && url.is_none() {
+            return Hooks {
+                sender: None,
+                health: None,
+            };
+        }
+        let (sender, receiver) = mpsc::sync_channel(PENDING_EVENTS);
+        thread::spawn(move || run(receiver, command, url));
+        Hooks {
+            sender: Some(sender),
+            health: None,
+        }
+    }
+
+    pub fn with_health(self, health: Option<Health>) -> Hooks {
+        Hooks { health, ..self }
+    }
+
+    pub fn fire(&self, event: Event, peer: &str, mac: Option<[u8; 6]>) {
+        if let Some(health) = &self.health {
+            health.record(event);
+        }
+        if let Some(sender) = &self.sender {
+            let payload = Payload {
+                event: event.name(),
+                peer: peer.to_string(),
+                mac: mac.map(|mac| format_mac(&mac)),
+            };
+            if let Err(TrySendError::Full(_)) = sender.try_send(payload) {
+                warn!("Hooks are not keeping up, dropping {} event", event.name());
+            }
+        }
+    }
+}
+
+// Runs until the router drops its Hooks
+fn run(receiver: Receiver<Payload>, command: Option<String>, url: Option<HookUrl>) {
+    for payload in receiver {
+        if let Some(command) = &command {
+            if let Err(e) = run_command(command, &payload, COMMAND_TIMEOUT) {
+                error!("Error running the {} hook: {:?}", payload.event, e);
+            }
+        }
+        if let Some(url) = &url {
+            if let Err(e) = post(url, &payload) {
+                error!("Error posting the {} hook: {:?}", payload.event, e);
+            }
+        }
+    }
+}
+
+// The command is called with the event, the peer and the MAC address (if known) as arguments.
+// It is killed if it is still running after the timeout
+fn run_command(command: &str, payload: &Payload, timeout: Duration) -> io::Result<()> {
+    let mut command = Command::new(command);
+    command.arg(payload.event).arg(&payload.peer);
+    if let Some(mac) = &payload.mac {
+        command.arg(mac);
+    }
+    let mut child = command.spawn()?;
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "The hook was still running after {}s, killed it",
+                    timeout.as_secs()
+                ),
+            ));
+        }
+        thread::sleep(COMMAND_POLL);
+    };
+    if !status.success() {
+        error!("The {} hook exited with {}", payload.event, status);
+    }
+    Ok(())
+}
+
+fn post(url: &HookUrl, payload: &Payload) -> io::Result<()> {
+    let body = serde_json::to_string(payload)?;
+    let socket_addr =
+        url.addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Unable to resolve hook address")
+        })?;
+    let mut stream = TcpStream::connect_timeout(&socket_addr, POST_TIMEOUT)?;
+    stream.set_write_timeout(Some(POST_TIMEOUT))?;
+    stream.set_read_timeout(Some(POST_TIMEOUT))?;
+    stream.write_all(request(url, &body).as_bytes())?;
+
+    // we only care about the status line, e.g. HTTP/1.1 200 OK
+    let mut response = Vec::new();
+    stream.take(1024).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or("");
+    let code = status.split_whitespace().nth(1).unwrap_or("");
+    if !code.starts_with('2') {
+        return Err(io::Error::other(format!("Unexpected response: {}", status)));
+    }
+    Ok(())
+}
+
+fn request(url: &HookUrl, body: &str) -> String {
+    format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        url.path,
+        url.addr,
+        body.len(),
+        body
+    )
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_url() {
+        assert_eq!(
+            HookUrl::parse("http://10.0.0.5:8080/alerts"),
+            Ok(HookUrl {
+                addr: "10.0.0.5:8080".to_string(),
+                path: "/alerts".to_string()
+            })
+        );
+        assert_eq!(
+            HookUrl::parse("http://alerts.local"),
+            Ok(HookUrl {
+                addr: "alerts.local:80".to_string(),
+                path: "/".to_string()
+            })
+        );
+        assert!(HookUrl::parse("https://alerts.local").is_err());
+        assert!(HookUrl::parse("http:///alerts").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn a_hook_that_hangs_is_killed() {
+        use std::os::unix::fs::PermissionsExt;
+        let script = env::temp_dir().join(format!("modbusrouter-hook-{}.sh", std::process::id()));
+        fs::write(&script, "#!/bin/sh\nsleep 30\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let payload = Payload {
+            event: Event::ModbusDown.name(),
+            peer: "127.0.0.1".to_string(),
+            mac: None,
+        };
+
+        let started = Instant::now();
+        let result = run_command(
+            script.to_str().unwrap(),
+            &payload,
+            Duration::from_millis(200),
+        );
+        fs::remove_file(&script).unwrap();
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // one that finishes in time is fine
+        run_command("true", &payload, Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn post_sends_the_event_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = HookUrl {
+            addr: listener.local_addr().unwrap().to_string(),
+            path: "/alerts".to_string(),
+        };
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 4096];
+            let n = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            String::from_utf8_lossy(&request[..n]).to_string()
+        });
+
+        let payload = Payload {
+            event: Event::DeviceConnected.name(),
+            peer: "192.168.1.87:10001".to_string(),
+            mac: Some("D0:CF:5E:82:93:7B".to_string()),
+        };
+        post(&url, &payload).unwrap();
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /alerts HTTP/1.1\r\n"));
+        assert!(request.ends_with(
+            "{\"event\":\"device-connected\",\"peer\":\"192.168.1.87:10001\",\"mac\":\"D0:CF:5E:82:93:7B\"}"
+        ));
+    }
+}
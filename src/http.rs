@@ -0,0 +1,154 @@
+use log::{error, info};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+// How long a client gets to send its request before we give up on it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What a handler sends back for a path it knows about
+pub struct Response {
+    // e.g. 200 OK
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn json(body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "application/json",
+            body,
+        }
+    }
+
+    // the content type Prometheus expects
+    pub fn metrics(body: String) -> Response {
+        Response {
+            status: "200 OK",
+            content_type: "text/plain; version=0.0.4",
+            body,
+        }
+    }
+
+    pub fn with_status(self, status: &'static str) -> Response {
+        Response { status, ..self }
+    }
+}
+
+// A very small HTTP server for the router's diagnostic endpoints.
+// It only understands GET, deals with one request at a time and closes the connection after each response,
+// which is all that's needed for the odd curl or scrape
+pub fn start<F>(addr: &str, handler: F) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response> + Send + 'static,
+{
+    let listener = TcpListener::bind(addr)?;
+    info!("Serving http on {}", addr);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| handle(stream, &handler));
+            if let Err(e) = result {
+                error!("Error handling http request: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+fn handle<F>(stream: TcpStream, handler: &F) -> io::Result<()>
+where
+    F: Fn(&str) -> Option<Response>,
+{
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+
+    // e.g. GET /debug/frames HTTP/1.1
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // skip the headers, we don't need any of them
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or("");
+    // ignore any query string
+    let path = parts.next().unwrap_or("").split('?').next().unwrap_or("");
+
+    let (status, response) = if method != "GET" {
+        ("405 Method Not Allowed", None)
+    } else {
+        match handler(path) {
+            Some(response) => (response.status, Some(response)),
+            None => ("404 Not Found", None),
+        }
+    };
+    let response = response.unwrap_or(Response {
+        status,
+        content_type: "text/plain",
+        body: format!("{}\n", status),
+    });
+
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        response.content_type,
+        response.body.len(),
+        response.body
+    )?;
+    stream.flush()
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::Read;
+
+    fn get(addr: &str, request: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn serves_known_paths_only() {
+        // port 0 asks the os for a free port, we then look up which one we got
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        start(&addr, |path| match path {
+            "/hello" => Some(Response::json("{\"hello\":1}".to_string())),
+            _ => None,
+        })
+        .unwrap();
+
+        let response = get(&addr, "GET /hello?x=1 HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"hello\":1}"));
+
+        let response = get(&addr, "GET /nope HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let response = get(&addr, "POST /hello HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
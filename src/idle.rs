@@ -0,0 +1,123 @@
+use std::time::{Duration, Instant};
+
+// Closes the device connection when no complete frame has arrived for a while and connects again (see
+// --device-idle-timeout), for gateways that keep the connection open but stop sending until it is remade.
+// Heartbeats count as frames, as do frames that don't decode, it is the gateway going quiet that matters here.
+// The socket read timeout is set to the idle timeout so that a gateway sending nothing at all still gets looked at.
+// The time is monotonic, the wall clock stepping either way (NTP, a gateway PC's daylight saving) doesn't count
+pub struct IdleTimer {
+    timeout: Duration,
+    // when the last complete frame arrived, or the connection was made if there hasn't been one yet
+    last_frame: Instant,
+}
+
+impl IdleTimer {
+    pub fn new(timeout: Duration, now: Instant) -> IdleTimer {
+        IdleTimer {
+            timeout,
+            last_frame: now,
+        }
+    }
+
+    // Call on every new connection to start timing again
+    pub fn connected(&mut self, now: Instant) {
+        self.last_frame = now;
+    }
+
+    // Call with every complete frame read from the device
+    pub fn frame(&mut self, now: Instant) {
+        self.last_frame = now;
+    }
+
+    // How long it has been since the last frame, once that is longer than the timeout
+    pub fn idle(&self, now: Instant) -> Option<Duration> {
+        let idle = now.saturating_duration_since(self.last_frame);
+        if idle >= self.timeout {
+            Some(idle)
+        } else {
+            None
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::clock::Clock;
+    use std::cell::Cell;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    // A clock that only moves when it is told to. The wall clock can be stepped on its own, the way NTP does
+    struct MockClock {
+        wall: Cell<SystemTime>,
+        monotonic: Cell<Instant>,
+    }
+
+    impl MockClock {
+        fn new() -> MockClock {
+            MockClock {
+                wall: Cell::new(UNIX_EPOCH + Duration::from_secs(1_571_388_795)),
+                monotonic: Cell::new(Instant::now()),
+            }
+        }
+
+        fn advance(&self, by: Duration) {
+            self.wall.set(self.wall.get() + by);
+            self.monotonic.set(self.monotonic.get() + by);
+        }
+
+        fn step_wall_clock(&self, forward: Duration) {
+            self.wall.set(self.wall.get() + forward);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> SystemTime {
+            self.wall.get()
+        }
+
+        fn monotonic(&self) -> Instant {
+            self.monotonic.get()
+        }
+    }
+
+    #[test]
+    fn idle_once_no_frame_has_arrived_for_the_timeout() {
+        let clock = MockClock::new();
+        let mut timer = IdleTimer::new(Duration::from_secs(60), clock.monotonic());
+
+        // counted from the connection before the first frame
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(timer.idle(clock.monotonic()), None);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(timer.idle(clock.monotonic()), Some(Duration::from_secs(60)));
+
+        // a frame, a heartbeat as much as a message, starts it again
+        timer.frame(clock.monotonic());
+        assert_eq!(timer.idle(clock.monotonic()), None);
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(timer.idle(clock.monotonic()), Some(Duration::from_secs(90)));
+
+        // and so does connecting again
+        timer.connected(clock.monotonic());
+        clock.advance(Duration::from_secs(30));
+        assert_eq!(timer.idle(clock.monotonic()), None);
+    }
+
+    #[test]
+    fn a_wall_clock_step_is_not_idle() {
+        let clock = MockClock::new();
+        let timer = IdleTimer::new(Duration::from_secs(60), clock.monotonic());
+        // the clock jumping an hour ahead doesn't drop a gateway that is still sending
+        clock.step_wall_clock(Duration::from_secs(3600));
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(timer.idle(clock.monotonic()), None);
+        clock.advance(Duration::from_secs(50));
+        assert_eq!(timer.idle(clock.monotonic()), Some(Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,138 @@
+use crate::frame::{format_mac, DeviceMessage};
+use crate::sink::{self, MessageSink, SinkError};
+use crate::units::{Reading, Units};
+use log::error;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How many lines can be waiting for a slow disk (or a slow reader of stdout) before new ones are dropped
+const PENDING_LINES: usize = 1024;
+
+// How long the buffered lines can sit in memory when nothing else is arriving
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// One decoded message as it is written out, e.g.
+// {"received_at_ms":1700000000000,"mac":"D0:CF:5E:82:93:7B","message":{...},"fields":{...}}
+#[derive(Serialize)]
+pub struct JsonLine {
+    // milliseconds since the unix epoch
+    pub received_at_ms: u64,
+    pub mac: String,
+    pub message: DeviceMessage,
+    // the same values with their units, like /debug/frames
+    pub fields: BTreeMap<&'static str, Reading>,
+}
+
+impl JsonLine {
+    pub fn new(msg: &DeviceMessage, units: &Units) -> JsonLine {
+        JsonLine {
+            received_at_ms: received_at_ms(msg),
+            mac: format_mac(&msg.mac),
+            message: msg.clone(),
+            fields: units.readings(msg),
+        }
+    }
+}
+
+// When the message arrived in milliseconds since the unix epoch, or now if it doesn't say
+pub fn received_at_ms(msg: &DeviceMessage) -> u64 {
+    msg.received_at
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// Writes every decoded message as a line of json to a file or stdout, for log pipelines that want the readings.
+// The writing happens on its own thread so a slow disk never holds up the modbus
+pub struct JsonOut {
+    sender: SyncSender<String>,
+    units: Units,
+}
+
+impl JsonOut {
+    // - is stdout, anything else is a file that is added to. The file is opened straight away so that a
+    // bad path is found at startup
+    pub fn start(path: &str, units: Units) -> io::Result<JsonOut> {
+        let mut writer: Box<dyn Write + Send> = if path == "-" {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            Box::new(BufWriter::new(file))
+        };
+        let (sender, receiver) = mpsc::sync_channel(PENDING_LINES);
+        thread::spawn(move || run(&mut writer, receiver));
+        Ok(JsonOut { sender, units })
+    }
+}
+
+impl MessageSink for JsonOut {
+    fn name(&self) -> &str {
+        "json output"
+    }
+
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        let line = serde_json::to_string(&JsonLine::new(msg, &self.units))
+            .map_err(|e| SinkError::Encode(e.to_string()))?;
+        sink::queue(&self.sender, line)
+    }
+}
+
+// Runs until the router drops its JsonOut
+fn run(writer: &mut dyn Write, receiver: Receiver<String>) {
+    loop {
+        let result = match receiver.recv_timeout(FLUSH_INTERVAL) {
+            Ok(line) => writeln!(writer, "{}", line),
+            Err(mpsc::RecvTimeoutError::Timeout) => writer.flush(),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let _ = writer.flush();
+                return;
+            }
+        };
+        if let Err(e) = result {
+            error!("Error writing to the json output: {:?}", e);
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    #[test]
+    fn json_line_of_a_message() {
+        let msg = DeviceMessage {
+            received_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            ..sample_message()
+        };
+        let mut units = Units::default();
+        units.parse("temperature=C:0.5").unwrap();
+        let json = serde_json::to_string(&JsonLine::new(&msg, &units)).unwrap();
+        assert_eq!(
+            json,
+            concat!(
+                r#"{"received_at_ms":1700000000123,"mac":"D0:CF:5E:82:93:7B","#,
+                r#""message":{"mac":[208,207,94,130,147,123],"batt_pid1":1,"batt_value":0,"#,
+                r#""temp_pid2":2,"temp_value":84,"vib_pid3":3,"vib_x":62206,"vib_y":602,"vib_z":1914,"#,
+                r#""msg_num_pid5":5,"msg_num_value":33850,"version_pid11":11,"version_value":2,"#,
+                r#""rssi_pid6":6,"rssi_value":189},"#,
+                r#""fields":{"battery":{"value":0.0,"unit":"raw"},"msg-num":{"value":33850.0,"unit":"raw"},"#,
+                r#""rssi":{"value":189.0,"unit":"raw"},"temperature":{"value":42.0,"unit":"C"},"#,
+                r#""version":{"value":2.0,"unit":"raw"},"vibration":{"value":[62206.0,602.0,1914.0],"unit":"raw"}}}"#
+            )
+        );
+    }
+}
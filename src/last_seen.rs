@@ -0,0 +1,108 @@
+use crate::modbus_client::ModbusClient;
+use crate::word_order::WordOrder;
+use log::debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A pair of registers that hold when the router last wrote a message to the modbus, in seconds since the unix
+// epoch, for a SCADA that polls the registers and wants to know how fresh they are (see --last-seen-register).
+// The high word goes in one register and the low word in the other
+pub struct LastSeen {
+    high: u16,
+    low: u16,
+}
+
+impl LastSeen {
+    pub fn new(high: u16, low: u16) -> LastSeen {
+        LastSeen { high, low }
+    }
+
+    // Call this once the message has been written, with the time it was
+    pub fn write(
+        &self,
+        modbus_client: &mut dyn ModbusClient,
+        now: SystemTime,
+    ) -> Result<(), modbus::Error> {
+        let [high, low] = words(now);
+        // one request when the registers are next to each other, so the PLC never reads half of a new time
+        if self.high.checked_add(1) == Some(self.low) {
+            modbus_client.write_multiple_registers(self.high, &[high, low])?;
+        } else {
+            modbus_client.write_single_register(self.high, high)?;
+            modbus_client.write_single_register(self.low, low)?;
+        }
+        debug!(
+            "Wrote last seen {:04X}{:04X} to registers {} and {}",
+            high, low, self.high, self.low
+        );
+        Ok(())
+    }
+}
+
+// The high and low words of the seconds since the unix epoch, which fit in a u32 until 2106
+pub fn words(at: SystemTime) -> [u16; 2] {
+    let seconds = at
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs().min(u64::from(u32::MAX)) as u32)
+        .unwrap_or(0);
+    WordOrder::Abcd.to_registers(seconds)
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{RecordingClient, Write};
+    use std::time::Duration;
+
+    fn at(seconds: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn the_seconds_are_split_into_a_high_and_a_low_word() {
+        // 2019-10-18T08:53:15Z
+        assert_eq!(words(at(1_571_388_795)), [0x5DA9, 0x7D7B]);
+        assert_eq!(words(at(0xFFFF)), [0x0000, 0xFFFF]);
+        assert_eq!(words(at(0x10000)), [0x0001, 0x0000]);
+        // the part of a second doesn't count
+        assert_eq!(
+            words(at(0x10000) + Duration::from_millis(999)),
+            [0x0001, 0x0000]
+        );
+        assert_eq!(words(at(u64::MAX / 2)), [0xFFFF, 0xFFFF]);
+    }
+
+    #[test]
+    fn written_to_the_registers_it_was_given() {
+        let mut client = RecordingClient::default();
+        LastSeen::new(300, 310)
+            .write(&mut client, at(1_571_388_795))
+            .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![Write::Single(300, 0x5DA9), Write::Single(310, 0x7D7B)]
+        );
+
+        // the low word first is fine too, and registers next to each other take one request
+        let mut client = RecordingClient::default();
+        LastSeen::new(201, 200)
+            .write(&mut client, at(1_571_388_795))
+            .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![Write::Single(201, 0x5DA9), Write::Single(200, 0x7D7B)]
+        );
+        let mut client = RecordingClient::default();
+        LastSeen::new(200, 201)
+            .write(&mut client, at(1_571_388_795))
+            .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![Write::Multiple(200, vec![0x5DA9, 0x7D7B])]
+        );
+    }
+}
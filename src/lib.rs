@@ -0,0 +1,82 @@
+// The router: decoding the frames sent by the device, writing the messages to the modbus and the loop
+// that runs it all, with main.rs only working out from the command line what it has been asked to do.
+// It lives in a library so that the benchmarks in benches/, tests outside of this crate and other
+// programs that embed the router can use the parts of it too
+mod alive_log;
+#[cfg(feature = "async")]
+pub mod async_frame;
+mod audit_log;
+mod capture;
+mod change;
+mod chaos;
+mod circuit_breaker;
+pub mod clock;
+mod coalesce;
+pub mod config;
+mod connections;
+mod csv_out;
+mod dedup;
+mod device_source;
+mod error_log;
+pub mod fields;
+pub mod formats;
+pub mod frame;
+mod freshness;
+mod gaps;
+#[cfg(feature = "grpc")]
+mod grpc_sink;
+mod health;
+pub mod histogram;
+mod hooks;
+mod http;
+mod idle;
+mod json_out;
+mod last_seen;
+mod listen_pool;
+mod load_shed;
+mod log_sampling;
+pub mod modbus_client;
+mod monitor;
+#[cfg(feature = "mqtt")]
+mod mqtt_sink;
+mod policy;
+mod rate_limit;
+mod raw_sink;
+mod read_ahead;
+pub mod read_registers;
+mod recent_frames;
+mod reconnect;
+mod recycle;
+pub mod register_map;
+mod retry_queue;
+mod router;
+pub mod rtu_transport;
+pub mod selftest;
+mod sentinel;
+pub mod simulator;
+pub mod sink;
+mod state;
+pub mod stats;
+mod stats_log;
+pub mod status;
+pub mod stream_transport;
+mod strobe;
+pub mod testing;
+#[cfg(feature = "tls")]
+mod tls;
+pub mod transform;
+mod units;
+pub mod validate;
+mod verify_writes;
+mod version_gate;
+mod watchdog;
+mod websocket;
+pub mod word_order;
+mod write_queue;
+mod ws_broadcast;
+
+pub use frame::{
+    read_frame, read_message, DeviceMessage, FramedReader, MessageReader, ParsedFrame,
+};
+pub use register_map::send_message_to_modbus;
+pub use router::{check_install, read_from_modbus, run, run_monitor};
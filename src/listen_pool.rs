@@ -0,0 +1,451 @@
+#[cfg(feature = "async")]
+use crate::async_frame;
+use crate::device_source::TcpOptions;
+use crate::frame::{read_extra_payload, resync_to_start, MAX_ALIGNMENT_SCAN};
+#[cfg(feature = "async")]
+use log::error;
+use log::{info, warn};
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// How many frames from all of the gateways can be waiting for the modbus before the gateways are held up
+const PENDING_FRAMES: usize = 256;
+
+// Built with --features async the connections share these threads rather than having one each
+#[cfg(feature = "async")]
+const ASYNC_WORKERS: usize = 2;
+
+// Reads from several gateways at once, for when they connect to us (see --device-listen-max).
+// Each connection gets a thread of its own that reads whole frames and hands them to the router's read loop
+// one after the other, so the loop sees a single stream of frames and the modbus writes stay one at a time.
+// A gateway that disconnects, sends rubbish or panics its thread only loses its own connection.
+// Built with --features async each connection is a tokio task instead, for sites with more gateways than
+// it makes sense to have threads for
+pub struct ListenPool {
+    frames: Arc<Mutex<Receiver<Vec<u8>>>>,
+    read_timeout: Option<Duration>,
+}
+
+impl ListenPool {
+    pub fn start(
+        listener: TcpListener,
+        tcp_options: TcpOptions,
+        start_seq: [u8; 2],
+        macs: Vec<[u8; 6]>,
+        max_connections: usize,
+    ) -> ListenPool {
+        let (sender, receiver) = mpsc::sync_channel(PENDING_FRAMES);
+        let read_timeout = tcp_options.read_timeout;
+        #[cfg(not(feature = "async"))]
+        thread::spawn(move || {
+            accept(
+                listener,
+                tcp_options,
+                start_seq,
+                macs,
+                max_connections,
+                sender,
+            )
+        });
+        #[cfg(feature = "async")]
+        thread::spawn(move || {
+            accept_tasks(
+                listener,
+                tcp_options,
+                start_seq,
+                macs,
+                max_connections,
+                sender,
+            )
+        });
+        ListenPool {
+            frames: Arc::new(Mutex::new(receiver)),
+            read_timeout,
+        }
+    }
+
+    // The frames of every gateway, connecting again carries on from the next frame
+    pub fn reader(&self) -> PoolReader {
+        PoolReader {
+            frames: self.frames.clone(),
+            read_timeout: self.read_timeout,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+// Runs for as long as the router does
+#[cfg_attr(feature = "async", allow(dead_code))]
+fn accept(
+    listener: TcpListener,
+    tcp_options: TcpOptions,
+    start_seq: [u8; 2],
+    macs: Vec<[u8; 6]>,
+    max_connections: usize,
+    sender: SyncSender<Vec<u8>>,
+) {
+    let active = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("Unable to accept a gateway connection: {:?}", e);
+                continue;
+            }
+        };
+        let worker = match admit(&stream, &tcp_options, &active, max_connections) {
+            Some(worker) => worker,
+            None => continue,
+        };
+        let peer = peer_of(&stream);
+        let macs = macs.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let _worker = worker;
+            read_frames(stream, &peer, start_seq, &macs, &sender);
+        });
+    }
+}
+
+// The same as accept with a task for each connection
+#[cfg(feature = "async")]
+fn accept_tasks(
+    listener: TcpListener,
+    tcp_options: TcpOptions,
+    start_seq: [u8; 2],
+    macs: Vec<[u8; 6]>,
+    max_connections: usize,
+    sender: SyncSender<Vec<u8>>,
+) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(ASYNC_WORKERS)
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            error!("Unable to start reading from the gateways: {:?}", e);
+            return;
+        }
+    };
+    runtime.block_on(async move {
+        let listener = match listener
+            .set_nonblocking(true)
+            .and_then(|_| tokio::net::TcpListener::from_std(listener))
+        {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Unable to start reading from the gateways: {:?}", e);
+                return;
+            }
+        };
+        let active = Arc::new(AtomicUsize::new(0));
+        loop {
+            // the tcp options are set on the std stream, tokio doesn't have all of them
+            let stream = match listener.accept().await.and_then(|(s, _)| s.into_std()) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Unable to accept a gateway connection: {:?}", e);
+                    continue;
+                }
+            };
+            let worker = match admit(&stream, &tcp_options, &active, max_connections) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            let peer = peer_of(&stream);
+            let stream = match tokio::net::TcpStream::from_std(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Unable to set up the connection from {}: {:?}", peer, e);
+                    continue;
+                }
+            };
+            let macs = macs.clone();
+            let sender = sender.clone();
+            let read_timeout = tcp_options.read_timeout;
+            tokio::spawn(async move {
+                let _worker = worker;
+                read_frames_task(stream, &peer, start_seq, &macs, &sender, read_timeout).await;
+            });
+        }
+    });
+}
+
+// Whether there is room for another gateway and its connection could be set up. The worker counts it
+// among the active connections until it is dropped
+fn admit(
+    stream: &TcpStream,
+    tcp_options: &TcpOptions,
+    active: &Arc<AtomicUsize>,
+    max_connections: usize,
+) -> Option<Worker> {
+    let peer = peer_of(stream);
+    if active.load(Ordering::SeqCst) >= max_connections {
+        warn!(
+            "Turning away {}, already reading from {} gateways",
+            peer, max_connections
+        );
+        return None;
+    }
+    if let Err(e) = tcp_options.apply(stream) {
+        warn!("Unable to set up the connection from {}: {:?}", peer, e);
+        return None;
+    }
+    info!("Accepted a connection from {}", peer);
+    active.fetch_add(1, Ordering::SeqCst);
+    Some(Worker {
+        active: active.clone(),
+    })
+}
+
+fn peer_of(stream: &TcpStream) -> String {
+    stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "an unknown gateway".to_string())
+}
+
+// Lets the acceptor know a connection has finished, however its thread ends (a panic included)
+struct Worker {
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Hands on every frame the gateway sends until it goes away. The start of each frame is checked so that a
+// gateway that loses its place finds the next frame on its own, the router checks the rest
+fn read_frames<T: Read>(
+    mut stream: T,
+    peer: &str,
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    sender: &SyncSender<Vec<u8>>,
+) {
+    loop {
+        let frame = resync_to_start(&mut stream, start_seq, macs, MAX_ALIGNMENT_SCAN).and_then(
+            |(frame, discarded)| {
+                // a longer payload is handed on whole, the router ignores what it doesn't know
+                let mut frame = frame.to_vec();
+                frame.extend(read_extra_payload(&mut stream, start_seq, &frame)?);
+                Ok((frame, discarded))
+            },
+        );
+        match frame {
+            Ok((frame, discarded)) => {
+                if discarded > 0 {
+                    warn!(
+                        "Discarded {} bytes from {} looking for the start of a frame",
+                        discarded, peer
+                    );
+                }
+                // the router has gone, there is nobody to hand the frames to
+                if sender.send(frame).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                info!("Closing the connection from {}: {}", peer, e);
+                return;
+            }
+        }
+    }
+}
+
+// The same as read_frames for a task. The socket's read timeout does nothing for tokio, so it is kept here
+#[cfg(feature = "async")]
+async fn read_frames_task<T: tokio::io::AsyncRead + Unpin>(
+    mut stream: T,
+    peer: &str,
+    start_seq: [u8; 2],
+    macs: &[[u8; 6]],
+    sender: &SyncSender<Vec<u8>>,
+    read_timeout: Option<Duration>,
+) {
+    loop {
+        let frame = async {
+            let (frame, discarded) =
+                async_frame::resync_to_start(&mut stream, start_seq, macs, MAX_ALIGNMENT_SCAN)
+                    .await?;
+            let mut frame = frame.to_vec();
+            frame.extend(async_frame::read_extra_payload(&mut stream, start_seq, &frame).await?);
+            Ok::<_, io::Error>((frame, discarded))
+        };
+        let result = match read_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, frame)
+                .await
+                .unwrap_or_else(|_| {
+                    let e = io::Error::new(ErrorKind::TimedOut, "No frames from the gateway");
+                    Err(e)
+                }),
+            None => frame.await,
+        };
+        match result {
+            Ok((frame, discarded)) => {
+                if discarded > 0 {
+                    warn!(
+                        "Discarded {} bytes from {} looking for the start of a frame",
+                        discarded, peer
+                    );
+                }
+                // the router has gone, there is nobody to hand the frames to. When the router falls behind
+                // this waits for it, which holds up this worker's other connections too
+                if tokio::task::block_in_place(|| sender.send(frame)).is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                info!("Closing the connection from {}: {}", peer, e);
+                return;
+            }
+        }
+    }
+}
+
+// The read loop's view of the pool, the frames of all the gateways back to back
+pub struct PoolReader {
+    frames: Arc<Mutex<Receiver<Vec<u8>>>>,
+    read_timeout: Option<Duration>,
+    // what is left of the latest frame
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl PoolReader {
+    fn next_frame(&self) -> io::Result<Vec<u8>> {
+        let frames = match self.frames.lock() {
+            Ok(frames) => frames,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let frame = match self.read_timeout {
+            Some(timeout) => frames.recv_timeout(timeout),
+            None => frames.recv().map_err(RecvTimeoutError::from),
+        };
+        frame.map_err(|e| match e {
+            // the same as a quiet tcp connection with a read timeout
+            RecvTimeoutError::Timeout => {
+                io::Error::new(ErrorKind::WouldBlock, "No frames from any of the gateways")
+            }
+            RecvTimeoutError::Disconnected => io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "No longer accepting gateway connections",
+            ),
+        })
+    }
+}
+
+impl Read for PoolReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            self.pending = self.next_frame()?;
+            self.position = 0;
+        }
+        let available = &self.pending[self.position..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{
+        encode_frame, read_message, DeviceMessage, FRAME_LEN, MAC_ADDRESS, START_SEQ,
+    };
+    use crate::testing::sample_message;
+    use std::collections::BTreeSet;
+    use std::io::Write;
+
+    const OTHER_MAC: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+    fn frame_from(mac: [u8; 6]) -> [u8; FRAME_LEN] {
+        encode_frame(&DeviceMessage {
+            mac,
+            ..sample_message()
+        })
+    }
+
+    #[test]
+    fn frames_from_two_gateways_at_once() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = TcpOptions {
+            read_timeout: Some(Duration::from_secs(5)),
+            ..TcpOptions::default()
+        };
+        let pool = ListenPool::start(
+            listener,
+            options,
+            START_SEQ,
+            vec![MAC_ADDRESS, OTHER_MAC],
+            2,
+        );
+
+        // both stay connected until both frames have been read
+        let mut first = TcpStream::connect(addr).unwrap();
+        let mut second = TcpStream::connect(addr).unwrap();
+        // rubbish in front of a frame doesn't spoil the other gateway's frames
+        first.write_all(&[0xAA, 0xBB]).unwrap();
+        first.write_all(&frame_from(MAC_ADDRESS)).unwrap();
+        second.write_all(&frame_from(OTHER_MAC)).unwrap();
+
+        let mut reader = pool.reader();
+        let macs: BTreeSet<[u8; 6]> = (0..2)
+            .map(|_| {
+                read_message(&mut reader, &[MAC_ADDRESS, OTHER_MAC])
+                    .unwrap()
+                    .mac
+            })
+            .collect();
+        assert_eq!(macs, [MAC_ADDRESS, OTHER_MAC].iter().cloned().collect());
+    }
+
+    #[test]
+    fn one_gateway_going_away_leaves_the_others() {
+        let (sender, receiver) = mpsc::sync_channel(4);
+        // a gateway that closes part way through its second frame
+        let mut bytes = frame_from(MAC_ADDRESS).to_vec();
+        bytes.extend_from_slice(&frame_from(MAC_ADDRESS)[..10]);
+        read_frames(
+            io::Cursor::new(bytes),
+            "test",
+            START_SEQ,
+            &[MAC_ADDRESS],
+            &sender,
+        );
+        assert!(receiver.try_recv().is_ok());
+        assert!(receiver.try_recv().is_err());
+
+        // the router carries on with the frames that do arrive
+        sender.send(frame_from(MAC_ADDRESS).to_vec()).unwrap();
+        let mut reader = PoolReader {
+            frames: Arc::new(Mutex::new(receiver)),
+            read_timeout: Some(Duration::from_millis(10)),
+            pending: Vec::new(),
+            position: 0,
+        };
+        assert!(read_message(&mut reader, &[MAC_ADDRESS]).is_ok());
+        // and a quiet pool times out like a quiet connection
+        let e = read_message(&mut reader, &[MAC_ADDRESS]).unwrap_err();
+        assert_eq!(io::Error::from(e).kind(), ErrorKind::WouldBlock);
+    }
+}
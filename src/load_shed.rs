@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+// How many messages in a row have to be too slow to write before the device connection is dropped, so that a
+// modbus server that is busy for a moment doesn't cost us the connection
+pub const SLOW_FORWARDS: u32 = 5;
+
+// Watches how long each message takes to write to the modbus (see --max-forward-latency). While the writes
+// block the device keeps sending, so its frames queue up in the socket buffers and by the time we get to them
+// they are stale. Once the writes keep taking longer than the limit the device connection is dropped, which
+// throws the queued frames away, and the router connects again and carries on with fresh ones
+pub struct LoadShedder {
+    max_latency: Duration,
+    // how many forwards in a row have been over the limit
+    slow: u32,
+}
+
+impl LoadShedder {
+    pub fn new(max_latency: Duration) -> LoadShedder {
+        LoadShedder {
+            max_latency,
+            slow: 0,
+        }
+    }
+
+    // Call with how long each message took to write, true when it is time to drop the connection.
+    // The count starts again after that, and after any forward that was quick enough
+    pub fn record(&mut self, took: Duration) -> bool {
+        if took <= self.max_latency {
+            self.slow = 0;
+            return false;
+        }
+        self.slow += 1;
+        if self.slow < SLOW_FORWARDS {
+            return false;
+        }
+        self.slow = 0;
+        true
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fields::FieldSet;
+    use crate::modbus_client::ModbusClient;
+    use crate::register_map::{send_message_to_modbus, RegisterMap};
+    use crate::testing::{sample_message, RecordingClient};
+    use std::thread;
+    use std::time::Instant;
+
+    // A modbus server that takes its time over every write
+    struct SlowClient {
+        inner: RecordingClient,
+        delay: Duration,
+    }
+
+    impl ModbusClient for SlowClient {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            thread::sleep(self.delay);
+            self.inner.write_single_register(address, value)
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            thread::sleep(self.delay);
+            self.inner.write_multiple_registers(address, values)
+        }
+    }
+
+    // Writes the sample message the way the router does, true if the shedder wants the connection dropped
+    fn forward(shedder: &mut LoadShedder, client: &mut SlowClient) -> bool {
+        let started = Instant::now();
+        let msg = sample_message();
+        send_message_to_modbus(
+            &msg,
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            client,
+        )
+        .unwrap();
+        shedder.record(started.elapsed())
+    }
+
+    #[test]
+    fn a_modbus_that_cant_keep_up_drops_the_connection() {
+        let mut shedder = LoadShedder::new(Duration::from_millis(20));
+        let mut client = SlowClient {
+            inner: RecordingClient::default(),
+            delay: Duration::from_millis(10),
+        };
+        let shed: Vec<bool> = (0..SLOW_FORWARDS)
+            .map(|_| forward(&mut shedder, &mut client))
+            .collect();
+        assert_eq!(shed, [false, false, false, false, true]);
+        // every message was still written
+        assert_eq!(client.inner.writes.len(), 6 * SLOW_FORWARDS as usize);
+
+        // once it is quick again the connection is left alone
+        client.delay = Duration::from_millis(0);
+        assert!((0..SLOW_FORWARDS * 2).all(|_| !forward(&mut shedder, &mut client)));
+    }
+
+    #[test]
+    fn the_odd_slow_write_is_let_through() {
+        let mut shedder = LoadShedder::new(Duration::from_millis(100));
+        let slow = Duration::from_millis(150);
+        let quick = Duration::from_millis(5);
+        for _ in 0..10 {
+            for _ in 0..SLOW_FORWARDS - 1 {
+                assert!(!shedder.record(slow));
+            }
+            assert!(!shedder.record(quick));
+        }
+        // exactly on the limit isn't too slow
+        for _ in 0..SLOW_FORWARDS {
+            assert!(!shedder.record(Duration::from_millis(100)));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use crate::fields::Field;
+use crate::frame::DeviceMessage;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+// Which messages are worth printing, this has nothing to do with what is forwarded to the modbus
+#[derive(Debug, Clone, Default)]
+pub struct LogSamplingConfig {
+    // the deadband of each watched field: a message is logged when one of these moves by more than its deadband
+    pub deadbands: BTreeMap<Field, u16>,
+    // log a message at least this often even when nothing has changed
+    pub interval: Option<Duration>,
+}
+
+impl LogSamplingConfig {
+    // with nothing configured every message is logged
+    pub fn is_enabled(&self) -> bool {
+        !self.deadbands.is_empty() || self.interval.is_some()
+    }
+}
+
+// The last message that was logged from one device
+struct Logged {
+    values: BTreeMap<Field, Vec<u16>>,
+    at: Instant,
+}
+
+// Keeps high rate telemetry from flooding the log.
+// Values are compared to the last message that was logged from the same device, not the last one received,
+// so that a slow drift still shows up once it has moved further than the deadband
+pub struct LogSampler {
+    config: LogSamplingConfig,
+    last_logged: BTreeMap<([u8; 6], Option<u8>), Logged>,
+}
+
+impl LogSampler {
+    pub fn new(config: LogSamplingConfig) -> LogSampler {
+        LogSampler {
+            config,
+            last_logged: BTreeMap::new(),
+        }
+    }
+
+    // Decides whether the message should be logged and if so remembers it as the last one logged
+    pub fn should_log(&mut self, msg: &DeviceMessage, now: Instant) -> bool {
+        if !self.config.is_enabled() {
+            return true;
+        }
+
+        let key = (msg.mac, msg.sensor_id);
+        if let Some(last) = self.last_logged.get(&key) {
+            let changed = self.config.deadbands.iter().any(|(field, deadband)| {
+                let values = msg.field_values(*field);
+                match last.values.get(field) {
+                    Some(last) => values.iter().zip(last.iter()).any(|(value, last)| {
+                        (*value as i32 - *last as i32).abs() > *deadband as i32
+                    }),
+                    None => true,
+                }
+            });
+            let due = match self.config.interval {
+                Some(interval) => now.duration_since(last.at) >= interval,
+                None => false,
+            };
+            if !changed && !due {
+                return false;
+            }
+        }
+
+        // the first message from each device is always logged
+        let values = self
+            .config
+            .deadbands
+            .keys()
+            .map(|field| (*field, msg.field_values(*field)))
+            .collect();
+        self.last_logged.insert(key, Logged { values, at: now });
+        true
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn message(temp_value: u8) -> DeviceMessage {
+        DeviceMessage {
+            temp_value,
+            ..crate::testing::sample_message()
+        }
+    }
+
+    fn sampler(deadbands: &[(Field, u16)], interval: Option<Duration>) -> LogSampler {
+        LogSampler::new(LogSamplingConfig {
+            deadbands: deadbands.iter().cloned().collect(),
+            interval,
+        })
+    }
+
+    #[test]
+    fn everything_is_logged_by_default() {
+        let mut sampler = sampler(&[], None);
+        let now = Instant::now();
+        assert!(sampler.should_log(&message(80), now));
+        assert!(sampler.should_log(&message(80), now));
+    }
+
+    #[test]
+    fn logged_when_the_change_is_bigger_than_the_deadband() {
+        let mut sampler = sampler(&[(Field::Temperature, 2)], None);
+        let now = Instant::now();
+        assert!(sampler.should_log(&message(80), now));
+        assert!(!sampler.should_log(&message(82), now));
+        assert!(!sampler.should_log(&message(78), now));
+        assert!(sampler.should_log(&message(83), now));
+        // compared to 83 now
+        assert!(!sampler.should_log(&message(81), now));
+    }
+
+    #[test]
+    fn each_device_is_compared_to_its_own_last_message() {
+        let mut sampler = sampler(&[(Field::Temperature, 2)], Some(Duration::from_secs(60)));
+        let start = Instant::now();
+        let other = |temp_value| DeviceMessage {
+            mac: [0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F],
+            ..message(temp_value)
+        };
+        assert!(sampler.should_log(&message(80), start));
+        assert!(!sampler.should_log(&message(80), start));
+        // the same value from another device is its first message
+        assert!(sampler.should_log(&other(80), start));
+        assert!(!sampler.should_log(&other(81), start));
+        // and so is another sensor behind the same gateway
+        let sensor = DeviceMessage {
+            sensor_id: Some(2),
+            ..message(80)
+        };
+        assert!(sampler.should_log(&sensor, start));
+
+        // the interval is kept for each device too
+        assert!(sampler.should_log(&message(80), start + Duration::from_secs(60)));
+        assert!(!sampler.should_log(&message(80), start + Duration::from_secs(90)));
+        assert!(sampler.should_log(&other(80), start + Duration::from_secs(90)));
+    }
+
+    #[test]
+    fn interval_logs_unchanged_values_as_a_heartbeat() {
+        let mut sampler = sampler(&[(Field::Temperature, 2)], Some(Duration::from_secs(60)));
+        let start = Instant::now();
+        assert!(sampler.should_log(&message(80), start));
+        assert!(!sampler.should_log(&message(80), start + Duration::from_secs(30)));
+        assert!(sampler.should_log(&message(80), start + Duration::from_secs(60)));
+        assert!(!sampler.should_log(&message(80), start + Duration::from_secs(90)));
+    }
+}
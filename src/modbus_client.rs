@@ -0,0 +1,764 @@
+#[cfg(feature = "serial")]
+use crate::rtu_transport::{self, RtuTransport, SerialConfig};
+use crate::stats::Stats;
+use crate::stream_transport::{ReadWrite, StreamTransport, TransactionIdConfig};
+use log::{info, warn};
+use modbus::tcp;
+use modbus::{Client, Coil, Transport};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpStream;
+use std::rc::Rc;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// The most registers a single write multiple registers request can carry
+pub const MAX_WRITE_REGISTERS: usize = 123;
+
+// The two kinds of 16-bit register a modbus server has
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegisterKind {
+    // read/write, where the router puts the values
+    Holding,
+    // read only
+    Input,
+}
+
+impl RegisterKind {
+    pub fn from_name(name: &str) -> Option<RegisterKind> {
+        match name {
+            "holding" => Some(RegisterKind::Holding),
+            "input" => Some(RegisterKind::Input),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            RegisterKind::Holding => "holding",
+            RegisterKind::Input => "input",
+        }
+    }
+}
+
+// The modbus operations the router needs.
+// Both the modbus crate's Transport and our own StreamTransport implement this so the rest of
+// the code doesn't care how the modbus connection was made
+pub trait ModbusClient {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error>;
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error>;
+
+    // Only the diagnostics read anything back, so only the clients that talk to a real modbus support it
+    fn read_registers(
+        &mut self,
+        _kind: RegisterKind,
+        _address: u16,
+        _count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        Err(modbus::Error::InvalidFunction)
+    }
+
+    // Coils are a separate address space from the registers, a field is only written to one when its write
+    // function is coil (see --write-function). True is on
+    fn write_single_coil(&mut self, _address: u16, _value: bool) -> Result<(), modbus::Error> {
+        Err(modbus::Error::InvalidFunction)
+    }
+}
+
+impl ModbusClient for Transport {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        Client::write_single_register(self, address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        write_in_chunks(address, values, |address, chunk| {
+            Client::write_multiple_registers(self, address, chunk)
+        })
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        match kind {
+            RegisterKind::Holding => Client::read_holding_registers(self, address, count),
+            RegisterKind::Input => Client::read_input_registers(self, address, count),
+        }
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        let coil = if value { Coil::On } else { Coil::Off };
+        Client::write_single_coil(self, address, coil)
+    }
+}
+
+// Splits a write that is too big for one request into as many requests as it takes, each one carrying on
+// from the address the last one stopped at. The transports that talk to a real modbus write through this
+pub fn write_in_chunks<F>(address: u16, values: &[u16], mut write: F) -> Result<(), modbus::Error>
+where
+    F: FnMut(u16, &[u16]) -> Result<(), modbus::Error>,
+{
+    if address as usize + values.len() > 0x10000 {
+        let e = io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "The registers run past the last address (65535)",
+        );
+        return Err(modbus::Error::Io(e));
+    }
+    for (i, chunk) in values.chunks(MAX_WRITE_REGISTERS).enumerate() {
+        write(address + (i * MAX_WRITE_REGISTERS) as u16, chunk)?;
+    }
+    Ok(())
+}
+
+// so that code that is generic over the client can be handed the boxed clients our connectors make
+impl<T: ModbusClient + ?Sized> ModbusClient for Box<T> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        (**self).write_single_register(address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        (**self).write_multiple_registers(address, values)
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        (**self).read_registers(kind, address, count)
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        (**self).write_single_coil(address, value)
+    }
+}
+
+// Stands in for the modbus when nothing should be written, e.g. the monitor. Every write goes nowhere
+pub struct NoModbus;
+
+impl ModbusClient for NoModbus {
+    fn write_single_register(&mut self, _address: u16, _value: u16) -> Result<(), modbus::Error> {
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        _address: u16,
+        _values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        Ok(())
+    }
+
+    fn write_single_coil(&mut self, _address: u16, _value: bool) -> Result<(), modbus::Error> {
+        Ok(())
+    }
+}
+
+// Logs every write instead of making it, for checking what the router would send before letting it near a PLC.
+// The writes are the same ones a real client would be given, only nothing leaves the machine
+pub struct DryRun;
+
+impl ModbusClient for DryRun {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        info!("Dry run, not writing {} to register {}", value, address);
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        info!(
+            "Dry run, not writing {:?} to the registers from {}",
+            values, address
+        );
+        Ok(())
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        info!("Dry run, not writing {} to coil {}", value, address);
+        Ok(())
+    }
+}
+
+// Spaces out the writes to the client by a fixed delay, for PLCs that drop writes that arrive back to back.
+// Only writes made through the same Paced are spaced out so make a new one for each message
+pub struct Paced<'a> {
+    client: &'a mut dyn ModbusClient,
+    delay: Duration,
+    first: bool,
+}
+
+impl<'a> Paced<'a> {
+    pub fn new(client: &'a mut dyn ModbusClient, delay: Duration) -> Paced<'a> {
+        Paced {
+            client,
+            delay,
+            first: true,
+        }
+    }
+
+    // waits before every write apart from the first
+    fn pace(&mut self) {
+        if !self.first && self.delay > Duration::from_millis(0) {
+            thread::sleep(self.delay);
+        }
+        self.first = false;
+    }
+}
+
+impl<'a> ModbusClient for Paced<'a> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.pace();
+        self.client.write_single_register(address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.pace();
+        self.client.write_multiple_registers(address, values)
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.pace();
+        self.client.write_single_coil(address, value)
+    }
+}
+
+// Holds on to the writes of a message so they can be sent in as few requests as possible (see --batch-writes).
+// A register written more than once ends up with the last value, as it would have on the modbus
+#[derive(Default)]
+pub struct Batch {
+    registers: BTreeMap<u16, u16>,
+    coils: BTreeMap<u16, bool>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    // Each run of consecutive registers goes in one write multiple registers, a register on its own is written
+    // with write single register. The runs are sent lowest address first, then the coils one at a time
+    pub fn send(self, modbus_client: &mut dyn ModbusClient) -> Result<(), modbus::Error> {
+        let mut run: Vec<u16> = Vec::new();
+        let mut start = 0;
+        for (address, value) in self.registers {
+            if !run.is_empty() && start as usize + run.len() != address as usize {
+                send_run(modbus_client, start, &run)?;
+                run.clear();
+            }
+            if run.is_empty() {
+                start = address;
+            }
+            run.push(value);
+        }
+        if !run.is_empty() {
+            send_run(modbus_client, start, &run)?;
+        }
+        for (address, value) in self.coils {
+            modbus_client.write_single_coil(address, value)?;
+        }
+        Ok(())
+    }
+}
+
+fn send_run(
+    modbus_client: &mut dyn ModbusClient,
+    address: u16,
+    values: &[u16],
+) -> Result<(), modbus::Error> {
+    match values {
+        [value] => modbus_client.write_single_register(address, *value),
+        values => modbus_client.write_multiple_registers(address, values),
+    }
+}
+
+impl ModbusClient for Batch {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.registers.insert(address, value);
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        for (offset, value) in values.iter().enumerate() {
+            self.registers
+                .insert(address.wrapping_add(offset as u16), *value);
+        }
+        Ok(())
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.coils.insert(address, value);
+        Ok(())
+    }
+}
+
+// A modbus connection for background threads that only write now and then.
+// It connects on first use and throws the connection away when a write fails, so the next write starts afresh
+pub struct LazyClient<F, C> {
+    connect: F,
+    client: Option<C>,
+}
+
+impl<F, C> LazyClient<F, C>
+where
+    F: FnMut() -> io::Result<C>,
+    C: ModbusClient,
+{
+    pub fn new(connect: F) -> LazyClient<F, C> {
+        LazyClient {
+            connect,
+            client: None,
+        }
+    }
+
+    fn client(&mut self) -> Result<&mut C, modbus::Error> {
+        if self.client.is_none() {
+            self.client = Some((self.connect)()?);
+        }
+        Ok(self.client.as_mut().unwrap())
+    }
+
+    // Connects straight away rather than waiting for the first write
+    pub fn connect_now(&mut self) -> io::Result<()> {
+        if self.client.is_none() {
+            self.client = Some((self.connect)()?);
+        }
+        Ok(())
+    }
+}
+
+// The connection, if there is one yet, so that tests can look at what was written through it
+impl<F, C> LazyClient<F, C> {
+    pub fn connected(&self) -> Option<&C> {
+        self.client.as_ref()
+    }
+}
+
+impl<F, C> ModbusClient for LazyClient<F, C>
+where
+    F: FnMut() -> io::Result<C>,
+    C: ModbusClient,
+{
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        let result = self.client()?.write_single_register(address, value);
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        let result = self.client()?.write_multiple_registers(address, values);
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        let result = self.client()?.write_single_coil(address, value);
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        let result = self.client()?.read_registers(kind, address, count);
+        if result.is_err() {
+            self.client = None;
+        }
+        result
+    }
+}
+
+// One of the modbus servers a RedundantClient writes to
+struct Endpoint<C> {
+    host: String,
+    client: C,
+    // whether the last write to it failed, so that a server that is down is only logged when it goes and
+    // when it comes back
+    failing: bool,
+}
+
+// Writes everything to several modbus servers, e.g. a primary PLC and a hot standby, so that either one has
+// the latest values when the other goes. A write only fails when every server turned it down. A server that
+// didn't take it is logged and counted in modbusrouter_endpoint_write_errors_total, and the others carry on
+pub struct RedundantClient<C> {
+    endpoints: Vec<Endpoint<C>>,
+    stats: Stats,
+}
+
+impl<C: ModbusClient> RedundantClient<C> {
+    // The host of each client is what it is called in the log and the metrics
+    pub fn new(clients: Vec<(String, C)>, stats: Stats) -> RedundantClient<C> {
+        let endpoints = clients
+            .into_iter()
+            .map(|(host, client)| Endpoint {
+                host,
+                client,
+                failing: false,
+            })
+            .collect();
+        RedundantClient { endpoints, stats }
+    }
+
+    fn write_each<F>(&mut self, mut write: F) -> Result<(), modbus::Error>
+    where
+        F: FnMut(&mut C) -> Result<(), modbus::Error>,
+    {
+        let mut written = false;
+        let mut first_error = None;
+        for endpoint in self.endpoints.iter_mut() {
+            match write(&mut endpoint.client) {
+                Ok(()) => {
+                    if endpoint.failing {
+                        info!("The modbus at {} is taking writes again", endpoint.host);
+                        endpoint.failing = false;
+                    }
+                    written = true;
+                }
+                Err(e) => {
+                    self.stats.record_endpoint_write_error(&endpoint.host);
+                    if !endpoint.failing {
+                        warn!(
+                            "Write to the modbus at {} failed, carrying on with the others: {:?}",
+                            endpoint.host, e
+                        );
+                        endpoint.failing = true;
+                    }
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) if !written => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<C: ModbusClient> ModbusClient for RedundantClient<C> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.write_each(|client| client.write_single_register(address, value))
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.write_each(|client| client.write_multiple_registers(address, values))
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.write_each(|client| client.write_single_coil(address, value))
+    }
+
+    // the first server that answers, they should all hold the same values
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        let mut last_error = modbus::Error::InvalidResponse;
+        for endpoint in self.endpoints.iter_mut() {
+            match endpoint.client.read_registers(kind, address, count) {
+                Ok(values) => return Ok(values),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+// Creates the stream the modbus connection runs over
+pub type StreamFactory = Box<dyn Fn() -> io::Result<Box<dyn ReadWrite>>>;
+
+// Knows how to make a new modbus connection
+pub enum ModbusConnector {
+    // a plain tcp connection to this host and port made by the modbus crate
+    Direct {
+        host: String,
+        port: u16,
+        unit: u8,
+    },
+    // A plain tcp connection to this host with our own modbus framing, so that the transaction ids
+    // are ours to choose and check. Every response with the wrong id is counted in mismatches
+    Framed {
+        host: String,
+        port: u16,
+        unit: u8,
+        transaction_ids: TransactionIdConfig,
+        mismatches: Arc<AtomicU64>,
+    },
+    // A stream made by the factory, with our own modbus framing on top.
+    // Use this to wrap the connection before any modbus traffic is sent, for example to
+    // perform a proxy handshake:
+    //
+    //     ModbusConnector::Stream(Box::new(|| {
+    //         let mut stream = TcpStream::connect("proxy:1502")?;
+    //         proxy_handshake(&mut stream)?;
+    //         Ok(Box::new(stream))
+    //     }))
+    // the router itself never builds one, this is a hook for anyone that needs to customise the connection
+    #[allow(dead_code)]
+    Stream(StreamFactory),
+    // Modbus RTU over a serial port, for PLCs on an RS-485 line rather than the network
+    // (needs the serial feature)
+    #[cfg(feature = "serial")]
+    Serial {
+        config: SerialConfig,
+        unit: u8,
+    },
+    // Several modbus servers that are all written to, see RedundantClient. Each server that can't be connected
+    // to, or whose connection breaks, is connected to again with the next write
+    Redundant {
+        endpoints: Vec<(String, Rc<ModbusConnector>)>,
+        stats: Stats,
+    },
+}
+
+impl ModbusConnector {
+    // Creates the connection to the local modbus
+    pub fn connect(&self) -> io::Result<Box<dyn ModbusClient>> {
+        match self {
+            ModbusConnector::Direct { host, port, unit } => {
+                let transport = tcp::Transport::new_with_cfg(host, tcp_config(*port, *unit))?;
+                Ok(Box::new(transport))
+            }
+            ModbusConnector::Framed {
+                host,
+                port,
+                unit,
+                transaction_ids,
+                mismatches,
+            } => {
+                let stream = TcpStream::connect((host.as_str(), *port))?;
+                let transport = StreamTransport::with_transaction_ids(
+                    Box::new(stream),
+                    *transaction_ids,
+                    mismatches.clone(),
+                );
+                Ok(Box::new(transport.with_unit_id(*unit)))
+            }
+            ModbusConnector::Stream(factory) => {
+                let stream = factory()?;
+                Ok(Box::new(StreamTransport::new(stream)))
+            }
+            #[cfg(feature = "serial")]
+            ModbusConnector::Serial { config, unit } => {
+                let port = rtu_transport::open(config)?;
+                Ok(Box::new(RtuTransport::new(Box::new(port), *unit)))
+            }
+            ModbusConnector::Redundant { endpoints, stats } => {
+                // only when none of them can be reached is there nothing to write to
+                let mut first_error = None;
+                let mut connected = false;
+                let mut clients = Vec::with_capacity(endpoints.len());
+                for (host, connector) in endpoints {
+                    let connector = connector.clone();
+                    let mut client = LazyClient::new(move || connector.connect());
+                    match client.connect_now() {
+                        Ok(()) => connected = true,
+                        Err(e) => {
+                            warn!(
+                                "Unable to connect to the modbus at {}, trying again with the next write: {:?}",
+                                host, e
+                            );
+                            first_error.get_or_insert(e);
+                        }
+                    }
+                    clients.push((host.clone(), client));
+                }
+                match first_error {
+                    Some(e) if !connected => Err(e),
+                    _ => Ok(Box::new(RedundantClient::new(clients, stats.clone()))),
+                }
+            }
+        }
+    }
+}
+
+// What the modbus crate needs to know to connect, everything else is left at its defaults
+fn tcp_config(port: u16, unit: u8) -> tcp::Config {
+    tcp::Config {
+        tcp_port: port,
+        modbus_uid: unit,
+        ..tcp::Config::default()
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::fields::FieldSet;
+    use crate::register_map::{send_message_to_modbus, RegisterMap};
+    use crate::testing::{sample_message, FailingClient, RecordingClient, Write};
+    use std::time::Instant;
+
+    #[test]
+    fn big_writes_are_split_into_requests_the_modbus_accepts() {
+        let values: Vec<u16> = (0..200).collect();
+        let mut writes = vec![];
+        write_in_chunks(1000, &values, |address, chunk| {
+            writes.push((address, chunk.to_vec()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            writes,
+            vec![
+                (1000, (0..123).collect::<Vec<u16>>()),
+                (1123, (123..200).collect::<Vec<u16>>())
+            ]
+        );
+
+        // the last register is 65535, there is nowhere after it to carry on to
+        let e = write_in_chunks(65500, &values, |_, _| Ok(()));
+        assert!(e.is_err());
+    }
+
+    #[test]
+    fn batches_send_runs_of_registers_together() {
+        let mut batch = Batch::new();
+        batch.write_single_register(2, 20).unwrap();
+        batch.write_single_register(1, 10).unwrap();
+        batch.write_multiple_registers(3, &[30, 40, 50]).unwrap();
+        // the last write to a register wins
+        batch.write_single_register(5, 55).unwrap();
+        batch.write_single_register(11, 110).unwrap();
+        batch.write_single_register(7, 70).unwrap();
+        batch.write_single_register(8, 80).unwrap();
+        // a coil doesn't join a run of registers even at the next address
+        batch.write_single_coil(9, true).unwrap();
+
+        let mut client = RecordingClient::default();
+        batch.send(&mut client).unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Multiple(1, vec![10, 20, 30, 40, 55]),
+                Write::Multiple(7, vec![70, 80]),
+                Write::Single(11, 110),
+                Write::Coil(9, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn paced_waits_between_writes_only() {
+        let mut client = RecordingClient::default();
+        let delay = Duration::from_millis(20);
+        let started = Instant::now();
+        {
+            let mut paced = Paced::new(&mut client, delay);
+            paced.write_single_register(1, 10).unwrap();
+            paced.write_multiple_registers(3, &[1, 2, 3]).unwrap();
+            paced.write_single_register(5, 50).unwrap();
+        }
+        // two gaps for three writes, none before the first
+        assert!(started.elapsed() >= delay * 2);
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(1, 10),
+                Write::Multiple(3, vec![1, 2, 3]),
+                Write::Single(5, 50),
+            ]
+        );
+    }
+
+    #[test]
+    fn the_unit_id_goes_to_the_modbus_crate() {
+        let cfg = tcp_config(1502, 17);
+        assert_eq!(cfg.tcp_port, 1502);
+        assert_eq!(cfg.modbus_uid, 17);
+        assert_eq!(
+            cfg.tcp_read_timeout,
+            tcp::Config::default().tcp_read_timeout
+        );
+    }
+
+    #[test]
+    fn a_standby_that_fails_doesnt_stop_the_message() {
+        let stats = Stats::new();
+        let mut client = RedundantClient::new(
+            vec![
+                ("10.0.0.2".to_string(), FailingClient::after(usize::MAX)),
+                ("10.0.0.3".to_string(), FailingClient::after(2)),
+            ],
+            stats.clone(),
+        );
+        let msg = sample_message();
+        send_message_to_modbus(
+            &msg,
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        let primary = &client.endpoints[0].client.inner;
+        let standby = &client.endpoints[1].client.inner;
+        // the primary got all of the writes and the standby the two it took before it went
+        assert_eq!(primary.writes.len(), 6);
+        assert_eq!(standby.writes[..], primary.writes[..2]);
+        assert!(stats
+            .metrics()
+            .contains("modbusrouter_endpoint_write_errors_total{endpoint=\"10.0.0.3\"} 4\n"));
+
+        // it is only a failure once nobody takes the write
+        client.endpoints[0].client.writes_left = 0;
+        assert!(client.write_single_register(0, 1).is_err());
+        client.endpoints[1].client.writes_left = 1;
+        client.write_single_coil(3, true).unwrap();
+        assert_eq!(
+            client.endpoints[1].client.inner.writes.last(),
+            Some(&Write::Coil(3, true))
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use crate::fields::Field;
+use crate::frame::{format_mac, DeviceMessage};
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{Event, KeyCode, KeyModifiers};
+use crossterm::style::{Color, Print, ResetColor, SetForegroundColor};
+use crossterm::terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{event, execute, queue, terminal};
+use std::collections::BTreeMap;
+use std::io;
+use std::io::Write;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How often the screen is redrawn when nothing arrives, so that the ages keep counting up
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+
+// Set while the terminal is in the monitor's hands so that restore knows whether there is anything to undo
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Highlights a field when its value is past a limit, e.g. battery<20 or temperature>60
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Alert {
+    field: Field,
+    below: bool,
+    limit: u16,
+}
+
+impl Alert {
+    pub fn parse(value: &str) -> Result<Alert, String> {
+        let (split, below) = match (value.find('<'), value.find('>')) {
+            (Some(i), None) => (i, true),
+            (None, Some(i)) => (i, false),
+            _ => return Err(format!("Expected <field><<|>><limit> but got: {}", value)),
+        };
+        let name = &value[..split];
+        let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+        let limit = value[split + 1..]
+            .parse()
+            .map_err(|_| format!("Invalid alert limit: {}", value))?;
+        Ok(Alert {
+            field,
+            below,
+            limit,
+        })
+    }
+
+    // any of the values counts, so one bad vibration axis is enough
+    fn is_raised(&self, values: &[u16]) -> bool {
+        values.iter().any(|value| {
+            if self.below {
+                *value < self.limit
+            } else {
+                *value > self.limit
+            }
+        })
+    }
+}
+
+// How a cell is drawn
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Highlight {
+    Normal,
+    // the value is past one of the alert limits
+    Alert,
+    // the device hasn't sent anything for a while so its values are out of date
+    Stale,
+}
+
+struct Device {
+    msg: DeviceMessage,
+    messages: u64,
+    last_seen: Instant,
+}
+
+// The latest values of every device seen so far, one row each
+pub struct Table {
+    title: String,
+    alerts: Vec<Alert>,
+    stale_after: Duration,
+    // keyed by MAC address and sensor id
+    devices: BTreeMap<([u8; 6], Option<u8>), Device>,
+}
+
+impl Table {
+    pub fn new(title: String, alerts: Vec<Alert>, stale_after: Duration) -> Table {
+        Table {
+            title,
+            alerts,
+            stale_after,
+            devices: BTreeMap::new(),
+        }
+    }
+
+    pub fn update(&mut self, msg: &DeviceMessage, now: Instant) {
+        let key = (msg.mac, msg.sensor_id);
+        let device = self.devices.entry(key).or_insert(Device {
+            msg: msg.clone(),
+            messages: 0,
+            last_seen: now,
+        });
+        device.msg = msg.clone();
+        device.messages += 1;
+        device.last_seen = now;
+    }
+
+    // The screen as lines of cells, already padded into columns
+    fn lines(&self, now: Instant) -> Vec<Vec<(String, Highlight)>> {
+        let mut rows = vec![];
+        let mut header = vec!["device".to_string()];
+        header.extend(Field::ALL.iter().map(|field| field.name().to_string()));
+        header.push("messages".to_string());
+        header.push("age".to_string());
+        rows.push(
+            header
+                .into_iter()
+                .map(|name| (name, Highlight::Normal))
+                .collect::<Vec<_>>(),
+        );
+
+        for ((mac, sensor_id), device) in &self.devices {
+            let age = now.duration_since(device.last_seen);
+            let stale = age >= self.stale_after;
+            let row_highlight = if stale {
+                Highlight::Stale
+            } else {
+                Highlight::Normal
+            };
+            let name = match sensor_id {
+                Some(id) => format!("{}#{}", format_mac(mac), id),
+                None => format_mac(mac),
+            };
+            let mut row = vec![(name, row_highlight)];
+            for field in Field::ALL.iter() {
+                let values = device.msg.field_values(*field);
+                let text: Vec<String> = values.iter().map(|value| value.to_string()).collect();
+                let raised = self
+                    .alerts
+                    .iter()
+                    .any(|alert| alert.field == *field && alert.is_raised(&values));
+                // an out of date value is still worth flagging, the alert wins
+                let highlight = if raised {
+                    Highlight::Alert
+                } else {
+                    row_highlight
+                };
+                row.push((text.join("/"), highlight));
+            }
+            row.push((device.messages.to_string(), row_highlight));
+            row.push((format!("{}s", age.as_secs()), row_highlight));
+            rows.push(row);
+        }
+
+        // pad each column to its widest cell, plus a gap
+        let columns = rows[0].len();
+        for column in 0..columns {
+            let width = rows
+                .iter()
+                .map(|row| row[column].0.chars().count())
+                .max()
+                .unwrap_or(0);
+            for row in rows.iter_mut() {
+                let text = &mut row[column].0;
+                *text = format!("{:width$}", text, width = width + 2);
+            }
+        }
+
+        let mut lines = vec![
+            vec![(
+                format!("{}  (q or Ctrl-C to quit)", self.title),
+                Highlight::Normal,
+            )],
+            vec![],
+        ];
+        lines.extend(rows);
+        if self.devices.is_empty() {
+            lines.push(vec![(
+                "Waiting for the first message ...".to_string(),
+                Highlight::Normal,
+            )]);
+        }
+        lines
+    }
+}
+
+// Cuts a line short so that it doesn't wrap onto the next one when the terminal is narrow
+fn fit(line: &[(String, Highlight)], width: usize) -> Vec<(String, Highlight)> {
+    let mut fitted = vec![];
+    let mut used = 0;
+    for (text, highlight) in line {
+        if used >= width {
+            break;
+        }
+        let text: String = text.chars().take(width - used).collect();
+        used += text.chars().count();
+        fitted.push((text, *highlight));
+    }
+    fitted
+}
+
+// The live monitor. Takes over the terminal and redraws the table in place
+pub struct Monitor {
+    table: Arc<Mutex<Table>>,
+}
+
+impl Monitor {
+    pub fn start(table: Table) -> io::Result<Monitor> {
+        execute!(io::stdout(), EnterAlternateScreen, Hide)?;
+        // raw mode so that Ctrl-C comes to us as a key, otherwise we wouldn't get to put the terminal back
+        terminal::enable_raw_mode()?;
+        ACTIVE.store(true, Ordering::SeqCst);
+
+        let table = Arc::new(Mutex::new(table));
+        draw(&table.lock().unwrap(), Instant::now())?;
+        {
+            let table = table.clone();
+            thread::spawn(move || handle_events(table));
+        }
+        Ok(Monitor { table })
+    }
+
+    pub fn update(&self, msg: &DeviceMessage) {
+        let now = Instant::now();
+        let mut table = self.table.lock().unwrap();
+        table.update(msg, now);
+        // a failed redraw just means a stale screen until the next one
+        let _ = draw(&table, now);
+    }
+}
+
+// Looks after the keyboard and the terminal size while the main loop is busy reading from the device
+fn handle_events(table: Arc<Mutex<Table>>) {
+    loop {
+        if let Ok(true) = event::poll(REDRAW_INTERVAL) {
+            match event::read() {
+                Ok(Event::Key(key)) => {
+                    let ctrl_c = key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL);
+                    if ctrl_c || key.code == KeyCode::Char('q') {
+                        restore();
+                        process::exit(0);
+                    }
+                }
+                // falls through to the redraw, which picks up the new size
+                Ok(Event::Resize(_, _)) => {}
+                Ok(_) => continue,
+                Err(_) => return,
+            }
+        }
+        let _ = draw(&table.lock().unwrap(), Instant::now());
+    }
+}
+
+fn draw(table: &Table, now: Instant) -> io::Result<()> {
+    let (width, height) = terminal::size()?;
+    let mut out = io::stdout();
+    queue!(out, Clear(ClearType::All))?;
+    for (y, line) in table.lines(now).iter().take(height as usize).enumerate() {
+        queue!(out, MoveTo(0, y as u16))?;
+        for (text, highlight) in fit(line, width as usize) {
+            match highlight {
+                Highlight::Normal => queue!(out, Print(text))?,
+                Highlight::Alert => {
+                    queue!(out, SetForegroundColor(Color::Red), Print(text), ResetColor)?
+                }
+                Highlight::Stale => queue!(
+                    out,
+                    SetForegroundColor(Color::DarkGrey),
+                    Print(text),
+                    ResetColor
+                )?,
+            }
+        }
+    }
+    out.flush()
+}
+
+// Gives the terminal back the way we found it. Safe to call whether or not the monitor was started
+pub fn restore() {
+    if ACTIVE.swap(false, Ordering::SeqCst) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(io::stdout(), Show, LeaveAlternateScreen);
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn texts(line: &[(String, Highlight)]) -> Vec<&str> {
+        line.iter().map(|(text, _)| text.trim_end()).collect()
+    }
+
+    #[test]
+    fn parse_alert() {
+        assert_eq!(
+            Alert::parse("battery<20"),
+            Ok(Alert {
+                field: Field::Battery,
+                below: true,
+                limit: 20
+            })
+        );
+        assert!(Alert::parse("temperature>60").is_ok());
+        assert!(Alert::parse("bogus<1").is_err());
+        assert!(Alert::parse("battery=20").is_err());
+        assert!(Alert::parse("battery<low").is_err());
+    }
+
+    #[test]
+    fn a_row_per_device_with_alerts_and_stale_values() {
+        let alerts = vec![Alert::parse("battery<20").unwrap()];
+        let mut table = Table::new("gateway".to_string(), alerts, Duration::from_secs(10));
+        let start = Instant::now();
+        table.update(&sample_message(), start);
+        table.update(&sample_message(), start);
+
+        let lines = table.lines(start);
+        assert_eq!(
+            texts(&lines[2]),
+            vec![
+                "device",
+                "battery",
+                "temperature",
+                "vibration",
+                "msg-num",
+                "version",
+                "rssi",
+                "messages",
+                "age"
+            ]
+        );
+        let row = &lines[3];
+        assert_eq!(
+            texts(row),
+            vec![
+                "D0:CF:5E:82:93:7B",
+                "0",
+                "84",
+                "62206/602/1914",
+                "33850",
+                "2",
+                "189",
+                "2",
+                "0s"
+            ]
+        );
+        // the sample battery is flat
+        assert_eq!(row[1].1, Highlight::Alert);
+        assert_eq!(row[2].1, Highlight::Normal);
+
+        let row = &table.lines(start + Duration::from_secs(10))[3];
+        assert_eq!(row[0].1, Highlight::Stale);
+        assert_eq!(row[1].1, Highlight::Alert);
+        assert_eq!(row[8].0.trim_end(), "10s");
+    }
+
+    #[test]
+    fn lines_are_cut_to_the_terminal_width() {
+        let line = vec![
+            ("abcd  ".to_string(), Highlight::Normal),
+            ("efgh  ".to_string(), Highlight::Alert),
+        ];
+        let fitted = fit(&line, 8);
+        assert_eq!(
+            fitted,
+            vec![
+                ("abcd  ".to_string(), Highlight::Normal),
+                ("ef".to_string(), Highlight::Alert)
+            ]
+        );
+        assert!(fit(&line, 0).is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+// Only built with --features mqtt
+use crate::fields::Field;
+use crate::frame::{format_mac, DeviceMessage};
+use crate::sink::{MessageSink, SinkError};
+use log::{error, info};
+use rumqttc::{Client, Connection, Event, MqttOptions, QoS};
+use std::thread;
+use std::time::Duration;
+
+// How many publishes can be waiting for the broker before new ones are dropped
+const PENDING_PUBLISHES: usize = 1024;
+
+// Don't hammer a broker that is down, wait this long between attempts
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+
+// Publishes every field of the decoded messages to an MQTT broker, one topic per field.
+// Like the gRPC sink the network side runs on its own thread, if the broker can't keep up messages are
+// dropped rather than holding up the modbus
+pub struct MqttSink {
+    client: Client,
+    topic_prefix: String,
+}
+
+impl MqttSink {
+    // broker is <host>:<port>, which the config has already checked
+    pub fn start(broker: &str, client_id: &str, topic_prefix: &str) -> MqttSink {
+        let (host, port) = broker.rsplit_once(':').unwrap_or((broker, "1883"));
+        let port = port.parse().unwrap_or(1883);
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(KEEP_ALIVE);
+        let (client, connection) = Client::new(options, PENDING_PUBLISHES);
+        let name = broker.to_string();
+        thread::spawn(move || run(connection, &name));
+        MqttSink {
+            client,
+            topic_prefix: topic_prefix.to_string(),
+        }
+    }
+}
+
+impl MessageSink for MqttSink {
+    fn name(&self) -> &str {
+        "MQTT sink"
+    }
+
+    // Queues the fields and returns straight away, if the queue is full the rest of the message is dropped
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        for (topic, payload) in publishes(&self.topic_prefix, msg) {
+            self.client
+                .try_publish(topic, QoS::AtLeastOnce, false, payload)
+                .map_err(|_| SinkError::Full)?;
+        }
+        Ok(())
+    }
+}
+
+// Drives the connection to the broker until the router drops its MqttSink. The client reconnects by itself
+// the next time round after an error
+fn run(mut connection: Connection, broker: &str) {
+    let mut connected = false;
+    for event in connection.iter() {
+        match event {
+            Ok(Event::Incoming(_)) if !connected => {
+                info!("Connected to MQTT broker {}", broker);
+                connected = true;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Error talking to MQTT broker {}: {}", broker, e);
+                connected = false;
+                thread::sleep(RECONNECT_INTERVAL);
+            }
+        }
+    }
+}
+
+// The topic and payload of each field, e.g. sensors/D0:CF:5E:82:93:7B/battery with a payload of 0.
+// The vibration is the three axes as a json array. A sensor behind a gateway (see --sensor-id-offset) gets
+// its id after the MAC address, e.g. sensors/D0:CF:5E:82:93:7B-2/battery
+pub fn publishes(topic_prefix: &str, msg: &DeviceMessage) -> Vec<(String, String)> {
+    let device = match msg.sensor_id {
+        Some(sensor_id) => format!("{}-{}", format_mac(&msg.mac), sensor_id),
+        None => format_mac(&msg.mac),
+    };
+    Field::ALL
+        .iter()
+        .map(|field| {
+            let values = msg.field_values(*field);
+            let payload = match values.as_slice() {
+                [value] => value.to_string(),
+                values => serde_json::to_string(values).unwrap_or_default(),
+            };
+            let topic = format!("{}/{}/{}", topic_prefix, device, field.name());
+            (topic, payload)
+        })
+        .collect()
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    #[test]
+    fn a_topic_for_each_field() {
+        assert_eq!(
+            publishes("sensors", &sample_message()),
+            vec![
+                (
+                    "sensors/D0:CF:5E:82:93:7B/battery".to_string(),
+                    "0".to_string()
+                ),
+                (
+                    "sensors/D0:CF:5E:82:93:7B/temperature".to_string(),
+                    "84".to_string()
+                ),
+                (
+                    "sensors/D0:CF:5E:82:93:7B/vibration".to_string(),
+                    "[62206,602,1914]".to_string()
+                ),
+                (
+                    "sensors/D0:CF:5E:82:93:7B/msg-num".to_string(),
+                    "33850".to_string()
+                ),
+                (
+                    "sensors/D0:CF:5E:82:93:7B/version".to_string(),
+                    "2".to_string()
+                ),
+                (
+                    "sensors/D0:CF:5E:82:93:7B/rssi".to_string(),
+                    "189".to_string()
+                ),
+            ]
+        );
+
+        let msg = DeviceMessage {
+            sensor_id: Some(2),
+            ..sample_message()
+        };
+        assert_eq!(
+            publishes("plant/line1", &msg)[0].0,
+            "plant/line1/D0:CF:5E:82:93:7B-2/battery"
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::ErrorKind;
+
+// The broad categories of errors the main loop can run into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ErrorClass {
+    // a frame was read but it isn't one we understand (start sequence, MAC address or length)
+    BadFrame,
+    // the device closed the connection cleanly, usually because it is restarting
+    Eof,
+    // the connection was reset or aborted, usually because the device crashed
+    ConnectionReset,
+    // a read from the device took too long
+    Timeout,
+    // any other error reading from the device
+    DeviceIo,
+    // the modbus server answered with an exception response (e.g. illegal data address)
+    ModbusException,
+    // the modbus connection failed or the server answered with something we don't understand
+    ModbusIo,
+}
+
+// What the main loop should do when it runs into an error
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    // try the same operation again without dropping any connection
+    // for device reads this means reading the next frame, for modbus writes it means resending the message
+    RetryInPlace,
+    // close the device connection and connect again (this was the only behaviour in earlier versions)
+    ReconnectDevice,
+    // throw away the modbus connection and create a new one
+    ReconnectModbus,
+    // drop the current frame and carry on with the next one
+    SkipFrame,
+    // stay connected and look byte by byte for the start of the next frame (see --max-resync-bytes).
+    // Only device reads can get out of step, for a modbus write it is the same as skip-frame
+    Resync,
+    // print the summary and exit the program
+    FatalExit,
+}
+
+// The number of times a modbus write is retried in place before we give up and reconnect to the modbus
+pub const RETRY_IN_PLACE_ATTEMPTS: u32 = 3;
+
+// Maps each class of error to the action the main loop takes
+#[derive(Debug, Clone)]
+pub struct ErrorPolicy {
+    actions: BTreeMap<ErrorClass, Action>,
+}
+
+impl Default for ErrorPolicy {
+    fn default() -> ErrorPolicy {
+        let mut actions = BTreeMap::new();
+        // The frames are a fixed size so a bad frame most likely means we are no longer aligned with the
+        // start of a frame. Finding it again keeps the connection and everything buffered behind the bad frame
+        actions.insert(ErrorClass::BadFrame, Action::Resync);
+        actions.insert(ErrorClass::Eof, Action::ReconnectDevice);
+        actions.insert(ErrorClass::ConnectionReset, Action::ReconnectDevice);
+        actions.insert(ErrorClass::Timeout, Action::ReconnectDevice);
+        actions.insert(ErrorClass::DeviceIo, Action::ReconnectDevice);
+        // an exception is the modbus server telling us that this write is wrong, reconnecting won't fix that
+        actions.insert(ErrorClass::ModbusException, Action::SkipFrame);
+        actions.insert(ErrorClass::ModbusIo, Action::ReconnectModbus);
+        ErrorPolicy { actions }
+    }
+}
+
+impl ErrorPolicy {
+    pub fn action_for(&self, class: ErrorClass) -> Action {
+        // every class has a default so this never falls through in practice
+        *self.actions.get(&class).unwrap_or(&Action::ReconnectDevice)
+    }
+
+    pub fn set(&mut self, class: ErrorClass, action: Action) {
+        self.actions.insert(class, action);
+    }
+
+    // Parses a rule in the form class=action, e.g. bad-frame=skip-frame
+    pub fn parse_rule(&mut self, rule: &str) -> Result<(), String> {
+        let mut parts = rule.splitn(2, '=');
+        let class = parts.next().unwrap_or("");
+        let action = parts
+            .next()
+            .ok_or_else(|| format!("Expected class=action but got: {}", rule))?;
+        let class = ErrorClass::from_name(class)
+            .ok_or_else(|| format!("Unknown error class: {}", class))?;
+        let action = match action {
+            "retry-in-place" => Action::RetryInPlace,
+            "reconnect-device" => Action::ReconnectDevice,
+            "reconnect-modbus" => Action::ReconnectModbus,
+            "skip-frame" => Action::SkipFrame,
+            "resync" => Action::Resync,
+            "fatal-exit" => Action::FatalExit,
+            _ => return Err(format!("Unknown error action: {}", action)),
+        };
+        self.set(class, action);
+        Ok(())
+    }
+}
+
+impl ErrorClass {
+    pub const ALL: [ErrorClass; 7] = [
+        ErrorClass::BadFrame,
+        ErrorClass::Eof,
+        ErrorClass::ConnectionReset,
+        ErrorClass::Timeout,
+        ErrorClass::DeviceIo,
+        ErrorClass::ModbusException,
+        ErrorClass::ModbusIo,
+    ];
+
+    // The name used for the class in --on-error rules and the metrics
+    pub fn name(self) -> &'static str {
+        match self {
+            ErrorClass::BadFrame => "bad-frame",
+            ErrorClass::Eof => "eof",
+            ErrorClass::ConnectionReset => "connection-reset",
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::DeviceIo => "device-io",
+            ErrorClass::ModbusException => "modbus-exception",
+            ErrorClass::ModbusIo => "modbus-io",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ErrorClass> {
+        ErrorClass::ALL
+            .iter()
+            .cloned()
+            .find(|class| class.name() == name)
+    }
+
+    // read_message reports frames it doesn't understand as InvalidData and a closed connection as UnexpectedEof
+    pub fn of_read_error(e: &io::Error) -> ErrorClass {
+        match e.kind() {
+            ErrorKind::InvalidData => ErrorClass::BadFrame,
+            ErrorKind::UnexpectedEof => ErrorClass::Eof,
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                ErrorClass::ConnectionReset
+            }
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ErrorClass::Timeout,
+            _ => ErrorClass::DeviceIo,
+        }
+    }
+
+    pub fn of_modbus_error(e: &modbus::Error) -> ErrorClass {
+        match e {
+            modbus::Error::Exception(_) => ErrorClass::ModbusException,
+            _ => ErrorClass::ModbusIo,
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn default_policy() {
+        let policy = ErrorPolicy::default();
+        assert_eq!(policy.action_for(ErrorClass::BadFrame), Action::Resync);
+        assert_eq!(policy.action_for(ErrorClass::Eof), Action::ReconnectDevice);
+        assert_eq!(
+            policy.action_for(ErrorClass::ModbusException),
+            Action::SkipFrame
+        );
+        assert_eq!(
+            policy.action_for(ErrorClass::ModbusIo),
+            Action::ReconnectModbus
+        );
+    }
+
+    #[test]
+    fn parse_rule_overrides_default() {
+        let mut policy = ErrorPolicy::default();
+        policy.parse_rule("bad-frame=skip-frame").unwrap();
+        policy.parse_rule("modbus-io=fatal-exit").unwrap();
+        policy.parse_rule("timeout=resync").unwrap();
+        assert_eq!(policy.action_for(ErrorClass::Timeout), Action::Resync);
+        assert_eq!(policy.action_for(ErrorClass::BadFrame), Action::SkipFrame);
+        assert_eq!(policy.action_for(ErrorClass::ModbusIo), Action::FatalExit);
+    }
+
+    #[test]
+    fn class_names_round_trip() {
+        for class in ErrorClass::ALL.iter() {
+            assert_eq!(ErrorClass::from_name(class.name()), Some(*class));
+        }
+    }
+
+    #[test]
+    fn parse_rule_invalid() {
+        let mut policy = ErrorPolicy::default();
+        assert!(policy.parse_rule("bad-frame").is_err());
+        assert!(policy.parse_rule("bogus=skip-frame").is_err());
+        assert!(policy.parse_rule("eof=bogus").is_err());
+    }
+
+    #[test]
+    fn classify_errors() {
+        let bad_frame = io::Error::new(ErrorKind::InvalidData, "Unexpected MAC address");
+        let eof = io::Error::new(ErrorKind::UnexpectedEof, "eof");
+        let reset = io::Error::new(ErrorKind::ConnectionReset, "reset");
+        let timeout = io::Error::new(ErrorKind::TimedOut, "timed out");
+        let other = io::Error::new(ErrorKind::PermissionDenied, "denied");
+        assert_eq!(ErrorClass::of_read_error(&bad_frame), ErrorClass::BadFrame);
+        assert_eq!(ErrorClass::of_read_error(&eof), ErrorClass::Eof);
+        assert_eq!(
+            ErrorClass::of_read_error(&reset),
+            ErrorClass::ConnectionReset
+        );
+        assert_eq!(ErrorClass::of_read_error(&timeout), ErrorClass::Timeout);
+        assert_eq!(ErrorClass::of_read_error(&other), ErrorClass::DeviceIo);
+
+        let exception = modbus::Error::Exception(modbus::ExceptionCode::IllegalDataAddress);
+        let modbus_io = modbus::Error::Io(reset);
+        assert_eq!(
+            ErrorClass::of_modbus_error(&exception),
+            ErrorClass::ModbusException
+        );
+        assert_eq!(
+            ErrorClass::of_modbus_error(&modbus_io),
+            ErrorClass::ModbusIo
+        );
+    }
+}
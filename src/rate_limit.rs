@@ -0,0 +1,86 @@
+use std::time::SystemTime;
+
+// Caps how many messages a second are written to the modbus (see --max-msgs-per-sec), so that a sensor gone
+// wrong can't bury the modbus server in writes. It is a token bucket holding a second's worth of tokens: each
+// message takes one and they come back at the given rate, so a short burst gets through but a flood doesn't.
+// Messages that find the bucket empty are dropped rather than queued, the next one that gets through has
+// newer values anyway
+pub struct RateLimiter {
+    per_sec: f64,
+    tokens: f64,
+    // when the tokens were last topped up
+    last: Option<SystemTime>,
+}
+
+impl RateLimiter {
+    pub fn new(per_sec: u32) -> RateLimiter {
+        RateLimiter {
+            per_sec: per_sec as f64,
+            tokens: per_sec as f64,
+            last: None,
+        }
+    }
+
+    // Whether a message that arrived at now can be written, call this once for every message.
+    // The time comes from the caller's clock so that tests can say exactly when each message arrived
+    pub fn allow(&mut self, now: SystemTime) -> bool {
+        if let Some(last) = self.last {
+            // a clock that goes backwards doesn't give any tokens back
+            let elapsed = now.duration_since(last).unwrap_or_default();
+            self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.per_sec);
+        }
+        self.last = Some(self.last.map_or(now, |last| last.max(now)));
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(ms: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_571_388_795) + Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn a_burst_is_cut_down_to_the_limit() {
+        let mut limiter = RateLimiter::new(10);
+        // 100 frames within the same second
+        let forwarded = (0..100).filter(|n| limiter.allow(at(*n * 5))).count();
+        assert_eq!(forwarded, 14);
+
+        // quiet for long enough to fill the bucket again, but it never holds more than a second's worth
+        let forwarded = (0..100).filter(|_| limiter.allow(at(60_000))).count();
+        assert_eq!(forwarded, 10);
+    }
+
+    #[test]
+    fn a_steady_rate_under_the_limit_all_gets_through() {
+        let mut limiter = RateLimiter::new(2);
+        assert!((0..20).all(|n| limiter.allow(at(n * 500))));
+        // the bucket has filled up between them, so there is room for one more at once but not two
+        assert!(limiter.allow(at(9_600)));
+        assert!(!limiter.allow(at(9_600)));
+        assert!(limiter.allow(at(10_100)));
+    }
+
+    #[test]
+    fn the_clock_going_backwards_gives_no_tokens() {
+        let mut limiter = RateLimiter::new(1);
+        assert!(limiter.allow(at(10_000)));
+        assert!(!limiter.allow(at(0)));
+        assert!(!limiter.allow(at(9_999)));
+        assert!(limiter.allow(at(11_000)));
+    }
+}
@@ -0,0 +1,221 @@
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How many frames are kept while the sink is unreachable, the oldest are dropped after that
+const PENDING_FRAMES: usize = 256;
+
+// Don't hammer an endpoint that is down, wait at least this long between connection attempts
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long a connection attempt or a write may take before we treat the sink as down
+const SINK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Mirrors the raw bytes of frames to another tcp endpoint (e.g. an archive).
+// The network side runs on its own thread so a slow or missing sink never holds up the modbus
+pub struct RawTcpSink {
+    sender: SyncSender<Vec<u8>>,
+}
+
+impl RawTcpSink {
+    pub fn start(addr: String) -> RawTcpSink {
+        let (sender, receiver) = mpsc::sync_channel(PENDING_FRAMES);
+        thread::spawn(move || {
+            let connect = || connect_sink(&addr);
+            let mut mirror = Mirror::new(connect, RECONNECT_INTERVAL);
+            run(&mut mirror, receiver);
+        });
+        RawTcpSink { sender }
+    }
+
+    // Queues the frame and returns straight away, if the queue is full the frame is dropped
+    pub fn send(&self, frame: &[u8]) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(frame.to_vec()) {
+            warn!("Raw sink is not keeping up, dropping frame");
+        }
+    }
+}
+
+fn connect_sink(addr: &str) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(
+        io::ErrorKind::NotFound,
+        "Unable to resolve raw sink address",
+    );
+    for socket_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&socket_addr, SINK_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(SINK_TIMEOUT))?;
+                info!("Connected to raw sink {}", addr);
+                return Ok(stream);
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+// Runs until the router drops its RawTcpSink
+fn run<F, W>(mirror: &mut Mirror<F, W>, receiver: Receiver<Vec<u8>>)
+where
+    F: FnMut() -> io::Result<W>,
+    W: Write,
+{
+    loop {
+        // wake up every now and then even with no new frames so that pending frames get another go
+        match receiver.recv_timeout(RECONNECT_INTERVAL) {
+            Ok(frame) => mirror.push(frame),
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+        }
+        mirror.flush();
+    }
+}
+
+// Keeps a connection to the sink open and the frames that haven't made it there yet
+struct Mirror<F, W> {
+    connect: F,
+    stream: Option<W>,
+    pending: VecDeque<Vec<u8>>,
+    reconnect_interval: Duration,
+    last_attempt: Option<Instant>,
+}
+
+impl<F, W> Mirror<F, W>
+where
+    F: FnMut() -> io::Result<W>,
+    W: Write,
+{
+    fn new(connect: F, reconnect_interval: Duration) -> Mirror<F, W> {
+        Mirror {
+            connect,
+            stream: None,
+            pending: VecDeque::new(),
+            reconnect_interval,
+            last_attempt: None,
+        }
+    }
+
+    fn push(&mut self, frame: Vec<u8>) {
+        if self.pending.len() == PENDING_FRAMES {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(frame);
+    }
+
+    // Sends as many of the pending frames as we can, connecting first if need be
+    fn flush(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+
+        if self.stream.is_none() {
+            let too_soon = self
+                .last_attempt
+                .is_some_and(|last| last.elapsed() < self.reconnect_interval);
+            if too_soon {
+                return;
+            }
+            self.last_attempt = Some(Instant::now());
+            match (self.connect)() {
+                Ok(stream) => self.stream = Some(stream),
+                Err(e) => {
+                    error!("Unable to connect to raw sink: {:?}", e);
+                    return;
+                }
+            }
+        }
+
+        if let Some(stream) = self.stream.as_mut() {
+            while let Some(frame) = self.pending.front() {
+                if let Err(e) = stream.write_all(frame) {
+                    // keep the frame, it goes out again once we have reconnected
+                    error!("Error writing to raw sink: {:?}", e);
+                    self.stream = None;
+                    return;
+                }
+                self.pending.pop_front();
+            }
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::FRAME_LEN;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Collects everything that is written to it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn frame(n: u8) -> Vec<u8> {
+        vec![n; FRAME_LEN]
+    }
+
+    #[test]
+    fn frames_are_kept_until_the_sink_is_up() {
+        let buffer = SharedBuffer::default();
+        let up = Rc::new(RefCell::new(false));
+        let connect = {
+            let buffer = buffer.clone();
+            let up = up.clone();
+            move || {
+                if *up.borrow() {
+                    Ok(buffer.clone())
+                } else {
+                    Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+                }
+            }
+        };
+        let mut mirror = Mirror::new(connect, Duration::from_secs(0));
+
+        mirror.push(frame(1));
+        mirror.flush();
+        mirror.push(frame(2));
+        mirror.flush();
+        assert!(buffer.0.borrow().is_empty());
+
+        *up.borrow_mut() = true;
+        mirror.flush();
+        let mut expected = frame(1);
+        expected.extend_from_slice(&frame(2));
+        assert_eq!(*buffer.0.borrow(), expected);
+    }
+
+    #[test]
+    fn oldest_frames_are_dropped_when_full() {
+        let connect = || -> io::Result<SharedBuffer> {
+            Err(io::Error::new(io::ErrorKind::ConnectionRefused, "down"))
+        };
+        let mut mirror = Mirror::new(connect, Duration::from_secs(0));
+        for n in 0..=PENDING_FRAMES {
+            mirror.push(frame(n as u8));
+        }
+        assert_eq!(mirror.pending.len(), PENDING_FRAMES);
+        assert_eq!(mirror.pending.front(), Some(&frame(1)));
+    }
+}
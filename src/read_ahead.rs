@@ -0,0 +1,254 @@
+use crate::frame::{read_extra_payload, resync_to_start};
+use crate::stats::Stats;
+use log::warn;
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::thread;
+
+// What the reader does with a frame when the read ahead is full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WhenFull {
+    // wait for the router to make room, so the device is held up behind it the way it is without a read ahead
+    Block,
+    // throw the frame away and count it, so the socket is always drained
+    Drop,
+}
+
+impl WhenFull {
+    pub fn from_name(name: &str) -> Option<WhenFull> {
+        match name {
+            "block" => Some(WhenFull::Block),
+            "drop" => Some(WhenFull::Drop),
+            _ => None,
+        }
+    }
+}
+
+// How the frames are found and what to do when there is no room for them (see --read-ahead)
+#[derive(Debug, Clone)]
+pub struct ReadAheadConfig {
+    pub frames: usize,
+    pub when_full: WhenFull,
+    pub start_seq: [u8; 2],
+    pub macs: Vec<[u8; 6]>,
+    pub max_resync: usize,
+}
+
+// Reads whole frames from the device on a thread of its own and hands them to the router's read loop through
+// a channel of up to --read-ahead frames, so that a slow modbus write doesn't keep the device's socket waiting
+// and the reads don't hold up the writes. The loop reads it like any other stream, the frames back to back.
+// A bad frame is handed on as an error and the reader carries on after it, as it does after a timeout. Any
+// other error is the last thing handed on. Dropping it for a new connection stops the reader once it next
+// has something to hand on, and the old connection is closed then
+pub struct ReadAhead {
+    frames: Receiver<io::Result<Vec<u8>>>,
+    // what is left of the latest frame
+    pending: Vec<u8>,
+    position: usize,
+}
+
+impl ReadAhead {
+    pub fn start<R: Read + Send + 'static>(
+        stream: R,
+        config: &ReadAheadConfig,
+        stats: Stats,
+    ) -> ReadAhead {
+        let (sender, receiver) = mpsc::sync_channel(config.frames);
+        let config = config.clone();
+        thread::spawn(move || read_frames(stream, &config, &stats, &sender));
+        ReadAhead {
+            frames: receiver,
+            pending: Vec::new(),
+            position: 0,
+        }
+    }
+}
+
+fn read_frames<R: Read>(
+    mut stream: R,
+    config: &ReadAheadConfig,
+    stats: &Stats,
+    sender: &SyncSender<io::Result<Vec<u8>>>,
+) {
+    loop {
+        let frame = resync_to_start(
+            &mut stream,
+            config.start_seq,
+            &config.macs,
+            config.max_resync,
+        )
+        .and_then(|(frame, discarded)| {
+            let mut frame = frame.to_vec();
+            frame.extend(read_extra_payload(&mut stream, config.start_seq, &frame)?);
+            Ok((frame, discarded))
+        });
+        let handed_on = match frame {
+            Ok((frame, discarded)) => {
+                if discarded > 0 {
+                    warn!(
+                        "Discarded {} bytes looking for the start of a frame",
+                        discarded
+                    );
+                    stats.record_discarded(discarded);
+                }
+                match config.when_full {
+                    WhenFull::Block => sender.send(Ok(frame)).is_ok(),
+                    WhenFull::Drop => match sender.try_send(Ok(frame)) {
+                        Err(TrySendError::Full(_)) => {
+                            warn!("The read ahead is full, dropping a frame");
+                            stats.record_read_ahead_dropped();
+                            true
+                        }
+                        result => result.is_ok(),
+                    },
+                }
+            }
+            // an error always waits for room, the router needs to know about it
+            Err(e) => {
+                // the bytes skipped before giving up are gone as well
+                if e.kind() == ErrorKind::InvalidData {
+                    stats.record_discarded(config.max_resync);
+                }
+                let carry_on = matches!(
+                    e.kind(),
+                    ErrorKind::InvalidData | ErrorKind::WouldBlock | ErrorKind::TimedOut
+                );
+                sender.send(Err(e)).is_ok() && carry_on
+            }
+        };
+        // the router has moved on to another connection, or this one has ended
+        if !handed_on {
+            return;
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.pending.len() {
+            // the reader has stopped after handing on why
+            let frame = self.frames.recv().unwrap_or_else(|_| {
+                Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "The device stream has ended",
+                ))
+            });
+            self.pending = frame?;
+            self.position = 0;
+        }
+        let available = &self.pending[self.position..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{encode_frame, read_message, DeviceMessage, DEFAULT_MACS, START_SEQ};
+    use crate::testing::sample_message;
+    use std::io::Cursor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // Counts the bytes the reader has taken from the device
+    struct Counted {
+        inner: Cursor<Vec<u8>>,
+        read: Arc<AtomicUsize>,
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read.fetch_add(n, Ordering::SeqCst);
+            Ok(n)
+        }
+    }
+
+    fn frames(count: u16) -> (Counted, Arc<AtomicUsize>) {
+        let mut bytes = Vec::new();
+        for msg_num in 0..count {
+            let msg = DeviceMessage {
+                msg_num_value: msg_num,
+                ..sample_message()
+            };
+            bytes.extend_from_slice(&encode_frame(&msg));
+        }
+        let read = Arc::new(AtomicUsize::new(0));
+        let stream = Counted {
+            inner: Cursor::new(bytes),
+            read: read.clone(),
+        };
+        (stream, read)
+    }
+
+    fn config(when_full: WhenFull) -> ReadAheadConfig {
+        ReadAheadConfig {
+            frames: 2,
+            when_full,
+            start_seq: START_SEQ,
+            macs: DEFAULT_MACS.to_vec(),
+            max_resync: 216,
+        }
+    }
+
+    // Gives the reader thread the time to get as far as it can
+    fn settle(done: impl Fn() -> bool) {
+        let started = Instant::now();
+        while !done() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    #[test]
+    fn a_full_read_ahead_holds_the_device_up() {
+        let (stream, read) = frames(5);
+        let frame_len = encode_frame(&sample_message()).len();
+        let mut read_ahead = ReadAhead::start(stream, &config(WhenFull::Block), Stats::new());
+        settle(|| read.load(Ordering::SeqCst) >= 3 * frame_len);
+        // two frames waiting and the reader holding the third, the rest are still with the device
+        assert_eq!(read.load(Ordering::SeqCst), 3 * frame_len);
+
+        // every one of them is handed over in order
+        for msg_num in 0..5 {
+            let msg = read_message(&mut read_ahead, &DEFAULT_MACS).unwrap();
+            assert_eq!(msg.msg_num_value, msg_num);
+        }
+        let e = io::Error::from(read_message(&mut read_ahead, &DEFAULT_MACS).unwrap_err());
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn a_full_read_ahead_drops_the_frames_that_dont_fit() {
+        let (stream, read) = frames(5);
+        let stats = Stats::new();
+        let mut read_ahead = ReadAhead::start(stream, &config(WhenFull::Drop), stats.clone());
+        let frame_len = encode_frame(&sample_message()).len();
+        // the device isn't kept waiting
+        settle(|| read.load(Ordering::SeqCst) == 5 * frame_len);
+        assert_eq!(read.load(Ordering::SeqCst), 5 * frame_len);
+        assert!(stats
+            .metrics()
+            .contains("modbusrouter_read_ahead_dropped_total 3\n"));
+
+        // the oldest two made it
+        for msg_num in 0..2 {
+            let msg = read_message(&mut read_ahead, &DEFAULT_MACS).unwrap();
+            assert_eq!(msg.msg_num_value, msg_num);
+        }
+        // and the end of the stream still gets through
+        let e = io::Error::from(read_message(&mut read_ahead, &DEFAULT_MACS).unwrap_err());
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::modbus_client::{ModbusClient, RegisterKind};
+
+// The most registers one modbus read can return, longer ranges take several reads
+const MAX_READ: u16 = 125;
+
+// What `modbusrouter read-registers` has been asked to read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Request {
+    pub kind: RegisterKind,
+    pub start: u16,
+    pub count: u16,
+}
+
+impl Request {
+    // Takes --start, --count and --kind out of the arguments, what is left is for the config
+    pub fn take_from(args: &mut Vec<String>) -> Result<Request, String> {
+        let mut kind = RegisterKind::Holding;
+        let mut start = None;
+        let mut count = None;
+        let mut i = 0;
+        while i < args.len() {
+            let name = args[i].as_str();
+            if !["--start", "--count", "--kind"].contains(&name) {
+                i += 1;
+                continue;
+            }
+            let value = args
+                .get(i + 1)
+                .cloned()
+                .ok_or_else(|| format!("{} requires a value", name))?;
+            match name {
+                "--start" => {
+                    start = Some(
+                        value
+                            .parse::<u16>()
+                            .map_err(|_| format!("Invalid start register: {}", value))?,
+                    )
+                }
+                "--count" => {
+                    count = Some(
+                        value
+                            .parse::<u16>()
+                            .map_err(|_| format!("Invalid register count: {}", value))?,
+                    )
+                }
+                _ => {
+                    kind = RegisterKind::from_name(&value).ok_or_else(|| {
+                        format!("Expected holding or input registers but got: {}", value)
+                    })?
+                }
+            }
+            args.drain(i..i + 2);
+        }
+
+        let start = start.ok_or("read-registers needs --start")?;
+        let count = count.ok_or("read-registers needs --count")?;
+        if count == 0 {
+            return Err("The register count must be at least 1".to_string());
+        }
+        if start as u32 + count as u32 > 0x10000 {
+            return Err("The registers run past the last address (65535)".to_string());
+        }
+        Ok(Request { kind, start, count })
+    }
+}
+
+// Reads the whole range, returning each address along with its value
+pub fn read(
+    client: &mut dyn ModbusClient,
+    request: &Request,
+) -> Result<Vec<(u16, u16)>, modbus::Error> {
+    let mut registers = Vec::with_capacity(request.count as usize);
+    let end = request.start as u32 + request.count as u32;
+    let mut address = request.start as u32;
+    while address < end {
+        let count = (end - address).min(MAX_READ as u32) as u16;
+        let values = client.read_registers(request.kind, address as u16, count)?;
+        if values.len() != count as usize {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        registers.extend((address..end).map(|address| address as u16).zip(values));
+        address += count as u32;
+    }
+    Ok(registers)
+}
+
+// One line per register, e.g. "100: 84 (0x0054)"
+pub fn print(request: &Request, registers: &[(u16, u16)]) {
+    println!(
+        "{} {} registers from {}",
+        request.count,
+        request.kind.name(),
+        request.start
+    );
+    for (address, value) in registers {
+        println!("{}: {} (0x{:04X})", address, value, value);
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // Every register holds its own address, and each read is remembered
+    #[derive(Default)]
+    struct EchoClient {
+        reads: Vec<(RegisterKind, u16, u16)>,
+    }
+
+    impl ModbusClient for EchoClient {
+        fn write_single_register(&mut self, _: u16, _: u16) -> Result<(), modbus::Error> {
+            Ok(())
+        }
+
+        fn write_multiple_registers(&mut self, _: u16, _: &[u16]) -> Result<(), modbus::Error> {
+            Ok(())
+        }
+
+        fn read_registers(
+            &mut self,
+            kind: RegisterKind,
+            address: u16,
+            count: u16,
+        ) -> Result<Vec<u16>, modbus::Error> {
+            self.reads.push((kind, address, count));
+            Ok((address..address + count).collect())
+        }
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn options_are_taken_out_of_the_arguments() {
+        let mut remaining = args(&[
+            "--start",
+            "100",
+            "--modbus-host",
+            "10.0.0.2",
+            "--count",
+            "3",
+            "--kind",
+            "input",
+        ]);
+        let request = Request::take_from(&mut remaining).unwrap();
+        assert_eq!(
+            request,
+            Request {
+                kind: RegisterKind::Input,
+                start: 100,
+                count: 3
+            }
+        );
+        assert_eq!(remaining, args(&["--modbus-host", "10.0.0.2"]));
+
+        assert!(Request::take_from(&mut args(&["--start", "1"])).is_err());
+        assert!(Request::take_from(&mut args(&["--start", "1", "--count", "0"])).is_err());
+        assert!(Request::take_from(&mut args(&["--start", "65535", "--count", "2"])).is_err());
+        assert!(Request::take_from(&mut args(&[
+            "--start", "1", "--count", "1", "--kind", "coil"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn long_ranges_take_several_reads() {
+        let mut client = EchoClient::default();
+        let request = Request {
+            kind: RegisterKind::Holding,
+            start: 10,
+            count: 200,
+        };
+        let registers = read(&mut client, &request).unwrap();
+        assert_eq!(registers.len(), 200);
+        assert_eq!(registers[0], (10, 10));
+        assert_eq!(registers[199], (209, 209));
+        assert_eq!(
+            client.reads,
+            vec![
+                (RegisterKind::Holding, 10, 125),
+                (RegisterKind::Holding, 135, 75)
+            ]
+        );
+    }
+}
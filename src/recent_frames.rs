@@ -0,0 +1,134 @@
+use crate::frame::{format_hex, format_mac, DeviceMessage, Frame};
+use crate::units::{Reading, Units};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A flight recorder of the last few frames read from the device along with what we made of them,
+// so there is something to look at after a problem even if --very-verbose was off
+pub struct RecentFrames {
+    capacity: usize,
+    units: Units,
+    frames: VecDeque<FrameRecord>,
+}
+
+#[derive(Serialize)]
+pub struct FrameRecord {
+    // milliseconds since the unix epoch
+    pub received_at_ms: u64,
+    // the frame as hex, e.g. "19 00 D0 CF ..."
+    pub raw: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<DeviceMessage>,
+    // the same values with their units, for anyone that doesn't know the device
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<BTreeMap<&'static str, Reading>>,
+    // the MAC address of a heartbeat frame
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub heartbeat: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl RecentFrames {
+    pub fn new(capacity: usize, units: Units) -> RecentFrames {
+        RecentFrames {
+            capacity,
+            units,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    // Keeps the frame and its decode result, throwing away the oldest one if we are full
+    pub fn record(
+        &mut self,
+        raw: &[u8],
+        result: &Result<Frame, io::Error>,
+        received_at: SystemTime,
+    ) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+
+        let received_at_ms = received_at
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let (message, heartbeat, error) = match result {
+            Ok(Frame::Message(msg)) => (Some(msg.clone()), None, None),
+            Ok(Frame::Heartbeat { mac }) => (None, Some(format_mac(mac)), None),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        let fields = message.as_ref().map(|msg| self.units.readings(msg));
+        self.frames.push_back(FrameRecord {
+            received_at_ms,
+            raw: format_hex(raw),
+            message,
+            fields,
+            heartbeat,
+            error,
+        });
+    }
+
+    // Oldest first
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.frames).unwrap_or_else(|_| "[]".to_string())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::io::ErrorKind;
+
+    #[test]
+    fn keeps_the_most_recent_frames() {
+        let mut recent = RecentFrames::new(2, Units::default());
+        let bad = Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Unexpected MAC address",
+        ));
+        recent.record(&[0x01], &bad, UNIX_EPOCH);
+        let message = Ok(Frame::Message(crate::testing::sample_message()));
+        recent.record(&[0x02], &message, UNIX_EPOCH);
+        recent.record(&[0x03], &bad, UNIX_EPOCH);
+
+        assert_eq!(recent.frames.len(), 2);
+        assert_eq!(recent.frames[0].raw, "02");
+        assert!(recent.frames[0].message.is_some());
+        assert_eq!(
+            recent.frames[1].error,
+            Some("Unexpected MAC address".to_string())
+        );
+
+        let json = recent.to_json();
+        assert!(json.contains("\"raw\":\"03\""));
+        assert!(json.contains("\"error\":\"Unexpected MAC address\""));
+        assert!(json.contains("\"temperature\":{\"value\":84.0,\"unit\":\"raw\"}"));
+
+        let heartbeat = Ok(Frame::Heartbeat {
+            mac: [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B],
+        });
+        recent.record(&[0x04], &heartbeat, UNIX_EPOCH);
+        assert!(recent
+            .to_json()
+            .contains("\"heartbeat\":\"D0:CF:5E:82:93:7B\""));
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut recent = RecentFrames::new(0, Units::default());
+        let message = Ok(Frame::Message(crate::testing::sample_message()));
+        recent.record(&[0x01], &message, UNIX_EPOCH);
+        assert_eq!(recent.to_json(), "[]");
+    }
+}
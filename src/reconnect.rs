@@ -0,0 +1,255 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How long to wait before trying to connect to the device again
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// The first wait before reconnecting to the modbus after a write error, it doubles with every failure
+pub const MODBUS_BACKOFF_START: Duration = Duration::from_secs(1);
+
+// The longest wait between attempts to reconnect to the modbus
+pub const MODBUS_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+// How long to wait between attempts once we have parked
+pub const PARKED_DELAY: Duration = Duration::from_secs(60);
+
+// What to do once the maximum number of consecutive reconnect attempts has been used up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escalation {
+    // print the summary and exit with EXIT_RECONNECTS_EXHAUSTED
+    Exit,
+    // keep trying but only once every PARKED_DELAY so the logs don't fill up
+    Park,
+}
+
+impl Escalation {
+    pub fn from_name(name: &str) -> Option<Escalation> {
+        match name {
+            "exit" => Some(Escalation::Exit),
+            "park" => Some(Escalation::Park),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    // None means keep trying forever
+    pub max_attempts: Option<u32>,
+    pub escalation: Escalation,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> ReconnectConfig {
+        ReconnectConfig {
+            max_attempts: None,
+            escalation: Escalation::Exit,
+        }
+    }
+}
+
+// What the main loop should do after a failed connection attempt
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    // wait this long and try again
+    Retry(Duration),
+    // we have just run out of attempts and are switching to the slow retry rate
+    Park(Duration),
+    // give up
+    Exit,
+}
+
+// Counts consecutive failed attempts to connect to the device.
+// A wrong host name fails every time whereas a flapping device eventually connects again,
+// escalating lets whatever is watching the router tell the two apart
+pub struct ReconnectTracker {
+    config: ReconnectConfig,
+    failures: u32,
+}
+
+impl ReconnectTracker {
+    pub fn new(config: ReconnectConfig) -> ReconnectTracker {
+        ReconnectTracker {
+            config,
+            failures: 0,
+        }
+    }
+
+    // Call this when a connection attempt fails
+    pub fn record_failure(&mut self) -> Decision {
+        self.failures = self.failures.saturating_add(1);
+        match self.config.max_attempts {
+            Some(max) if self.failures > max => match self.config.escalation {
+                Escalation::Exit => Decision::Exit,
+                Escalation::Park if self.failures == max + 1 => Decision::Park(PARKED_DELAY),
+                Escalation::Park => Decision::Retry(PARKED_DELAY),
+            },
+            _ => Decision::Retry(RECONNECT_DELAY),
+        }
+    }
+
+    // Call this when we manage to connect, the count starts again from zero
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+// Exponential backoff with jitter: 1s, 2s, 4s ... up to the maximum, each one made up to half shorter at random
+// so that several routers restarting a shared modbus server don't all come back at the same moment
+pub struct Backoff {
+    next: Duration,
+    start: Duration,
+    max: Duration,
+    // a number from 0 up to 1
+    jitter: fn() -> f64,
+}
+
+impl Backoff {
+    pub fn new(start: Duration, max: Duration) -> Backoff {
+        Backoff::with_jitter(start, max, clock_jitter)
+    }
+
+    pub fn with_jitter(start: Duration, max: Duration, jitter: fn() -> f64) -> Backoff {
+        Backoff {
+            next: start,
+            start,
+            max,
+            jitter,
+        }
+    }
+
+    // How long to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let base = self.next;
+        self.next = (self.next * 2).min(self.max);
+        base / 2 + base.mul_f64((self.jitter)().clamp(0.0, 1.0) / 2.0)
+    }
+
+    // Call this once we have connected, the next failure waits the shortest time again
+    pub fn reset(&mut self) {
+        self.next = self.start;
+    }
+}
+
+// Random enough to spread out the reconnects without needing a random number generator
+fn clock_jitter() -> f64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.subsec_nanos())
+        .unwrap_or(0);
+    f64::from(nanos % 1000) / 1000.0
+}
+
+// Keeps trying to connect until it works, waiting for the backoff between attempts.
+// wait is given each error and how long to wait after it, so the router can log it and sleep
+pub fn retry_with_backoff<T, E, C, W>(backoff: &mut Backoff, mut connect: C, mut wait: W) -> T
+where
+    C: FnMut() -> Result<T, E>,
+    W: FnMut(&E, Duration),
+{
+    loop {
+        match connect() {
+            Ok(connected) => {
+                backoff.reset();
+                return connected;
+            }
+            Err(e) => wait(&e, backoff.next_delay()),
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn tracker(max_attempts: Option<u32>, escalation: Escalation) -> ReconnectTracker {
+        ReconnectTracker::new(ReconnectConfig {
+            max_attempts,
+            escalation,
+        })
+    }
+
+    #[test]
+    fn unlimited_by_default() {
+        let mut tracker = ReconnectTracker::new(ReconnectConfig::default());
+        for _ in 0..1000 {
+            assert_eq!(tracker.record_failure(), Decision::Retry(RECONNECT_DELAY));
+        }
+    }
+
+    #[test]
+    fn exit_after_max_attempts() {
+        let mut tracker = tracker(Some(2), Escalation::Exit);
+        assert_eq!(tracker.record_failure(), Decision::Retry(RECONNECT_DELAY));
+        assert_eq!(tracker.record_failure(), Decision::Retry(RECONNECT_DELAY));
+        assert_eq!(tracker.record_failure(), Decision::Exit);
+    }
+
+    #[test]
+    fn park_after_max_attempts() {
+        let mut tracker = tracker(Some(1), Escalation::Park);
+        assert_eq!(tracker.record_failure(), Decision::Retry(RECONNECT_DELAY));
+        assert_eq!(tracker.record_failure(), Decision::Park(PARKED_DELAY));
+        assert_eq!(tracker.record_failure(), Decision::Retry(PARKED_DELAY));
+    }
+
+    #[test]
+    fn success_resets_the_count() {
+        let mut tracker = tracker(Some(1), Escalation::Exit);
+        tracker.record_failure();
+        tracker.record_success();
+        assert_eq!(tracker.failures(), 0);
+        assert_eq!(tracker.record_failure(), Decision::Retry(RECONNECT_DELAY));
+    }
+
+    // A modbus server that refuses the first few connections
+    fn failing_connect(failures: u32) -> impl FnMut() -> Result<&'static str, String> {
+        let mut attempts = 0;
+        move || {
+            attempts += 1;
+            if attempts <= failures {
+                Err("Connection refused".to_string())
+            } else {
+                Ok("connected")
+            }
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum() {
+        let mut backoff = Backoff::with_jitter(MODBUS_BACKOFF_START, MODBUS_BACKOFF_MAX, || 1.0);
+        let mut delays = vec![];
+        let connected = retry_with_backoff(&mut backoff, failing_connect(7), |_, delay| {
+            delays.push(delay.as_secs())
+        });
+        assert_eq!(connected, "connected");
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+
+        // connecting starts it again from the beginning
+        assert_eq!(backoff.next_delay(), MODBUS_BACKOFF_START);
+    }
+
+    #[test]
+    fn jitter_takes_off_up_to_half() {
+        let mut backoff = Backoff::with_jitter(MODBUS_BACKOFF_START, MODBUS_BACKOFF_MAX, || 0.0);
+        assert_eq!(backoff.next_delay(), Duration::from_millis(500));
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+
+        // each wait is longer than the one before whatever the jitter
+        let mut backoff = Backoff::new(MODBUS_BACKOFF_START, MODBUS_BACKOFF_MAX);
+        let mut last = Duration::from_secs(0);
+        for _ in 0..5 {
+            let delay = backoff.next_delay();
+            assert!(delay >= last);
+            last = delay;
+        }
+    }
+}
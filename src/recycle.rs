@@ -0,0 +1,71 @@
+// Closes the device connection once it has carried enough frames and connects again (see
+// --max-frames-per-connection), for gateways whose firmware gets into a muddle on a connection that has been up
+// too long. The frames are counted once they have been dealt with, so the last one is written in full before
+// the connection goes
+pub struct Recycler {
+    max_frames: u64,
+    frames: u64,
+}
+
+impl Recycler {
+    pub fn new(max_frames: u64) -> Recycler {
+        Recycler {
+            max_frames,
+            frames: 0,
+        }
+    }
+
+    // Call on every new connection to start counting again
+    pub fn connected(&mut self) {
+        self.frames = 0;
+    }
+
+    // Call with every frame read from the device, messages and heartbeats alike
+    pub fn frame(&mut self) {
+        self.frames += 1;
+    }
+
+    // Whether it is time to connect again, checked before reading the next frame
+    pub fn due(&self) -> bool {
+        self.frames >= self.max_frames
+    }
+
+    pub fn max_frames(&self) -> u64 {
+        self.max_frames
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn the_connection_is_closed_after_exactly_n_frames() {
+        let mut recycler = Recycler::new(3);
+        for connection in 0..2 {
+            recycler.connected();
+            let mut read = 0;
+            while !recycler.due() {
+                recycler.frame();
+                read += 1;
+                assert!(read <= 3, "still connected after {} frames", read);
+            }
+            assert_eq!(read, 3, "connection {}", connection);
+        }
+
+        // a connection that drops on its own counts from zero on the next one too
+        recycler.connected();
+        recycler.frame();
+        recycler.connected();
+        recycler.frame();
+        recycler.frame();
+        assert!(!recycler.due());
+        recycler.frame();
+        assert!(recycler.due());
+    }
+}
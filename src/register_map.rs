@@ -0,0 +1,1761 @@
+use crate::fields::{Field, FieldSet};
+use crate::frame::{format_mac, parse_mac, DeviceMessage};
+use crate::modbus_client::{Batch, ModbusClient, Paced};
+use crate::word_order::WordOrder;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::time::Duration;
+
+// The modbus function used to write a field's registers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteFunction {
+    // 0x06, one request per register
+    Single,
+    // 0x10, one request for all the registers of the field (even if there is only one)
+    Multiple,
+    // 0x05, one request per value to coils rather than registers. A value that isn't 0 turns the coil on
+    Coil,
+}
+
+impl WriteFunction {
+    pub fn code(self) -> u8 {
+        match self {
+            WriteFunction::Single => 0x06,
+            WriteFunction::Multiple => 0x10,
+            WriteFunction::Coil => 0x05,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WriteFunction> {
+        match name {
+            "single" | "0x06" => Some(WriteFunction::Single),
+            "multiple" | "0x10" => Some(WriteFunction::Multiple),
+            "coil" | "0x05" => Some(WriteFunction::Coil),
+            _ => None,
+        }
+    }
+}
+
+// What to do with a scaled value that doesn't fit in a register (0 to 65535)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    // 65535 when it is too big and 0 when it is below 0
+    Saturate,
+    // keep the low 16 bits, so 65536 becomes 0 and -1 becomes 65535
+    Wrap,
+    // leave the registers of the field as they are and log it
+    Skip,
+}
+
+impl Overflow {
+    pub fn from_name(name: &str) -> Option<Overflow> {
+        match name {
+            "saturate" => Some(Overflow::Saturate),
+            "wrap" => Some(Overflow::Wrap),
+            "skip" => Some(Overflow::Skip),
+            _ => None,
+        }
+    }
+
+    fn to_register(self, scaled: f64) -> u16 {
+        match self {
+            Overflow::Wrap => scaled as i64 as u16,
+            // skipped values never get this far
+            Overflow::Saturate | Overflow::Skip => scaled.clamp(0.0, 65535.0) as u16,
+        }
+    }
+}
+
+// How the 16-bit values of a field are read before they are encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signedness {
+    // 0 to 65535, the way the older firmware sends them
+    Unsigned,
+    // two's complement, -32768 to 32767. An integer register gets the same 16 bits the device sent
+    Signed,
+    // two's complement moved up by 32768, so -32768 is 0, 0 is 32768 and 32767 is 65535.
+    // For PLCs that can't read a negative number out of a register
+    Offset,
+}
+
+impl Signedness {
+    pub fn from_name(name: &str) -> Option<Signedness> {
+        match name {
+            "unsigned" => Some(Signedness::Unsigned),
+            "signed" => Some(Signedness::Signed),
+            "offset" => Some(Signedness::Offset),
+            _ => None,
+        }
+    }
+
+    // The value the device meant by the raw 16 bits
+    pub fn reading(self, raw: u16) -> f64 {
+        match self {
+            Signedness::Unsigned => raw as f64,
+            Signedness::Signed => raw as i16 as f64,
+            Signedness::Offset => raw as i16 as f64 + 32768.0,
+        }
+    }
+}
+
+// What goes into the registers of a field
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    // each value as it is in the frame, one register per value
+    Integer,
+    // each value multiplied by the scale, plus the offset, and rounded, one register per value. This is the
+    // linear scaling of --scale and --offset: a field without either is Integer, the same as a scale of 1 and an
+    // offset of 0. --scale saturates at 0 and 65535 unless it is told to wrap or skip
+    Scaled {
+        scale: f64,
+        offset: f64,
+        overflow: Overflow,
+    },
+    // each value multiplied by the scale, plus the offset, and written as an IEEE-754 float across two registers
+    Float {
+        scale: f64,
+        offset: f64,
+        word_order: WordOrder,
+    },
+}
+
+impl Encoding {
+    // Turns the values of a field into what gets written to its registers.
+    // Negative values only come from signed fields, as integers they are written as two's complement
+    pub fn encode<V: Into<f64> + Copy>(self, values: &[V]) -> Vec<u16> {
+        match self {
+            Encoding::Integer => values
+                .iter()
+                .map(|value| (*value).into() as i64 as u16)
+                .collect(),
+            Encoding::Scaled {
+                scale,
+                offset,
+                overflow,
+            } => values
+                .iter()
+                .map(|value| overflow.to_register(scaled((*value).into(), scale, offset)))
+                .collect(),
+            Encoding::Float {
+                scale,
+                offset,
+                word_order,
+            } => values
+                .iter()
+                .flat_map(|value| {
+                    let float = ((*value).into() * scale + offset) as f32;
+                    word_order.to_registers(float.to_bits()).to_vec()
+                })
+                .collect(),
+        }
+    }
+
+    // Whether the field mustn't be written because a value doesn't fit and it is set to skip
+    pub fn skips<V: Into<f64> + Copy>(self, values: &[V]) -> bool {
+        match self {
+            Encoding::Scaled {
+                scale,
+                offset,
+                overflow: Overflow::Skip,
+            } => values.iter().any(|value| {
+                let scaled = scaled((*value).into(), scale, offset);
+                !(0.0..=65535.0).contains(&scaled)
+            }),
+            _ => false,
+        }
+    }
+
+    // What is added after scaling, none for plain integers
+    pub fn offset(self) -> f64 {
+        match self {
+            Encoding::Integer => 0.0,
+            Encoding::Scaled { offset, .. } | Encoding::Float { offset, .. } => offset,
+        }
+    }
+}
+
+fn scaled(value: f64, scale: f64, offset: f64) -> f64 {
+    (value * scale + offset).round()
+}
+
+// A vendor table from the raw value of a field to what it stands for, e.g. the rssi byte to dBm when the two
+// don't have a straight line between them (see --lookup). The value from the table is what gets scaled and
+// encoded in place of the raw value. A raw value that isn't in the table gets the entry of the nearest raw
+// value below it, or the first entry when it is below all of them, so a table only needs the raw values where
+// the reading changes
+#[derive(Debug, Clone, PartialEq)]
+pub struct LookupTransform {
+    table: BTreeMap<u16, f64>,
+}
+
+impl LookupTransform {
+    pub fn new(table: BTreeMap<u16, f64>) -> Result<LookupTransform, String> {
+        if table.is_empty() {
+            return Err("A lookup table needs at least one entry".to_string());
+        }
+        Ok(LookupTransform { table })
+    }
+
+    // One raw = value a line, e.g. 189 = -60. Blank lines and lines starting with # are skipped
+    pub fn parse(text: &str) -> Result<LookupTransform, String> {
+        let mut table = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || {
+                format!(
+                    "Expected raw = value on line {} but got: {}",
+                    number + 1,
+                    line
+                )
+            };
+            let mut parts = line.splitn(2, '=');
+            let raw = parts.next().unwrap_or("").trim();
+            let value = parts.next().ok_or_else(invalid)?.trim();
+            let raw: u16 = raw.parse().map_err(|_| invalid())?;
+            let value: f64 = value.parse().map_err(|_| invalid())?;
+            if table.insert(raw, value).is_some() {
+                return Err(format!("Raw value {} is in the lookup table twice", raw));
+            }
+        }
+        LookupTransform::new(table)
+    }
+
+    pub fn map(&self, raw: u16) -> f64 {
+        self.table
+            .range(..=raw)
+            .next_back()
+            .or_else(|| self.table.iter().next())
+            .map(|(_, value)| *value)
+            .unwrap_or(raw as f64)
+    }
+}
+
+// How a single field is written to the modbus
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegisterEntry {
+    pub function: WriteFunction,
+    // the first register of the field, None means use the PID byte from the frame
+    pub address: Option<u16>,
+    pub encoding: Encoding,
+    pub signedness: Signedness,
+}
+
+impl RegisterEntry {
+    // How many consecutive registers the field takes up once encoded
+    pub fn register_count(&self, field: Field) -> u16 {
+        match self.encoding {
+            Encoding::Integer | Encoding::Scaled { .. } => field.register_count(),
+            Encoding::Float { .. } => field.register_count() * 2,
+        }
+    }
+}
+
+// How each field of a DeviceMessage is written to the modbus
+#[derive(Debug, Clone)]
+pub struct RegisterMap {
+    entries: BTreeMap<Field, RegisterEntry>,
+    // the fields whose raw values go through a table first
+    lookups: BTreeMap<Field, LookupTransform>,
+    // the fields that are written at all, the others are never sent whatever the caller asks for
+    enabled: FieldSet,
+    // added to the address of every field, so that each device can have a block of registers of its own
+    base: u16,
+}
+
+impl Default for RegisterMap {
+    fn default() -> RegisterMap {
+        // the three vibration axes have always been written together, everything else one register at a time
+        let entries = Field::ALL
+            .iter()
+            .map(|field| {
+                let function = match field {
+                    Field::Vibration => WriteFunction::Multiple,
+                    _ => WriteFunction::Single,
+                };
+                let entry = RegisterEntry {
+                    function,
+                    address: None,
+                    encoding: Encoding::Integer,
+                    signedness: Signedness::Unsigned,
+                };
+                (*field, entry)
+            })
+            .collect();
+        RegisterMap {
+            entries,
+            lookups: BTreeMap::new(),
+            enabled: FieldSet::all(),
+            base: 0,
+        }
+    }
+}
+
+impl RegisterMap {
+    pub fn entry(&self, field: Field) -> RegisterEntry {
+        self.entries[&field]
+    }
+
+    pub fn enabled(&self) -> FieldSet {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: FieldSet) {
+        self.enabled = enabled;
+    }
+
+    pub fn set_function(&mut self, field: Field, function: WriteFunction) {
+        if let Some(entry) = self.entries.get_mut(&field) {
+            entry.function = function;
+        }
+    }
+
+    pub fn set_address(&mut self, field: Field, address: u16) {
+        if let Some(entry) = self.entries.get_mut(&field) {
+            entry.address = Some(address);
+        }
+    }
+
+    pub fn set_encoding(&mut self, field: Field, encoding: Encoding) {
+        if let Some(entry) = self.entries.get_mut(&field) {
+            entry.encoding = encoding;
+        }
+    }
+
+    pub fn set_signedness(&mut self, field: Field, signedness: Signedness) {
+        if let Some(entry) = self.entries.get_mut(&field) {
+            entry.signedness = signedness;
+        }
+    }
+
+    pub fn set_lookup(&mut self, field: Field, lookup: LookupTransform) {
+        self.lookups.insert(field, lookup);
+    }
+
+    pub fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub fn set_base(&mut self, base: u16) {
+        self.base = base;
+    }
+
+    // The register the field of this message starts at, the base plus the configured address or the PID
+    pub fn address(&self, field: Field, msg: &DeviceMessage) -> u16 {
+        let address = self
+            .entry(field)
+            .address
+            .unwrap_or_else(|| msg.field_address(field));
+        self.base.saturating_add(address)
+    }
+
+    // Parses a comma separated list of the fields to write, e.g. temperature,battery,rssi
+    pub fn parse_enabled(&mut self, list: &str) -> Result<(), String> {
+        let mut enabled = FieldSet::empty();
+        for name in list.split(',') {
+            let name = name.trim();
+            let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+            enabled.insert(field);
+        }
+        self.set_enabled(enabled);
+        Ok(())
+    }
+
+    // Parses a rule in the form field=function, e.g. battery=multiple
+    pub fn parse_write_function(&mut self, rule: &str) -> Result<(), String> {
+        let (field, function) = split_rule(rule, "function")?;
+        let function = WriteFunction::from_name(function)
+            .ok_or_else(|| format!("Unknown write function: {}", function))?;
+        self.set_function(field, function);
+        Ok(())
+    }
+
+    // Parses a rule in the form field=address, e.g. battery=100
+    pub fn parse_address(&mut self, rule: &str) -> Result<(), String> {
+        let (field, address) = split_rule(rule, "address")?;
+        let address = address
+            .parse()
+            .map_err(|_| format!("Invalid register address: {}", address))?;
+        self.set_address(field, address);
+        Ok(())
+    }
+
+    // Parses the register the addresses start from, e.g. 100
+    pub fn parse_base(&mut self, value: &str) -> Result<(), String> {
+        let base = value
+            .parse()
+            .map_err(|_| format!("Invalid register base: {}", value))?;
+        self.set_base(base);
+        Ok(())
+    }
+
+    // Parses a rule in the form field=signedness, e.g. vibration=signed.
+    // Only the fields that come as 16 bits can be signed, the others are a single byte from 0 to 255
+    pub fn parse_signedness(&mut self, rule: &str) -> Result<(), String> {
+        let (field, name) = split_rule(rule, "signedness")?;
+        let signedness = Signedness::from_name(name)
+            .ok_or_else(|| format!("Expected unsigned, signed or offset but got: {}", name))?;
+        if !matches!(field, Field::Vibration | Field::MsgNum) {
+            return Err(format!(
+                "Only vibration and msg-num can be signed, not {}",
+                field.name()
+            ));
+        }
+        self.set_signedness(field, signedness);
+        Ok(())
+    }
+
+    // Parses a rule in the form field=path, e.g. rssi=rssi-dbm.txt, and reads the table from the file
+    pub fn parse_lookup(&mut self, rule: &str) -> Result<(), String> {
+        let (field, path) = split_rule(rule, "path")?;
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("Unable to read lookup table {}: {}", path, e))?;
+        let lookup = LookupTransform::parse(&text).map_err(|e| format!("{} in {}", e, path))?;
+        self.set_lookup(field, lookup);
+        Ok(())
+    }
+
+    // Parses a rule in the form field=scale[:word-order], e.g. temperature=0.1 or temperature=0.1:cdab
+    pub fn parse_float(&mut self, rule: &str) -> Result<(), String> {
+        let (field, value) = split_rule(rule, "scale")?;
+        let mut parts = value.splitn(2, ':');
+        let scale = parts.next().unwrap_or("");
+        let scale = scale
+            .parse()
+            .map_err(|_| format!("Invalid float scale: {}", scale))?;
+        let word_order = match parts.next() {
+            Some(name) => {
+                WordOrder::from_name(name).ok_or_else(|| format!("Unknown word order: {}", name))?
+            }
+            None => WordOrder::default(),
+        };
+        // an --offset given before this still applies
+        let offset = self.entry(field).encoding.offset();
+        self.set_encoding(
+            field,
+            Encoding::Float {
+                scale,
+                offset,
+                word_order,
+            },
+        );
+        Ok(())
+    }
+
+    // Parses a rule in the form field=scale[:overflow], e.g. temperature=10 or temperature=10:skip
+    pub fn parse_scale(&mut self, rule: &str) -> Result<(), String> {
+        let (field, value) = split_rule(rule, "scale")?;
+        let mut parts = value.splitn(2, ':');
+        let scale = parts.next().unwrap_or("");
+        let scale = scale
+            .parse()
+            .map_err(|_| format!("Invalid scale: {}", scale))?;
+        let overflow = match parts.next() {
+            Some(name) => Overflow::from_name(name)
+                .ok_or_else(|| format!("Expected saturate, wrap or skip but got: {}", name))?,
+            None => Overflow::Saturate,
+        };
+        let offset = self.entry(field).encoding.offset();
+        self.set_encoding(
+            field,
+            Encoding::Scaled {
+                scale,
+                offset,
+                overflow,
+            },
+        );
+        Ok(())
+    }
+
+    // Parses a rule in the form field=offset, e.g. temperature=-400. The offset is added after the scale,
+    // a field that isn't scaled is scaled by 1
+    pub fn parse_offset(&mut self, rule: &str) -> Result<(), String> {
+        let (field, value) = split_rule(rule, "offset")?;
+        let offset: f64 = value
+            .parse()
+            .map_err(|_| format!("Invalid offset: {}", value))?;
+        let encoding = match self.entry(field).encoding {
+            Encoding::Integer => Encoding::Scaled {
+                scale: 1.0,
+                offset,
+                overflow: Overflow::Saturate,
+            },
+            Encoding::Scaled {
+                scale, overflow, ..
+            } => Encoding::Scaled {
+                scale,
+                offset,
+                overflow,
+            },
+            Encoding::Float {
+                scale, word_order, ..
+            } => Encoding::Float {
+                scale,
+                offset,
+                word_order,
+            },
+        };
+        self.set_encoding(field, encoding);
+        Ok(())
+    }
+
+    // Makes sure no two fields write to the same register, or the same coil.
+    // Only fields with a configured address can be checked, the others depend on the frame
+    pub fn validate(&self) -> Result<(), String> {
+        let ranges: Vec<(Field, u32, u32, bool)> = self
+            .entries
+            .iter()
+            // a field that isn't written can share its registers with anything
+            .filter(|(field, _)| self.enabled.contains(**field))
+            .filter_map(|(field, entry)| {
+                let start = self.base as u32 + entry.address? as u32;
+                let end = start + entry.register_count(*field) as u32;
+                Some((*field, start, end, entry.function == WriteFunction::Coil))
+            })
+            .collect();
+        for (i, (field, start, end, coil)) in ranges.iter().enumerate() {
+            if *end > 0x1_0000 {
+                return Err(format!(
+                    "The {} registers run past the last register",
+                    field.name()
+                ));
+            }
+            for (other, other_start, other_end, other_coil) in &ranges[i + 1..] {
+                if coil == other_coil && start < other_end && other_start < end {
+                    return Err(format!(
+                        "The {} and {} registers overlap",
+                        field.name(),
+                        other.name()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // What writing the values of a field to consecutive registers starting at the address comes to,
+    // None when the values are skipped
+    pub fn encode(&self, field: Field, address: u16, values: &[u16]) -> Option<RegisterWrite> {
+        let entry = self.entry(field);
+        let lookup = self.lookups.get(&field);
+        let readings: Vec<f64> = values
+            .iter()
+            .map(|value| match lookup {
+                Some(lookup) => lookup.map(*value),
+                None => entry.signedness.reading(*value),
+            })
+            .collect();
+        if entry.encoding.skips(&readings) {
+            warn!(
+                "Not writing the {} {:?}, scaled they don't fit in a register",
+                field.name(),
+                values
+            );
+            return None;
+        }
+        // the two halves of a float must arrive together so they always go in one request,
+        // unless they are going to coils which can only be written one at a time
+        let function = match entry.encoding {
+            Encoding::Float { .. } if entry.function != WriteFunction::Coil => {
+                WriteFunction::Multiple
+            }
+            _ => entry.function,
+        };
+        Some(RegisterWrite {
+            field,
+            address,
+            values: entry.encoding.encode(&readings),
+            function,
+        })
+    }
+
+    // Every write send_message_to_modbus makes for these fields of the message, in the order it makes them.
+    // Fields that aren't enabled are left out
+    pub fn registers(&self, msg: &DeviceMessage, fields: FieldSet) -> Vec<RegisterWrite> {
+        Field::ALL
+            .iter()
+            .filter(|field| fields.contains(**field) && self.enabled.contains(**field))
+            .filter_map(|field| {
+                self.encode(*field, self.address(*field, msg), &msg.field_values(*field))
+            })
+            .collect()
+    }
+
+    // Writes the values of a field to consecutive registers starting at the address
+    pub fn write(
+        &self,
+        modbus_client: &mut dyn ModbusClient,
+        field: Field,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        match self.encode(field, address, values) {
+            Some(write) => write.send(modbus_client),
+            None => Ok(()),
+        }
+    }
+}
+
+// Splits field=value
+fn split_rule<'a>(rule: &'a str, what: &str) -> Result<(Field, &'a str), String> {
+    let mut parts = rule.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("Expected field={} but got: {}", what, rule))?;
+    let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+    Ok((field, value))
+}
+
+// A change to the register map of a single device
+#[derive(Debug, Clone)]
+enum Override {
+    Function(String),
+    Address(String),
+    Float(String),
+    Scale(String),
+    Offset(String),
+    Signedness(String),
+    Lookup(String),
+    Base(String),
+}
+
+// A device, or one of the sensors behind it when the gateway sends a sensor id
+type DeviceKey = ([u8; 6], Option<u8>);
+
+// The default register map and the maps of devices that need something different.
+// Overrides only list what is different, they are merged with the default by merge() once all the
+// settings have been read
+#[derive(Debug, Clone, Default)]
+pub struct RegisterMaps {
+    pub default: RegisterMap,
+    overrides: BTreeMap<DeviceKey, Vec<Override>>,
+    merged: BTreeMap<DeviceKey, RegisterMap>,
+}
+
+impl RegisterMaps {
+    // field,..., e.g. temperature,battery. Every device writes the same fields
+    pub fn parse_enabled(&mut self, list: &str) -> Result<(), String> {
+        self.default.parse_enabled(list)
+    }
+
+    // [mac[#sensor]/]field=function, e.g. battery=multiple or D0:CF:5E:82:93:7B/battery=multiple
+    pub fn parse_write_function(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Function(rule.to_string())),
+            (None, rule) => self.default.parse_write_function(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=address, e.g. battery=100 or D0:CF:5E:82:93:7B#2/battery=100
+    pub fn parse_address(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Address(rule.to_string())),
+            (None, rule) => self.default.parse_address(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=scale[:word-order], e.g. temperature=0.1 or D0:CF:5E:82:93:7B/temperature=0.1:cdab
+    pub fn parse_float(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Float(rule.to_string())),
+            (None, rule) => self.default.parse_float(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=scale[:overflow], e.g. temperature=10 or D0:CF:5E:82:93:7B/temperature=10:wrap
+    pub fn parse_scale(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Scale(rule.to_string())),
+            (None, rule) => self.default.parse_scale(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=signedness, e.g. vibration=signed or D0:CF:5E:82:93:7B/vibration=offset
+    pub fn parse_signedness(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => {
+                self.add_override(device, Override::Signedness(rule.to_string()))
+            }
+            (None, rule) => self.default.parse_signedness(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=path, e.g. rssi=rssi-dbm.txt or D0:CF:5E:82:93:7B/rssi=rssi-dbm.txt
+    pub fn parse_lookup(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Lookup(rule.to_string())),
+            (None, rule) => self.default.parse_lookup(rule),
+        }
+    }
+
+    // [mac[#sensor]/]field=offset, e.g. temperature=-400
+    pub fn parse_offset(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Offset(rule.to_string())),
+            (None, rule) => self.default.parse_offset(rule),
+        }
+    }
+
+    // [mac[#sensor]/]base, e.g. D0:CF:5E:82:93:7B/100 to move every register of that device up by 100
+    pub fn parse_base(&mut self, rule: &str) -> Result<(), String> {
+        match split_device(rule)? {
+            (Some(device), rule) => self.add_override(device, Override::Base(rule.to_string())),
+            (None, rule) => self.default.parse_base(rule),
+        }
+    }
+
+    fn add_override(&mut self, device: DeviceKey, rule: Override) -> Result<(), String> {
+        // check the rule now so that the error points at the right setting
+        let mut check = RegisterMap::default();
+        match &rule {
+            Override::Function(rule) => check.parse_write_function(rule)?,
+            Override::Address(rule) => check.parse_address(rule)?,
+            Override::Float(rule) => check.parse_float(rule)?,
+            Override::Scale(rule) => check.parse_scale(rule)?,
+            Override::Offset(rule) => check.parse_offset(rule)?,
+            Override::Signedness(rule) => check.parse_signedness(rule)?,
+            Override::Lookup(rule) => check.parse_lookup(rule)?,
+            Override::Base(rule) => check.parse_base(rule)?,
+        }
+        self.overrides.entry(device).or_default().push(rule);
+        Ok(())
+    }
+
+    // Builds the map of each device with an override and checks every map for overlapping registers.
+    // Two devices writing to the same register is allowed, one device writing to a register twice is not.
+    // A sensor gets the overrides of its device first and then its own
+    pub fn merge(&mut self) -> Result<(), String> {
+        self.default
+            .validate()
+            .map_err(|e| format!("{} in the default register map", e))?;
+        self.merged.clear();
+        for (mac, sensor_id) in self.overrides.keys() {
+            let mut map = self.default.clone();
+            let device_rules = self.overrides.get(&(*mac, None));
+            let sensor_rules = match sensor_id {
+                Some(_) => self.overrides.get(&(*mac, *sensor_id)),
+                None => None,
+            };
+            for rule in device_rules.into_iter().chain(sensor_rules).flatten() {
+                match rule {
+                    Override::Function(rule) => map.parse_write_function(rule)?,
+                    Override::Address(rule) => map.parse_address(rule)?,
+                    Override::Float(rule) => map.parse_float(rule)?,
+                    Override::Scale(rule) => map.parse_scale(rule)?,
+                    Override::Offset(rule) => map.parse_offset(rule)?,
+                    Override::Signedness(rule) => map.parse_signedness(rule)?,
+                    Override::Lookup(rule) => map.parse_lookup(rule)?,
+                    Override::Base(rule) => map.parse_base(rule)?,
+                }
+            }
+            map.validate().map_err(|e| {
+                format!(
+                    "{} in the register map of {}",
+                    e,
+                    format_device(mac, *sensor_id)
+                )
+            })?;
+            self.merged.insert((*mac, *sensor_id), map);
+        }
+        Ok(())
+    }
+
+    // The map of the sensor, or failing that of its device, or failing that the default
+    pub fn for_device(&self, mac: &[u8; 6], sensor_id: Option<u8>) -> &RegisterMap {
+        self.merged
+            .get(&(*mac, sensor_id))
+            .or_else(|| self.merged.get(&(*mac, None)))
+            .unwrap_or(&self.default)
+    }
+}
+
+// e.g. D0:CF:5E:82:93:7B or D0:CF:5E:82:93:7B#2 for a sensor behind it
+fn format_device(mac: &[u8; 6], sensor_id: Option<u8>) -> String {
+    match sensor_id {
+        Some(id) => format!("{}#{}", format_mac(mac), id),
+        None => format_mac(mac),
+    }
+}
+
+// Splits an optional mac[#sensor]/ off the front of a rule. Only a / before the = counts, the value can be a path
+fn split_device(rule: &str) -> Result<(Option<DeviceKey>, &str), String> {
+    let field_end = rule.find('=').unwrap_or(rule.len());
+    match rule[..field_end].find('/') {
+        Some(i) => {
+            let mut parts = rule[..i].splitn(2, '#');
+            let mac = parts.next().unwrap_or("");
+            let mac = parse_mac(mac).ok_or_else(|| format!("Invalid MAC address: {}", mac))?;
+            let sensor_id = match parts.next() {
+                Some(id) => Some(
+                    id.parse()
+                        .map_err(|_| format!("Invalid sensor id: {}", id))?,
+                ),
+                None => None,
+            };
+            Ok((Some((mac, sensor_id)), &rule[i + 1..]))
+        }
+        None => Ok((None, rule)),
+    }
+}
+
+// A message that only made it part of the way to the modbus. The fields that were written are in the PLC
+// already, so a retry only needs the rest of them
+#[derive(Debug)]
+pub struct SendError {
+    pub written: FieldSet,
+    // the field being written when it went wrong, None when it wasn't any one field
+    pub failed: Option<Field>,
+    // the first register of that field
+    pub address: Option<u16>,
+    pub error: modbus::Error,
+}
+
+impl SendError {
+    // The fields of the set that still have to be written
+    pub fn remaining(&self, fields: FieldSet) -> FieldSet {
+        let mut remaining = fields;
+        for field in Field::ALL.iter() {
+            if self.written.contains(*field) {
+                remaining.remove(*field);
+            }
+        }
+        remaining
+    }
+
+    // Whether the modbus server turned the write down because there is no such register or it won't take
+    // the value. That is the register map being wrong rather than the connection, there is no point
+    // connecting again or trying the write again
+    pub fn rejected(&self) -> bool {
+        matches!(
+            self.error,
+            modbus::Error::Exception(modbus::ExceptionCode::IllegalDataAddress)
+                | modbus::Error::Exception(modbus::ExceptionCode::IllegalDataValue)
+        )
+    }
+}
+
+// e.g. writing temperature to register 2: Exception(IllegalDataAddress)
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.failed, self.address) {
+            (Some(field), Some(address)) => write!(
+                f,
+                "writing {} to register {}: {:?}",
+                field.name(),
+                address,
+                self.error
+            ),
+            _ => write!(f, "writing to the modbus: {:?}", self.error),
+        }
+    }
+}
+
+// For callers that only care that it went wrong
+impl From<SendError> for modbus::Error {
+    fn from(e: SendError) -> modbus::Error {
+        e.error
+    }
+}
+
+// The registers of one field as they go to the modbus, see RegisterMap::registers
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterWrite {
+    pub field: Field,
+    pub address: u16,
+    pub values: Vec<u16>,
+    pub function: WriteFunction,
+}
+
+impl RegisterWrite {
+    pub fn send(&self, modbus_client: &mut dyn ModbusClient) -> Result<(), modbus::Error> {
+        match self.function {
+            WriteFunction::Single => {
+                for (offset, value) in self.values.iter().enumerate() {
+                    modbus_client.write_single_register(self.address + offset as u16, *value)?;
+                }
+            }
+            WriteFunction::Multiple => {
+                modbus_client.write_multiple_registers(self.address, &self.values)?
+            }
+            WriteFunction::Coil => {
+                for (offset, value) in self.values.iter().enumerate() {
+                    modbus_client.write_single_coil(self.address + offset as u16, *value != 0)?;
+                }
+            }
+        }
+        debug!(
+            "Wrote {} as {:?} to register {} using function 0x{:02X}",
+            self.field.name(),
+            self.values,
+            self.address,
+            self.function.code()
+        );
+        Ok(())
+    }
+}
+
+// Sends our extracted message to the modbus
+// Only the fields in the set are written, the rest are left as they are. The fields are written one after
+// the other so when one fails the error says which of them are already written
+pub fn send_message_to_modbus(
+    msg: &DeviceMessage,
+    fields: FieldSet,
+    register_map: &RegisterMap,
+    write_delay: Duration,
+    modbus_client: &mut dyn ModbusClient,
+) -> Result<(), SendError> {
+    // the delay is only between the writes of this message, the next message starts straight away
+    let mut paced = Paced::new(modbus_client, write_delay);
+    let mut written = FieldSet::empty();
+    for write in register_map.registers(msg, fields) {
+        write.send(&mut paced).map_err(|error| SendError {
+            written,
+            failed: Some(write.field),
+            address: Some(write.address),
+            error,
+        })?;
+        written.insert(write.field);
+    }
+    Ok(())
+}
+
+// send_message_to_modbus in as few requests as the register map allows, see Batch. The write functions of
+// the register map are not kept to, a run of registers always goes as one write multiple registers
+pub fn send_message_batched(
+    msg: &DeviceMessage,
+    fields: FieldSet,
+    register_map: &RegisterMap,
+    write_delay: Duration,
+    modbus_client: &mut dyn ModbusClient,
+) -> Result<(), SendError> {
+    let mut batch = Batch::new();
+    send_message_to_modbus(
+        msg,
+        fields,
+        register_map,
+        Duration::from_millis(0),
+        &mut batch,
+    )?;
+    // the runs of registers don't line up with the fields, so after a failure none of them count as written
+    batch
+        .send(&mut Paced::new(modbus_client, write_delay))
+        .map_err(|error| SendError {
+            written: FieldSet::empty(),
+            failed: None,
+            address: None,
+            error,
+        })
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{sample_message, FailingClient, RecordingClient, Write};
+
+    #[test]
+    fn default_functions() {
+        let map = RegisterMap::default();
+        assert_eq!(map.entry(Field::Battery).function, WriteFunction::Single);
+        assert_eq!(
+            map.entry(Field::Vibration).function,
+            WriteFunction::Multiple
+        );
+    }
+
+    #[test]
+    fn write_uses_the_configured_function() {
+        let mut map = RegisterMap::default();
+        map.parse_write_function("battery=multiple").unwrap();
+        map.parse_write_function("vibration=0x06").unwrap();
+
+        let mut client = RecordingClient::default();
+        map.write(&mut client, Field::Battery, 1, &[10]).unwrap();
+        map.write(&mut client, Field::Vibration, 3, &[7, 8, 9])
+            .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Multiple(1, vec![10]),
+                Write::Single(3, 7),
+                Write::Single(4, 8),
+                Write::Single(5, 9),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_write_function_invalid() {
+        let mut map = RegisterMap::default();
+        assert!(map.parse_write_function("battery").is_err());
+        assert!(map.parse_write_function("bogus=single").is_err());
+        // a read function
+        assert!(map.parse_write_function("battery=0x03").is_err());
+    }
+
+    #[test]
+    fn coil_fields_are_written_to_coils() {
+        let mut map = RegisterMap::default();
+        map.parse_write_function("battery=coil").unwrap();
+        map.parse_write_function("vibration=0x05").unwrap();
+        map.parse_enabled("battery,temperature,vibration").unwrap();
+        let msg = DeviceMessage {
+            vib_y: 0,
+            ..sample_message()
+        };
+        let mut client = RecordingClient::default();
+        send_message_to_modbus(
+            &msg,
+            FieldSet::all(),
+            &map,
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        // the battery of the sample message is 0, so its coil is off
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Coil(1, false),
+                Write::Single(2, 84),
+                Write::Coil(3, true),
+                Write::Coil(4, false),
+                Write::Coil(5, true),
+            ]
+        );
+
+        // the coils are somewhere else, so they don't overlap the registers
+        map.parse_address("battery=2").unwrap();
+        map.parse_address("vibration=1").unwrap();
+        assert!(map.validate().is_err());
+        map.parse_address("vibration=3").unwrap();
+        assert!(map.validate().is_ok());
+    }
+
+    const OTHER_MAC: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+    #[test]
+    fn configured_address_replaces_the_pid() {
+        let mut map = RegisterMap::default();
+        let msg = sample_message();
+        assert_eq!(map.address(Field::Temperature, &msg), 2);
+        map.parse_address("temperature=100").unwrap();
+        assert_eq!(map.address(Field::Temperature, &msg), 100);
+    }
+
+    #[test]
+    fn self_overlap_is_rejected() {
+        let mut map = RegisterMap::default();
+        map.parse_address("vibration=10").unwrap();
+        map.parse_address("battery=13").unwrap();
+        assert!(map.validate().is_ok());
+        map.parse_address("battery=12").unwrap();
+        assert!(map.validate().is_err());
+    }
+
+    #[test]
+    fn per_mac_overrides_are_merged_with_the_default() {
+        let mut maps = RegisterMaps::default();
+        maps.parse_address("battery=100").unwrap();
+        maps.parse_address("01:02:03:04:05:06/temperature=200")
+            .unwrap();
+        maps.parse_write_function("01:02:03:04:05:06/battery=multiple")
+            .unwrap();
+        maps.merge().unwrap();
+
+        let other = maps.for_device(&OTHER_MAC, None);
+        assert_eq!(other.entry(Field::Battery).address, Some(100));
+        assert_eq!(
+            other.entry(Field::Battery).function,
+            WriteFunction::Multiple
+        );
+        assert_eq!(other.entry(Field::Temperature).address, Some(200));
+
+        // a device without an override gets the default
+        let default = maps.for_device(&[0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B], None);
+        assert_eq!(
+            default.entry(Field::Battery).function,
+            WriteFunction::Single
+        );
+        assert_eq!(default.entry(Field::Temperature).address, None);
+    }
+
+    #[test]
+    fn two_macs_may_share_a_register_but_one_mac_may_not_overlap() {
+        let mut maps = RegisterMaps::default();
+        maps.parse_address("battery=100").unwrap();
+        maps.parse_address("01:02:03:04:05:06/battery=100").unwrap();
+        assert!(maps.merge().is_ok());
+
+        maps.parse_address("01:02:03:04:05:06/temperature=100")
+            .unwrap();
+        assert!(maps.merge().is_err());
+    }
+
+    #[test]
+    fn each_device_writes_to_its_own_block_of_registers() {
+        let mut maps = RegisterMaps::default();
+        maps.parse_base("D0:CF:5E:82:93:7B/100").unwrap();
+        maps.parse_base("01:02:03:04:05:06/200").unwrap();
+        maps.parse_address("01:02:03:04:05:06/temperature=50")
+            .unwrap();
+        maps.merge().unwrap();
+        assert!(maps.parse_base("01:02:03:04:05:06/-1").is_err());
+
+        let first = sample_message();
+        let second = DeviceMessage {
+            mac: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            ..sample_message()
+        };
+        let registers = |msg: &DeviceMessage| {
+            let mut client = RecordingClient::default();
+            let map = maps.for_device(&msg.mac, msg.sensor_id);
+            send_message_to_modbus(
+                msg,
+                FieldSet::all(),
+                map,
+                Duration::from_millis(0),
+                &mut client,
+            )
+            .unwrap();
+            client.registers()
+        };
+        let first = registers(&first);
+        let second = registers(&second);
+        // the PID of each field from the base, and a configured address from the base too
+        assert_eq!(first[&102], sample_message().temp_value as u16);
+        assert_eq!(second[&250], sample_message().temp_value as u16);
+        assert!(!second.contains_key(&202));
+        assert!(first.keys().all(|address| (100..200).contains(address)));
+        assert!(second.keys().all(|address| (200..300).contains(address)));
+        // and a device without a base is where it always was
+        assert_eq!(
+            maps.for_device(&[0x0A; 6], None)
+                .address(Field::Battery, &sample_message()),
+            1
+        );
+    }
+
+    #[test]
+    fn float_encoding() {
+        // 25.4 is 0x41CB3333 as an IEEE-754 float
+        let plain = Encoding::Float {
+            scale: 0.1,
+            offset: 0.0,
+            word_order: WordOrder::Abcd,
+        };
+        assert_eq!(plain.encode(&[254]), vec![0x41CB, 0x3333]);
+        let swapped = Encoding::Float {
+            scale: 0.1,
+            offset: 0.0,
+            word_order: WordOrder::Cdab,
+        };
+        assert_eq!(swapped.encode(&[254]), vec![0x3333, 0x41CB]);
+        assert_eq!(Encoding::Integer.encode(&[254]), vec![254]);
+    }
+
+    #[test]
+    fn float_fields_are_written_as_register_pairs() {
+        let mut map = RegisterMap::default();
+        map.parse_float("temperature=0.1").unwrap();
+        map.parse_float("vibration=1:dcba").unwrap();
+
+        let mut client = RecordingClient::default();
+        map.write(&mut client, Field::Temperature, 2, &[254])
+            .unwrap();
+        map.write(&mut client, Field::Vibration, 10, &[1, 2, 3])
+            .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Multiple(2, vec![0x41CB, 0x3333]),
+                // 1.0, 2.0 and 3.0 are 0x3F800000, 0x40000000 and 0x40400000
+                Write::Multiple(10, vec![0x0000, 0x803F, 0x0000, 0x0040, 0x0000, 0x4040]),
+            ]
+        );
+    }
+
+    #[test]
+    fn float_fields_take_twice_the_registers() {
+        let mut map = RegisterMap::default();
+        map.parse_address("temperature=10").unwrap();
+        map.parse_address("battery=11").unwrap();
+        assert!(map.validate().is_ok());
+        map.parse_float("temperature=1").unwrap();
+        assert!(map.validate().is_err());
+    }
+
+    #[test]
+    fn raw_values_are_looked_up_in_the_table() {
+        let lookup = LookupTransform::parse(
+            "
+            # rssi byte = dBm
+            180 = -70
+            189 = -60
+            200 = -45.5
+            ",
+        )
+        .unwrap();
+        assert_eq!(lookup.map(189), -60.0);
+        // between entries is the one below, and below the first is the first
+        assert_eq!(lookup.map(195), -60.0);
+        assert_eq!(lookup.map(255), -45.5);
+        assert_eq!(lookup.map(0), -70.0);
+
+        // negative dBm go in as two's complement, or through a scale like any other reading
+        let mut map = RegisterMap::default();
+        map.set_lookup(Field::Rssi, lookup);
+        map.parse_address("rssi=40").unwrap();
+        let msg = sample_message();
+        let only = |field| {
+            let mut fields = FieldSet::empty();
+            fields.insert(field);
+            fields
+        };
+        let rssi = |map: &RegisterMap| {
+            map.registers(&msg, only(Field::Rssi))
+                .into_iter()
+                .map(|write| write.values)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(msg.rssi_value, 189);
+        assert_eq!(rssi(&map), vec![vec![-60i16 as u16]]);
+        map.parse_offset("rssi=100").unwrap();
+        assert_eq!(rssi(&map), vec![vec![40]]);
+        // the other fields are written as they are
+        assert_eq!(
+            map.registers(&msg, only(Field::Battery))[0].values,
+            vec![msg.batt_value as u16]
+        );
+
+        assert!(LookupTransform::parse("").is_err());
+        assert!(LookupTransform::parse("189 = -60\n189 = -59").is_err());
+        assert!(LookupTransform::parse("189 -60").is_err());
+        assert!(LookupTransform::parse("low = -60").is_err());
+        assert!(map.parse_lookup("rssi=/not/a/table.txt").is_err());
+    }
+
+    fn scaled_by_ten(overflow: Overflow) -> Encoding {
+        Encoding::Scaled {
+            scale: 10.0,
+            offset: 0.0,
+            overflow,
+        }
+    }
+
+    #[test]
+    fn scaled_values_at_the_top_of_the_range() {
+        // 6553.5 is the biggest value that still fits once it is multiplied by 10
+        for overflow in [Overflow::Saturate, Overflow::Wrap, Overflow::Skip] {
+            let encoding = Encoding::Scaled {
+                scale: 0.5,
+                offset: 0.0,
+                overflow,
+            };
+            assert_eq!(encoding.encode(&[5]), vec![3], "rounded");
+            assert_eq!(scaled_by_ten(overflow).encode(&[6553]), vec![65530]);
+            assert!(!scaled_by_ten(overflow).skips(&[6553]));
+        }
+        assert_eq!(
+            scaled_by_ten(Overflow::Saturate).encode(&[6554]),
+            vec![65535]
+        );
+        assert_eq!(scaled_by_ten(Overflow::Wrap).encode(&[6554]), vec![4]);
+        assert!(scaled_by_ten(Overflow::Skip).skips(&[6554]));
+        // any axis that doesn't fit skips them all
+        assert!(scaled_by_ten(Overflow::Skip).skips(&[1, 6554, 2]));
+        assert!(!scaled_by_ten(Overflow::Saturate).skips(&[6554]));
+    }
+
+    #[test]
+    fn a_scale_saturates_unless_told_otherwise() {
+        let mut map = RegisterMap::default();
+        // nothing set is the identity
+        assert_eq!(map.entry(Field::Battery).encoding, Encoding::Integer);
+        assert_eq!(map.entry(Field::Battery).encoding.encode(&[84]), vec![84]);
+
+        map.parse_scale("battery=1000").unwrap();
+        let encoding = map.entry(Field::Battery).encoding;
+        assert_eq!(
+            encoding,
+            Encoding::Scaled {
+                scale: 1000.0,
+                offset: 0.0,
+                overflow: Overflow::Saturate
+            }
+        );
+        assert_eq!(encoding.encode(&[65]), vec![65000]);
+        assert_eq!(encoding.encode(&[66]), vec![65535]);
+        assert_eq!(encoding.encode(&[255]), vec![65535]);
+    }
+
+    #[test]
+    fn scaled_values_below_zero() {
+        let negative = |overflow| Encoding::Scaled {
+            scale: -1.0,
+            offset: 0.0,
+            overflow,
+        };
+        assert_eq!(negative(Overflow::Saturate).encode(&[0, 1]), vec![0, 0]);
+        assert_eq!(negative(Overflow::Wrap).encode(&[0, 1]), vec![0, 65535]);
+        assert!(!negative(Overflow::Skip).skips(&[0]));
+        assert!(negative(Overflow::Skip).skips(&[1]));
+    }
+
+    #[test]
+    fn offsets_are_added_after_the_scale() {
+        let mut map = RegisterMap::default();
+        map.parse_scale("temperature=10").unwrap();
+        map.parse_offset("temperature=-400").unwrap();
+        let encoding = map.entry(Field::Temperature).encoding;
+        assert_eq!(encoding.encode(&[84]), vec![440]);
+        assert_eq!(encoding.encode(&[40]), vec![0]);
+        // below 0 and above 65535 saturate rather than wrapping round
+        assert_eq!(encoding.encode(&[39]), vec![0]);
+        assert_eq!(encoding.encode(&[6593]), vec![65530]);
+        assert_eq!(encoding.encode(&[6594]), vec![65535]);
+
+        // an offset on its own leaves the scale at 1, and a scale that comes after keeps the offset
+        let mut map = RegisterMap::default();
+        map.parse_offset("battery=2.5").unwrap();
+        assert_eq!(map.entry(Field::Battery).encoding.encode(&[10]), vec![13]);
+        assert_eq!(
+            map.entry(Field::Battery).encoding.encode(&[65535]),
+            vec![65535]
+        );
+        map.parse_scale("battery=2:wrap").unwrap();
+        assert_eq!(map.entry(Field::Battery).encoding.encode(&[10]), vec![23]);
+
+        // floats take an offset too, 25.4 - 40 is -14.6 (0xC1699999)
+        map.parse_float("temperature=0.1").unwrap();
+        map.parse_offset("temperature=-40").unwrap();
+        assert_eq!(
+            map.entry(Field::Temperature).encoding.encode(&[254]),
+            vec![0xC169, 0x999A]
+        );
+        assert!(map.parse_offset("temperature=cold").is_err());
+    }
+
+    #[test]
+    fn signed_vibration() {
+        // -2, 0 and 1 as two's complement
+        let raw = [0xFFFE, 0x0000, 0x0001];
+        let write = |rule: &str| {
+            let mut map = RegisterMap::default();
+            map.parse_signedness(rule).unwrap();
+            let mut client = RecordingClient::default();
+            map.write(&mut client, Field::Vibration, 3, &raw).unwrap();
+            client.writes
+        };
+        // the same bits either way, as an integer register is two's complement to the PLC
+        assert_eq!(
+            write("vibration=unsigned"),
+            vec![Write::Multiple(3, raw.to_vec())]
+        );
+        assert_eq!(
+            write("vibration=signed"),
+            vec![Write::Multiple(3, raw.to_vec())]
+        );
+        assert_eq!(
+            write("vibration=offset"),
+            vec![Write::Multiple(3, vec![32766, 32768, 32769])]
+        );
+
+        // scaling sees the negative value
+        let mut map = RegisterMap::default();
+        map.parse_signedness("vibration=signed").unwrap();
+        map.parse_scale("vibration=10:wrap").unwrap();
+        let mut client = RecordingClient::default();
+        map.write(&mut client, Field::Vibration, 3, &raw).unwrap();
+        assert_eq!(client.writes, vec![Write::Multiple(3, vec![65516, 0, 10])]);
+
+        assert!(map.parse_signedness("battery=signed").is_err());
+        assert!(map.parse_signedness("vibration=maybe").is_err());
+    }
+
+    #[test]
+    fn skipped_fields_are_not_written() {
+        let mut map = RegisterMap::default();
+        map.parse_scale("temperature=1000:skip").unwrap();
+        map.parse_scale("battery=1000").unwrap();
+
+        let mut client = RecordingClient::default();
+        map.write(&mut client, Field::Temperature, 2, &[84])
+            .unwrap();
+        map.write(&mut client, Field::Battery, 1, &[84]).unwrap();
+        map.write(&mut client, Field::Temperature, 2, &[65])
+            .unwrap();
+        // saturate is the default
+        assert_eq!(
+            client.writes,
+            vec![Write::Single(1, 65535), Write::Single(2, 65000)]
+        );
+
+        assert!(map.parse_scale("temperature=10:clamp").is_err());
+        assert!(map.parse_scale("temperature=x").is_err());
+    }
+
+    #[test]
+    fn parse_float_invalid() {
+        let mut map = RegisterMap::default();
+        assert!(map.parse_float("temperature").is_err());
+        assert!(map.parse_float("temperature=x").is_err());
+        assert!(map.parse_float("temperature=0.1:abdc").is_err());
+    }
+
+    #[test]
+    fn invalid_override() {
+        let mut maps = RegisterMaps::default();
+        assert!(maps.parse_address("01:02:03/battery=1").is_err());
+        assert!(maps.parse_address("01:02:03:04:05:06/battery=x").is_err());
+        assert!(maps.parse_address("01:02:03:04:05:06#x/battery=1").is_err());
+    }
+
+    #[test]
+    fn per_sensor_overrides_build_on_their_device() {
+        let mut maps = RegisterMaps::default();
+        maps.parse_address("01:02:03:04:05:06/battery=100").unwrap();
+        maps.parse_address("01:02:03:04:05:06#2/temperature=200")
+            .unwrap();
+        maps.merge().unwrap();
+
+        let sensor = maps.for_device(&OTHER_MAC, Some(2));
+        assert_eq!(sensor.entry(Field::Battery).address, Some(100));
+        assert_eq!(sensor.entry(Field::Temperature).address, Some(200));
+
+        // any other sensor of the device uses the device's map
+        let other_sensor = maps.for_device(&OTHER_MAC, Some(3));
+        assert_eq!(other_sensor.entry(Field::Battery).address, Some(100));
+        assert_eq!(other_sensor.entry(Field::Temperature).address, None);
+    }
+
+    #[test]
+    fn send_message_to_modbus_default_register_map() {
+        let mut client = RecordingClient::default();
+        let msg = sample_message();
+        send_message_to_modbus(
+            &msg,
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(1, 0),
+                Write::Single(2, 84),
+                Write::Multiple(3, vec![62206, 602, 1914]),
+                Write::Single(5, 33850),
+                Write::Single(11, 2),
+                Write::Single(6, 189),
+            ]
+        );
+    }
+
+    #[test]
+    fn send_message_to_modbus_custom_register_map() {
+        let mut map = RegisterMap::default();
+        for address in [
+            "battery=100",
+            "temperature=101",
+            "vibration=102",
+            "msg-num=105",
+            "version=106",
+            "rssi=107",
+        ] {
+            map.parse_address(address).unwrap();
+        }
+        let mut client = RecordingClient::default();
+        send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &map,
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        // none of the PID bytes are used
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(100, 0),
+                Write::Single(101, 84),
+                Write::Multiple(102, vec![62206, 602, 1914]),
+                Write::Single(105, 33850),
+                Write::Single(106, 2),
+                Write::Single(107, 189),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_enabled_fields_are_written() {
+        let mut map = RegisterMap::default();
+        map.parse_enabled("temperature").unwrap();
+        let mut client = RecordingClient::default();
+        send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &map,
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(client.writes, vec![Write::Single(2, 84)]);
+
+        map.parse_enabled("temperature, battery,rssi").unwrap();
+        assert_eq!(map.registers(&sample_message(), FieldSet::all()).len(), 3);
+        assert!(map.parse_enabled("temp").is_err());
+
+        // the registers of a field that isn't written are free for the others
+        map.parse_address("battery=3").unwrap();
+        map.parse_address("vibration=3").unwrap();
+        assert!(map.validate().is_ok());
+        map.parse_enabled("battery,vibration").unwrap();
+        assert!(map.validate().is_err());
+    }
+
+    #[test]
+    fn send_message_batched_ends_with_the_same_registers() {
+        // temperature as a float takes registers 2 and 3, the default map writes the vibration over its second half
+        let mut float_map = RegisterMap::default();
+        float_map.parse_float("temperature=0.5").unwrap();
+        for map in [RegisterMap::default(), float_map].iter() {
+            let msg = sample_message();
+            let mut unbatched = RecordingClient::default();
+            send_message_to_modbus(
+                &msg,
+                FieldSet::all(),
+                map,
+                Duration::from_millis(0),
+                &mut unbatched,
+            )
+            .unwrap();
+            let mut batched = RecordingClient::default();
+            send_message_batched(
+                &msg,
+                FieldSet::all(),
+                map,
+                Duration::from_millis(0),
+                &mut batched,
+            )
+            .unwrap();
+            assert_eq!(batched.registers(), unbatched.registers());
+        }
+
+        // 1 to 6 are next to each other, only 11 is on its own
+        let mut client = RecordingClient::default();
+        send_message_batched(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Multiple(1, vec![0, 84, 62206, 602, 33850, 189]),
+                Write::Single(11, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn registers_without_a_modbus() {
+        let mut map = RegisterMap::default();
+        map.parse_address("battery=100").unwrap();
+        map.parse_float("temperature=0.5").unwrap();
+        let mut fields = FieldSet::empty();
+        fields.insert(Field::Battery);
+        fields.insert(Field::Temperature);
+        fields.insert(Field::Rssi);
+        assert_eq!(
+            map.registers(&sample_message(), fields),
+            vec![
+                RegisterWrite {
+                    field: Field::Battery,
+                    address: 100,
+                    values: vec![0],
+                    function: WriteFunction::Single,
+                },
+                RegisterWrite {
+                    field: Field::Temperature,
+                    address: 2,
+                    values: vec![0x4228, 0x0000],
+                    function: WriteFunction::Multiple,
+                },
+                RegisterWrite {
+                    field: Field::Rssi,
+                    address: 6,
+                    values: vec![189],
+                    function: WriteFunction::Single,
+                },
+            ]
+        );
+
+        // the default map, which is the registers send_message_to_modbus writes
+        let mut client = RecordingClient::default();
+        send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        let registers = sample_message().to_registers();
+        assert_eq!(
+            registers,
+            vec![
+                (1, vec![0]),
+                (2, vec![84]),
+                (3, vec![62206, 602, 1914]),
+                (5, vec![33850]),
+                (11, vec![2]),
+                (6, vec![189]),
+            ]
+        );
+        let mut expected = RecordingClient::default();
+        for (address, values) in &registers {
+            expected.write_multiple_registers(*address, values).unwrap();
+        }
+        assert_eq!(client.registers(), expected.registers());
+    }
+
+    #[test]
+    fn a_failed_send_says_how_far_it_got() {
+        // the third write is the vibration
+        let mut client = FailingClient::after(2);
+        let e = send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap_err();
+        let mut written = FieldSet::empty();
+        written.insert(Field::Battery);
+        written.insert(Field::Temperature);
+        assert_eq!(e.written, written);
+        assert_eq!(e.failed, Some(Field::Vibration));
+        assert_eq!(client.inner.writes.len(), 2);
+
+        // retrying the rest finishes the message without writing the first two again
+        let remaining = e.remaining(FieldSet::all());
+        assert!(!remaining.contains(Field::Battery));
+        assert!(remaining.contains(Field::Vibration));
+        let mut client = RecordingClient::default();
+        send_message_to_modbus(
+            &sample_message(),
+            remaining,
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(client.writes.len(), 4);
+        assert_eq!(client.writes[0], Write::Multiple(3, vec![62206, 602, 1914]));
+
+        // a batch is all or nothing as far as the fields go
+        let e = send_message_batched(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut FailingClient::after(1),
+        )
+        .unwrap_err();
+        assert!(e.written.is_empty());
+        assert_eq!(e.failed, None);
+    }
+
+    // Answers a write to the register with an illegal data address exception, like a modbus server with
+    // nothing at that address
+    struct RejectingClient {
+        register: u16,
+        inner: RecordingClient,
+    }
+
+    impl ModbusClient for RejectingClient {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            self.write_multiple_registers(address, &[value])
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            if (address..address + values.len() as u16).contains(&self.register) {
+                let code = modbus::ExceptionCode::IllegalDataAddress;
+                return Err(modbus::Error::Exception(code));
+            }
+            self.inner.write_multiple_registers(address, values)
+        }
+    }
+
+    #[test]
+    fn an_exception_is_told_apart_from_a_broken_connection() {
+        let mut client = RejectingClient {
+            register: 4,
+            inner: RecordingClient::default(),
+        };
+        let e = send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap_err();
+        // the vibration's registers are 3 to 5
+        assert_eq!(e.failed, Some(Field::Vibration));
+        assert_eq!(e.address, Some(3));
+        assert!(e.rejected());
+        assert_eq!(
+            e.to_string(),
+            "writing vibration to register 3: Exception(IllegalDataAddress)"
+        );
+
+        let e = send_message_to_modbus(
+            &sample_message(),
+            FieldSet::all(),
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut FailingClient::after(0),
+        )
+        .unwrap_err();
+        assert!(!e.rejected());
+        assert_eq!(e.address, Some(1));
+    }
+
+    #[test]
+    fn send_message_to_modbus_only_the_given_fields() {
+        let mut client = RecordingClient::default();
+        let mut fields = FieldSet::empty();
+        fields.insert(Field::Rssi);
+        send_message_to_modbus(
+            &sample_message(),
+            fields,
+            &RegisterMap::default(),
+            Duration::from_millis(0),
+            &mut client,
+        )
+        .unwrap();
+        assert_eq!(client.writes, vec![Write::Single(6, 189)]);
+    }
+}
@@ -0,0 +1,155 @@
+use crate::fields::FieldSet;
+use crate::frame::DeviceMessage;
+use std::collections::VecDeque;
+use std::fmt::Write;
+
+// Holds the whole messages that couldn't be written while the modbus was down and writes them again, in the
+// order they arrived, once it is back (see --retry-queue). Unlike the write queue every message is kept, for
+// a PLC that logs each reading rather than only looking at the latest one
+pub struct RetryQueue {
+    // the most messages we hold on to, once we are full the oldest is dropped to make room
+    capacity: usize,
+    // each message with the fields of it that were to be written
+    messages: VecDeque<(DeviceMessage, FieldSet)>,
+    dropped: u64,
+}
+
+impl RetryQueue {
+    pub fn new(capacity: usize) -> RetryQueue {
+        RetryQueue {
+            capacity,
+            messages: VecDeque::new(),
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn push(&mut self, msg: DeviceMessage, fields: FieldSet) {
+        if self.messages.len() >= self.capacity {
+            self.dropped += 1;
+            // a queue that holds nothing
+            if self.messages.pop_front().is_none() {
+                return;
+            }
+        }
+        self.messages.push_back((msg, fields));
+    }
+
+    // Writes every queued message with forward, oldest first. A message only leaves the queue once it has
+    // been written, on an error it and the ones after it stay queued for next time
+    pub fn drain<F>(&mut self, mut forward: F) -> Result<(), modbus::Error>
+    where
+        F: FnMut(&DeviceMessage, FieldSet) -> Result<(), modbus::Error>,
+    {
+        while let Some((msg, fields)) = self.messages.front() {
+            forward(msg, *fields)?;
+            self.messages.pop_front();
+        }
+        Ok(())
+    }
+
+    // The queue metrics in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_retry_queue_depth The number of messages waiting for the modbus"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_retry_queue_depth gauge");
+        let _ = writeln!(
+            out,
+            "modbusrouter_retry_queue_depth {}",
+            self.messages.len()
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_retry_queue_dropped_total Messages dropped because the retry queue was full"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_retry_queue_dropped_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_retry_queue_dropped_total {}",
+            self.dropped
+        );
+        out
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn message(msg_num: u16) -> DeviceMessage {
+        let mut msg = sample_message();
+        msg.msg_num_value = msg_num;
+        msg
+    }
+
+    fn numbers(queue: &RetryQueue) -> Vec<u16> {
+        queue
+            .messages
+            .iter()
+            .map(|(msg, _)| msg.msg_num_value)
+            .collect()
+    }
+
+    #[test]
+    fn a_full_queue_drops_the_oldest_message() {
+        let mut queue = RetryQueue::new(2);
+        queue.push(message(1), FieldSet::all());
+        queue.push(message(2), FieldSet::all());
+        queue.push(message(3), FieldSet::all());
+        assert_eq!(numbers(&queue), vec![2, 3]);
+        assert_eq!(queue.dropped, 1);
+        assert!(queue
+            .metrics()
+            .contains("modbusrouter_retry_queue_dropped_total 1\n"));
+
+        let mut queue = RetryQueue::new(0);
+        queue.push(message(1), FieldSet::all());
+        assert_eq!((queue.len(), queue.dropped), (0, 1));
+    }
+
+    #[test]
+    fn every_message_is_written_in_order_after_a_reconnect() {
+        let mut queue = RetryQueue::new(10);
+        queue.push(message(1), FieldSet::all());
+        queue.push(message(2), FieldSet::empty());
+        let mut written = Vec::new();
+        queue
+            .drain(|msg, fields| {
+                written.push((msg.msg_num_value, fields));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(written, vec![(1, FieldSet::all()), (2, FieldSet::empty())]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn a_failed_drain_keeps_what_was_not_written() {
+        let mut queue = RetryQueue::new(10);
+        queue.push(message(1), FieldSet::all());
+        queue.push(message(2), FieldSet::all());
+        queue.push(message(3), FieldSet::all());
+        let mut written = 0;
+        let result = queue.drain(|_, _| {
+            if written == 1 {
+                return Err(modbus::Error::InvalidResponse);
+            }
+            written += 1;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(numbers(&queue), vec![2, 3]);
+    }
+}
@@ -0,0 +1,2331 @@
+use crate::alive_log::AliveLog;
+use crate::audit_log::{AuditLog, AuditingClient};
+use crate::capture::{Capture, CaptureConfig};
+use crate::change::ChangeFilter;
+use crate::chaos::{ChaosClient, ChaosReader};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::clock::{Clock, SystemClock};
+use crate::coalesce::Coalescer;
+use crate::config::{Config, LogFormat};
+use crate::connections::{Connections, SilenceAction};
+use crate::csv_out::CsvOut;
+use crate::dedup::{Deduplicator, Seen};
+use crate::device_source::DeviceSource;
+use crate::error_log::ErrorLog;
+use crate::fields::{Field, FieldSet};
+use crate::formats::Formats;
+use crate::frame::{
+    check_constant_bytes, check_payload_len, decode_frame_as, format_hex, format_mac,
+    framing_error_kind, is_partial_frame, read_delimited_frame, read_extra_payload,
+    read_first_delimited_frame, read_raw_frame, resync_to_start, verify_checksum, DeviceMessage,
+    Frame, FRAME_LEN,
+};
+use crate::freshness::FreshnessFlag;
+use crate::gaps::GapDetector;
+#[cfg(feature = "grpc")]
+use crate::grpc_sink;
+use crate::health::Health;
+use crate::hooks::{Event, Hooks};
+use crate::idle::IdleTimer;
+use crate::json_out::JsonOut;
+use crate::last_seen::LastSeen;
+use crate::load_shed::LoadShedder;
+use crate::log_sampling::LogSampler;
+use crate::modbus_client::{DryRun, ModbusClient, ModbusConnector, NoModbus};
+use crate::monitor::Monitor;
+#[cfg(feature = "mqtt")]
+use crate::mqtt_sink;
+use crate::policy::{Action, ErrorClass, RETRY_IN_PLACE_ATTEMPTS};
+use crate::rate_limit::RateLimiter;
+use crate::raw_sink::RawTcpSink;
+use crate::read_ahead::{ReadAhead, ReadAheadConfig};
+use crate::recent_frames::RecentFrames;
+use crate::reconnect::{
+    retry_with_backoff, Backoff, Decision, ReconnectTracker, MODBUS_BACKOFF_MAX,
+    MODBUS_BACKOFF_START,
+};
+use crate::recycle::Recycler;
+use crate::register_map::{send_message_batched, send_message_to_modbus, Signedness};
+use crate::retry_queue::RetryQueue;
+use crate::sentinel::Sentinels;
+use crate::sink::{MessageSink, SinkError};
+use crate::state::StateFile;
+use crate::stats::Stats;
+use crate::status::Thresholds;
+use crate::stream_transport::{self, TransactionIdConfig, TransactionIdMismatch, TransactionIds};
+use crate::strobe::Strobe;
+use crate::transform::{Clamp, Pipeline, Transform};
+use crate::verify_writes::VerifyingClient;
+use crate::version_gate::{MismatchAction, VersionGate};
+use crate::write_queue::WriteQueue;
+use crate::ws_broadcast::WsBroadcast;
+use crate::{http, monitor, read_registers, selftest, stats_log, watchdog};
+use log::{debug, error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::io;
+use std::io::{ErrorKind, Read};
+use std::iter;
+use std::mem;
+use std::process;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// The exit code used when we give up trying to connect to the device, so that whatever started the router
+// can tell a host that is never going to answer apart from other failures
+const EXIT_RECONNECTS_EXHAUSTED: i32 = 3;
+
+// How long to wait before trying a message again (see --forward-retries)
+const FORWARD_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+// Checks the install (modbusrouter selftest) against the modbus of the settings
+pub fn check_install(config: &Config) -> selftest::Report {
+    let connector = modbus_connector(config, Arc::new(AtomicU64::new(0)), &Stats::new());
+    selftest::run(|| connector.connect(), config.selftest_register)
+}
+
+// What the modbus holds in the registers of the request (modbusrouter read-registers)
+pub fn read_from_modbus(
+    config: &Config,
+    request: &read_registers::Request,
+) -> Result<Vec<(u16, u16)>, modbus::Error> {
+    let connector = modbus_connector(config, Arc::new(AtomicU64::new(0)), &Stats::new());
+    connector
+        .connect()
+        .map_err(modbus::Error::Io)
+        .and_then(|mut client| read_registers::read(client.as_mut(), request))
+}
+
+// Routes the messages of the device to the modbus until it is told to stop, or gives up, and then exits the
+// process with the summary
+pub fn run(config: Config) {
+    route(config, false)
+}
+
+// Shows the values live as they arrive (modbusrouter monitor), only writing them with --monitor-write
+pub fn run_monitor(config: Config) {
+    route(config, true)
+}
+
+fn route(config: Config, monitoring: bool) {
+    // set by SIGINT or SIGTERM, the router stops once it has finished with the current message
+    let shutdown = Arc::new(AtomicBool::new(false));
+    if let Err(e) = catch_shutdown_signals(&shutdown) {
+        warn!(
+            "Unable to catch SIGINT and SIGTERM, they will stop the router straight away: {:?}",
+            e
+        );
+    }
+
+    // what standard frames are checked against, nothing at all with --unknown-mac-policy allow
+    let accepted_macs = config.unknown_mac_policy.accepted(&config.macs);
+
+    // a tcp host unless a listener, a unix socket, stdin, a replay or the simulator has been asked for
+    let device_source = if config.stdin {
+        Ok(DeviceSource::Stdin)
+    } else if let Some(rate) = config.simulate {
+        Ok(DeviceSource::simulate(rate, &config.macs))
+    } else if let Some(path) = &config.replay {
+        DeviceSource::replay(path)
+    } else if let Some(addr) = &config.device_listen {
+        DeviceSource::tcp_listen(
+            addr,
+            &config.tcp,
+            config.device_listen_max,
+            config.start_seq,
+            accepted_macs,
+        )
+    } else if let (true, Some(ca_file)) = (config.tls, &config.tls_ca) {
+        DeviceSource::tls(
+            &config.device_host,
+            &config.tcp,
+            ca_file,
+            &config.tls_server_name,
+        )
+    } else {
+        DeviceSource::new(
+            &config.device_host,
+            &config.tcp,
+            config.device_unix.as_ref(),
+            config.device_ws.as_ref(),
+        )
+    };
+    let device_source = match device_source {
+        Ok(source) => source,
+        Err(e) => {
+            error!("Unable to set up the device socket: {:?}", e);
+            process::exit(1);
+        }
+    };
+    let host = &device_source.name();
+    let modbus_at = match &config.serial {
+        Some(device) => device.clone(),
+        None => iter::once(&config.modbus_host)
+            .chain(config.standby_hosts.iter())
+            .map(|host| format!("{}:{}", host, config.modbus_port))
+            .collect::<Vec<_>>()
+            .join(", "),
+    };
+    info!(
+        "Reading from {} and writing to the modbus at {}",
+        host, modbus_at
+    );
+
+    let read_ahead = config.read_ahead.map(|frames| ReadAheadConfig {
+        frames,
+        when_full: config.read_ahead_full,
+        start_seq: config.start_seq,
+        macs: accepted_macs.to_vec(),
+        max_resync: config.max_resync,
+    });
+
+    let mut state = RunState::start(
+        &config,
+        monitoring,
+        host,
+        device_source.comes_to_an_end(),
+        shutdown,
+    );
+
+    // counts failed attempts to connect to the device
+    let mut reconnects = ReconnectTracker::new(config.reconnect.clone());
+
+    // whether we got as far as connecting to the device last time round
+    let mut device_connected = false;
+
+    // this keeps looping until a fatal error is encountered
+    loop {
+        if state.shutdown.load(Ordering::SeqCst) {
+            state.exit(&config, 0, "Shutting down gracefully");
+        }
+
+        // whatever connection we had before is gone by now
+        state.connections.lock().unwrap().disconnected(host);
+        if let Some(alive_log) = &state.alive_log {
+            alive_log.disconnected();
+        }
+        if device_connected {
+            state
+                .hooks
+                .fire(Event::DeviceDisconnected, host, state.device_mac);
+            device_connected = false;
+        }
+
+        info!("Connecting to {} ...", host);
+        let mut stream = match device_source.connect() {
+            Ok(stream) => stream,
+            Err(e) => {
+                match reconnects.record_failure() {
+                    Decision::Retry(delay) => {
+                        error!(
+                            "Unable to connect to remote host: {:?}, retrying in {}s",
+                            e,
+                            delay.as_secs()
+                        );
+                        thread::sleep(delay);
+                    }
+                    Decision::Park(delay) => {
+                        error!(
+                            "Unable to connect to remote host after {} attempts: {:?}, retrying every {}s from now on",
+                            reconnects.failures(),
+                            e,
+                            delay.as_secs()
+                        );
+                        thread::sleep(delay);
+                    }
+                    Decision::Exit => exit(
+                        &state.stats,
+                        &config,
+                        &format!(
+                            "Unable to connect to remote host after {} attempts: {:?}",
+                            reconnects.failures(),
+                            e
+                        ),
+                        EXIT_RECONNECTS_EXHAUSTED,
+                    ),
+                }
+                continue;
+            }
+        };
+        if let Some(chaos) = &config.chaos {
+            stream = Box::new(ChaosReader::new(stream, chaos));
+        }
+        // the frames arrive from the reader's thread from here on, it finds their starts itself
+        if let Some(read_ahead) = &read_ahead {
+            stream = Box::new(ReadAhead::start(stream, read_ahead, state.stats.clone()));
+        }
+        info!("Connected");
+        reconnects.record_success();
+        state
+            .connections
+            .lock()
+            .unwrap()
+            .connected(host, Instant::now());
+        state.hooks.fire(Event::DeviceConnected, host, None);
+        if let Some(alive_log) = &state.alive_log {
+            alive_log.connected(host);
+        }
+        device_connected = true;
+        state.device_mac = None;
+        match run_connection(&mut state, stream, &config) {
+            ConnectionEnd::Reconnect => {}
+            ConnectionEnd::Exit(code, reason) => state.exit(&config, code, &reason),
+        }
+    }
+}
+
+// Everything that lasts from one device connection to the next: the modbus client and whether it is down, the
+// outage queue, the error policy's counts, the change filter and the rest of what a message goes through, and
+// everywhere else it is sent. route() connects to the device and run_connection() makes use of the connection
+struct RunState {
+    shutdown: Arc<AtomicBool>,
+    // what we are reading from, for the logs, the metrics and the hooks
+    host: String,
+    // stdin and a replay come to an end, and the router with them
+    input_ends: bool,
+    writes_enabled: bool,
+    modbus_connected: bool,
+    stats: Stats,
+    deduplicator: Deduplicator,
+    gap_detector: GapDetector,
+    state_file: Option<StateFile>,
+    rate_limiter: Option<RateLimiter>,
+    coalescer: Option<Coalescer>,
+    load_shedder: Option<LoadShedder>,
+    recycler: Option<Recycler>,
+    breaker: Option<CircuitBreaker>,
+    change_filter: ChangeFilter,
+    sentinels: Sentinels,
+    transforms: Pipeline,
+    strobe: Option<Strobe>,
+    last_seen: Option<LastSeen>,
+    error_log: ErrorLog,
+    log_sampler: LogSampler,
+    clock: Box<dyn Clock>,
+    idle_timer: Option<IdleTimer>,
+    formats: Option<Formats>,
+    raw_sink: Option<RawTcpSink>,
+    capture: Option<Capture>,
+    audit_log: Option<AuditLog>,
+    // the json and csv outputs, the WebSocket server, gRPC and MQTT, whichever were asked for
+    sinks: Vec<Box<dyn MessageSink>>,
+    recent_frames: Arc<Mutex<RecentFrames>>,
+    connections: Arc<Mutex<Connections>>,
+    outage_queue: Option<Arc<Mutex<OutageQueue>>>,
+    version_gate: Arc<Mutex<VersionGate>>,
+    freshness: Option<FreshnessFlag>,
+    alive_log: Option<AliveLog>,
+    hooks: Hooks,
+    modbus_connector: ModbusConnector,
+    modbus_backoff: Backoff,
+    modbus_client: Box<dyn ModbusClient>,
+    monitor: Option<Monitor>,
+    // only ever true with a write queue or a circuit breaker, without them the modbus is reconnected straight away
+    modbus_down: bool,
+    // who the device on the current connection turned out to be
+    device_mac: Option<[u8; 6]>,
+}
+
+impl RunState {
+    // Sets up everything a run needs before the first connection to the device: the outputs, the http server,
+    // the hooks and the modbus connection. A setup error exits the router
+    fn start(
+        config: &Config,
+        monitoring: bool,
+        host: &str,
+        input_ends: bool,
+        shutdown: Arc<AtomicBool>,
+    ) -> RunState {
+        // the monitor only reads unless it has been told otherwise
+        let writes_enabled = !monitoring || config.monitor_write;
+
+        // a dry run goes through the motions of every write without ever connecting to the modbus
+        let modbus_connected = writes_enabled && !config.dry_run;
+
+        // counters used to print a summary when the program exits
+        let stats = Stats::new();
+
+        // drops the frames the device sends more than once
+        let mut deduplicator =
+            Deduplicator::new().with_min_repeat_interval(config.min_repeat_interval);
+        let mut gap_detector = GapDetector::new();
+        // the repeat check and the gap detection carry on from the messages before the restart
+        let state_file = config.state_file.as_ref().map(|path| {
+            let (file, state) = StateFile::open(path);
+            for msg in &state.messages {
+                gap_detector.missing(msg);
+                deduplicator.should_forward(msg);
+            }
+            file
+        });
+        let rate_limiter = config.max_msgs_per_sec.map(RateLimiter::new);
+        let coalescer = config.coalesce.map(Coalescer::new);
+        let load_shedder = config.max_forward_latency.map(LoadShedder::new);
+        let recycler = config.max_frames_per_connection.map(Recycler::new);
+        let breaker = config
+            .circuit_breaker
+            .map(|failures| CircuitBreaker::new(failures, config.circuit_breaker_cooldown));
+
+        // decides which fields of each message are worth sending to the modbus
+        let change_filter = ChangeFilter::new(config.change.clone());
+
+        // deals with the readings the device couldn't take, before the change filter sees them
+        let sentinels = Sentinels::new(config.sentinels.clone());
+
+        // changes to every message (see --clamp) that run after the sentinels and before the change filter
+        let transforms = transforms(config);
+
+        // an edge for the PLC with every new message
+        let strobe = config
+            .strobe_register
+            .filter(|_| writes_enabled)
+            .map(|register| Strobe::new(register, config.strobe_modulus));
+
+        // when the last message was written, for a SCADA that wants to know how fresh the registers are
+        let last_seen = config
+            .last_seen_registers
+            .filter(|_| writes_enabled)
+            .map(|(high, low)| LastSeen::new(high, low));
+
+        // keeps a stream of identical errors from flooding the log
+        let error_log = ErrorLog::new(config.error_log);
+
+        // decides which messages are worth printing, independently of the change filter
+        let log_sampler = LogSampler::new(config.log_sampling.clone());
+
+        // used to timestamp each message as it arrives
+        let clock: Box<dyn Clock> = Box::new(SystemClock);
+
+        // connects to the device again when it stops sending (see --device-idle-timeout)
+        let idle_timer = config
+            .device_idle_timeout
+            .map(|timeout| IdleTimer::new(timeout, clock.monotonic()));
+
+        // only set up with other formats as well as the standard one, otherwise the frames are read the quicker way.
+        // The config has already checked that the formats can be told apart
+        let formats = if config.frame_formats.is_empty() && !config.payload_crc {
+            None
+        } else {
+            Formats::new(config.frame_formats.clone())
+                .ok()
+                .map(|formats| formats.with_standard_endian(config.endian))
+                .map(|formats| {
+                    if config.payload_crc {
+                        formats.with_standard_crc()
+                    } else {
+                        formats
+                    }
+                })
+        };
+
+        // an optional copy of every valid frame, sent on to another tcp endpoint untouched
+        let raw_sink = config
+            .raw_sink
+            .as_ref()
+            .map(|addr| RawTcpSink::start(addr.clone()));
+
+        // an optional copy of every valid frame, appended to a file for building test fixtures
+        let capture = config.capture.as_ref().map(|path| {
+            let capture = Capture::start(CaptureConfig {
+                path: path.clone(),
+                max_bytes: config.capture_max_bytes,
+                keep: config.capture_keep,
+            });
+            match capture {
+                Ok(capture) => capture,
+                Err(e) => {
+                    error!("Unable to open the capture file {}: {:?}", path, e);
+                    process::exit(1);
+                }
+            }
+        });
+
+        // an optional copy of every decoded message, as json lines for a log pipeline
+        let json_out =
+            config
+                .json_out
+                .as_ref()
+                .map(|path| match JsonOut::start(path, config.units.clone()) {
+                    Ok(json_out) => json_out,
+                    Err(e) => {
+                        error!("Unable to open the json output {}: {:?}", path, e);
+                        process::exit(1);
+                    }
+                });
+
+        // and as csv rows for a spreadsheet
+        let csv_out = config
+            .csv_out
+            .as_ref()
+            .map(|path| match CsvOut::start(path) {
+                Ok(csv_out) => csv_out,
+                Err(e) => {
+                    error!("Unable to open the csv output {}: {:?}", path, e);
+                    process::exit(1);
+                }
+            });
+
+        // every write to the modbus goes on record, the file is opened now so that a bad path stops us at startup
+        let audit_log = config
+            .audit_log
+            .as_ref()
+            .map(|path| match AuditLog::open(path) {
+                Ok(audit_log) => audit_log,
+                Err(e) => {
+                    error!("Unable to open the audit log {}: {:?}", path, e);
+                    process::exit(1);
+                }
+            });
+
+        // and as json to the browsers of a live dashboard
+        let ws_broadcast = config.ws_addr.as_ref().map(|addr| {
+            match WsBroadcast::start(addr, config.units.clone()) {
+                Ok(ws_broadcast) => ws_broadcast,
+                Err(e) => {
+                    error!("Unable to start the WebSocket server on {}: {:?}", addr, e);
+                    process::exit(1);
+                }
+            }
+        });
+
+        // an optional copy of every decoded message, published to a gRPC service
+        #[cfg(feature = "grpc")]
+        let grpc_sink = config
+            .grpc
+            .as_ref()
+            .map(|endpoint| grpc_sink::GrpcSink::start(endpoint.clone(), config.grpc_batch));
+
+        // and another copy of each message, published field by field to an MQTT broker
+        #[cfg(feature = "mqtt")]
+        let mqtt_sink = config.mqtt.as_ref().map(|broker| {
+            mqtt_sink::MqttSink::start(broker, &config.mqtt_client_id, &config.mqtt_topic_prefix)
+        });
+
+        // each decoded message is sent to all of them in turn
+        let mut sinks: Vec<Box<dyn MessageSink>> = Vec::new();
+        if let Some(json_out) = json_out {
+            sinks.push(Box::new(json_out));
+        }
+        if let Some(csv_out) = csv_out {
+            sinks.push(Box::new(csv_out));
+        }
+        if let Some(ws_broadcast) = ws_broadcast {
+            sinks.push(Box::new(ws_broadcast));
+        }
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_sink) = grpc_sink {
+            sinks.push(Box::new(grpc_sink));
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(mqtt_sink) = mqtt_sink {
+            sinks.push(Box::new(mqtt_sink));
+        }
+
+        // the last few frames and how they decoded, shared with the http server
+        let recent_frames = Arc::new(Mutex::new(RecentFrames::new(
+            config.recent_frames,
+            config.units.clone(),
+        )));
+
+        // connection uptime and reconnects for each device, also shared with the http server
+        let connections = Arc::new(Mutex::new(Connections::new()));
+
+        // holds the writes (or the whole messages) made while the modbus is down, if enabled. Shared with the http
+        // server for its metrics
+        let outage_queue = match (config.write_queue, config.retry_queue) {
+            (Some(capacity), _) => Some(OutageQueue::Writes(WriteQueue::new(capacity))),
+            (None, Some(capacity)) => Some(OutageQueue::Messages(RetryQueue::new(capacity))),
+            (None, None) => None,
+        }
+        .map(|queue| Arc::new(Mutex::new(queue)));
+
+        // responses from the modbus with the wrong transaction id, when we number the requests ourselves
+        let transaction_id_mismatches = Arc::new(AtomicU64::new(0));
+
+        // warns about or drops messages from unexpected firmware, also shared with the http server for its metrics
+        let version_gate = Arc::new(Mutex::new(VersionGate::new(config.version.clone())));
+
+        if let Some(addr) = &config.http {
+            let recent_frames = recent_frames.clone();
+            let version_gate = version_gate.clone();
+            let connections = connections.clone();
+            let outage_queue = outage_queue.clone();
+            let totals = stats.clone();
+            let transaction_id_mismatches = transaction_id_mismatches.clone();
+            let framed = own_transaction_ids(config);
+            let served = http::start(addr, move |path| match path {
+                "/debug/frames" => {
+                    let json = recent_frames.lock().unwrap().to_json();
+                    Some(http::Response::json(json))
+                }
+                "/metrics" => {
+                    let mut metrics = totals.metrics();
+                    metrics.push_str(&connections.lock().unwrap().metrics(Instant::now()));
+                    if let Some(queue) = &outage_queue {
+                        metrics.push_str(&queue.lock().unwrap().metrics());
+                    }
+                    metrics.push_str(&version_gate.lock().unwrap().metrics());
+                    if framed {
+                        let mismatches = transaction_id_mismatches.load(Ordering::Relaxed);
+                        metrics.push_str(&stream_transport::transaction_id_metrics(mismatches));
+                    }
+                    Some(http::Response::metrics(metrics))
+                }
+                _ => None,
+            });
+            if let Err(e) = served {
+                fatal(
+                    &stats,
+                    config,
+                    &format!("Unable to start the http server: {:?}", e),
+                );
+            }
+        }
+
+        // lets the PLC know when the values stop coming
+        let freshness = config
+            .fresh_register
+            .filter(|_| modbus_connected)
+            .map(|register| {
+                FreshnessFlag::start(
+                    config.modbus_host.clone(),
+                    config.modbus_port,
+                    config.modbus_unit,
+                    register,
+                    config.fresh_timeout,
+                )
+            });
+
+        // lets the PLC know the router itself is still alive
+        if let Some(register) = config.watchdog_register.filter(|_| modbus_connected) {
+            watchdog::start(
+                config.modbus_host.clone(),
+                config.modbus_port,
+                config.modbus_unit,
+                register,
+                config.watchdog_interval,
+            );
+        }
+
+        // a breadcrumb in the log during quiet periods, the monitor has the screen to itself
+        let alive_log = config
+            .alive_interval
+            .filter(|_| !monitoring)
+            .map(|interval| AliveLog::start(interval, SystemClock));
+
+        // and a pulse of the message and error counts for anyone not scraping /metrics
+        if let Some(interval) = config.stats_interval.filter(|_| !monitoring) {
+            stats_log::start(interval, stats.clone(), connections.clone());
+        }
+
+        // answers the probes of a load balancer or Kubernetes, on its own address so that it stays cheap
+        let health = config.health_addr.as_ref().map(|addr| {
+            let health = Health::new();
+            if let Err(e) = health.serve(addr) {
+                fatal(
+                    &stats,
+                    config,
+                    &format!("Unable to start the health check: {:?}", e),
+                );
+            }
+            health
+        });
+
+        // lets the outside world know when connections come and go
+        let hooks =
+            Hooks::start(config.hook_command.clone(), config.hook_url.clone()).with_health(health);
+
+        // local modbus connection details
+        // swap in ModbusConnector::Stream to set up the stream (e.g. a proxy handshake) before the modbus takes over
+        let modbus_connector = modbus_connector(config, transaction_id_mismatches, &stats);
+
+        // how long to wait between attempts to connect the modbus, at startup and after a write error
+        let mut modbus_backoff = Backoff::new(MODBUS_BACKOFF_START, MODBUS_BACKOFF_MAX);
+
+        let mut modbus_client: Box<dyn ModbusClient> = if !writes_enabled {
+            Box::new(NoModbus)
+        } else if config.dry_run {
+            Box::new(DryRun)
+        } else {
+            connect_modbus_at_startup(
+                &modbus_connector,
+                &mut modbus_backoff,
+                config,
+                &stats,
+                &audit_log,
+                &hooks,
+            )
+        };
+
+        // a modbus that won't talk to us is better found now than when the first frame arrives. There is nothing
+        // to check for a dry run or a monitor that doesn't write
+        if config.startup_check && modbus_connected {
+            match selftest::startup_check(modbus_client.as_mut()) {
+                Ok(()) => info!(
+                    "Startup check of the modbus at {} passed",
+                    config.modbus_host
+                ),
+                Err(e) => fatal(
+                    &stats,
+                    config,
+                    &format!(
+                        "Startup check of the modbus at {} failed: {}",
+                        config.modbus_host, e
+                    ),
+                ),
+            }
+        }
+
+        // takes over the terminal, so it starts once everything else is up and any setup errors have been seen
+        let monitor = if monitoring {
+            let table = monitor::Table::new(
+                format!("modbusrouter monitor - {}", host),
+                config.alerts.clone(),
+                config.fresh_timeout,
+            );
+            match Monitor::start(table) {
+                Ok(monitor) => Some(monitor),
+                Err(e) => fatal(
+                    &stats,
+                    config,
+                    &format!("Unable to start the monitor: {:?}", e),
+                ),
+            }
+        } else {
+            None
+        };
+
+        RunState {
+            shutdown,
+            host: host.to_string(),
+            input_ends,
+            writes_enabled,
+            modbus_connected,
+            stats,
+            deduplicator,
+            gap_detector,
+            state_file,
+            rate_limiter,
+            coalescer,
+            load_shedder,
+            recycler,
+            breaker,
+            change_filter,
+            sentinels,
+            transforms,
+            strobe,
+            last_seen,
+            error_log,
+            log_sampler,
+            clock,
+            idle_timer,
+            formats,
+            raw_sink,
+            capture,
+            audit_log,
+            sinks,
+            recent_frames,
+            connections,
+            outage_queue,
+            version_gate,
+            freshness,
+            alive_log,
+            hooks,
+            modbus_connector,
+            modbus_backoff,
+            modbus_client,
+            monitor,
+            modbus_down: false,
+            device_mac: None,
+        }
+    }
+
+    // Exits the router with the summary, the modbus connection is closed before we go
+    fn exit(&mut self, config: &Config, code: i32, reason: &str) -> ! {
+        drop(mem::replace(&mut self.modbus_client, Box::new(NoModbus)));
+        exit(&self.stats, config, reason, code)
+    }
+}
+
+// How run_connection() left the connection to the device
+#[derive(Debug, PartialEq)]
+enum ConnectionEnd {
+    // connect to the device again, for the error policy, --max-frames-per-connection, a device gone idle or
+    // silent or the load shedder
+    Reconnect,
+    // the router is done and exits with the code, saying why: the end of the input, --once or a shutdown, and
+    // the errors that it can't carry on from
+    Exit(i32, String),
+}
+
+// Reads the frames of one connection to the device and writes their messages to the modbus, until the
+// connection is to be made again or the router is done
+fn run_connection(
+    state: &mut RunState,
+    mut stream: Box<dyn Read + Send>,
+    config: &Config,
+) -> ConnectionEnd {
+    // what standard frames are checked against, nothing at all with --unknown-mac-policy allow
+    let accepted_macs = config.unknown_mac_policy.accepted(&config.macs);
+    let writes_enabled = state.writes_enabled;
+    let modbus_connected = state.modbus_connected;
+    let input_ends = state.input_ends;
+    let RunState {
+        shutdown,
+        host,
+        stats,
+        deduplicator,
+        gap_detector,
+        state_file,
+        rate_limiter,
+        coalescer,
+        load_shedder,
+        recycler,
+        breaker,
+        change_filter,
+        sentinels,
+        transforms,
+        strobe,
+        last_seen,
+        error_log,
+        log_sampler,
+        clock,
+        idle_timer,
+        formats,
+        raw_sink,
+        capture,
+        audit_log,
+        sinks,
+        recent_frames,
+        connections,
+        outage_queue,
+        version_gate,
+        freshness,
+        alive_log,
+        hooks,
+        modbus_connector,
+        modbus_backoff,
+        modbus_client,
+        monitor,
+        modbus_down,
+        device_mac,
+        ..
+    } = state;
+
+    // a new connection may be a restarted device so start again with a complete set of values
+    change_filter.reset();
+    if let Some(recycler) = recycler {
+        recycler.connected();
+    }
+    if let Some(idle_timer) = idle_timer {
+        idle_timer.connected(clock.monotonic());
+    }
+
+    // we don't know where the first frame starts until we have found it
+    let mut aligned = false;
+
+    // this keeps looping until the error policy tells us to reconnect to the device
+    // If that happens then the connection will be closed (the stream goes out of scope) and a new connection will be made
+    loop {
+        // the last message has been dealt with in full so nothing is left half written
+        if shutdown.load(Ordering::SeqCst) {
+            return ConnectionEnd::Exit(0, "Shutting down gracefully".to_string());
+        }
+        if let Some(recycler) = recycler.as_ref().filter(|recycler| recycler.due()) {
+            info!(
+                "Read {} frames on this connection, connecting again",
+                recycler.max_frames()
+            );
+            return ConnectionEnd::Reconnect;
+        }
+
+        // read the message from from the stream
+        // the raw frame is kept alongside the message so that it can be mirrored exactly as it arrived
+        let result = match &formats {
+            // a mixed stream, the start of each frame says how long it is and how to decode it
+            Some(formats) => {
+                let max_skip = if aligned { 0 } else { config.max_resync };
+                formats.read_frame(&mut stream, max_skip)
+            }
+            // the terminator after each frame says whether we are still in step
+            None => match (&config.frame_terminator, aligned) {
+                (Some(terminator), true) => {
+                    read_delimited_frame(&mut stream, terminator).map(|raw| (raw.to_vec(), 0))
+                }
+                (Some(terminator), false) => read_first_delimited_frame(
+                    &mut stream,
+                    config.start_seq,
+                    terminator,
+                    config.max_resync,
+                )
+                .map(|(raw, discarded)| (raw.to_vec(), discarded)),
+                (None, true) => read_raw_frame(&mut stream).map(|raw| (raw.to_vec(), 0)),
+                (None, false) => resync_to_start(
+                    &mut stream,
+                    config.start_seq,
+                    accepted_macs,
+                    config.max_resync,
+                )
+                .map(|(raw, discarded)| (raw.to_vec(), discarded))
+                .inspect_err(|e| {
+                    // the bytes skipped before giving up are gone as well
+                    if e.kind() == ErrorKind::InvalidData {
+                        stats.record_discarded(config.max_resync);
+                    }
+                }),
+            }
+            .and_then(|(mut raw, discarded)| {
+                // the rest of a standard frame with a longer payload, delimited frames are never longer
+                if config.frame_terminator.is_none() {
+                    raw.extend(read_extra_payload(&mut stream, config.start_seq, &raw)?);
+                }
+                Ok((raw, discarded))
+            }),
+        };
+        // any complete frame shows the gateway is still sending, whatever is in it
+        if let Some(idle_timer) = idle_timer {
+            if result.is_ok() {
+                idle_timer.frame(clock.monotonic());
+            } else if let Some(idle) = idle_timer.idle(clock.monotonic()) {
+                error_log.error(
+                    "DeviceIdle",
+                    &format!(
+                        "WARNING: No complete frame from the device for {}s, connecting again",
+                        idle.as_secs()
+                    ),
+                );
+                return ConnectionEnd::Reconnect;
+            }
+        }
+        // a constant byte that has changed, when that isn't a bad frame
+        let mut drifted = None;
+        let result = result.and_then(|(raw, discarded)| {
+            let decoded = match &formats {
+                Some(formats) => formats.decode(&raw, accepted_macs).map(|decoded| {
+                    debug!("Decoded a {} frame", decoded.format);
+                    decoded.frame
+                }),
+                None => decode_standard(&raw, accepted_macs, config),
+            };
+            let decoded = decoded.and_then(|frame| identify_sensor(frame, &raw, config));
+            let decoded = decoded.and_then(|frame| match frame {
+                Frame::Message(_) => match check_constant_bytes(&raw, &config.constant_bytes) {
+                    Err(e) if config.strict => Err(e.into()),
+                    Err(e) => {
+                        drifted = Some(e);
+                        Ok(frame)
+                    }
+                    Ok(()) => Ok(frame),
+                },
+                frame => Ok(frame),
+            });
+            if config.log_raw {
+                match &decoded {
+                    Ok(_) => debug!("Raw frame: {}", format_hex(&raw)),
+                    Err(e) => debug!(
+                        "Raw frame that failed to decode ({}): {}",
+                        e,
+                        format_hex(&raw)
+                    ),
+                }
+            }
+            recent_frames
+                .lock()
+                .unwrap()
+                .record(&raw, &decoded, clock.now());
+            Ok((raw, decoded?, discarded))
+        });
+        if let Some(e) = drifted {
+            error_log.error(
+                "UnexpectedByte",
+                &format!("WARNING: {}, the firmware may have changed", e),
+            );
+        }
+        // only a read that comes back gets us here, with no read timeout a device that sends nothing at all
+        // is waited for forever
+        if let Ok((_, Frame::Message(_), _)) = &result {
+            connections.lock().unwrap().message(host, Instant::now());
+        }
+        if let Some(threshold) = config.silence_timeout {
+            let silent = connections
+                .lock()
+                .unwrap()
+                .silent(host, threshold, Instant::now());
+            if let Some(silence) = silent {
+                let reconnect = config.on_silence == SilenceAction::Reconnect;
+                error_log.error(
+                    "Silence",
+                    &format!(
+                        "WARNING: No messages from the device for {}s, {}",
+                        silence.as_secs(),
+                        if reconnect {
+                            "connecting again"
+                        } else {
+                            "it may have stopped sending"
+                        }
+                    ),
+                );
+                if reconnect {
+                    return ConnectionEnd::Reconnect;
+                }
+            }
+        }
+        let msg = match result {
+            Ok((raw, frame, discarded)) => {
+                let received_at = clock.now();
+                if let Some(recycler) = recycler {
+                    recycler.frame();
+                }
+                if let Some(sink) = &raw_sink {
+                    sink.send(&raw);
+                }
+                if let Some(capture) = &capture {
+                    capture.send(&raw);
+                }
+                if discarded > 0 {
+                    warn!(
+                        "Discarded {} bytes looking for the start of a frame",
+                        discarded
+                    );
+                    stats.record_discarded(discarded);
+                }
+                aligned = true;
+                match frame {
+                    Frame::Message(mut msg) => {
+                        msg.received_at = Some(received_at);
+                        connections.lock().unwrap().identified(host, msg.mac);
+                        *device_mac = Some(msg.mac);
+                        msg
+                    }
+                    Frame::Heartbeat { mac } => {
+                        // the device is alive but there are no values so nothing goes to the modbus
+                        // (and the freshness flag isn't touched, the values in the registers are no newer)
+                        debug!("Heartbeat from {}", format_mac(&mac));
+                        connections.lock().unwrap().identified(host, mac);
+                        *device_mac = Some(mac);
+                        stats.record_heartbeat(mac, received_at);
+                        continue;
+                    }
+                }
+            }
+            // a neighbour's sensor, counted but not worth a line in the log
+            Err(e) if config.unknown_mac_policy.drops(&e) => {
+                debug!("Dropped a frame: {}", e);
+                stats.record_framing_error("unexpected-mac");
+                continue;
+            }
+            Err(e) => {
+                // these mean different things on a flapping gateway so say which one it was
+                let class = ErrorClass::of_read_error(&e);
+                // a bad frame may mean a missing terminator, find the next one before reading on
+                if class == ErrorClass::BadFrame && config.frame_terminator.is_some() {
+                    aligned = false;
+                }
+                // piped input and replays don't come back, once they are done so are we
+                if input_ends && class == ErrorClass::Eof {
+                    if is_partial_frame(&e) {
+                        return ConnectionEnd::Exit(
+                            1,
+                            "The input ended part way through a frame".to_string(),
+                        );
+                    }
+                    return ConnectionEnd::Exit(0, "Reached the end of the input".to_string());
+                }
+                let line = match class {
+                    // the frame it was sending was cut short, which is worth a look
+                    ErrorClass::Eof if is_partial_frame(&e) => format!(
+                        "WARNING: The device closed the connection part way through a frame: {}",
+                        e
+                    ),
+                    ErrorClass::Eof => format!(
+                        "The device closed the connection, it may have restarted: {:?}",
+                        e
+                    ),
+                    ErrorClass::ConnectionReset => format!(
+                        "The connection to the device was reset, it may have crashed: {:?}",
+                        e
+                    ),
+                    // the read timeout is WouldBlock on unix, so a timed out read there means the probes went unanswered
+                    ErrorClass::Timeout
+                        if e.kind() == ErrorKind::TimedOut
+                            && config.tcp.keepalive_idle.is_some() =>
+                    {
+                        format!(
+                            "TCP keepalive found the connection to the device dead: {:?}",
+                            e
+                        )
+                    }
+                    ErrorClass::Timeout => format!(
+                        "Timed out reading from the device, the network may have stalled: {:?}",
+                        e
+                    ),
+                    _ => format!("Error reading message from host: {:?}", e),
+                };
+                let kind = read_error_kind(&e);
+                // a device that closes the connection between frames has done nothing wrong, we connect again quietly
+                if class == ErrorClass::Eof && !is_partial_frame(&e) {
+                    info!("{}", line);
+                } else {
+                    error_log.error(&kind, &line);
+                }
+                stats.record_error(&kind);
+                if let Some(kind) = framing_error_kind(&e) {
+                    stats.record_framing_error(kind);
+                }
+                connections.lock().unwrap().read_error(host, class.name());
+                match config.error_policy.action_for(class) {
+                    // the frame has already been consumed so retrying is the same as moving on to the next one
+                    Action::RetryInPlace | Action::SkipFrame => continue,
+                    Action::Resync => {
+                        aligned = false;
+                        continue;
+                    }
+                    Action::ReconnectDevice => return ConnectionEnd::Reconnect,
+                    // there is no modbus connection to reconnect
+                    Action::ReconnectModbus if !modbus_connected => continue,
+                    Action::ReconnectModbus => {
+                        *modbus_client = reconnect_modbus(
+                            modbus_connector,
+                            modbus_backoff,
+                            config,
+                            stats,
+                            audit_log,
+                            hooks,
+                        );
+                        change_filter.reset();
+                        continue;
+                    }
+                    Action::FatalExit => {
+                        return ConnectionEnd::Exit(1, "Exiting due to error policy".to_string())
+                    }
+                }
+            }
+        };
+
+        // {:?} automatically prints all the members of the msg
+        // the lines about a message are left out when the log sampler thinks it isn't interesting, errors never are
+        // the monitor has the screen to itself
+        let logged = monitor.is_none() && log_sampler.should_log(&msg, Instant::now());
+        if logged {
+            debug!(
+                "Received message #{} at {}: {:?}",
+                msg.msg_num_value,
+                format_timestamp(msg.received_at),
+                msg
+            );
+        }
+        stats.record_received(&msg);
+        error_log.flush();
+        if let Some(missing) = gap_detector.missing(&msg) {
+            stats.record_dropped_frames(missing);
+            error_log.error(
+                "MessageGap",
+                &format!(
+                    "WARNING: {} messages from {} are missing before message #{}",
+                    missing,
+                    format_mac(&msg.mac),
+                    msg.msg_num_value
+                ),
+            );
+        }
+        if let Some(state_file) = state_file {
+            state_file.record(&msg, Instant::now());
+        }
+        {
+            let mut version_gate = version_gate.lock().unwrap();
+            if let Err(mismatch) = version_gate.check(&msg) {
+                let dropped = version_gate.action() == MismatchAction::Drop;
+                let outcome = if dropped {
+                    "dropping it"
+                } else {
+                    "forwarding it anyway"
+                };
+                error_log.error(
+                    "VersionMismatch",
+                    &format!("WARNING: {}, {}", mismatch, outcome),
+                );
+                if dropped {
+                    continue;
+                }
+            }
+        }
+        for sink in sinks.iter_mut() {
+            match sink.send(&msg) {
+                Ok(()) => {}
+                Err(SinkError::Full) => {
+                    warn!("The {} is not keeping up, dropping message", sink.name())
+                }
+                Err(e) => error_log.error(
+                    &format!("Sink {}", sink.name()),
+                    &format!("Unable to send message to the {}: {}", sink.name(), e),
+                ),
+            }
+        }
+        if let Some(freshness) = &freshness {
+            freshness.update();
+        }
+        if let Some(alive_log) = &alive_log {
+            alive_log.message(clock.now());
+        }
+        if let Some(monitor) = &monitor {
+            monitor.update(&msg);
+        }
+        if !writes_enabled {
+            if config.once {
+                return ConnectionEnd::Exit(0, "Read one message, exiting (--once)".to_string());
+            }
+            continue;
+        }
+        // before the repeats are looked for, so that a good copy of a weak frame still goes through
+        if !strong_enough(&msg, config.min_rssi) {
+            error_log.error(
+                "WeakSignal",
+                &format!(
+                    "WARNING: Message #{} from {} has an rssi of {}, below --min-rssi {}, not sending it to modbus",
+                    msg.msg_num_value,
+                    format_mac(&msg.mac),
+                    msg.rssi_value,
+                    config.min_rssi
+                ),
+            );
+            continue;
+        }
+        match deduplicator.check(&msg) {
+            Seen::New => {}
+            Seen::Repeat => {
+                if logged {
+                    debug!(
+                        "Message #{} is a repeat, not sending it to modbus",
+                        msg.msg_num_value
+                    );
+                }
+                continue;
+            }
+            Seen::Flood(after) => {
+                stats.record_duplicate_flood();
+                error_log.error(
+                    "DuplicateFlood",
+                    &format!(
+                        "WARNING: Message #{} from {} came again {}ms after the frame before it, sooner than --min-repeat-interval {}ms, the gateway may be sending frames again by mistake. Not sending it to modbus",
+                        msg.msg_num_value,
+                        format_mac(&msg.mac),
+                        after.as_millis(),
+                        config.min_repeat_interval.unwrap_or_default().as_millis()
+                    ),
+                );
+                continue;
+            }
+        }
+
+        let mut msg = msg;
+        let handled = sentinels.apply(&mut msg);
+        for line in &handled.lines {
+            error_log.error("Sentinel", line);
+        }
+        transforms.apply(&mut msg);
+        let mut fields = change_filter.filter(
+            &msg,
+            config.register_maps.for_device(&msg.mac, msg.sensor_id),
+        );
+        for field in Field::ALL.iter() {
+            if handled.skipped.contains(*field) {
+                fields.remove(*field);
+            }
+        }
+        if fields.is_empty() {
+            if logged {
+                debug!("Nothing has changed, not sending message to modbus");
+            }
+            continue;
+        }
+        // with --coalesce a device is written at most once a window, what is written may be a message
+        // that was held earlier rather than this one
+        let (msg, fields) = match coalescer {
+            Some(coalescer) => match coalescer.offer(msg, fields, clock.now()) {
+                Some(ready) => ready,
+                None => continue,
+            },
+            None => (msg, fields),
+        };
+        if let Some(rate_limiter) = rate_limiter {
+            if !rate_limiter.allow(clock.now()) {
+                error_log.error(
+                    "RateLimited",
+                    &format!(
+                        "WARNING: More than --max-msgs-per-sec {} messages a second, not sending message #{} from {} to modbus",
+                        config.max_msgs_per_sec.unwrap_or(0),
+                        msg.msg_num_value,
+                        format_mac(&msg.mac)
+                    ),
+                );
+                stats.record_rate_limited();
+                continue;
+            }
+        }
+
+        // while the circuit breaker is open nothing is written, not even a reconnect is tried
+        if let Some(breaker) = breaker {
+            if !breaker.allow(Instant::now()) {
+                match &outage_queue {
+                    Some(queue) => hold_message(&mut queue.lock().unwrap(), &msg, fields, config),
+                    None => stats.record_breaker_dropped(),
+                }
+                continue;
+            }
+        }
+
+        // while the modbus is down each message is queued (or with only a circuit breaker, dropped) until we
+        // manage to reconnect
+        if *modbus_down {
+            // the queue is only locked once we are connected, /metrics reads it while the connect waits
+            match connect_modbus(modbus_connector, config, stats, audit_log) {
+                Ok(client) => {
+                    *modbus_client = client;
+                    *modbus_down = false;
+                    if let Some(queue) = &outage_queue {
+                        let mut queue = queue.lock().unwrap();
+                        info!("Reconnected to modbus, sending {}", queue.describe());
+                        if let Err(e) = queue.flush(modbus_client.as_mut(), config) {
+                            error!("Error sending what was queued to modbus: {:?}", e);
+                            stats.record_error(modbus_error_kind(&e));
+                            stats.record_modbus_write_error();
+                            *modbus_down = true;
+                        }
+                    } else {
+                        info!("Reconnected to modbus");
+                    }
+                    if !*modbus_down {
+                        hooks.fire(Event::ModbusUp, &config.modbus_host, None);
+                    }
+                }
+                Err(e) => error!("Unable to reconnect modbus client: {:?}", e),
+            }
+            if *modbus_down {
+                if config.once {
+                    return ConnectionEnd::Exit(1, "The modbus is down (--once)".to_string());
+                }
+                if let Some(breaker) = breaker {
+                    breaker.record_failure(Instant::now());
+                }
+                match &outage_queue {
+                    Some(queue) => hold_message(&mut queue.lock().unwrap(), &msg, fields, config),
+                    None => stats.record_breaker_dropped(),
+                }
+                continue;
+            }
+        }
+
+        // send the message to the modbus
+        let mut attempts = 0;
+        let started = Instant::now();
+        // a retry doesn't write the fields that made it the time before again
+        let mut progress = Progress::new(fields);
+        loop {
+            progress.rejected = None;
+            // each retry in place of the error policy makes the --forward-retries tries again
+            let forward = || {
+                forward_message(
+                    &msg,
+                    &mut progress,
+                    config,
+                    last_seen.as_ref(),
+                    strobe.as_mut(),
+                    clock.now(),
+                    modbus_client.as_mut(),
+                )
+            };
+            let e = match forward_with_retries(config.forward_retries, FORWARD_RETRY_DELAY, forward)
+            {
+                Ok(_) => {
+                    // every write of the message, and the retries it took
+                    let took = started.elapsed();
+                    if logged {
+                        debug!(
+                            "Successfully sent message to modbus {}ms after it was received",
+                            msg.age().as_millis()
+                        );
+                    }
+                    stats.record_forwarded();
+                    stats.record_write_latency(took);
+                    change_filter.record_forwarded(&msg, fields);
+                    if let Some(breaker) = breaker {
+                        if breaker.record_success() {
+                            info!("The modbus is taking writes again, closing the circuit breaker");
+                        }
+                    }
+                    if config.once {
+                        return ConnectionEnd::Exit(
+                            0,
+                            "Sent one message, exiting (--once)".to_string(),
+                        );
+                    }
+                    if let Some(load_shedder) = load_shedder {
+                        if load_shedder.record(took) {
+                            warn!(
+                                "Writing to modbus is taking {}ms, longer than --max-forward-latency {}ms, dropping the device connection to skip the frames that have queued up",
+                                took.as_millis(),
+                                config.max_forward_latency.unwrap_or_default().as_millis()
+                            );
+                            stats.record_load_shed();
+                            return ConnectionEnd::Reconnect;
+                        }
+                    }
+                    break;
+                }
+                Err(e) => e,
+            };
+            let kind = modbus_error_kind(&e);
+            match &progress.rejected {
+                // the modbus is fine, it is the register map that is wrong (the error policy doesn't
+                // reconnect for an exception unless it has been told to)
+                Some(write) => error_log.error(
+                    kind,
+                    &format!(
+                        "Error {}, the modbus server doesn't accept it so check the register map",
+                        write
+                    ),
+                ),
+                None => error_log.error(kind, &format!("Error sending message to modbus: {:?}", e)),
+            }
+            stats.record_error(kind);
+            stats.record_modbus_write_error();
+            // a modbus that keeps failing is left alone for a while rather than reconnected again and again
+            let opened = match breaker {
+                Some(breaker) => breaker.record_failure(Instant::now()),
+                None => false,
+            };
+            if opened && !config.once {
+                let breaker = breaker.as_ref().unwrap();
+                warn!(
+                    "{} writes to modbus in a row have failed, not writing to it for {}s",
+                    breaker.threshold(),
+                    breaker.cooldown().as_secs()
+                );
+                if let Some(queue) = &outage_queue {
+                    hold_message(&mut queue.lock().unwrap(), &msg, fields, config);
+                }
+                if !*modbus_down {
+                    hooks.fire(Event::ModbusDown, &config.modbus_host, None);
+                }
+                *modbus_down = true;
+                break;
+            }
+            match config
+                .error_policy
+                .action_for(ErrorClass::of_modbus_error(&e))
+            {
+                Action::RetryInPlace if attempts < RETRY_IN_PLACE_ATTEMPTS => {
+                    attempts += 1;
+                }
+                // there is only the one message, it has to get through
+                _ if config.once => {
+                    return ConnectionEnd::Exit(
+                        1,
+                        "Unable to send the message to modbus (--once)".to_string(),
+                    )
+                }
+                // we have run out of retries so the modbus connection is probably broken.
+                // The whole message is queued, the PLC may not be the same one by the time we are back
+                Action::RetryInPlace | Action::ReconnectModbus => {
+                    match &outage_queue {
+                        // reconnect with the next message, until then the writes wait in the queue
+                        Some(queue) => {
+                            hold_message(&mut queue.lock().unwrap(), &msg, fields, config);
+                            *modbus_down = true;
+                            hooks.fire(Event::ModbusDown, &config.modbus_host, None);
+                        }
+                        None => {
+                            *modbus_client = reconnect_modbus(
+                                modbus_connector,
+                                modbus_backoff,
+                                config,
+                                stats,
+                                audit_log,
+                                hooks,
+                            );
+                            change_filter.reset();
+                        }
+                    }
+                    break;
+                }
+                Action::ReconnectDevice => return ConnectionEnd::Reconnect,
+                Action::SkipFrame | Action::Resync => break,
+                Action::FatalExit => {
+                    return ConnectionEnd::Exit(1, "Exiting due to error policy".to_string())
+                }
+            }
+        }
+    }
+}
+
+// Throws away the current modbus connection and creates a new one, backing off while the modbus is unreachable.
+// There is nothing to write to until it is back so the device waits too
+fn reconnect_modbus(
+    connector: &ModbusConnector,
+    backoff: &mut Backoff,
+    config: &Config,
+    stats: &Stats,
+    audit_log: &Option<AuditLog>,
+    hooks: &Hooks,
+) -> Box<dyn ModbusClient> {
+    info!("Reconnecting to modbus ...");
+    hooks.fire(Event::ModbusDown, &config.modbus_host, None);
+    let client = retry_with_backoff(
+        backoff,
+        || connect_modbus(connector, config, stats, audit_log),
+        |e, delay| {
+            error!(
+                "Unable to reconnect modbus client: {:?}, retrying in {:.1}s",
+                e,
+                delay.as_secs_f64()
+            );
+            thread::sleep(delay);
+        },
+    );
+    hooks.fire(Event::ModbusUp, &config.modbus_host, None);
+    client
+}
+
+// Creates the first modbus connection, backing off while the modbus is unreachable the same way as when it goes
+// away later, so a router that starts before its PLC waits for it rather than exiting. Nothing has been read from
+// the device yet so it is connected to once the modbus is there
+fn connect_modbus_at_startup(
+    connector: &ModbusConnector,
+    backoff: &mut Backoff,
+    config: &Config,
+    stats: &Stats,
+    audit_log: &Option<AuditLog>,
+    hooks: &Hooks,
+) -> Box<dyn ModbusClient> {
+    let mut down = false;
+    let client = retry_with_backoff(
+        backoff,
+        || connect_modbus(connector, config, stats, audit_log),
+        |e, delay| {
+            if !down {
+                hooks.fire(Event::ModbusDown, &config.modbus_host, None);
+                down = true;
+            }
+            error!(
+                "Unable to create modbus client: {:?}, retrying in {:.1}s",
+                e,
+                delay.as_secs_f64()
+            );
+            thread::sleep(delay);
+        },
+    );
+    if down {
+        info!("Connected to modbus at {}", config.modbus_host);
+        hooks.fire(Event::ModbusUp, &config.modbus_host, None);
+    }
+    client
+}
+
+// A serial port is framed by us, there is nothing to number. Any standby hosts are written to as well as the
+// modbus host, each over a connection of its own
+fn modbus_connector(config: &Config, mismatches: Arc<AtomicU64>, stats: &Stats) -> ModbusConnector {
+    #[cfg(feature = "serial")]
+    {
+        if let Some(serial) = config.serial_config() {
+            return ModbusConnector::Serial {
+                config: serial,
+                unit: config.modbus_unit,
+            };
+        }
+    }
+    if config.standby_hosts.is_empty() {
+        return tcp_modbus_connector(config, &config.modbus_host, mismatches);
+    }
+    let endpoints = iter::once(&config.modbus_host)
+        .chain(config.standby_hosts.iter())
+        .map(|host| {
+            let connector = tcp_modbus_connector(config, host, mismatches.clone());
+            (host.clone(), Rc::new(connector))
+        })
+        .collect();
+    ModbusConnector::Redundant {
+        endpoints,
+        stats: stats.clone(),
+    }
+}
+
+// The modbus crate numbers the requests unless we have been asked to take that over
+fn tcp_modbus_connector(
+    config: &Config,
+    host: &str,
+    mismatches: Arc<AtomicU64>,
+) -> ModbusConnector {
+    if !own_transaction_ids(config) {
+        return ModbusConnector::Direct {
+            host: host.to_string(),
+            port: config.modbus_port,
+            unit: config.modbus_unit,
+        };
+    }
+    ModbusConnector::Framed {
+        host: host.to_string(),
+        port: config.modbus_port,
+        unit: config.modbus_unit,
+        transaction_ids: TransactionIdConfig {
+            ids: config.transaction_ids.unwrap_or(TransactionIds::Sequential),
+            check: config.check_transaction_ids,
+        },
+        mismatches,
+    }
+}
+
+fn own_transaction_ids(config: &Config) -> bool {
+    config.transaction_ids.is_some() || config.check_transaction_ids
+}
+
+// The main loop's modbus connection, with failures injected into it, the writes read back and put on record
+// if asked for
+fn connect_modbus(
+    connector: &ModbusConnector,
+    config: &Config,
+    stats: &Stats,
+    audit_log: &Option<AuditLog>,
+) -> io::Result<Box<dyn ModbusClient>> {
+    let client = connector.connect()?;
+    let client: Box<dyn ModbusClient> = match &config.chaos {
+        Some(chaos) => Box::new(ChaosClient::new(client, chaos)),
+        None => client,
+    };
+    let client: Box<dyn ModbusClient> = if config.verify_writes {
+        Box::new(VerifyingClient::new(client, stats.clone()))
+    } else {
+        client
+    };
+    // outermost, so what is recorded is what the router asked for
+    Ok(match audit_log {
+        Some(audit_log) => Box::new(AuditingClient::new(client, audit_log.clone())),
+        None => client,
+    })
+}
+
+// The frames read without any other formats configured are FRAME_LEN long, and the bytes of a longer payload
+// after that are ignored
+fn decode_standard(raw: &[u8], macs: &[[u8; 6]], config: &Config) -> Result<Frame, io::Error> {
+    let mut buffer = [0; FRAME_LEN];
+    buffer.copy_from_slice(&raw[..FRAME_LEN]);
+    if let Some(checksum) = config.checksum {
+        verify_checksum(&buffer, checksum)?;
+    }
+    if let Some(len) = config.payload_len {
+        check_payload_len(raw, len)?;
+    }
+    Ok(decode_frame_as(
+        &buffer,
+        config.start_seq,
+        macs,
+        config.endian,
+    )?)
+}
+
+// Fills in the sensor id of a message, if the gateway sends one, and checks that it is a sensor we know about
+fn identify_sensor(frame: Frame, raw: &[u8], config: &Config) -> Result<Frame, io::Error> {
+    match (frame, config.sensor_id_offset) {
+        (Frame::Message(mut msg), Some(offset)) => {
+            // other frame formats can be shorter than the standard one
+            let sensor_id = *raw.get(offset).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    "The frame is too short for a sensor id",
+                )
+            })?;
+            if !config.sensor_ids.is_empty() && !config.sensor_ids.contains(&sensor_id) {
+                let e = io::Error::new(ErrorKind::InvalidData, "Unexpected sensor id");
+                return Err(e);
+            }
+            msg.sensor_id = Some(sensor_id);
+            Ok(Frame::Message(msg))
+        }
+        (frame, _) => Ok(frame),
+    }
+}
+
+// Writes the magnitude of the vibration, if asked for, whenever the vibration itself is written
+fn send_vib_magnitude(
+    msg: &DeviceMessage,
+    fields: FieldSet,
+    config: &Config,
+    modbus_client: &mut dyn ModbusClient,
+) -> Result<(), modbus::Error> {
+    match config.vib_magnitude_register {
+        Some(register) if fields.contains(Field::Vibration) => {
+            let register_map = config.register_maps.for_device(&msg.mac, msg.sensor_id);
+            let magnitude = match register_map.entry(Field::Vibration).signedness {
+                Signedness::Unsigned => msg.vib_magnitude(),
+                Signedness::Signed | Signedness::Offset => msg.vib_signed_magnitude(),
+            };
+            modbus_client.write_single_register(register, magnitude)
+        }
+        _ => Ok(()),
+    }
+}
+
+// The transforms the config asks for, in the order they were given
+fn transforms(config: &Config) -> Pipeline {
+    let mut pipeline = Pipeline::new();
+    for (field, min, max) in &config.clamps {
+        pipeline.add(Clamp::new(*field, *min, *max));
+    }
+    pipeline
+}
+
+// How far the writes of a message have got, so that trying it again carries on from there
+struct Progress {
+    // every field of the message that is to be written
+    fields: FieldSet,
+    // the fields that haven't made it to the modbus yet
+    remaining: FieldSet,
+    // the failed write, when the modbus server turned it down
+    rejected: Option<String>,
+}
+
+impl Progress {
+    fn new(fields: FieldSet) -> Progress {
+        Progress {
+            fields,
+            remaining: fields,
+            rejected: None,
+        }
+    }
+}
+
+// Makes the writes of a message that are still to do: its fields and then the vibration magnitude, the
+// status word, the last seen time and the strobe. Once the fields are in they aren't written again when one
+// of the writes after them fails, those are only the registers that say something about the fields
+fn forward_message(
+    msg: &DeviceMessage,
+    progress: &mut Progress,
+    config: &Config,
+    last_seen: Option<&LastSeen>,
+    strobe: Option<&mut Strobe>,
+    now: SystemTime,
+    modbus_client: &mut dyn ModbusClient,
+) -> Result<(), modbus::Error> {
+    let send = if config.batch_writes {
+        send_message_batched
+    } else {
+        send_message_to_modbus
+    };
+    if !progress.remaining.is_empty() {
+        send(
+            msg,
+            progress.remaining,
+            config.register_maps.for_device(&msg.mac, msg.sensor_id),
+            config.write_delay,
+            modbus_client,
+        )
+        .map_err(|e| {
+            progress.remaining = e.remaining(progress.remaining);
+            if e.rejected() {
+                progress.rejected = Some(e.to_string());
+            }
+            e.error
+        })?;
+        progress.remaining = FieldSet::empty();
+    }
+    send_vib_magnitude(msg, progress.fields, config, modbus_client)?;
+    send_status_word(msg, config, modbus_client)?;
+    if let Some(last_seen) = last_seen {
+        last_seen.write(modbus_client, now)?;
+    }
+    // after the values so that the PLC sees them before the edge
+    if let Some(strobe) = strobe {
+        strobe.write(modbus_client, msg)?;
+    }
+    Ok(())
+}
+
+// Makes the writes of a message up to retries more times when the connection fails, waiting delay in between,
+// for blips that are over by the time the router tries again (see --forward-retries). An exception is the modbus
+// server answering, so it isn't tried again here
+fn forward_with_retries<F>(
+    retries: u32,
+    delay: Duration,
+    mut forward: F,
+) -> Result<(), modbus::Error>
+where
+    F: FnMut() -> Result<(), modbus::Error>,
+{
+    let mut attempt = 0;
+    loop {
+        match forward() {
+            Err(e)
+                if attempt < retries && ErrorClass::of_modbus_error(&e) == ErrorClass::ModbusIo =>
+            {
+                attempt += 1;
+                warn!(
+                    "Unable to send the message to modbus: {:?}, trying again in {}ms (retry {} of {})",
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+// The status word goes with every message that is written, whichever of its fields have changed
+fn send_status_word(
+    msg: &DeviceMessage,
+    config: &Config,
+    modbus_client: &mut dyn ModbusClient,
+) -> Result<(), modbus::Error> {
+    match config.status_register {
+        Some(register) => {
+            let register_map = config.register_maps.for_device(&msg.mac, msg.sensor_id);
+            let thresholds = Thresholds {
+                signed_vibration: register_map.entry(Field::Vibration).signedness
+                    != Signedness::Unsigned,
+                ..config.status_thresholds
+            };
+            modbus_client.write_single_register(register, msg.status_word(&thresholds))
+        }
+        None => Ok(()),
+    }
+}
+
+// What is held on to while the modbus is down, the latest write to each register (--write-queue) or every
+// message (--retry-queue)
+enum OutageQueue {
+    Writes(WriteQueue),
+    Messages(RetryQueue),
+}
+
+impl OutageQueue {
+    // What a flush is about to send, for the log
+    fn describe(&self) -> String {
+        match self {
+            OutageQueue::Writes(queue) => format!("{} queued writes", queue.len()),
+            OutageQueue::Messages(queue) => format!("{} queued messages", queue.len()),
+        }
+    }
+
+    // Sends everything that is held, what doesn't make it stays held for next time
+    fn flush(
+        &mut self,
+        modbus_client: &mut dyn ModbusClient,
+        config: &Config,
+    ) -> Result<(), modbus::Error> {
+        match self {
+            OutageQueue::Writes(queue) => queue.flush(modbus_client, config.write_delay),
+            OutageQueue::Messages(queue) => queue.drain(|msg, fields| {
+                let mut progress = Progress::new(fields);
+                // the same as a held write, a held message doesn't move the last seen time or the strobe
+                match forward_message(
+                    msg,
+                    &mut progress,
+                    config,
+                    None,
+                    None,
+                    SystemTime::now(),
+                    modbus_client,
+                ) {
+                    // it would never get through, so it mustn't hold up the ones behind it
+                    Err(e) if progress.rejected.is_some() => {
+                        error!(
+                            "Error {}, dropping queued message #{}, the modbus server doesn't accept it",
+                            e, msg.msg_num_value
+                        );
+                        Ok(())
+                    }
+                    result => result,
+                }
+            }),
+        }
+    }
+
+    fn metrics(&self) -> String {
+        match self {
+            OutageQueue::Writes(queue) => queue.metrics(),
+            OutageQueue::Messages(queue) => queue.metrics(),
+        }
+    }
+}
+
+// Puts the writes of the message (or the message itself) in the queue instead of sending them
+fn hold_message(queue: &mut OutageQueue, msg: &DeviceMessage, fields: FieldSet, config: &Config) {
+    match queue {
+        OutageQueue::Writes(queue) => {
+            let register_map = config.register_maps.for_device(&msg.mac, msg.sensor_id);
+            // writing to the queue never fails and there is no point pacing writes that aren't going anywhere yet
+            let _ =
+                send_message_to_modbus(msg, fields, register_map, Duration::from_millis(0), queue);
+            let _ = send_vib_magnitude(msg, fields, config, queue);
+            let _ = send_status_word(msg, config, queue);
+        }
+        OutageQueue::Messages(queue) => queue.push(msg.clone(), fields),
+    }
+    info!("Modbus is down, queued message #{}", msg.msg_num_value);
+}
+
+// Frames with a weak signal are often corrupt, or from a sensor at the edge of range
+fn strong_enough(msg: &DeviceMessage, min_rssi: u8) -> bool {
+    msg.rssi_value >= min_rssi
+}
+
+// Sets the flag on SIGINT or SIGTERM. A second signal while we are still finishing off stops us straight away
+fn catch_shutdown_signals(flag: &Arc<AtomicBool>) -> io::Result<()> {
+    for signal in [SIGINT, SIGTERM].iter() {
+        signal_hook::flag::register_conditional_shutdown(*signal, 1, Arc::clone(flag))?;
+        signal_hook::flag::register(*signal, Arc::clone(flag))?;
+    }
+    Ok(())
+}
+
+// Reports the error along with the summary and exits the program with a non zero exit code
+fn fatal(stats: &Stats, config: &Config, error: &str) -> ! {
+    exit(stats, config, error, 1)
+}
+
+fn exit(stats: &Stats, config: &Config, error: &str, code: i32) -> ! {
+    // otherwise the error ends up on the monitor's screen, which is about to disappear
+    monitor::restore();
+    if code == 0 {
+        info!("{}", error);
+    } else {
+        error!("{}", error);
+    }
+    report(stats, config.log_format);
+    process::exit(code);
+}
+
+// Prints the summary to std out in the requested format
+fn report(stats: &Stats, format: LogFormat) {
+    let summary = stats.snapshot();
+    match format {
+        LogFormat::Json => match serde_json::to_string(&summary) {
+            Ok(json) => println!("{}", json),
+            Err(e) => error!("Unable to serialize summary: {:?}", e),
+        },
+        LogFormat::Human => print!("{}", summary),
+    }
+}
+
+// Seconds (with milliseconds) since the unix epoch, e.g. 1571388795.123
+fn format_timestamp(time: Option<SystemTime>) -> String {
+    match time.and_then(|time| time.duration_since(UNIX_EPOCH).ok()) {
+        Some(since_epoch) => format!(
+            "{}.{:03}",
+            since_epoch.as_secs(),
+            since_epoch.subsec_millis()
+        ),
+        None => "unknown".to_string(),
+    }
+}
+
+// A short description of a read error used to count errors by type
+// Framing errors carry their own description, anything else is described by its ErrorKind
+fn read_error_kind(e: &io::Error) -> String {
+    match e.kind() {
+        ErrorKind::InvalidData => e.to_string(),
+        kind => format!("{:?}", kind),
+    }
+}
+
+// A short description of a modbus error used to count errors by type
+fn modbus_error_kind(e: &modbus::Error) -> &'static str {
+    match e {
+        modbus::Error::Exception(_) => "ModbusException",
+        e if TransactionIdMismatch::is(e) => "ModbusTransactionId",
+        modbus::Error::Io(_) => "ModbusIo",
+        _ => "ModbusProtocol",
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::encode_frame;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+
+    use crate::testing::{sample_message, RecordingClient, Write};
+
+    #[test]
+    fn weak_signals_are_not_forwarded() {
+        let msg = |rssi_value| DeviceMessage {
+            rssi_value,
+            ..sample_message()
+        };
+        assert!(!strong_enough(&msg(119), 120));
+        assert!(strong_enough(&msg(120), 120));
+        assert!(strong_enough(&msg(255), 120));
+        // the default lets everything through
+        assert!(strong_enough(&msg(0), 0));
+    }
+
+    // Fails the first few writes and makes the rest, a modbus connection with a blip in it
+    struct FlakyClient {
+        failures_left: usize,
+        inner: RecordingClient,
+    }
+
+    impl ModbusClient for FlakyClient {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err(modbus::Error::InvalidResponse);
+            }
+            self.inner.write_single_register(address, value)
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            if self.failures_left > 0 {
+                self.failures_left -= 1;
+                return Err(modbus::Error::InvalidResponse);
+            }
+            self.inner.write_multiple_registers(address, values)
+        }
+    }
+
+    #[test]
+    fn a_forward_is_tried_again_after_a_failed_write() {
+        use crate::register_map::RegisterMap;
+        // the attempts a message takes on a modbus that fails twice, and the writes that made it
+        let forward = |retries| {
+            let mut client = FlakyClient {
+                failures_left: 2,
+                inner: RecordingClient::default(),
+            };
+            let mut attempts = 0;
+            let result = forward_with_retries(retries, Duration::from_millis(0), || {
+                attempts += 1;
+                send_message_to_modbus(
+                    &sample_message(),
+                    FieldSet::all(),
+                    &RegisterMap::default(),
+                    Duration::from_millis(0),
+                    &mut client,
+                )
+                .map_err(|e| e.error)
+            });
+            (result.is_ok(), attempts, client.inner.writes.len())
+        };
+        let writes = sample_message().to_registers().len();
+        assert_eq!(forward(3), (true, 3, writes));
+        assert_eq!(forward(2), (true, 3, writes));
+        assert_eq!(forward(1), (false, 2, 0));
+        // the default is the one attempt
+        assert_eq!(forward(0), (false, 1, 0));
+
+        // the modbus server turning the write down isn't something to wait out
+        let mut attempts = 0;
+        let result = forward_with_retries(3, Duration::from_millis(0), || {
+            attempts += 1;
+            Err(modbus::Error::Exception(
+                modbus::ExceptionCode::IllegalDataAddress,
+            ))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn the_clamps_are_applied_in_order() {
+        let config = Config {
+            clamps: vec![(Field::Battery, 10, 100), (Field::Battery, 0, 5)],
+            ..Config::default()
+        };
+        let mut msg = sample_message();
+        transforms(&config).apply(&mut msg);
+        assert_eq!(msg.batt_value, 5);
+        assert!(transforms(&Config::default()).is_empty());
+    }
+
+    // Turns down the first write to one register and takes every other write
+    struct FailsOnceAt {
+        address: u16,
+        failed: bool,
+        inner: RecordingClient,
+    }
+
+    impl ModbusClient for FailsOnceAt {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            if address == self.address && !self.failed {
+                self.failed = true;
+                return Err(modbus::Error::InvalidResponse);
+            }
+            self.inner.write_single_register(address, value)
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            self.inner.write_multiple_registers(address, values)
+        }
+    }
+
+    #[test]
+    fn the_fields_are_not_written_again_when_a_write_after_them_fails() {
+        let config = Config {
+            last_seen_registers: Some((300, 310)),
+            ..Config::default()
+        };
+        let last_seen = LastSeen::new(300, 310);
+        let mut client = FailsOnceAt {
+            address: 300,
+            failed: false,
+            inner: RecordingClient::default(),
+        };
+        let msg = sample_message();
+        let mut progress = Progress::new(FieldSet::all());
+        let mut forward = |client: &mut FailsOnceAt| {
+            forward_message(
+                &msg,
+                &mut progress,
+                &config,
+                Some(&last_seen),
+                None,
+                UNIX_EPOCH + Duration::from_secs(1_571_388_795),
+                client,
+            )
+        };
+
+        // the fields made it, the last seen time didn't
+        assert!(forward(&mut client).is_err());
+        let writes = msg.to_registers().len();
+        assert_eq!(client.inner.writes.len(), writes);
+
+        // only the last seen time is written the second time around
+        assert!(forward(&mut client).is_ok());
+        assert_eq!(
+            client.inner.writes[writes..],
+            [Write::Single(300, 0x5DA9), Write::Single(310, 0x7D7B)]
+        );
+        assert!(progress.remaining.is_empty());
+    }
+
+    #[test]
+    fn format_timestamp_millis() {
+        let time = UNIX_EPOCH + std::time::Duration::from_millis(1_571_388_795_042);
+        assert_eq!(format_timestamp(Some(time)), "1571388795.042");
+        assert_eq!(format_timestamp(None), "unknown");
+    }
+
+    #[test]
+    fn identify_sensor_by_offset() {
+        let mut config = Config::default();
+        let raw = crate::frame::encode_frame(&sample_message());
+        let frame = Frame::Message(sample_message());
+        // without an offset there is no sensor id
+        match identify_sensor(frame.clone(), &raw, &config).unwrap() {
+            Frame::Message(msg) => assert_eq!(msg.sensor_id, None),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        // the version byte
+        config.sensor_id_offset = Some(24);
+        match identify_sensor(frame.clone(), &raw, &config).unwrap() {
+            Frame::Message(msg) => assert_eq!(msg.sensor_id, Some(2)),
+            other => panic!("unexpected frame: {:?}", other),
+        }
+
+        config.sensor_ids = vec![1, 3];
+        let e = identify_sensor(frame, &raw, &config).unwrap_err();
+        assert_eq!(e.to_string(), "Unexpected sensor id");
+    }
+
+    #[test]
+    fn standby_hosts_are_written_to_as_well() {
+        let config = Config {
+            modbus_host: "10.0.0.2".to_string(),
+            standby_hosts: vec!["10.0.0.3".to_string()],
+            ..Config::default()
+        };
+        let hosts: Vec<String> =
+            match modbus_connector(&config, Arc::new(AtomicU64::new(0)), &Stats::new()) {
+                ModbusConnector::Redundant { endpoints, .. } => endpoints
+                    .iter()
+                    .map(|(host, connector)| match &**connector {
+                        ModbusConnector::Direct { host: direct, .. } => {
+                            assert_eq!(direct, host);
+                            host.clone()
+                        }
+                        _ => panic!("expected a tcp connector"),
+                    })
+                    .collect(),
+                _ => panic!("expected a redundant connector"),
+            };
+        assert_eq!(hosts, ["10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn startup_waits_for_a_modbus_that_isnt_up_yet() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let config = Config {
+            modbus_host: "127.0.0.1".to_string(),
+            modbus_port: port,
+            ..Config::default()
+        };
+        let connector = modbus_connector(&config, Arc::new(AtomicU64::new(0)), &Stats::new());
+        // nothing is listening yet
+        assert!(connector.connect().is_err());
+
+        // the PLC comes up a little after the router
+        let plc = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = std::net::TcpListener::bind(("127.0.0.1", port)).unwrap();
+            listener.accept().map(|_| ())
+        });
+        let mut backoff = Backoff::new(Duration::from_millis(50), Duration::from_millis(100));
+        let started = Instant::now();
+        connect_modbus_at_startup(
+            &connector,
+            &mut backoff,
+            &config,
+            &Stats::new(),
+            &None,
+            &Hooks::start(None, None),
+        );
+        assert!(started.elapsed() >= Duration::from_millis(200));
+        plc.join().unwrap().unwrap();
+    }
+
+    // A recording client the test can still look at once the run state has it
+    #[derive(Clone, Default)]
+    struct SharedClient(Rc<RefCell<RecordingClient>>);
+
+    impl ModbusClient for SharedClient {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            self.0.borrow_mut().write_single_register(address, value)
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            self.0
+                .borrow_mut()
+                .write_multiple_registers(address, values)
+        }
+    }
+
+    // A dry run never connects to the modbus, its client is swapped for the recording one
+    fn run_state(config: &Config, input_ends: bool, client: &SharedClient) -> RunState {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let mut state = RunState::start(config, false, "device", input_ends, shutdown);
+        state.modbus_client = Box::new(client.clone());
+        state
+    }
+
+    fn frames(msg_nums: &[u16]) -> Box<dyn Read + Send> {
+        let mut bytes = Vec::new();
+        for msg_num in msg_nums {
+            let msg = DeviceMessage {
+                msg_num_value: *msg_num,
+                ..sample_message()
+            };
+            bytes.extend_from_slice(&encode_frame(&msg));
+        }
+        Box::new(Cursor::new(bytes))
+    }
+
+    // The message numbers that made it to the modbus, in the order they were written
+    fn written(client: &SharedClient) -> Vec<u16> {
+        let client = client.0.borrow();
+        client
+            .writes
+            .iter()
+            .filter_map(|write| match write {
+                Write::Single(5, msg_num) => Some(*msg_num),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn a_connection_writes_each_message_until_the_device_hangs_up() {
+        let config = Config {
+            dry_run: true,
+            ..Config::default()
+        };
+        let client = SharedClient::default();
+        let mut state = run_state(&config, false, &client);
+        let end = run_connection(&mut state, frames(&[100, 101]), &config);
+        assert_eq!(end, ConnectionEnd::Reconnect);
+        assert_eq!(written(&client), vec![100, 101]);
+        let registers = client.0.borrow().registers();
+        assert_eq!((registers[&1], registers[&2], registers[&6]), (0, 84, 189));
+        assert_eq!(state.device_mac, Some(sample_message().mac));
+
+        // the state carries on into the next connection, so a repeat is still a repeat
+        let end = run_connection(&mut state, frames(&[101, 102]), &config);
+        assert_eq!(end, ConnectionEnd::Reconnect);
+        assert_eq!(written(&client), vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn the_end_of_the_input_is_the_end_of_the_run() {
+        let config = Config {
+            dry_run: true,
+            ..Config::default()
+        };
+        let client = SharedClient::default();
+        let mut state = run_state(&config, true, &client);
+        let end = run_connection(&mut state, frames(&[100]), &config);
+        assert_eq!(
+            end,
+            ConnectionEnd::Exit(0, "Reached the end of the input".to_string())
+        );
+        assert_eq!(written(&client), vec![100]);
+    }
+
+    #[test]
+    fn a_connection_is_made_again_after_max_frames_per_connection() {
+        let config = Config {
+            dry_run: true,
+            max_frames_per_connection: Some(3),
+            ..Config::default()
+        };
+        let client = SharedClient::default();
+        let mut state = run_state(&config, false, &client);
+        let end = run_connection(&mut state, frames(&[100, 101, 102, 103, 104]), &config);
+        assert_eq!(end, ConnectionEnd::Reconnect);
+        assert_eq!(written(&client), vec![100, 101, 102]);
+    }
+
+    #[cfg(feature = "serial")]
+    #[test]
+    fn a_serial_port_replaces_the_modbus_host() {
+        let config = Config {
+            modbus_unit: 17,
+            serial: Some("/dev/ttyUSB0".to_string()),
+            serial_baud: 19200,
+            ..Config::default()
+        };
+        match modbus_connector(&config, Arc::new(AtomicU64::new(0)), &Stats::new()) {
+            ModbusConnector::Serial {
+                config: serial,
+                unit,
+            } => {
+                assert_eq!(serial, config.serial_config().unwrap());
+                assert_eq!(serial.baud, 19200);
+                assert_eq!(unit, 17);
+            }
+            _ => panic!("expected a serial connector"),
+        }
+    }
+}
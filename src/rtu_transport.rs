@@ -0,0 +1,346 @@
+use crate::modbus_client::{write_in_chunks, ModbusClient, RegisterKind};
+use crate::stream_transport::{
+    exception_code, ReadWrite, READ_HOLDING_REGISTERS, READ_INPUT_REGISTERS,
+    WRITE_MULTIPLE_REGISTERS, WRITE_SINGLE_COIL, WRITE_SINGLE_REGISTER,
+};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io;
+use std::io::Read;
+#[cfg(feature = "serial")]
+use std::time::Duration;
+
+// How long to wait for the PLC to answer a request before the write fails
+#[cfg(feature = "serial")]
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// The parity bit of each character on the serial line
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl Parity {
+    pub fn from_name(name: &str) -> Option<Parity> {
+        match name {
+            "none" => Some(Parity::None),
+            "even" => Some(Parity::Even),
+            "odd" => Some(Parity::Odd),
+            _ => None,
+        }
+    }
+}
+
+// How the serial port to a Modbus RTU PLC is set up (see --serial). The characters are always 8 data bits,
+// which is all RTU allows
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialConfig {
+    // e.g. /dev/ttyUSB0 or COM3
+    pub device: String,
+    pub baud: u32,
+    pub parity: Parity,
+    // 1 or 2
+    pub stop_bits: u8,
+}
+
+// Opens the serial port, only built with --features serial
+#[cfg(feature = "serial")]
+pub fn open(config: &SerialConfig) -> io::Result<Box<dyn serialport::SerialPort>> {
+    let parity = match config.parity {
+        Parity::None => serialport::Parity::None,
+        Parity::Even => serialport::Parity::Even,
+        Parity::Odd => serialport::Parity::Odd,
+    };
+    let stop_bits = match config.stop_bits {
+        2 => serialport::StopBits::Two,
+        _ => serialport::StopBits::One,
+    };
+    let port = serialport::new(config.device.as_str(), config.baud)
+        .data_bits(serialport::DataBits::Eight)
+        .parity(parity)
+        .stop_bits(stop_bits)
+        .timeout(RESPONSE_TIMEOUT)
+        .open()?;
+    Ok(port)
+}
+
+// The CRC at the end of every RTU frame, sent low byte first
+pub fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for byte in bytes {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xA001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+// Modbus RTU over a stream, which on site is a serial port to an RS-485 line. The requests are the same as
+// over tcp, only framed with the unit id in front and a CRC behind instead of the MBAP header, so the rest of
+// the router doesn't know the difference
+pub struct RtuTransport {
+    stream: Box<dyn ReadWrite>,
+    unit_id: u8,
+}
+
+impl RtuTransport {
+    pub fn new(stream: Box<dyn ReadWrite>, unit_id: u8) -> RtuTransport {
+        RtuTransport { stream, unit_id }
+    }
+
+    // Sends a request and returns the pdu of the response (without the function code).
+    // RTU frames don't say how long they are so the length comes from the function
+    fn request(&mut self, function: u8, data: &[u8]) -> Result<Vec<u8>, modbus::Error> {
+        let mut frame = Vec::with_capacity(4 + data.len());
+        frame.push(self.unit_id);
+        frame.push(function);
+        frame.extend_from_slice(data);
+        let crc = crc16(&frame);
+        frame.push(crc as u8);
+        frame.push((crc >> 8) as u8);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+
+        let mut response = vec![0; 2];
+        self.stream.read_exact(&mut response)?;
+        let length = match response[1] {
+            code if code == function | 0x80 => 1,
+            READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                let count = read_byte(&mut self.stream)?;
+                response.push(count);
+                count as usize
+            }
+            _ => 4,
+        };
+        let mut rest = vec![0; length + 2];
+        self.stream.read_exact(&mut rest)?;
+        response.extend_from_slice(&rest);
+        let crc = (&response[response.len() - 2..]).read_u16::<LittleEndian>()?;
+        response.truncate(response.len() - 2);
+        if crc != crc16(&response) || response[0] != self.unit_id {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        if response[1] == function | 0x80 {
+            return match exception_code(response[2]) {
+                Some(code) => Err(modbus::Error::Exception(code)),
+                None => Err(modbus::Error::InvalidResponse),
+            };
+        }
+        if response[1] != function {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(response.split_off(2))
+    }
+
+    // One write multiple registers request, no bigger than the modbus allows (see write_in_chunks)
+    fn write_chunk(&mut self, address: u16, values: &[u16]) -> Result<(), modbus::Error> {
+        let mut data = Vec::with_capacity(5 + values.len() * 2);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(values.len() as u16)?;
+        data.push((values.len() * 2) as u8);
+        for value in values {
+            data.write_u16::<BigEndian>(*value)?;
+        }
+
+        // the PLC replies with the address and quantity written
+        let response = self.request(WRITE_MULTIPLE_REGISTERS, &data)?;
+        if response[..] != data[..4] {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    // write single register and write single coil both echo the request back
+    fn write_echoed(
+        &mut self,
+        function: u8,
+        address: u16,
+        value: u16,
+    ) -> Result<(), modbus::Error> {
+        let mut data = Vec::with_capacity(4);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(value)?;
+        let response = self.request(function, &data)?;
+        if response != data {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+}
+
+fn read_byte(stream: &mut dyn ReadWrite) -> io::Result<u8> {
+    let mut byte = [0];
+    stream.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+impl ModbusClient for RtuTransport {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.write_echoed(WRITE_SINGLE_REGISTER, address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        write_in_chunks(address, values, |address, chunk| {
+            self.write_chunk(address, chunk)
+        })
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        let function = match kind {
+            RegisterKind::Holding => READ_HOLDING_REGISTERS,
+            RegisterKind::Input => READ_INPUT_REGISTERS,
+        };
+        let mut data = Vec::with_capacity(4);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(count)?;
+
+        // the PLC replies with a byte count and then the values
+        let response = self.request(function, &data)?;
+        if response.len() != 1 + count as usize * 2 || response[0] as usize != count as usize * 2 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        let mut values = &response[1..];
+        (0..count)
+            .map(|_| Ok(values.read_u16::<BigEndian>()?))
+            .collect()
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        // on is 0xFF00 and off is 0x0000, nothing else is allowed
+        self.write_echoed(
+            WRITE_SINGLE_COIL,
+            address,
+            if value { 0xFF00 } else { 0x0000 },
+        )
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::{Cursor, Write};
+    use std::rc::Rc;
+
+    // A serial line with canned replies that keeps hold of everything written to it
+    struct MockPort {
+        replies: Cursor<Vec<u8>>,
+        written: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for MockPort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for MockPort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn transport(replies: Vec<u8>) -> (RtuTransport, Rc<RefCell<Vec<u8>>>) {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let port = MockPort {
+            replies: Cursor::new(replies),
+            written: written.clone(),
+        };
+        (RtuTransport::new(Box::new(port), 17), written)
+    }
+
+    // the frame with its CRC on the end
+    fn framed(bytes: &[u8]) -> Vec<u8> {
+        let crc = crc16(bytes);
+        let mut frame = bytes.to_vec();
+        frame.extend_from_slice(&[crc as u8, (crc >> 8) as u8]);
+        frame
+    }
+
+    #[test]
+    fn crc_of_a_known_frame() {
+        // read 2 holding registers from 0 on unit 1, from the modbus spec
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x02]), 0x0BC4);
+    }
+
+    #[test]
+    fn write_single_register_frame() {
+        let request = framed(&[0x11, 0x06, 0x00, 0x02, 0x00, 0x54]);
+        let (mut transport, written) = transport(request.clone());
+        transport.write_single_register(2, 84).unwrap();
+        // the request and the echoed reply are identical
+        assert_eq!(*written.borrow(), request);
+    }
+
+    #[test]
+    fn write_multiple_registers_frame() {
+        let reply = framed(&[0x11, 0x10, 0x00, 0x03, 0x00, 0x02]);
+        let (mut transport, written) = transport(reply);
+        transport
+            .write_multiple_registers(3, &[0xF2FE, 0x025A])
+            .unwrap();
+        assert_eq!(
+            *written.borrow(),
+            framed(&[0x11, 0x10, 0x00, 0x03, 0x00, 0x02, 0x04, 0xF2, 0xFE, 0x02, 0x5A])
+        );
+    }
+
+    #[test]
+    fn read_holding_registers_frame() {
+        let reply = framed(&[0x11, 0x03, 0x04, 0x00, 0x54, 0x02, 0x5A]);
+        let (mut transport, written) = transport(reply);
+        let values = transport
+            .read_registers(RegisterKind::Holding, 2, 2)
+            .unwrap();
+        assert_eq!(values, vec![84, 602]);
+        assert_eq!(
+            *written.borrow(),
+            framed(&[0x11, 0x03, 0x00, 0x02, 0x00, 0x02])
+        );
+    }
+
+    #[test]
+    fn exception_and_corrupt_responses() {
+        let (mut rtu, _) = transport(framed(&[0x11, 0x86, 0x02]));
+        assert!(matches!(
+            rtu.write_single_register(2, 84),
+            Err(modbus::Error::Exception(
+                modbus::ExceptionCode::IllegalDataAddress
+            ))
+        ));
+
+        // a bit flipped on the line
+        let mut reply = framed(&[0x11, 0x05, 0x00, 0x07, 0xFF, 0x00]);
+        reply[4] ^= 0x01;
+        let (mut rtu, _) = transport(reply);
+        assert!(matches!(
+            rtu.write_single_coil(7, true),
+            Err(modbus::Error::InvalidResponse)
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+use crate::frame::{encode_frame, parse_frame, DeviceMessage};
+use crate::modbus_client::{ModbusClient, RegisterKind};
+use crate::testing;
+use std::io;
+
+// The sample message with a battery of its own, so that every field is something different and a mix up shows
+fn sample_message() -> DeviceMessage {
+    DeviceMessage {
+        batt_value: 97,
+        ..testing::sample_message()
+    }
+}
+
+// The outcome of each step, in the order they ran
+pub struct Report {
+    pub steps: Vec<(&'static str, Result<String, String>)>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.steps.iter().all(|(_, result)| result.is_ok())
+    }
+
+    pub fn print(&self) {
+        for (name, result) in &self.steps {
+            match result {
+                Ok(detail) => println!("PASS {}: {}", name, detail),
+                Err(detail) => println!("FAIL {}: {}", name, detail),
+            }
+        }
+    }
+}
+
+// Checks that the binary can do its job on this machine: encode and parse a frame, connect to the modbus
+// and, if a scratch register has been given, write to it. Steps that depend on an earlier one are skipped
+// when that fails
+pub fn run<F>(connect: F, test_register: Option<u16>) -> Report
+where
+    F: FnOnce() -> io::Result<Box<dyn ModbusClient>>,
+{
+    let mut steps = Vec::new();
+
+    let msg = sample_message();
+    let frame = encode_frame(&msg);
+    steps.push(("encode", Ok(format!("{} bytes", frame.len()))));
+
+    let parsed = match parse_frame(&frame, &[msg.mac]) {
+        Ok(parsed) if parsed == msg => Ok("decoded the same message".to_string()),
+        Ok(parsed) => Err(format!("decoded a different message: {:?}", parsed)),
+        Err(e) => Err(e.to_string()),
+    };
+    steps.push(("parse", parsed));
+
+    let client = connect();
+    let connected = match &client {
+        Ok(_) => Ok("connected".to_string()),
+        Err(e) => Err(format!("{:?}", e)),
+    };
+    steps.push(("modbus connect", connected));
+
+    match (client, test_register) {
+        (Ok(mut client), Some(address)) => {
+            let written = match client.write_single_register(address, 0) {
+                Ok(()) => Ok(format!("wrote 0 to register {}", address)),
+                Err(e) => Err(format!("{:?}", e)),
+            };
+            steps.push(("modbus write", written));
+        }
+        (Ok(_), None) => steps.push((
+            "modbus write",
+            Ok("skipped, no --selftest-register given".to_string()),
+        )),
+        (Err(_), _) => {}
+    }
+
+    Report { steps }
+}
+
+// The check behind --startup-check, a read of holding register 0 that changes nothing, so that a modbus that
+// is unreachable or won't talk to us stops the router at startup instead of at the first frame. A server that
+// answers with an exception is there and talking, register 0 just isn't one of its registers
+pub fn startup_check(client: &mut dyn ModbusClient) -> Result<(), String> {
+    match client.read_registers(RegisterKind::Holding, 0, 1) {
+        Ok(_) | Err(modbus::Error::Exception(_)) => Ok(()),
+        Err(e) => Err(format!("Unable to read holding register 0: {:?}", e)),
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{RecordingClient, Write};
+
+    #[test]
+    fn all_steps_pass() {
+        let report = run(|| Ok(Box::new(RecordingClient::default())), Some(900));
+        assert!(report.passed());
+        let names: Vec<&str> = report.steps.iter().map(|(name, _)| *name).collect();
+        assert_eq!(
+            names,
+            vec!["encode", "parse", "modbus connect", "modbus write"]
+        );
+    }
+
+    #[test]
+    fn writes_to_the_test_register() {
+        // keep hold of what was written by handing out a client that shares its log
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        struct Shared(Rc<RefCell<RecordingClient>>);
+        impl ModbusClient for Shared {
+            fn write_single_register(&mut self, a: u16, v: u16) -> Result<(), modbus::Error> {
+                self.0.borrow_mut().write_single_register(a, v)
+            }
+            fn write_multiple_registers(&mut self, a: u16, v: &[u16]) -> Result<(), modbus::Error> {
+                self.0.borrow_mut().write_multiple_registers(a, v)
+            }
+        }
+
+        let client = Rc::new(RefCell::new(RecordingClient::default()));
+        let shared = client.clone();
+        run(move || Ok(Box::new(Shared(shared))), Some(900));
+        assert_eq!(client.borrow().writes, vec![Write::Single(900, 0)]);
+    }
+
+    #[test]
+    fn connection_failure_fails_the_test() {
+        let report = run(
+            || Err(io::Error::new(io::ErrorKind::ConnectionRefused, "refused")),
+            Some(900),
+        );
+        assert!(!report.passed());
+        // the write is not attempted
+        assert_eq!(report.steps.len(), 3);
+    }
+
+    // Answers every read with the given result
+    struct Reads(fn() -> Result<Vec<u16>, modbus::Error>);
+
+    impl ModbusClient for Reads {
+        fn write_single_register(&mut self, _a: u16, _v: u16) -> Result<(), modbus::Error> {
+            Ok(())
+        }
+        fn write_multiple_registers(&mut self, _a: u16, _v: &[u16]) -> Result<(), modbus::Error> {
+            Ok(())
+        }
+        fn read_registers(
+            &mut self,
+            _kind: RegisterKind,
+            _address: u16,
+            _count: u16,
+        ) -> Result<Vec<u16>, modbus::Error> {
+            (self.0)()
+        }
+    }
+
+    #[test]
+    fn startup_check_of_the_modbus() {
+        assert!(startup_check(&mut Reads(|| Ok(vec![0]))).is_ok());
+        // an exception is an answer, the server is there
+        let exception = || {
+            Err(modbus::Error::Exception(
+                modbus::ExceptionCode::IllegalDataAddress,
+            ))
+        };
+        assert!(startup_check(&mut Reads(exception)).is_ok());
+
+        let broken = || {
+            Err(modbus::Error::Io(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "broken pipe",
+            )))
+        };
+        let e = startup_check(&mut Reads(broken)).unwrap_err();
+        assert!(e.starts_with("Unable to read holding register 0: "));
+        // a client that can't read at all can't be checked
+        assert!(startup_check(&mut RecordingClient::default()).is_err());
+    }
+}
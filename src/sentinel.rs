@@ -0,0 +1,234 @@
+use crate::fields::{Field, FieldSet};
+use crate::frame::DeviceMessage;
+use std::collections::BTreeMap;
+
+// What happens to a field that holds its sentinel value, i.e. the device couldn't take a reading
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SentinelAction {
+    // write it like any other value and leave it to the PLC
+    ForwardAnyway,
+    // leave the register as it is
+    SkipWrite,
+    // write the last good value instead
+    HoldLast,
+    WriteZero,
+}
+
+impl SentinelAction {
+    pub fn from_name(name: &str) -> Option<SentinelAction> {
+        match name {
+            "forward-anyway" => Some(SentinelAction::ForwardAnyway),
+            "skip-write" => Some(SentinelAction::SkipWrite),
+            "hold-last" => Some(SentinelAction::HoldLast),
+            "write-zero" => Some(SentinelAction::WriteZero),
+            _ => None,
+        }
+    }
+}
+
+// The sentinel value of each field that has one and what to do about it
+#[derive(Debug, Clone, Default)]
+pub struct SentinelConfig {
+    pub values: BTreeMap<Field, u16>,
+    // fields that are not in the map are forwarded anyway, the same as without a sentinel
+    pub actions: BTreeMap<Field, SentinelAction>,
+}
+
+impl SentinelConfig {
+    // e.g. temperature=255
+    pub fn parse_value(&mut self, value: &str) -> Result<(), String> {
+        let (field, value) = split(value)?;
+        let sentinel = value
+            .parse()
+            .map_err(|_| format!("Invalid sentinel value: {}", value))?;
+        self.values.insert(field, sentinel);
+        Ok(())
+    }
+
+    // e.g. temperature=hold-last
+    pub fn parse_action(&mut self, value: &str) -> Result<(), String> {
+        let (field, name) = split(value)?;
+        let action = SentinelAction::from_name(name).ok_or_else(|| {
+            format!(
+                "Expected forward-anyway, skip-write, hold-last or write-zero but got: {}",
+                name
+            )
+        })?;
+        self.actions.insert(field, action);
+        Ok(())
+    }
+}
+
+fn split(value: &str) -> Result<(Field, &str), String> {
+    let mut parts = value.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+    let value = parts
+        .next()
+        .ok_or_else(|| format!("Expected <field>=<value> but got: {}", value))?;
+    Ok((field, value))
+}
+
+// What was done about the sentinels in a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Handled {
+    // the fields that must not be written
+    pub skipped: FieldSet,
+    // a line for each field that held its sentinel value, for the log
+    pub lines: Vec<String>,
+}
+
+// The last good values of one device
+type Readings = BTreeMap<Field, Vec<u16>>;
+
+// Applies each field's sentinel action to the messages before they are written
+pub struct Sentinels {
+    config: SentinelConfig,
+    // the last value of each field that wasn't a sentinel, keyed by MAC address and sensor id
+    last_good: BTreeMap<([u8; 6], Option<u8>), Readings>,
+}
+
+impl Sentinels {
+    pub fn new(config: SentinelConfig) -> Sentinels {
+        Sentinels {
+            config,
+            last_good: BTreeMap::new(),
+        }
+    }
+
+    // Changes the message in place for hold-last and write-zero and says which fields to skip
+    pub fn apply(&mut self, msg: &mut DeviceMessage) -> Handled {
+        let mut handled = Handled {
+            skipped: FieldSet::empty(),
+            lines: vec![],
+        };
+        let last_good = self.last_good.entry((msg.mac, msg.sensor_id)).or_default();
+        for (field, sentinel) in &self.config.values {
+            let values = msg.field_values(*field);
+            // one bad vibration axis makes the whole reading suspect
+            if !values.contains(sentinel) {
+                last_good.insert(*field, values);
+                continue;
+            }
+            let action = self
+                .config
+                .actions
+                .get(field)
+                .cloned()
+                .unwrap_or(SentinelAction::ForwardAnyway);
+            let outcome = match (action, last_good.get(field)) {
+                (SentinelAction::ForwardAnyway, _) => "forwarding it anyway",
+                (SentinelAction::SkipWrite, _) => {
+                    handled.skipped.insert(*field);
+                    "not writing it"
+                }
+                (SentinelAction::HoldLast, Some(last)) => {
+                    msg.set_field_values(*field, last);
+                    "writing the last good value instead"
+                }
+                // there is nothing to hold on to yet
+                (SentinelAction::HoldLast, None) => {
+                    handled.skipped.insert(*field);
+                    "not writing it as there is no good value yet"
+                }
+                (SentinelAction::WriteZero, _) => {
+                    msg.set_field_values(*field, &vec![0; values.len()]);
+                    "writing 0 instead"
+                }
+            };
+            handled.lines.push(format!(
+                "WARNING: the {} of message #{} is the sentinel value {}, {}",
+                field.name(),
+                msg.msg_num_value,
+                sentinel,
+                outcome
+            ));
+        }
+        handled
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn sentinels(actions: &[&str]) -> Sentinels {
+        let mut config = SentinelConfig::default();
+        config.parse_value("temperature=255").unwrap();
+        config.parse_value("vibration=65535").unwrap();
+        for action in actions {
+            config.parse_action(action).unwrap();
+        }
+        Sentinels::new(config)
+    }
+
+    fn bad_message() -> DeviceMessage {
+        DeviceMessage {
+            temp_value: 255,
+            vib_y: 65535,
+            ..sample_message()
+        }
+    }
+
+    #[test]
+    fn parse_config() {
+        let mut config = SentinelConfig::default();
+        assert!(config.parse_value("temperature=hot").is_err());
+        assert!(config.parse_value("bogus=1").is_err());
+        assert!(config.parse_action("temperature=ignore").is_err());
+        config.parse_action("battery=write-zero").unwrap();
+        assert_eq!(
+            config.actions.get(&Field::Battery),
+            Some(&SentinelAction::WriteZero)
+        );
+    }
+
+    #[test]
+    fn forwarded_anyway_by_default() {
+        let mut sentinels = sentinels(&[]);
+        let mut msg = bad_message();
+        let handled = sentinels.apply(&mut msg);
+        assert_eq!(msg, bad_message());
+        assert!(handled.skipped.is_empty());
+        assert_eq!(handled.lines.len(), 2);
+        assert_eq!(
+            handled.lines[0],
+            "WARNING: the temperature of message #33850 is the sentinel value 255, forwarding it anyway"
+        );
+    }
+
+    #[test]
+    fn each_field_has_its_own_action() {
+        let mut sentinels = sentinels(&["temperature=hold-last", "vibration=skip-write"]);
+
+        // nothing good to hold on to yet
+        let handled = sentinels.apply(&mut bad_message());
+        assert!(handled.skipped.contains(Field::Temperature));
+        assert!(handled.skipped.contains(Field::Vibration));
+
+        let mut msg = sample_message();
+        assert!(sentinels.apply(&mut msg).lines.is_empty());
+        let mut msg = bad_message();
+        let handled = sentinels.apply(&mut msg);
+        assert_eq!(msg.temp_value, sample_message().temp_value);
+        assert!(!handled.skipped.contains(Field::Temperature));
+        assert!(handled.skipped.contains(Field::Vibration));
+        assert!(!handled.skipped.contains(Field::Battery));
+    }
+
+    #[test]
+    fn write_zero_clears_every_axis() {
+        let mut sentinels = sentinels(&["vibration=write-zero"]);
+        let mut msg = bad_message();
+        sentinels.apply(&mut msg);
+        assert_eq!((msg.vib_x, msg.vib_y, msg.vib_z), (0, 0, 0));
+        // a different field is untouched
+        assert_eq!(msg.temp_value, 255);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::frame::{encode_frame, DeviceMessage, FRAME_LEN, MAC_ADDRESS};
+use std::io;
+use std::io::Read;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Frames that look like they came from a real device, for load tests and demos without the hardware.
+// They are the standard frame with the device's MAC address and msg_num, and the battery, temperature,
+// vibration and rssi made up but within what a real sensor sends. The made up values only depend on the
+// MAC address and msg_num, so the same frame comes out every time
+pub fn generate_frame(msg_num: u16) -> [u8; FRAME_LEN] {
+    encode_frame(&generate_message(MAC_ADDRESS, msg_num))
+}
+
+// The message inside generate_frame, for any device
+pub fn generate_message(mac: [u8; 6], msg_num: u16) -> DeviceMessage {
+    let mut seed = [0; 8];
+    seed[..6].copy_from_slice(&mac);
+    seed[6..].copy_from_slice(&msg_num.to_be_bytes());
+    let mut random = SplitMix(u64::from_be_bytes(seed));
+    DeviceMessage {
+        mac,
+        batt_pid1: 1,
+        batt_value: random.between(60, 100) as u8,
+        temp_pid2: 2,
+        // 20C to 50C in the sensor's half degrees
+        temp_value: random.between(40, 100) as u8,
+        vib_pid3: 3,
+        // the axes are signed, a small vibration either way
+        vib_x: (random.between(0, 4000) as i16 - 2000) as u16,
+        vib_y: (random.between(0, 4000) as i16 - 2000) as u16,
+        vib_z: (random.between(0, 4000) as i16 - 2000) as u16,
+        msg_num_pid5: 5,
+        msg_num_value: msg_num,
+        version_pid11: 11,
+        version_value: 2,
+        rssi_pid6: 6,
+        rssi_value: random.between(150, 200) as u8,
+        sensor_id: None,
+        received_at: None,
+    }
+}
+
+// A stream of generated frames at a steady rate (see --simulate), as if the devices were sending them.
+// Each device counts its own msg_num from 0 and takes its turn, so with two devices and a rate of 10 each
+// one sends 5 frames a second
+pub struct Simulator {
+    macs: Vec<[u8; 6]>,
+    interval: Duration,
+    // the frames sent so far
+    sent: u64,
+    next_due: Option<Instant>,
+    frame: [u8; FRAME_LEN],
+    position: usize,
+}
+
+impl Simulator {
+    // rate is frames a second between all of the devices, there needs to be at least one device
+    pub fn new(rate: f64, macs: &[[u8; 6]]) -> Simulator {
+        Simulator {
+            macs: macs.to_vec(),
+            interval: Duration::from_secs_f64(1.0 / rate),
+            sent: 0,
+            next_due: None,
+            frame: [0; FRAME_LEN],
+            position: FRAME_LEN,
+        }
+    }
+
+    // Waits for the next frame to be due, a reader that has fallen behind doesn't get a burst to catch up
+    fn next_frame(&mut self) -> [u8; FRAME_LEN] {
+        let now = Instant::now();
+        let due = self.next_due.unwrap_or(now);
+        if due > now {
+            thread::sleep(due - now);
+        }
+        self.next_due = Some(due.max(now) + self.interval);
+
+        let devices = self.macs.len() as u64;
+        let mac = self.macs[(self.sent % devices) as usize];
+        // msg_num wraps like the real thing
+        let msg_num = (self.sent / devices) as u16;
+        self.sent += 1;
+        encode_frame(&generate_message(mac, msg_num))
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == FRAME_LEN {
+            self.frame = self.next_frame();
+            self.position = 0;
+        }
+        let available = &self.frame[self.position..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+// splitmix64, plenty for made up sensor values
+struct SplitMix(u64);
+
+impl SplitMix {
+    // from min to max, both included
+    fn between(&mut self, min: u64, max: u64) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        min + z % (max - min + 1)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{read_message, DEFAULT_MACS};
+
+    const OTHER_MAC: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+    #[test]
+    fn generated_frames_read_back() {
+        for msg_num in [0, 1, 33850, 65535].iter() {
+            let frame = generate_frame(*msg_num);
+            let msg = read_message(&mut &frame[..], &DEFAULT_MACS).unwrap();
+            assert_eq!(msg.msg_num_value, *msg_num);
+            assert!((60..=100).contains(&msg.batt_value));
+            assert!((40..=100).contains(&msg.temp_value));
+            for axis in [msg.vib_x, msg.vib_y, msg.vib_z].iter() {
+                assert!((-2000..=2000).contains(&(*axis as i16)));
+            }
+            assert!((150..=200).contains(&msg.rssi_value));
+        }
+        // the same every time, but not the same for every message
+        assert_eq!(generate_frame(7), generate_frame(7));
+        assert_ne!(generate_frame(7)[8..20], generate_frame(8)[8..20]);
+    }
+
+    #[test]
+    fn each_device_takes_its_turn_at_the_rate() {
+        let macs = [MAC_ADDRESS, OTHER_MAC];
+        let mut simulator = Simulator::new(200.0, &macs);
+        let started = Instant::now();
+        let messages: Vec<([u8; 6], u16)> = (0..5)
+            .map(|_| {
+                let msg = read_message(&mut simulator, &macs).unwrap();
+                (msg.mac, msg.msg_num_value)
+            })
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (MAC_ADDRESS, 0),
+                (OTHER_MAC, 0),
+                (MAC_ADDRESS, 1),
+                (OTHER_MAC, 1),
+                (MAC_ADDRESS, 2),
+            ]
+        );
+        // the first frame is straight away, the other four 5ms apart
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+}
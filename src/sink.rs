@@ -0,0 +1,137 @@
+use crate::fields::FieldSet;
+use crate::frame::DeviceMessage;
+use crate::modbus_client::ModbusClient;
+use crate::register_map::{send_message_to_modbus, RegisterMap};
+use std::fmt;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::time::Duration;
+
+// Why a sink didn't take a message
+#[derive(Debug)]
+pub enum SinkError {
+    // the sink is behind and the message was dropped, the next one may well get through
+    Full,
+    // whatever the sink hands its messages to has gone, e.g. the thread writing the file stopped on an error
+    Closed,
+    // the message couldn't be turned into what the sink sends
+    Encode(String),
+    Modbus(modbus::Error),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::Full => write!(f, "not keeping up, message dropped"),
+            SinkError::Closed => write!(f, "closed"),
+            SinkError::Encode(e) => write!(f, "unable to encode the message: {}", e),
+            SinkError::Modbus(e) => write!(f, "modbus error: {:?}", e),
+        }
+    }
+}
+
+// Somewhere each decoded message is sent on to, e.g. the json and csv outputs or an MQTT broker.
+// A sink must never hold up the router for long, the ones that write to a file or the network hand the
+// message to a thread of their own with queue()
+pub trait MessageSink {
+    // what the sink is called in the log, e.g. "csv output"
+    fn name(&self) -> &str;
+
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError>;
+}
+
+// Queues the item and returns straight away, if the queue is full the item is dropped
+pub fn queue<T>(sender: &SyncSender<T>, item: T) -> Result<(), SinkError> {
+    sender.try_send(item).map_err(|e| match e {
+        TrySendError::Full(_) => SinkError::Full,
+        TrySendError::Disconnected(_) => SinkError::Closed,
+    })
+}
+
+// Writes every field of each message to the modbus with send_message_to_modbus, for a program that embeds
+// the register map without the rest of the router. It is the plainest of writers, retrying, the change
+// filter and the queues for when the modbus is down are left to the router
+pub struct ModbusSink<C> {
+    pub client: C,
+    register_map: RegisterMap,
+    write_delay: Duration,
+}
+
+impl<C: ModbusClient> ModbusSink<C> {
+    pub fn new(client: C, register_map: RegisterMap, write_delay: Duration) -> ModbusSink<C> {
+        ModbusSink {
+            client,
+            register_map,
+            write_delay,
+        }
+    }
+}
+
+impl<C: ModbusClient> MessageSink for ModbusSink<C> {
+    fn name(&self) -> &str {
+        "modbus"
+    }
+
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        send_message_to_modbus(
+            msg,
+            FieldSet::all(),
+            &self.register_map,
+            self.write_delay,
+            &mut self.client,
+        )
+        .map_err(|e| SinkError::Modbus(e.error))
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{sample_message, FailingClient, RecordingClient};
+    use std::sync::mpsc;
+
+    #[test]
+    fn a_full_queue_drops_the_item() {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        assert!(queue(&sender, 1).is_ok());
+        assert!(matches!(queue(&sender, 2), Err(SinkError::Full)));
+        assert_eq!(receiver.try_iter().collect::<Vec<i32>>(), vec![1]);
+
+        drop(receiver);
+        assert!(matches!(queue(&sender, 3), Err(SinkError::Closed)));
+    }
+
+    #[test]
+    fn the_modbus_sink_writes_every_field() {
+        let msg = sample_message();
+        let register_map = RegisterMap::default();
+        let mut expected = RecordingClient::default();
+        send_message_to_modbus(
+            &msg,
+            FieldSet::all(),
+            &register_map,
+            Duration::from_millis(0),
+            &mut expected,
+        )
+        .unwrap();
+
+        let mut sink = ModbusSink::new(
+            RecordingClient::default(),
+            register_map.clone(),
+            Duration::from_millis(0),
+        );
+        sink.send(&msg).unwrap();
+        assert_eq!(sink.client.writes, expected.writes);
+
+        let mut sink = ModbusSink::new(
+            FailingClient::after(0),
+            register_map,
+            Duration::from_millis(0),
+        );
+        assert!(matches!(sink.send(&msg), Err(SinkError::Modbus(_))));
+    }
+}
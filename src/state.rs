@@ -0,0 +1,160 @@
+use crate::frame::DeviceMessage;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+// How often the state file is written while messages are arriving
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+// What the router remembers across a restart (see --state-file): the latest message from each device and
+// sensor. Their msg_num is what the gap detection and the repeat check carry on from, so a restart doesn't
+// look like a lot of lost messages or let the last message through again
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct State {
+    pub messages: Vec<DeviceMessage>,
+}
+
+// Writes to a file next to the state file and renames it over the top, so that a router killed part way
+// through leaves the last state file as it was rather than half of a new one
+pub fn save_state(path: &Path, state: &State) -> io::Result<()> {
+    let json = serde_json::to_string(state)?;
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    fs::write(&partial, json)?;
+    fs::rename(&partial, path)
+}
+
+// A file that isn't there yet is the first run, which is no state at all
+pub fn load_state(path: &Path) -> io::Result<State> {
+    let json = match fs::read_to_string(path) {
+        Ok(json) => json,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(State::default()),
+        Err(e) => return Err(e),
+    };
+    Ok(serde_json::from_str(&json)?)
+}
+
+// Keeps the latest messages and writes them out every SAVE_INTERVAL. Whatever arrived after the last save is
+// lost when the router stops, which at worst is a gap or a repeat that is reported when it wasn't
+pub struct StateFile {
+    path: String,
+    latest: BTreeMap<([u8; 6], Option<u8>), DeviceMessage>,
+    saved: Instant,
+}
+
+impl StateFile {
+    // Reads what was saved last time, a state file that can't be read is started again with a warning
+    // rather than stopping the router from starting
+    pub fn open(path: &str) -> (StateFile, State) {
+        let state = match load_state(Path::new(path)) {
+            Ok(state) => {
+                info!(
+                    "Carrying on from the latest messages of {} devices in {}",
+                    state.messages.len(),
+                    path
+                );
+                state
+            }
+            Err(e) => {
+                warn!(
+                    "Unable to read the state file {}, starting afresh: {}",
+                    path, e
+                );
+                State::default()
+            }
+        };
+        let latest = state
+            .messages
+            .iter()
+            .map(|msg| ((msg.mac, msg.sensor_id), msg.clone()))
+            .collect();
+        let file = StateFile {
+            path: path.to_string(),
+            latest,
+            saved: Instant::now(),
+        };
+        (file, state)
+    }
+
+    // Call with every message, the file is written if it is due
+    pub fn record(&mut self, msg: &DeviceMessage, now: Instant) {
+        self.latest.insert((msg.mac, msg.sensor_id), msg.clone());
+        if now.duration_since(self.saved) < SAVE_INTERVAL {
+            return;
+        }
+        let state = State {
+            messages: self.latest.values().cloned().collect(),
+        };
+        if let Err(e) = save_state(Path::new(&self.path), &state) {
+            warn!("Unable to write the state file {}: {}", self.path, e);
+        }
+        self.saved = now;
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+    use std::env;
+    use std::path::PathBuf;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("modbusrouter-{}-{}.json", name, std::process::id()))
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let path = temp_path("state");
+        let other = DeviceMessage {
+            mac: [0x01, 0x02, 0x03, 0x04, 0x05, 0x06],
+            msg_num_value: 7,
+            sensor_id: Some(2),
+            ..sample_message()
+        };
+        let state = State {
+            messages: vec![sample_message(), other],
+        };
+        save_state(&path, &state).unwrap();
+        assert_eq!(load_state(&path).unwrap(), state);
+        fs::remove_file(&path).unwrap();
+
+        // the first run has nothing to carry on from
+        assert_eq!(load_state(&path).unwrap(), State::default());
+        fs::write(&path, "not json").unwrap();
+        assert!(load_state(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn the_latest_message_of_each_device_is_saved() {
+        let path = temp_path("state-file");
+        let message = |msg_num| DeviceMessage {
+            msg_num_value: msg_num,
+            ..sample_message()
+        };
+        let (mut file, state) = StateFile::open(path.to_str().unwrap());
+        let started = Instant::now();
+        assert!(state.messages.is_empty());
+        file.record(&message(10), started);
+        // not due yet
+        assert!(!path.exists());
+        file.record(&message(11), started + SAVE_INTERVAL);
+        assert_eq!(load_state(&path).unwrap().messages, vec![message(11)]);
+
+        // the next run carries on from there
+        let (_, state) = StateFile::open(path.to_str().unwrap());
+        assert_eq!(state.messages, vec![message(11)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
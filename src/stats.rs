@@ -0,0 +1,584 @@
+use crate::frame::{format_mac, DeviceMessage};
+use crate::histogram::Histogram;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Running totals kept by the router, for the summary printed when it exits and for anyone embedding it.
+// Cloning a Stats gives another handle on the same totals, so one can be kept by the code that reads
+// the device while another is looked at from a different thread
+#[derive(Clone)]
+pub struct Stats {
+    totals: Arc<Mutex<Totals>>,
+}
+
+struct Totals {
+    started: Instant,
+    messages_received: u64,
+    messages_forwarded: u64,
+    // keepalive frames from the gateway, they are not messages
+    heartbeats_received: u64,
+    // bytes skipped while looking for the first frame on a new connection
+    bytes_discarded: u64,
+    // keyed by a short description of the error
+    errors: BTreeMap<String, u64>,
+    // bad frames by RouterError::kind, for the metrics
+    framing_errors: BTreeMap<&'static str, u64>,
+    // writes to the modbus that failed, whatever the reason
+    modbus_write_errors: u64,
+    // writes that one of several modbus servers didn't take, by host (see RedundantClient)
+    endpoint_write_errors: BTreeMap<String, u64>,
+    // messages that weren't written because of --max-msgs-per-sec
+    rate_limited: u64,
+    // messages that never arrived, going by the gaps in msg_num
+    dropped_frames: u64,
+    // writes that read back as something else (see --verify-writes)
+    write_mismatches: u64,
+    // device connections dropped because the modbus writes couldn't keep up (see --max-forward-latency)
+    load_shed: u64,
+    // messages that weren't written because the circuit breaker was open (see --circuit-breaker)
+    breaker_dropped: u64,
+    // frames dropped because the read ahead was full (see --read-ahead)
+    read_ahead_dropped: u64,
+    // repeats that came sooner than --min-repeat-interval after the frame before them
+    duplicate_floods: u64,
+    // how long writing each message to the modbus took, all of its writes and any retries
+    write_latency: Histogram,
+    devices: BTreeMap<[u8; 6], DeviceStats>,
+}
+
+// The percentiles the metrics and the stats line give for the write latency
+pub const LATENCY_PERCENTILES: [f64; 3] = [0.5, 0.95, 0.99];
+
+struct DeviceStats {
+    messages: u64,
+    last_seen: SystemTime,
+    last_message: Option<DeviceMessage>,
+}
+
+// A snapshot of the totals, all taken at the same moment. Deriving Serialize lets us print it as json
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub uptime_secs: u64,
+    pub messages_received: u64,
+    pub messages_forwarded: u64,
+    pub heartbeats_received: u64,
+    pub bytes_discarded: u64,
+    pub errors: BTreeMap<String, u64>,
+    pub devices: BTreeMap<String, DeviceSummary>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceSummary {
+    pub messages: u64,
+    // seconds since the unix epoch
+    pub last_seen: u64,
+    // the values of the latest message, None if only heartbeats have arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_message: Option<DeviceMessage>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        let totals = Totals {
+            started: Instant::now(),
+            messages_received: 0,
+            messages_forwarded: 0,
+            heartbeats_received: 0,
+            bytes_discarded: 0,
+            errors: BTreeMap::new(),
+            framing_errors: BTreeMap::new(),
+            modbus_write_errors: 0,
+            endpoint_write_errors: BTreeMap::new(),
+            rate_limited: 0,
+            dropped_frames: 0,
+            write_mismatches: 0,
+            load_shed: 0,
+            breaker_dropped: 0,
+            read_ahead_dropped: 0,
+            duplicate_floods: 0,
+            write_latency: Histogram::new(),
+            devices: BTreeMap::new(),
+        };
+        Stats {
+            totals: Arc::new(Mutex::new(totals)),
+        }
+    }
+
+    pub fn record_received(&self, msg: &DeviceMessage) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.messages_received += 1;
+        // use the time the message arrived at rather than now in case it took a while to get here
+        let received_at = msg.received_at.unwrap_or_else(SystemTime::now);
+        let device = totals.device(msg.mac, received_at);
+        device.messages += 1;
+        device.last_seen = received_at;
+        device.last_message = Some(msg.clone());
+    }
+
+    // A heartbeat counts towards when the device was last seen but not towards its messages
+    pub fn record_heartbeat(&self, mac: [u8; 6], received_at: SystemTime) {
+        let mut totals = self.totals.lock().unwrap();
+        totals.heartbeats_received += 1;
+        totals.device(mac, received_at).last_seen = received_at;
+    }
+
+    pub fn record_forwarded(&self) {
+        self.totals.lock().unwrap().messages_forwarded += 1;
+    }
+
+    pub fn record_discarded(&self, bytes: usize) {
+        self.totals.lock().unwrap().bytes_discarded += bytes as u64;
+    }
+
+    pub fn record_error(&self, kind: &str) {
+        *self
+            .totals
+            .lock()
+            .unwrap()
+            .errors
+            .entry(kind.to_string())
+            .or_insert(0) += 1;
+    }
+
+    // A frame was read but it was bad, kind is one of those from framing_error_kind
+    pub fn record_framing_error(&self, kind: &'static str) {
+        *self
+            .totals
+            .lock()
+            .unwrap()
+            .framing_errors
+            .entry(kind)
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_modbus_write_error(&self) {
+        self.totals.lock().unwrap().modbus_write_errors += 1;
+    }
+
+    pub fn record_endpoint_write_error(&self, host: &str) {
+        *self
+            .totals
+            .lock()
+            .unwrap()
+            .endpoint_write_errors
+            .entry(host.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.totals.lock().unwrap().rate_limited += 1;
+    }
+
+    pub fn record_dropped_frames(&self, missing: u16) {
+        self.totals.lock().unwrap().dropped_frames += missing as u64;
+    }
+
+    pub fn record_write_mismatch(&self) {
+        self.totals.lock().unwrap().write_mismatches += 1;
+    }
+
+    pub fn record_load_shed(&self) {
+        self.totals.lock().unwrap().load_shed += 1;
+    }
+
+    pub fn record_breaker_dropped(&self) {
+        self.totals.lock().unwrap().breaker_dropped += 1;
+    }
+
+    pub fn record_read_ahead_dropped(&self) {
+        self.totals.lock().unwrap().read_ahead_dropped += 1;
+    }
+
+    pub fn record_duplicate_flood(&self) {
+        self.totals.lock().unwrap().duplicate_floods += 1;
+    }
+
+    // Call with how long a message took to write once all of it has been written
+    pub fn record_write_latency(&self, took: Duration) {
+        self.totals.lock().unwrap().write_latency.record(took);
+    }
+
+    // A copy of the write latencies so far, Histogram::since gives those of an interval
+    pub fn write_latency(&self) -> Histogram {
+        self.totals.lock().unwrap().write_latency.clone()
+    }
+
+    // The message and error counts in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let totals = self.totals.lock().unwrap();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_messages_decoded_total Messages read from the devices and decoded"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_messages_decoded_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_messages_decoded_total {}",
+            totals.messages_received
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_messages_forwarded_total Messages written to the modbus"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_messages_forwarded_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_messages_forwarded_total {}",
+            totals.messages_forwarded
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_framing_errors_total Bad frames from the devices by kind"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_framing_errors_total counter");
+        for (kind, count) in &totals.framing_errors {
+            let _ = writeln!(
+                out,
+                "modbusrouter_framing_errors_total{{kind=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_bytes_discarded_total Bytes skipped looking for the start of a frame"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_bytes_discarded_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_bytes_discarded_total {}",
+            totals.bytes_discarded
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_modbus_write_errors_total Writes to the modbus that failed"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_modbus_write_errors_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_modbus_write_errors_total {}",
+            totals.modbus_write_errors
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_endpoint_write_errors_total Writes that one of several modbus servers didn't take"
+        );
+        let _ = writeln!(
+            out,
+            "# TYPE modbusrouter_endpoint_write_errors_total counter"
+        );
+        for (host, count) in &totals.endpoint_write_errors {
+            let _ = writeln!(
+                out,
+                "modbusrouter_endpoint_write_errors_total{{endpoint=\"{}\"}} {}",
+                host, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_rate_limited_total Messages not written to the modbus because of the rate limit"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_rate_limited_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_rate_limited_total {}",
+            totals.rate_limited
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_dropped_frames_total Messages that never arrived, going by the gaps in msg_num"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_dropped_frames_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_dropped_frames_total {}",
+            totals.dropped_frames
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_write_mismatches_total Writes that read back as something else"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_write_mismatches_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_write_mismatches_total {}",
+            totals.write_mismatches
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_load_shed_total Device connections dropped because the modbus writes were too slow"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_load_shed_total counter");
+        let _ = writeln!(out, "modbusrouter_load_shed_total {}", totals.load_shed);
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_breaker_dropped_total Messages dropped while the circuit breaker was open"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_breaker_dropped_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_breaker_dropped_total {}",
+            totals.breaker_dropped
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_read_ahead_dropped_total Frames dropped because the read ahead was full"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_read_ahead_dropped_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_read_ahead_dropped_total {}",
+            totals.read_ahead_dropped
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_duplicate_floods_total Repeated frames that came too soon after the one before them"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_duplicate_floods_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_duplicate_floods_total {}",
+            totals.duplicate_floods
+        );
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_modbus_write_seconds How long writing each message to the modbus took"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_modbus_write_seconds summary");
+        for q in LATENCY_PERCENTILES.iter() {
+            if let Some(p) = totals.write_latency.percentile(*q) {
+                let _ = writeln!(
+                    out,
+                    "modbusrouter_modbus_write_seconds{{quantile=\"{}\"}} {}",
+                    q,
+                    p.as_secs_f64()
+                );
+            }
+        }
+        let _ = writeln!(
+            out,
+            "modbusrouter_modbus_write_seconds_sum {}",
+            totals.write_latency.sum().as_secs_f64()
+        );
+        let _ = writeln!(
+            out,
+            "modbusrouter_modbus_write_seconds_count {}",
+            totals.write_latency.count()
+        );
+        out
+    }
+
+    // Everything is copied out under the one lock so the numbers always agree with each other
+    pub fn snapshot(&self) -> Summary {
+        let totals = self.totals.lock().unwrap();
+        let devices = totals
+            .devices
+            .iter()
+            .map(|(mac, device)| {
+                let last_seen = device
+                    .last_seen
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0);
+                let summary = DeviceSummary {
+                    messages: device.messages,
+                    last_seen,
+                    last_message: device.last_message.clone(),
+                };
+                (format_mac(mac), summary)
+            })
+            .collect();
+
+        Summary {
+            uptime_secs: totals.started.elapsed().as_secs(),
+            messages_received: totals.messages_received,
+            messages_forwarded: totals.messages_forwarded,
+            heartbeats_received: totals.heartbeats_received,
+            bytes_discarded: totals.bytes_discarded,
+            errors: totals.errors.clone(),
+            devices,
+        }
+    }
+}
+
+impl Totals {
+    fn device(&mut self, mac: [u8; 6], received_at: SystemTime) -> &mut DeviceStats {
+        self.devices.entry(mac).or_insert(DeviceStats {
+            messages: 0,
+            last_seen: received_at,
+            last_message: None,
+        })
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new()
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Summary:")?;
+        writeln!(f, "  uptime: {}s", self.uptime_secs)?;
+        writeln!(f, "  messages received: {}", self.messages_received)?;
+        writeln!(f, "  messages forwarded: {}", self.messages_forwarded)?;
+        writeln!(f, "  heartbeats received: {}", self.heartbeats_received)?;
+        writeln!(f, "  bytes discarded: {}", self.bytes_discarded)?;
+        writeln!(f, "  errors:")?;
+        for (kind, count) in &self.errors {
+            writeln!(f, "    {}: {}", kind, count)?;
+        }
+        writeln!(f, "  devices:")?;
+        for (mac, device) in &self.devices {
+            writeln!(
+                f,
+                "    {}: {} messages, last seen at {} (unix time)",
+                mac, device.messages, device.last_seen
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn message(mac: [u8; 6]) -> DeviceMessage {
+        DeviceMessage {
+            mac,
+            ..sample_message()
+        }
+    }
+
+    #[test]
+    fn summary_counts_messages_errors_and_devices() {
+        let stats = Stats::new();
+        stats.record_received(&message([0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]));
+        stats.record_received(&message([0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]));
+        stats.record_received(&message([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]));
+        stats.record_forwarded();
+        stats.record_discarded(5);
+        stats.record_error("UnexpectedEof");
+        stats.record_error("UnexpectedEof");
+
+        let summary = stats.snapshot();
+        assert_eq!(summary.messages_received, 3);
+        assert_eq!(summary.messages_forwarded, 1);
+        assert_eq!(summary.bytes_discarded, 5);
+        assert_eq!(summary.errors["UnexpectedEof"], 2);
+        assert_eq!(summary.devices["D0:CF:5E:82:93:7B"].messages, 2);
+        assert_eq!(summary.devices["01:02:03:04:05:06"].messages, 1);
+
+        let json = serde_json::to_string(&summary).unwrap();
+        assert!(json.contains("\"messages_received\":3"));
+    }
+
+    #[test]
+    fn metrics_count_messages_and_errors() {
+        let stats = Stats::new();
+        stats.record_received(&message([0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]));
+        stats.record_received(&message([0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]));
+        stats.record_forwarded();
+        stats.record_framing_error("bad-pid");
+        stats.record_framing_error("bad-pid");
+        stats.record_framing_error("other");
+        stats.record_discarded(3);
+        stats.record_discarded(216);
+        stats.record_modbus_write_error();
+        stats.record_rate_limited();
+        stats.record_dropped_frames(2);
+        stats.record_dropped_frames(3);
+        stats.record_write_mismatch();
+        stats.record_load_shed();
+        stats.record_breaker_dropped();
+        stats.record_read_ahead_dropped();
+        stats.record_duplicate_flood();
+        assert!(!stats
+            .metrics()
+            .contains("modbusrouter_modbus_write_seconds{"));
+        stats.record_write_latency(Duration::from_micros(3));
+        stats.record_write_latency(Duration::from_micros(5));
+
+        let metrics = stats.metrics();
+        assert!(metrics.contains("modbusrouter_messages_decoded_total 2\n"));
+        assert!(metrics.contains("modbusrouter_messages_forwarded_total 1\n"));
+        assert!(metrics.contains("modbusrouter_framing_errors_total{kind=\"bad-pid\"} 2\n"));
+        assert!(metrics.contains("modbusrouter_framing_errors_total{kind=\"other\"} 1\n"));
+        assert!(metrics.contains("modbusrouter_bytes_discarded_total 219\n"));
+        assert!(metrics.contains("modbusrouter_modbus_write_errors_total 1\n"));
+        assert!(metrics.contains("modbusrouter_rate_limited_total 1\n"));
+        assert!(metrics.contains("modbusrouter_dropped_frames_total 5\n"));
+        assert!(metrics.contains("modbusrouter_write_mismatches_total 1\n"));
+        assert!(metrics.contains("modbusrouter_load_shed_total 1\n"));
+        assert!(metrics.contains("modbusrouter_breaker_dropped_total 1\n"));
+        assert!(metrics.contains("modbusrouter_read_ahead_dropped_total 1\n"));
+        assert!(metrics.contains("modbusrouter_duplicate_floods_total 1\n"));
+        assert!(metrics.contains("modbusrouter_modbus_write_seconds{quantile=\"0.5\"} 0.000003\n"));
+        assert!(metrics.contains("modbusrouter_modbus_write_seconds{quantile=\"0.99\"} 0.000005\n"));
+        assert!(metrics.contains("modbusrouter_modbus_write_seconds_sum 0.000008\n"));
+        assert!(metrics.contains("modbusrouter_modbus_write_seconds_count 2\n"));
+    }
+
+    #[test]
+    fn heartbeats_are_counted_apart_from_messages() {
+        let stats = Stats::new();
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let received_at = UNIX_EPOCH + std::time::Duration::from_secs(1_571_388_795);
+        stats.record_heartbeat(mac, received_at);
+
+        let summary = stats.snapshot();
+        assert_eq!(summary.heartbeats_received, 1);
+        assert_eq!(summary.messages_received, 0);
+        assert_eq!(summary.devices["01:02:03:04:05:06"].messages, 0);
+        assert!(summary.devices["01:02:03:04:05:06"].last_message.is_none());
+        assert_eq!(
+            summary.devices["01:02:03:04:05:06"].last_seen,
+            1_571_388_795
+        );
+    }
+
+    #[test]
+    fn last_seen_is_the_received_time() {
+        let stats = Stats::new();
+        let received_at = UNIX_EPOCH + std::time::Duration::from_secs(1_571_388_795);
+        stats.record_received(&DeviceMessage {
+            received_at: Some(received_at),
+            ..message([0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B])
+        });
+        assert_eq!(
+            stats.snapshot().devices["D0:CF:5E:82:93:7B"].last_seen,
+            1_571_388_795
+        );
+    }
+
+    #[test]
+    fn snapshots_from_another_thread() {
+        let stats = Stats::new();
+        let reader = stats.clone();
+        let mut msg = message([0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+        msg.temp_value = 21;
+        std::thread::spawn(move || {
+            reader.record_received(&msg);
+            reader.record_error("ModbusIo");
+        })
+        .join()
+        .unwrap();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.messages_received, 1);
+        assert_eq!(snapshot.errors["ModbusIo"], 1);
+        let last = snapshot.devices["01:02:03:04:05:06"].last_message.as_ref();
+        assert_eq!(last.map(|msg| msg.temp_value), Some(21));
+    }
+}
@@ -0,0 +1,140 @@
+use crate::connections::Connections;
+use crate::histogram::Histogram;
+use crate::stats::{Stats, Summary, LATENCY_PERCENTILES};
+use log::info;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The counts the last line was worked out from, so that each line only covers its own interval
+#[derive(Default)]
+struct Pulse {
+    messages_received: u64,
+    messages_forwarded: u64,
+    errors: u64,
+    write_latency: Histogram,
+}
+
+impl Pulse {
+    // The line for the interval that has just ended, elapsed being how long it was
+    fn line(
+        &mut self,
+        summary: &Summary,
+        write_latency: &Histogram,
+        uptime: Option<Duration>,
+        elapsed: Duration,
+    ) -> String {
+        let errors: u64 = summary.errors.values().sum();
+        let decoded = summary
+            .messages_received
+            .saturating_sub(self.messages_received);
+        let forwarded = summary
+            .messages_forwarded
+            .saturating_sub(self.messages_forwarded);
+        let failed = errors.saturating_sub(self.errors);
+        let writes = write_latency.since(&self.write_latency);
+        *self = Pulse {
+            messages_received: summary.messages_received,
+            messages_forwarded: summary.messages_forwarded,
+            errors,
+            write_latency: write_latency.clone(),
+        };
+        let rate = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => decoded as f64 / secs,
+            _ => 0.0,
+        };
+        let connection = match uptime {
+            Some(uptime) => format!("connected for {}s", uptime.as_secs()),
+            None => "not connected".to_string(),
+        };
+        // e.g. 2.1/4.8/9.0ms, nothing when there were no writes
+        let latencies: Vec<String> = LATENCY_PERCENTILES
+            .iter()
+            .filter_map(|q| writes.percentile(*q))
+            .map(|p| format!("{:.1}", p.as_secs_f64() * 1000.0))
+            .collect();
+        let latency = match latencies.is_empty() {
+            true => String::new(),
+            false => format!(", writes took {}ms (p50/p95/p99)", latencies.join("/")),
+        };
+        format!(
+            "Stats for the last {}s: {} messages decoded, {} forwarded, {} errors, {:.1} msgs/sec{}, {}",
+            elapsed.as_secs(),
+            decoded,
+            forwarded,
+            failed,
+            rate,
+            latency,
+            connection
+        )
+    }
+}
+
+// Logs the message and error counts every interval (see --stats-interval), a cheap pulse for operators that
+// don't scrape /metrics. Like the alive line it runs on its own thread so the line turns up on time even while
+// the main loop is blocked reading the device
+pub fn start(interval: Duration, stats: Stats, connections: Arc<Mutex<Connections>>) {
+    thread::spawn(move || {
+        let mut pulse = Pulse::default();
+        let mut last = Instant::now();
+        loop {
+            thread::sleep(interval);
+            let now = Instant::now();
+            let uptime = connections.lock().unwrap().uptime(now);
+            let line = pulse.line(
+                &stats.snapshot(),
+                &stats.write_latency(),
+                uptime,
+                now - last,
+            );
+            info!("{}", line);
+            last = now;
+        }
+    });
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    #[test]
+    fn each_line_only_counts_its_own_interval() {
+        let stats = Stats::new();
+        let mut pulse = Pulse::default();
+        for took in 0..120 {
+            stats.record_received(&sample_message());
+            stats.record_forwarded();
+            // 1ms for most of them and one in ten slow
+            let took = if took % 10 == 9 { 12 } else { 1 };
+            stats.record_write_latency(Duration::from_millis(took));
+        }
+        stats.record_error("CrcMismatch");
+        stats.record_error("Timeout");
+        assert_eq!(
+            pulse.line(
+                &stats.snapshot(),
+                &stats.write_latency(),
+                Some(Duration::from_secs(3600)),
+                Duration::from_secs(60)
+            ),
+            "Stats for the last 60s: 120 messages decoded, 120 forwarded, 2 errors, 2.0 msgs/sec, writes took 1.0/12.3/12.3ms (p50/p95/p99), connected for 3600s"
+        );
+
+        stats.record_received(&sample_message());
+        assert_eq!(
+            pulse.line(
+                &stats.snapshot(),
+                &stats.write_latency(),
+                None,
+                Duration::from_secs(60)
+            ),
+            "Stats for the last 60s: 1 messages decoded, 0 forwarded, 0 errors, 0.0 msgs/sec, not connected"
+        );
+    }
+}
@@ -0,0 +1,77 @@
+// The health of a device packed into the bits of one register, for SCADA screens that want a single status
+// word rather than working it out from the raw values (see --status-register and DeviceMessage::status_word)
+
+// Which bit of the status word each condition sets
+pub const LOW_BATTERY: u16 = 1 << 0;
+pub const WEAK_RSSI: u16 = 1 << 1;
+pub const HIGH_VIBRATION: u16 = 1 << 2;
+
+// When each bit is set. A condition without a threshold is never set, so the bits that a site doesn't
+// care about stay 0
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Thresholds {
+    // batt_value below this is a low battery
+    pub low_battery: Option<u8>,
+    // rssi_value below this is a weak signal
+    pub weak_rssi: Option<u8>,
+    // the magnitude of the vibration above this is high vibration
+    pub high_vibration: Option<u16>,
+    // the vibration axes are two's complement (see --signedness)
+    pub signed_vibration: bool,
+}
+
+impl Thresholds {
+    // Parses condition=n, e.g. low-battery=20, weak-rssi=60 or high-vibration=3000
+    pub fn parse(&mut self, value: &str) -> Result<(), String> {
+        let mut parts = value.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let threshold = parts
+            .next()
+            .ok_or_else(|| format!("Expected <condition>=<n> but got: {}", value))?;
+        let invalid = |_| format!("Invalid threshold for {}: {}", name, threshold);
+        match name {
+            "low-battery" => self.low_battery = Some(threshold.parse().map_err(invalid)?),
+            "weak-rssi" => self.weak_rssi = Some(threshold.parse().map_err(invalid)?),
+            "high-vibration" => self.high_vibration = Some(threshold.parse().map_err(invalid)?),
+            _ => {
+                return Err(format!(
+                "Unknown status condition, expected low-battery, weak-rssi or high-vibration: {}",
+                name
+            ))
+            }
+        }
+        Ok(())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_thresholds() {
+        let mut thresholds = Thresholds::default();
+        thresholds.parse("low-battery=20").unwrap();
+        thresholds.parse("weak-rssi=60").unwrap();
+        thresholds.parse("high-vibration=3000").unwrap();
+        assert_eq!(
+            thresholds,
+            Thresholds {
+                low_battery: Some(20),
+                weak_rssi: Some(60),
+                high_vibration: Some(3000),
+                signed_vibration: false,
+            }
+        );
+
+        assert!(thresholds.parse("low-battery").is_err());
+        // a battery reading is a single byte
+        assert!(thresholds.parse("low-battery=256").is_err());
+        assert!(thresholds.parse("hot=50").is_err());
+    }
+}
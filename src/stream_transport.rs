@@ -0,0 +1,507 @@
+use crate::modbus_client::{write_in_chunks, ModbusClient, RegisterKind};
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use modbus::ExceptionCode;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Anything we can both read from and write to, e.g. a TcpStream
+pub trait ReadWrite: Read + Write {}
+impl<T: Read + Write> ReadWrite for T {}
+
+// modbus function codes, the same over RTU
+pub(crate) const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub(crate) const READ_INPUT_REGISTERS: u8 = 0x04;
+pub(crate) const WRITE_SINGLE_COIL: u8 = 0x05;
+pub(crate) const WRITE_SINGLE_REGISTER: u8 = 0x06;
+pub(crate) const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+// How the transaction id of each request is chosen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TransactionIds {
+    // 1, 2, 3 ... wrapping round after 65535
+    Sequential,
+    // the same id for every request, for gateways that only answer to one
+    Fixed(u16),
+}
+
+impl TransactionIds {
+    // sequential or a number
+    pub fn parse(value: &str) -> Result<TransactionIds, String> {
+        match value {
+            "sequential" => Ok(TransactionIds::Sequential),
+            _ => value.parse().map(TransactionIds::Fixed).map_err(|_| {
+                format!(
+                    "Expected sequential or a transaction id (0-65535) but got: {}",
+                    value
+                )
+            }),
+        }
+    }
+}
+
+// How the transaction ids are chosen and whether a response with a different one is a failure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TransactionIdConfig {
+    pub ids: TransactionIds,
+    pub check: bool,
+}
+
+impl Default for TransactionIdConfig {
+    fn default() -> TransactionIdConfig {
+        TransactionIdConfig {
+            ids: TransactionIds::Sequential,
+            check: true,
+        }
+    }
+}
+
+// The response to a request came back with a different transaction id, i.e. it is the answer to some other request.
+// It is wrapped in a modbus::Error::Io so that it can be told apart from the other failures
+#[derive(Debug)]
+pub struct TransactionIdMismatch {
+    pub sent: u16,
+    pub received: u16,
+}
+
+impl fmt::Display for TransactionIdMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Sent transaction id {} but the response has {}",
+            self.sent, self.received
+        )
+    }
+}
+
+impl Error for TransactionIdMismatch {}
+
+impl TransactionIdMismatch {
+    // Whether the modbus error is one of these
+    pub fn is(e: &modbus::Error) -> bool {
+        match e {
+            modbus::Error::Io(e) => e
+                .get_ref()
+                .is_some_and(|inner| inner.is::<TransactionIdMismatch>()),
+            _ => false,
+        }
+    }
+}
+
+// The count of responses with the wrong transaction id, for /metrics
+pub fn transaction_id_metrics(mismatches: u64) -> String {
+    format!(
+        "# HELP modbusrouter_transaction_id_mismatch_total Modbus responses with a different transaction id to the request\n\
+         # TYPE modbusrouter_transaction_id_mismatch_total counter\n\
+         modbusrouter_transaction_id_mismatch_total {}\n",
+        mismatches
+    )
+}
+
+// A minimal Modbus TCP client that runs over any stream.
+// The modbus crate's Transport always opens its own TcpStream, this lets the caller set the
+// stream up first (e.g. a proxy handshake). Only the functions the router uses are supported
+pub struct StreamTransport {
+    stream: Box<dyn ReadWrite>,
+    unit_id: u8,
+    transaction_id: u16,
+    transaction_ids: TransactionIdConfig,
+    // counts every response with the wrong transaction id, checked or not
+    mismatches: Arc<AtomicU64>,
+}
+
+impl StreamTransport {
+    pub fn new(stream: Box<dyn ReadWrite>) -> StreamTransport {
+        StreamTransport::with_transaction_ids(
+            stream,
+            TransactionIdConfig::default(),
+            Arc::new(AtomicU64::new(0)),
+        )
+    }
+
+    pub fn with_transaction_ids(
+        stream: Box<dyn ReadWrite>,
+        transaction_ids: TransactionIdConfig,
+        mismatches: Arc<AtomicU64>,
+    ) -> StreamTransport {
+        StreamTransport {
+            stream,
+            // the same default unit id as the modbus crate
+            unit_id: 1,
+            transaction_id: 0,
+            transaction_ids,
+            mismatches,
+        }
+    }
+
+    // The unit id put in every request, for a gateway that passes the requests on to several devices
+    pub fn with_unit_id(mut self, unit_id: u8) -> StreamTransport {
+        self.unit_id = unit_id;
+        self
+    }
+
+    // Sends a request and returns the pdu of the response (without the function code)
+    fn request(&mut self, function: u8, data: &[u8]) -> Result<Vec<u8>, modbus::Error> {
+        self.transaction_id = match self.transaction_ids.ids {
+            TransactionIds::Sequential => self.transaction_id.wrapping_add(1),
+            TransactionIds::Fixed(id) => id,
+        };
+
+        // MBAP header: transaction id, protocol id (always 0), length of what follows, unit id
+        let mut frame = Vec::with_capacity(8 + data.len());
+        frame.write_u16::<BigEndian>(self.transaction_id)?;
+        frame.write_u16::<BigEndian>(0)?;
+        frame.write_u16::<BigEndian>(2 + data.len() as u16)?;
+        frame.push(self.unit_id);
+        frame.push(function);
+        frame.extend_from_slice(data);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+
+        let mut pdu = loop {
+            let mut header = [0; 7];
+            self.stream.read_exact(&mut header)?;
+            let transaction_id = (&header[0..2]).read_u16::<BigEndian>()?;
+            let length = (&header[4..6]).read_u16::<BigEndian>()? as usize;
+            if length < 2 {
+                return Err(modbus::Error::InvalidResponse);
+            }
+
+            // the length includes the unit id which is part of the header we have already read.
+            // The pdu is read whatever the transaction id so the next response starts where it should
+            let mut pdu = vec![0; length - 1];
+            self.stream.read_exact(&mut pdu)?;
+            if transaction_id == self.transaction_id {
+                break pdu;
+            }
+            self.mismatches.fetch_add(1, Ordering::Relaxed);
+            if !self.transaction_ids.check {
+                break pdu;
+            }
+            // the late answer to a request we have already given up on, ours is still to come
+            if self.is_earlier(transaction_id) {
+                continue;
+            }
+            let mismatch = TransactionIdMismatch {
+                sent: self.transaction_id,
+                received: transaction_id,
+            };
+            return Err(modbus::Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                mismatch,
+            )));
+        };
+        if pdu[0] == function | 0x80 {
+            return match pdu.get(1).and_then(|code| exception_code(*code)) {
+                Some(code) => Err(modbus::Error::Exception(code)),
+                None => Err(modbus::Error::InvalidResponse),
+            };
+        }
+        if pdu[0] != function {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(pdu.split_off(1))
+    }
+
+    // Whether the transaction id is one we sent before the current request, allowing for the ids wrapping round.
+    // With a fixed id there is no telling, every request has the same one
+    fn is_earlier(&self, transaction_id: u16) -> bool {
+        match self.transaction_ids.ids {
+            TransactionIds::Sequential => {
+                (1..0x8000).contains(&self.transaction_id.wrapping_sub(transaction_id))
+            }
+            TransactionIds::Fixed(_) => false,
+        }
+    }
+
+    // One write multiple registers request, no bigger than the modbus allows (see write_in_chunks)
+    fn write_chunk(&mut self, address: u16, values: &[u16]) -> Result<(), modbus::Error> {
+        let mut data = Vec::with_capacity(5 + values.len() * 2);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(values.len() as u16)?;
+        data.push((values.len() * 2) as u8);
+        for value in values {
+            data.write_u16::<BigEndian>(*value)?;
+        }
+
+        // the server replies with the address and quantity written
+        let response = self.request(WRITE_MULTIPLE_REGISTERS, &data)?;
+        if response[..] != data[..4] {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+}
+
+impl ModbusClient for StreamTransport {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        let mut data = Vec::with_capacity(4);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(value)?;
+
+        // the server echoes the request back
+        let response = self.request(WRITE_SINGLE_REGISTER, &data)?;
+        if response != data {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        write_in_chunks(address, values, |address, chunk| {
+            self.write_chunk(address, chunk)
+        })
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        let function = match kind {
+            RegisterKind::Holding => READ_HOLDING_REGISTERS,
+            RegisterKind::Input => READ_INPUT_REGISTERS,
+        };
+        let mut data = Vec::with_capacity(4);
+        data.write_u16::<BigEndian>(address)?;
+        data.write_u16::<BigEndian>(count)?;
+
+        // the server replies with a byte count and then the values
+        let response = self.request(function, &data)?;
+        if response.len() != 1 + count as usize * 2 || response[0] as usize != count as usize * 2 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        let mut values = &response[1..];
+        (0..count)
+            .map(|_| Ok(values.read_u16::<BigEndian>()?))
+            .collect()
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        let mut data = Vec::with_capacity(4);
+        data.write_u16::<BigEndian>(address)?;
+        // on is 0xFF00 and off is 0x0000, nothing else is allowed
+        data.write_u16::<BigEndian>(if value { 0xFF00 } else { 0x0000 })?;
+
+        // the server echoes the request back
+        let response = self.request(WRITE_SINGLE_COIL, &data)?;
+        if response != data {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn exception_code(code: u8) -> Option<ExceptionCode> {
+    let code = match code {
+        0x01 => ExceptionCode::IllegalFunction,
+        0x02 => ExceptionCode::IllegalDataAddress,
+        0x03 => ExceptionCode::IllegalDataValue,
+        0x04 => ExceptionCode::SlaveOrServerFailure,
+        0x05 => ExceptionCode::Acknowledge,
+        0x06 => ExceptionCode::SlaveOrServerBusy,
+        0x07 => ExceptionCode::NegativeAcknowledge,
+        0x08 => ExceptionCode::MemoryParity,
+        0x0A => ExceptionCode::GatewayPath,
+        0x0B => ExceptionCode::GatewayTarget,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    // Replies with canned bytes and keeps hold of everything written to it
+    struct MockStream {
+        replies: Cursor<Vec<u8>>,
+        written: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.replies.read(buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.written.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn transport(replies: Vec<u8>) -> (StreamTransport, Rc<RefCell<Vec<u8>>>) {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let stream = MockStream {
+            replies: Cursor::new(replies),
+            written: written.clone(),
+        };
+        (StreamTransport::new(Box::new(stream)), written)
+    }
+
+    #[test]
+    fn write_single_register_frame() {
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x54,
+        ];
+        let (mut transport, written) = transport(reply.clone());
+        transport.write_single_register(2, 84).unwrap();
+        // the request and the echoed reply are identical
+        assert_eq!(*written.borrow(), reply);
+    }
+
+    #[test]
+    fn write_single_coil_frame() {
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x05, 0x00, 0x07, 0xFF, 0x00,
+        ];
+        let (mut transport, written) = transport(reply.clone());
+        transport.write_single_coil(7, true).unwrap();
+        assert_eq!(*written.borrow(), reply);
+    }
+
+    #[test]
+    fn write_multiple_registers_frame() {
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x10, 0x00, 0x03, 0x00, 0x02,
+        ];
+        let (mut transport, written) = transport(reply);
+        transport
+            .write_multiple_registers(3, &[0xF2FE, 0x025A])
+            .unwrap();
+        assert_eq!(
+            *written.borrow(),
+            vec![
+                0x00, 0x01, 0x00, 0x00, 0x00, 0x0B, 0x01, 0x10, 0x00, 0x03, 0x00, 0x02, 0x04, 0xF2,
+                0xFE, 0x02, 0x5A
+            ]
+        );
+    }
+
+    #[test]
+    fn read_holding_registers_frame() {
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x07, 0x01, 0x03, 0x04, 0x00, 0x54, 0xF2, 0xFE,
+        ];
+        let (mut transport, written) = transport(reply);
+        let values = transport
+            .read_registers(RegisterKind::Holding, 2, 2)
+            .unwrap();
+        assert_eq!(values, vec![84, 0xF2FE]);
+        assert_eq!(
+            *written.borrow(),
+            vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x03, 0x00, 0x02, 0x00, 0x02]
+        );
+    }
+
+    #[test]
+    fn exception_response() {
+        let reply = vec![0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x01, 0x86, 0x02];
+        let (mut transport, _) = transport(reply);
+        match transport.write_single_register(2, 84) {
+            Err(modbus::Error::Exception(ExceptionCode::IllegalDataAddress)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mismatched_transaction_id() {
+        let reply = vec![
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x54,
+        ];
+        let (mut transport, _) = transport(reply);
+        let e = transport.write_single_register(2, 84).unwrap_err();
+        assert!(TransactionIdMismatch::is(&e), "unexpected error: {:?}", e);
+        assert_eq!(transport.mismatches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn the_rest_of_a_mismatched_response_is_read() {
+        // the answer to some other request and then the answer to our next one
+        let reply = vec![
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x54, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x55,
+        ];
+        let (mut transport, _) = transport(reply);
+        assert!(transport.write_single_register(2, 84).is_err());
+        transport.write_single_register(2, 85).unwrap();
+    }
+
+    #[test]
+    fn a_late_response_is_skipped() {
+        // transaction 2 is sent, the answer to 1 turns up first (the write of 1 timed out waiting for it)
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x54, 0x00, 0x02,
+            0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x55,
+        ];
+        let (mut transport, _) = transport(reply);
+        transport.transaction_id = 1;
+        transport.write_single_register(2, 85).unwrap();
+        assert_eq!(transport.mismatches.load(Ordering::Relaxed), 1);
+
+        // across the wrap round
+        transport.transaction_id = 0;
+        assert!(transport.is_earlier(0xFFFF));
+        assert!(!transport.is_earlier(0x0001));
+    }
+
+    #[test]
+    fn fixed_unchecked_transaction_id() {
+        // the response is for transaction 9 but we have been told not to mind
+        let reply = vec![
+            0x00, 0x09, 0x00, 0x00, 0x00, 0x06, 0x01, 0x06, 0x00, 0x02, 0x00, 0x54,
+        ];
+        let (transport, written) = transport(reply);
+        let mismatches = Arc::new(AtomicU64::new(0));
+        let config = TransactionIdConfig {
+            ids: TransactionIds::Fixed(0),
+            check: false,
+        };
+        let mut transport =
+            StreamTransport::with_transaction_ids(transport.stream, config, mismatches.clone());
+        transport.write_single_register(2, 84).unwrap();
+        assert_eq!(written.borrow()[0..2], [0x00, 0x00]);
+        // still counted
+        assert_eq!(mismatches.load(Ordering::Relaxed), 1);
+
+        assert_eq!(
+            TransactionIds::parse("sequential"),
+            Ok(TransactionIds::Sequential)
+        );
+        assert_eq!(TransactionIds::parse("7"), Ok(TransactionIds::Fixed(7)));
+        assert!(TransactionIds::parse("70000").is_err());
+    }
+
+    #[test]
+    fn unit_id_in_the_header() {
+        let reply = vec![
+            0x00, 0x01, 0x00, 0x00, 0x00, 0x06, 0x11, 0x06, 0x00, 0x02, 0x00, 0x54,
+        ];
+        let (transport, written) = transport(reply.clone());
+        let mut transport = transport.with_unit_id(0x11);
+        transport.write_single_register(2, 84).unwrap();
+        assert_eq!(*written.borrow(), reply);
+    }
+}
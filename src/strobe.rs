@@ -0,0 +1,97 @@
+use crate::frame::DeviceMessage;
+use crate::modbus_client::ModbusClient;
+use log::debug;
+
+// A register that changes once for every new message written to the modbus, for PLCs that look for an edge
+// rather than reading msg_num. msg_num itself is no good for this because it starts again when the device reboots.
+// With a modulus of 2 the strobe toggles between 0 and 1, a bigger modulus counts up and wraps
+pub struct Strobe {
+    register: u16,
+    modulus: u16,
+    value: u16,
+    // the msg_num of the last message we strobed for, the same message sent again is not a new one
+    last_msg_num: Option<u16>,
+}
+
+impl Strobe {
+    pub fn new(register: u16, modulus: u16) -> Strobe {
+        Strobe {
+            register,
+            modulus,
+            value: 0,
+            last_msg_num: None,
+        }
+    }
+
+    // Call this once the message has been written, the strobe only moves on if the write succeeds
+    pub fn write(
+        &mut self,
+        modbus_client: &mut dyn ModbusClient,
+        msg: &DeviceMessage,
+    ) -> Result<(), modbus::Error> {
+        if self.last_msg_num == Some(msg.msg_num_value) {
+            return Ok(());
+        }
+        let next = (self.value + 1) % self.modulus;
+        modbus_client.write_single_register(self.register, next)?;
+        debug!("Wrote strobe {} to register {}", next, self.register);
+        self.value = next;
+        self.last_msg_num = Some(msg.msg_num_value);
+        Ok(())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{RecordingClient, Write};
+
+    fn message(msg_num_value: u16) -> DeviceMessage {
+        DeviceMessage {
+            msg_num_value,
+            ..crate::testing::sample_message()
+        }
+    }
+
+    #[test]
+    fn toggles_once_per_new_message() {
+        let mut strobe = Strobe::new(80, 2);
+        let mut client = RecordingClient::default();
+        for msg_num in &[10, 11, 11, 12] {
+            strobe.write(&mut client, &message(*msg_num)).unwrap();
+        }
+        // the resend of 11 doesn't count
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(80, 1),
+                Write::Single(80, 0),
+                Write::Single(80, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_and_wraps_even_when_msg_num_starts_again() {
+        let mut strobe = Strobe::new(80, 3);
+        let mut client = RecordingClient::default();
+        // the device reboots after 500 and starts counting from 1 again
+        for msg_num in &[499, 500, 1, 2] {
+            strobe.write(&mut client, &message(*msg_num)).unwrap();
+        }
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(80, 1),
+                Write::Single(80, 2),
+                Write::Single(80, 0),
+                Write::Single(80, 1),
+            ]
+        );
+    }
+}
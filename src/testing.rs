@@ -0,0 +1,130 @@
+// Stand-ins for the device and the modbus, for the tests here and in the router as well as for anyone
+// writing their own tests against the library
+use crate::frame::DeviceMessage;
+use crate::modbus_client::ModbusClient;
+use std::collections::BTreeMap;
+
+// A register write seen by the RecordingClient
+#[derive(Debug, Clone, PartialEq)]
+pub enum Write {
+    Single(u16, u16),
+    Multiple(u16, Vec<u16>),
+    Coil(u16, bool),
+}
+
+// A modbus client that remembers what was written to it instead of sending it anywhere
+#[derive(Default)]
+pub struct RecordingClient {
+    pub writes: Vec<Write>,
+}
+
+impl RecordingClient {
+    // What each register holds once all of the writes have been made, the coils are left out
+    pub fn registers(&self) -> BTreeMap<u16, u16> {
+        let mut registers = BTreeMap::new();
+        for write in &self.writes {
+            match write {
+                Write::Single(address, value) => {
+                    registers.insert(*address, *value);
+                }
+                Write::Multiple(address, values) => {
+                    for (offset, value) in values.iter().enumerate() {
+                        registers.insert(address + offset as u16, *value);
+                    }
+                }
+                Write::Coil(..) => {}
+            }
+        }
+        registers
+    }
+}
+
+impl ModbusClient for RecordingClient {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.writes.push(Write::Single(address, value));
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.writes.push(Write::Multiple(address, values.to_vec()));
+        Ok(())
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.writes.push(Write::Coil(address, value));
+        Ok(())
+    }
+}
+
+// A modbus client that makes the first few writes and fails every one after them, like a PLC that drops off
+// part way through a message
+pub struct FailingClient {
+    pub writes_left: usize,
+    pub inner: RecordingClient,
+}
+
+impl FailingClient {
+    pub fn after(writes: usize) -> FailingClient {
+        FailingClient {
+            writes_left: writes,
+            inner: RecordingClient::default(),
+        }
+    }
+}
+
+impl ModbusClient for FailingClient {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        if self.writes_left == 0 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        self.writes_left -= 1;
+        self.inner.write_single_register(address, value)
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        if self.writes_left == 0 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        self.writes_left -= 1;
+        self.inner.write_multiple_registers(address, values)
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        if self.writes_left == 0 {
+            return Err(modbus::Error::InvalidResponse);
+        }
+        self.writes_left -= 1;
+        self.inner.write_single_coil(address, value)
+    }
+}
+
+// The first message of the read_message_multiple_messages stream in frame.rs
+pub fn sample_message() -> DeviceMessage {
+    DeviceMessage {
+        mac: [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B],
+        batt_pid1: 1,
+        batt_value: 0,
+        temp_pid2: 2,
+        temp_value: 84,
+        vib_pid3: 3,
+        vib_x: 62206,
+        vib_y: 602,
+        vib_z: 1914,
+        msg_num_pid5: 5,
+        msg_num_value: 33850,
+        version_pid11: 11,
+        version_value: 2,
+        rssi_pid6: 6,
+        rssi_value: 189,
+        sensor_id: None,
+        received_at: None,
+    }
+}
@@ -0,0 +1,88 @@
+// Only built with --features tls
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::Arc;
+
+// The TLS side of the connection to a gateway that wants it (see --tls). The CA file is read once at startup
+// so that a bad one is reported straight away rather than on every reconnect
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>,
+}
+
+impl TlsConnector {
+    // The gateway's certificate has to be signed by one of the certificates in the PEM file and be for
+    // server_name, which is also sent as the SNI
+    pub fn new(ca_file: &Path, server_name: &str) -> io::Result<TlsConnector> {
+        let mut roots = RootCertStore::empty();
+        let mut reader = BufReader::new(File::open(ca_file)?);
+        for cert in rustls_pemfile::certs(&mut reader) {
+            roots
+                .add(cert?)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        }
+        if roots.is_empty() {
+            let e = format!("No certificates in the CA file {}", ca_file.display());
+            return Err(io::Error::new(ErrorKind::InvalidData, e));
+        }
+        let server_name = ServerName::try_from(server_name.to_string()).map_err(|_| {
+            let e = format!("Invalid TLS server name: {}", server_name);
+            io::Error::new(ErrorKind::InvalidInput, e)
+        })?;
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(TlsConnector {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    // The handshake is done here rather than on the first read so that a certificate the gateway shouldn't
+    // have fails the connection, instead of looking like a bad frame
+    pub fn connect(
+        &self,
+        mut stream: TcpStream,
+    ) -> io::Result<StreamOwned<ClientConnection, TcpStream>> {
+        let mut connection = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        while connection.is_handshaking() {
+            connection.complete_io(&mut stream).map_err(|e| {
+                let e = format!("TLS handshake with the device failed: {}", e);
+                io::Error::new(ErrorKind::ConnectionRefused, e)
+            })?;
+        }
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn the_ca_file_must_hold_a_certificate() {
+        let missing = env::temp_dir().join("modbusrouter-no-such-ca.pem");
+        assert!(TlsConnector::new(&missing, "gateway").is_err());
+
+        let empty =
+            env::temp_dir().join(format!("modbusrouter-empty-ca-{}.pem", std::process::id()));
+        fs::write(&empty, "not a certificate\n").unwrap();
+        let e = TlsConnector::new(&empty, "gateway").err().unwrap();
+        assert!(e.to_string().starts_with("No certificates in the CA file"));
+        fs::remove_file(&empty).unwrap();
+    }
+}
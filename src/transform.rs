@@ -0,0 +1,196 @@
+use crate::fields::Field;
+use crate::frame::DeviceMessage;
+
+// A change made to every message before it is written to the modbus, e.g. a calibration curve
+// that can't be put in the config. Implement it in your own crate and add it to a Pipeline
+pub trait Transform {
+    fn apply(&self, msg: &mut DeviceMessage);
+}
+
+// Any closure that takes a message will do for the simple cases
+impl<F: Fn(&mut DeviceMessage)> Transform for F {
+    fn apply(&self, msg: &mut DeviceMessage) {
+        self(msg)
+    }
+}
+
+// The same change made by value, for a transform that would rather hand back a new message than change the one
+// it was given. Every Transform is one of these already, a MessageTransform of your own goes in a Pipeline
+// with add_by_value
+pub trait MessageTransform {
+    fn transform(&self, msg: DeviceMessage) -> DeviceMessage;
+}
+
+impl<T: Transform + ?Sized> MessageTransform for T {
+    fn transform(&self, mut msg: DeviceMessage) -> DeviceMessage {
+        self.apply(&mut msg);
+        msg
+    }
+}
+
+// Leaves the message as it is, what the router does when no transform has been asked for
+pub struct Identity;
+
+impl Transform for Identity {
+    fn apply(&self, _msg: &mut DeviceMessage) {}
+}
+
+// Transforms that run one after the other, in the order they were added.
+// Each one sees the message as the one before it left it
+#[derive(Default)]
+pub struct Pipeline {
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    pub fn add<T: Transform + 'static>(&mut self, transform: T) -> &mut Pipeline {
+        self.transforms.push(Box::new(transform));
+        self
+    }
+
+    pub fn add_by_value<M: MessageTransform + 'static>(&mut self, transform: M) -> &mut Pipeline {
+        self.add(move |msg: &mut DeviceMessage| *msg = transform.transform(msg.clone()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+}
+
+// A pipeline is a transform too, so pipelines can be put together out of smaller ones
+impl Transform for Pipeline {
+    fn apply(&self, msg: &mut DeviceMessage) {
+        for transform in &self.transforms {
+            transform.apply(msg);
+        }
+    }
+}
+
+// Keeps a field within a range, e.g. Clamp::new(Field::Battery, 0, 100) for a battery that reports more than
+// 100% when it is charging. Each of the field's register values is clamped, so for the vibration all three axes.
+// It is also the example to copy for a transform of your own
+pub struct Clamp {
+    field: Field,
+    min: u16,
+    max: u16,
+}
+
+impl Clamp {
+    pub fn new(field: Field, min: u16, max: u16) -> Clamp {
+        Clamp { field, min, max }
+    }
+}
+
+impl Transform for Clamp {
+    fn apply(&self, msg: &mut DeviceMessage) {
+        let values: Vec<u16> = msg
+            .field_values(self.field)
+            .iter()
+            .map(|value| (*value).max(self.min).min(self.max))
+            .collect();
+        msg.set_field_values(self.field, &values);
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    // A made up calibration, a fixed offset
+    struct Offset(u8);
+
+    impl Transform for Offset {
+        fn apply(&self, msg: &mut DeviceMessage) {
+            msg.temp_value = msg.temp_value.saturating_add(self.0);
+        }
+    }
+
+    #[test]
+    fn transforms_run_in_order() {
+        let mut inner = Pipeline::new();
+        inner.add(Offset(10));
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .add(|msg: &mut DeviceMessage| msg.temp_value /= 2)
+            .add(inner)
+            .add(|msg: &mut DeviceMessage| msg.batt_value = 100);
+        let mut msg = sample_message();
+        pipeline.apply(&mut msg);
+        // halved and then offset, the other way round would be 47
+        assert_eq!(msg.temp_value, 52);
+        assert_eq!(msg.batt_value, 100);
+        assert!(Pipeline::new().is_empty());
+    }
+
+    #[test]
+    fn clamp_keeps_a_field_within_range() {
+        let battery = Clamp::new(Field::Battery, 0, 100);
+        let mut msg = DeviceMessage {
+            batt_value: 107,
+            ..sample_message()
+        };
+        battery.apply(&mut msg);
+        assert_eq!(msg.batt_value, 100);
+        msg.batt_value = 42;
+        battery.apply(&mut msg);
+        assert_eq!(msg.batt_value, 42);
+        // nothing else is touched
+        assert_eq!(msg.temp_value, 84);
+
+        // every axis of the vibration
+        let mut msg = sample_message();
+        Clamp::new(Field::Vibration, 1000, 60000).apply(&mut msg);
+        assert_eq!((msg.vib_x, msg.vib_y, msg.vib_z), (60000, 1000, 1914));
+    }
+
+    // A made up transform that builds a new message, the battery from the rssi
+    struct BatteryFromRssi;
+
+    impl MessageTransform for BatteryFromRssi {
+        fn transform(&self, msg: DeviceMessage) -> DeviceMessage {
+            DeviceMessage {
+                batt_value: msg.rssi_value / 2,
+                ..msg
+            }
+        }
+    }
+
+    #[test]
+    fn the_default_leaves_a_message_as_it_is() {
+        assert_eq!(Identity.transform(sample_message()), sample_message());
+        assert_eq!(
+            Pipeline::default().transform(sample_message()),
+            sample_message()
+        );
+        let mut msg = sample_message();
+        Pipeline::new().apply(&mut msg);
+        assert_eq!(msg, sample_message());
+
+        // a transform by value goes in a pipeline beside the others
+        let mut pipeline = Pipeline::new();
+        pipeline
+            .add(Identity)
+            .add_by_value(BatteryFromRssi)
+            .add(Clamp::new(Field::Battery, 0, 90));
+        assert_eq!(pipeline.transform(sample_message()).batt_value, 90);
+        assert_eq!(
+            Clamp::new(Field::Battery, 0, 100)
+                .transform(DeviceMessage {
+                    batt_value: 107,
+                    ..sample_message()
+                })
+                .batt_value,
+            100
+        );
+    }
+}
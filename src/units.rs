@@ -0,0 +1,153 @@
+use crate::fields::Field;
+use crate::frame::DeviceMessage;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// What the numbers of a field mean, for the outputs that leave the router (not the modbus, the PLC already knows)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unit {
+    pub name: String,
+    // the raw value is multiplied by this
+    pub scale: f64,
+}
+
+impl Default for Unit {
+    // the values exactly as the device sent them
+    fn default() -> Unit {
+        Unit {
+            name: "raw".to_string(),
+            scale: 1.0,
+        }
+    }
+}
+
+// A field's value in its unit, e.g. {"value":25.4,"unit":"C"}
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Reading {
+    pub value: Value,
+    pub unit: String,
+}
+
+// vibration has a value for each axis, everything else has one
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Value {
+    One(f64),
+    Axes(Vec<f64>),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Units {
+    // fields that aren't here are raw
+    units: BTreeMap<Field, Unit>,
+}
+
+impl Units {
+    pub fn get(&self, field: Field) -> Unit {
+        self.units.get(&field).cloned().unwrap_or_default()
+    }
+
+    // Parses field=unit[:scale], e.g. temperature=C:0.1
+    pub fn parse(&mut self, value: &str) -> Result<(), String> {
+        let mut parts = value.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let field = Field::from_name(name).ok_or_else(|| format!("Unknown field: {}", name))?;
+        let unit = parts
+            .next()
+            .ok_or_else(|| format!("Expected <field>=<unit>[:<scale>] but got: {}", value))?;
+        let mut parts = unit.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        if name.is_empty() {
+            return Err(format!("Missing unit for {}", field.name()));
+        }
+        let scale = match parts.next() {
+            Some(scale) => scale
+                .parse()
+                .map_err(|_| format!("Invalid scale: {}", scale))?,
+            None => 1.0,
+        };
+        self.units.insert(
+            field,
+            Unit {
+                name: name.to_string(),
+                scale,
+            },
+        );
+        Ok(())
+    }
+
+    // Every field of the message in its unit, keyed by the field name
+    pub fn readings(&self, msg: &DeviceMessage) -> BTreeMap<&'static str, Reading> {
+        Field::ALL
+            .iter()
+            .map(|field| {
+                let unit = self.get(*field);
+                let mut values: Vec<f64> = msg
+                    .field_values(*field)
+                    .iter()
+                    .map(|value| *value as f64 * unit.scale)
+                    .collect();
+                let value = if values.len() == 1 {
+                    Value::One(values.remove(0))
+                } else {
+                    Value::Axes(values)
+                };
+                let reading = Reading {
+                    value,
+                    unit: unit.name,
+                };
+                (field.name(), reading)
+            })
+            .collect()
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    #[test]
+    fn readings_in_their_units() {
+        let mut units = Units::default();
+        units.parse("temperature=C:0.5").unwrap();
+        units.parse("vibration=counts").unwrap();
+        let readings = units.readings(&sample_message());
+
+        assert_eq!(
+            readings["temperature"],
+            Reading {
+                value: Value::One(42.0),
+                unit: "C".to_string()
+            }
+        );
+        assert_eq!(
+            readings["vibration"].value,
+            Value::Axes(vec![62206.0, 602.0, 1914.0])
+        );
+        // untouched fields are raw
+        assert_eq!(
+            readings["rssi"],
+            Reading {
+                value: Value::One(189.0),
+                unit: "raw".to_string()
+            }
+        );
+        let json = serde_json::to_string(&readings["temperature"]).unwrap();
+        assert_eq!(json, "{\"value\":42.0,\"unit\":\"C\"}");
+    }
+
+    #[test]
+    fn parse_invalid() {
+        let mut units = Units::default();
+        assert!(units.parse("bogus=C").is_err());
+        assert!(units.parse("temperature").is_err());
+        assert!(units.parse("temperature=").is_err());
+        assert!(units.parse("temperature=C:hot").is_err());
+    }
+}
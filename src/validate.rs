@@ -0,0 +1,106 @@
+use crate::frame::{MessageReader, RouterError};
+use std::collections::BTreeMap;
+use std::io::Read;
+
+// What `modbusrouter validate` found in a capture file
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub parsed: usize,
+    // how many frames failed with each kind of error, and the first of them to say what was wrong
+    pub failed: BTreeMap<&'static str, (usize, String)>,
+}
+
+impl Report {
+    pub fn failures(&self) -> usize {
+        self.failed.values().map(|(count, _)| count).sum()
+    }
+
+    // A capture with nothing in it is as much use in CI as a bad one
+    pub fn passed(&self) -> bool {
+        self.parsed > 0 && self.failed.is_empty()
+    }
+
+    pub fn print(&self) {
+        println!("{} frames parsed, {} failed", self.parsed, self.failures());
+        for (kind, (count, first)) in &self.failed {
+            println!("FAIL {}: {} frames, the first was {}", kind, count, first);
+        }
+    }
+}
+
+// Reads every frame with read_message, just as the router does from a device, without connecting to anything.
+// A bad frame doesn't stop the ones after it from being read, a stream error (e.g. a frame cut off at the end
+// of the file) is the last failure
+pub fn validate<R: Read>(stream: R, macs: &[[u8; 6]]) -> Report {
+    let mut report = Report::default();
+    for result in MessageReader::with_macs(stream, macs) {
+        match result {
+            Ok(_) => report.parsed += 1,
+            Err(e) => record(&mut report, &e),
+        }
+    }
+    report
+}
+
+fn record(report: &mut Report, e: &RouterError) {
+    report
+        .failed
+        .entry(e.kind())
+        .or_insert_with(|| (0, e.to_string()))
+        .0 += 1;
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::DEFAULT_MACS;
+    use std::io::Cursor;
+
+    // 7 correctly formed messages, one after the other
+    fn seven_messages() -> Vec<u8> {
+        vec![
+            0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03,
+            0xFE, 0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD, 0x19,
+            0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFF,
+            0xF2, 0x77, 0x02, 0x74, 0x07, 0x05, 0x3B, 0x84, 0x0B, 0x02, 0x06, 0xCB, 0x19, 0x00,
+            0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFF, 0xF2,
+            0x63, 0x02, 0x76, 0x07, 0x05, 0x3C, 0x84, 0x0B, 0x02, 0x06, 0xC9, 0x19, 0x00, 0xD0,
+            0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x15, 0xF3, 0x78,
+            0x02, 0x66, 0x07, 0x05, 0x3D, 0x84, 0x0B, 0x02, 0x06, 0xBE, 0x19, 0x00, 0xD0, 0xCF,
+            0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x0E, 0xF3, 0x75, 0x02,
+            0x38, 0x07, 0x05, 0x3E, 0x84, 0x0B, 0x02, 0x06, 0xCB, 0x19, 0x00, 0xD0, 0xCF, 0x5E,
+            0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x07, 0xF3, 0x7B, 0x02, 0x65,
+            0x07, 0x05, 0x3F, 0x84, 0x0B, 0x02, 0x06, 0xC9, 0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82,
+            0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0x20, 0xF3, 0x6F, 0x02, 0x5B, 0x07,
+            0x05, 0x40, 0x84, 0x0B, 0x02, 0x06, 0xBE,
+        ]
+    }
+
+    #[test]
+    fn a_good_capture_passes_and_a_bad_one_fails() {
+        let report = validate(Cursor::new(seven_messages()), &DEFAULT_MACS);
+        assert_eq!(report.parsed, 7);
+        assert_eq!(report.failures(), 0);
+        assert!(report.passed());
+
+        // two frames from somebody else and one cut off at the end
+        let mut bytes = seven_messages();
+        bytes[2] = 0x01;
+        bytes[29] = 0x01;
+        bytes.truncate(bytes.len() - 3);
+        let report = validate(Cursor::new(bytes), &DEFAULT_MACS);
+        assert_eq!(report.parsed, 4);
+        assert_eq!(report.failures(), 3);
+        assert_eq!(report.failed["unexpected-mac"].0, 2);
+        assert_eq!(report.failed["truncated-frame"].0, 1);
+        assert!(!report.passed());
+
+        // and nothing at all
+        assert!(!validate(Cursor::new(Vec::new()), &DEFAULT_MACS).passed());
+    }
+}
@@ -0,0 +1,146 @@
+use crate::modbus_client::{ModbusClient, RegisterKind};
+use crate::stats::Stats;
+use log::error;
+
+// Reads back every write from the holding registers and checks that the values landed (see --verify-writes),
+// for registers where a write that silently went nowhere matters. A mismatch is logged and counted in
+// modbusrouter_write_mismatches_total but the write still counts as done, the modbus did accept it
+pub struct VerifyingClient<C> {
+    inner: C,
+    stats: Stats,
+}
+
+impl<C: ModbusClient> VerifyingClient<C> {
+    pub fn new(inner: C, stats: Stats) -> VerifyingClient<C> {
+        VerifyingClient { inner, stats }
+    }
+
+    fn verify(&mut self, address: u16, written: &[u16]) {
+        let read =
+            match self
+                .inner
+                .read_registers(RegisterKind::Holding, address, written.len() as u16)
+            {
+                Ok(read) => read,
+                Err(e) => {
+                    error!(
+                        "Unable to read back register {} to verify the write: {:?}",
+                        address, e
+                    );
+                    return;
+                }
+            };
+        let mismatch = written
+            .iter()
+            .zip(read.iter())
+            .enumerate()
+            .find(|(_, (wrote, read))| wrote != read);
+        if let Some((offset, (wrote, read))) = mismatch {
+            error!(
+                "Register {} read back as {} after writing {}, the write didn't land",
+                address as usize + offset,
+                read,
+                wrote
+            );
+            self.stats.record_write_mismatch();
+        } else if read.len() < written.len() {
+            error!(
+                "Only {} of the {} registers from {} read back, unable to verify the write",
+                read.len(),
+                written.len(),
+                address
+            );
+        }
+    }
+}
+
+impl<C: ModbusClient> ModbusClient for VerifyingClient<C> {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.inner.write_single_register(address, value)?;
+        self.verify(address, &[value]);
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.inner.write_multiple_registers(address, values)?;
+        self.verify(address, values);
+        Ok(())
+    }
+
+    // only the holding registers are read back
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.inner.write_single_coil(address, value)
+    }
+
+    fn read_registers(
+        &mut self,
+        kind: RegisterKind,
+        address: u16,
+        count: u16,
+    ) -> Result<Vec<u16>, modbus::Error> {
+        self.inner.read_registers(kind, address, count)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::RecordingClient;
+
+    // Takes the writes but every register reads back as 7, like a PLC that overwrites them straight away
+    #[derive(Default)]
+    struct StuckClient {
+        inner: RecordingClient,
+    }
+
+    impl ModbusClient for StuckClient {
+        fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+            self.inner.write_single_register(address, value)
+        }
+
+        fn write_multiple_registers(
+            &mut self,
+            address: u16,
+            values: &[u16],
+        ) -> Result<(), modbus::Error> {
+            self.inner.write_multiple_registers(address, values)
+        }
+
+        fn read_registers(
+            &mut self,
+            _kind: RegisterKind,
+            _address: u16,
+            count: u16,
+        ) -> Result<Vec<u16>, modbus::Error> {
+            Ok(vec![7; count as usize])
+        }
+    }
+
+    fn mismatches(stats: &Stats) -> bool {
+        stats
+            .metrics()
+            .contains("modbusrouter_write_mismatches_total 1\n")
+    }
+
+    #[test]
+    fn a_write_that_reads_back_different_is_reported() {
+        let stats = Stats::new();
+        let mut client = VerifyingClient::new(StuckClient::default(), stats.clone());
+        client.write_multiple_registers(10, &[7, 7, 7]).unwrap();
+        assert!(!mismatches(&stats));
+
+        // the write itself still went through
+        client.write_single_register(3, 42).unwrap();
+        assert_eq!(client.inner.inner.writes.len(), 2);
+        assert!(mismatches(&stats));
+    }
+}
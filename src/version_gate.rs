@@ -0,0 +1,154 @@
+use crate::frame::{format_mac, DeviceMessage};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// What happens to a message from firmware we weren't expecting
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MismatchAction {
+    // forward it anyway, after the warning
+    Warn,
+    // don't let it anywhere near the PLC
+    Drop,
+}
+
+impl MismatchAction {
+    pub fn from_name(name: &str) -> Option<MismatchAction> {
+        match name {
+            "warn" => Some(MismatchAction::Warn),
+            "drop" => Some(MismatchAction::Drop),
+            _ => None,
+        }
+    }
+}
+
+// The firmware versions we are happy with. The default accepts any version
+#[derive(Debug, Clone, PartialEq)]
+pub struct VersionConfig {
+    pub min: Option<u8>,
+    pub expected: Option<u8>,
+    pub action: MismatchAction,
+}
+
+impl Default for VersionConfig {
+    fn default() -> VersionConfig {
+        VersionConfig {
+            min: None,
+            expected: None,
+            action: MismatchAction::Warn,
+        }
+    }
+}
+
+// Checks the version_value of each message, a firmware downgrade can change what the payload means
+// without changing its shape so this is the only way to catch it before the PLC gets the wrong values
+pub struct VersionGate {
+    config: VersionConfig,
+    // keyed by device
+    mismatches: BTreeMap<[u8; 6], u64>,
+}
+
+impl VersionGate {
+    pub fn new(config: VersionConfig) -> VersionGate {
+        VersionGate {
+            config,
+            mismatches: BTreeMap::new(),
+        }
+    }
+
+    pub fn action(&self) -> MismatchAction {
+        self.config.action
+    }
+
+    // Says what is wrong with the version, if anything, and counts it
+    pub fn check(&mut self, msg: &DeviceMessage) -> Result<(), String> {
+        let version = msg.version_value;
+        let problem = match (self.config.expected, self.config.min) {
+            (Some(expected), _) if version != expected => {
+                format!("version {} but expected {}", version, expected)
+            }
+            (_, Some(min)) if version < min => {
+                format!("version {} which is older than {}", version, min)
+            }
+            _ => return Ok(()),
+        };
+        *self.mismatches.entry(msg.mac).or_insert(0) += 1;
+        Err(format!(
+            "Device {} reports firmware {}",
+            format_mac(&msg.mac),
+            problem
+        ))
+    }
+
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_version_mismatch_total Messages from firmware that is older than or different to the one expected"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_version_mismatch_total counter");
+        for (mac, count) in &self.mismatches {
+            let _ = writeln!(
+                out,
+                "modbusrouter_version_mismatch_total{{device=\"{}\"}} {}",
+                format_mac(mac),
+                count
+            );
+        }
+        out
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+
+    fn message(version_value: u8) -> DeviceMessage {
+        DeviceMessage {
+            version_value,
+            ..sample_message()
+        }
+    }
+
+    #[test]
+    fn any_version_by_default() {
+        let mut gate = VersionGate::new(VersionConfig::default());
+        assert!(gate.check(&message(0)).is_ok());
+        assert!(gate.check(&message(255)).is_ok());
+    }
+
+    #[test]
+    fn older_versions_are_counted() {
+        let mut gate = VersionGate::new(VersionConfig {
+            min: Some(2),
+            ..VersionConfig::default()
+        });
+        assert!(gate.check(&message(2)).is_ok());
+        assert!(gate.check(&message(3)).is_ok());
+        assert_eq!(
+            gate.check(&message(1)),
+            Err(
+                "Device D0:CF:5E:82:93:7B reports firmware version 1 which is older than 2"
+                    .to_string()
+            )
+        );
+        assert!(gate
+            .metrics()
+            .contains("modbusrouter_version_mismatch_total{device=\"D0:CF:5E:82:93:7B\"} 1"));
+    }
+
+    #[test]
+    fn expected_version_must_match_exactly() {
+        let mut gate = VersionGate::new(VersionConfig {
+            expected: Some(2),
+            ..VersionConfig::default()
+        });
+        assert!(gate.check(&message(2)).is_ok());
+        assert!(gate.check(&message(3)).is_err());
+    }
+}
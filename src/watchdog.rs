@@ -0,0 +1,82 @@
+use crate::modbus_client::{LazyClient, ModbusClient, ModbusConnector};
+use log::error;
+use std::thread;
+use std::time::Duration;
+
+// Lets the PLC check that the router itself is still running.
+// A counter in the watchdog register goes up by one every interval, if it stops changing the router
+// has died or hung. It runs on its own thread with its own modbus connection so that it keeps counting
+// when no messages are arriving from the device
+pub fn start(
+    modbus_host: String,
+    modbus_port: u16,
+    modbus_unit: u8,
+    register: u16,
+    interval: Duration,
+) {
+    thread::spawn(move || {
+        let connector = ModbusConnector::Direct {
+            host: modbus_host,
+            port: modbus_port,
+            unit: modbus_unit,
+        };
+        let mut watchdog = Watchdog::new(LazyClient::new(|| connector.connect()), register);
+        loop {
+            thread::sleep(interval);
+            watchdog.tick();
+        }
+    });
+}
+
+struct Watchdog<C> {
+    client: C,
+    register: u16,
+    counter: u16,
+}
+
+impl<C: ModbusClient> Watchdog<C> {
+    fn new(client: C, register: u16) -> Watchdog<C> {
+        Watchdog {
+            client,
+            register,
+            counter: 0,
+        }
+    }
+
+    // The counter keeps going up even if a write fails, the PLC only cares that it changes
+    fn tick(&mut self) {
+        self.counter = self.counter.wrapping_add(1);
+        if let Err(e) = self
+            .client
+            .write_single_register(self.register, self.counter)
+        {
+            error!("Error writing the watchdog register: {:?}", e);
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{RecordingClient, Write};
+
+    #[test]
+    fn counter_goes_up_every_tick_and_wraps() {
+        let mut watchdog = Watchdog::new(RecordingClient::default(), 70);
+        watchdog.tick();
+        watchdog.tick();
+        assert_eq!(
+            watchdog.client.writes,
+            vec![Write::Single(70, 1), Write::Single(70, 2)]
+        );
+
+        watchdog.counter = u16::MAX;
+        watchdog.tick();
+        assert_eq!(watchdog.client.writes.last(), Some(&Write::Single(70, 0)));
+    }
+}
@@ -0,0 +1,195 @@
+use crate::device_source::TcpOptions;
+use log::warn;
+use std::io;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use tungstenite::{Message, WebSocket};
+
+// The host and port to open the tcp connection to, e.g. ws://10.0.0.5:8080/frames is 10.0.0.5:8080
+pub fn host_of(url: &str) -> Result<String, String> {
+    if url.starts_with("wss://") {
+        return Err("wss:// WebSocket urls are not supported, only ws://".to_string());
+    }
+    let rest = url
+        .strip_prefix("ws://")
+        .ok_or_else(|| format!("Expected a ws:// url but got: {}", url))?;
+    let authority = rest.split('/').next().unwrap_or("");
+    if authority.is_empty() {
+        return Err(format!("The WebSocket url has no host: {}", url));
+    }
+    // ipv6 addresses are in brackets so the port is whatever follows the last ]
+    let has_port = match authority.rfind(']') {
+        Some(end) => authority[end..].contains(':'),
+        None => authority.contains(':'),
+    };
+    Ok(if has_port {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    })
+}
+
+// Opens the WebSocket and gives back the payloads of its binary messages as one stream of bytes
+pub fn connect(url: &str, options: &TcpOptions) -> io::Result<Box<dyn Read + Send>> {
+    let host = host_of(url).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let stream = TcpStream::connect(host)?;
+    options.apply(&stream)?;
+    let (socket, _) = tungstenite::client(url, stream).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("WebSocket handshake failed: {}", e),
+        )
+    })?;
+    Ok(Box::new(WebSocketReader::new(socket)))
+}
+
+// Reads the binary messages from a WebSocket one after the other, so that the framing works the same
+// whether the gateway sends one frame per message or splits a continuous stream up however it likes
+pub struct WebSocketReader<S> {
+    socket: WebSocket<S>,
+    // what is left of the latest binary message
+    pending: Vec<u8>,
+    position: usize,
+    // text messages aren't frames, we only say so the first time
+    warned_text: bool,
+}
+
+impl<S: Read + Write> WebSocketReader<S> {
+    pub fn new(socket: WebSocket<S>) -> WebSocketReader<S> {
+        WebSocketReader {
+            socket,
+            pending: Vec::new(),
+            position: 0,
+            warned_text: false,
+        }
+    }
+}
+
+impl<S: Read + Write> Read for WebSocketReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.pending.len() {
+            match self.socket.read() {
+                Ok(Message::Binary(payload)) => {
+                    self.pending = payload;
+                    self.position = 0;
+                }
+                Ok(Message::Text(text)) => {
+                    if !self.warned_text {
+                        warn!(
+                            "Ignoring a text message from the WebSocket, only binary messages hold frames: {:?}",
+                            text
+                        );
+                        self.warned_text = true;
+                    }
+                }
+                // the closing handshake is answered by tungstenite, to us it is the end of the stream
+                Ok(Message::Close(_)) => return Ok(0),
+                Err(tungstenite::Error::ConnectionClosed)
+                | Err(tungstenite::Error::AlreadyClosed) => return Ok(0),
+                // tungstenite answers pings itself, with the next read
+                Ok(_) => {}
+                Err(tungstenite::Error::Io(e)) => return Err(e),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+        let available = &self.pending[self.position..];
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::frame::{read_message, DEFAULT_MACS};
+    use std::io::Cursor;
+    use tungstenite::protocol::Role;
+
+    const FRAME: [u8; 27] = [
+        0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFE,
+        0xF2, 0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+    ];
+
+    // The server's messages, followed by wherever the client's replies go
+    struct Server {
+        messages: Cursor<Vec<u8>>,
+        replies: Vec<u8>,
+    }
+
+    impl Read for Server {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.messages.read(buf)
+        }
+    }
+
+    impl Write for Server {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.replies.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // A single unmasked message from the server, as the server side of a WebSocket sends them
+    fn message(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut message = vec![0x80 | opcode, payload.len() as u8];
+        message.extend_from_slice(payload);
+        message
+    }
+
+    fn reader(messages: Vec<u8>) -> WebSocketReader<Server> {
+        let server = Server {
+            messages: Cursor::new(messages),
+            replies: Vec::new(),
+        };
+        WebSocketReader::new(WebSocket::from_raw_socket(server, Role::Client, None))
+    }
+
+    #[test]
+    fn host_from_url() {
+        assert_eq!(
+            host_of("ws://10.0.0.5:8080/frames"),
+            Ok("10.0.0.5:8080".to_string())
+        );
+        assert_eq!(host_of("ws://gateway"), Ok("gateway:80".to_string()));
+        assert_eq!(host_of("ws://[::1]/frames"), Ok("[::1]:80".to_string()));
+        assert!(host_of("wss://gateway/frames").is_err());
+        assert!(host_of("http://gateway/frames").is_err());
+        assert!(host_of("ws:///frames").is_err());
+    }
+
+    #[test]
+    fn frames_from_binary_messages() {
+        let mut messages = message(0x2, &FRAME);
+        // a ping and a text message in between are not part of the stream
+        messages.extend(message(0x9, b"hi"));
+        messages.extend(message(0x1, b"hello"));
+        // a frame split across two messages
+        messages.extend(message(0x2, &FRAME[..10]));
+        messages.extend(message(0x2, &FRAME[10..]));
+        messages.extend(message(0x8, &[]));
+        let mut reader = reader(messages);
+
+        assert_eq!(
+            read_message(&mut reader, &DEFAULT_MACS).unwrap().mac,
+            [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]
+        );
+        assert_eq!(
+            read_message(&mut reader, &DEFAULT_MACS).unwrap().mac,
+            [0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B]
+        );
+        // closing is the end of the stream, like a tcp device closing the connection
+        let mut buf = [0; 1];
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+}
@@ -0,0 +1,93 @@
+// How a 32-bit value is laid out across a pair of 16-bit modbus registers.
+// The letters are the bytes of the value from most to least significant (A is the top byte),
+// in the order they end up in the two registers. Modbus itself doesn't say which is right so every PLC
+// vendor has picked their own
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WordOrder {
+    // big endian, high word first (the most common choice)
+    #[default]
+    Abcd,
+    // high word first but the bytes within each register are swapped
+    Badc,
+    // low word first
+    Cdab,
+    // little endian, low word first with the bytes swapped
+    Dcba,
+}
+
+impl WordOrder {
+    pub fn from_name(name: &str) -> Option<WordOrder> {
+        match name.to_ascii_lowercase().as_str() {
+            "abcd" => Some(WordOrder::Abcd),
+            "badc" => Some(WordOrder::Badc),
+            "cdab" => Some(WordOrder::Cdab),
+            "dcba" => Some(WordOrder::Dcba),
+            _ => None,
+        }
+    }
+
+    // Splits the value into the two register values, in the order they are written
+    pub fn to_registers(self, value: u32) -> [u16; 2] {
+        let high = (value >> 16) as u16;
+        let low = value as u16;
+        match self {
+            WordOrder::Abcd => [high, low],
+            WordOrder::Badc => [high.swap_bytes(), low.swap_bytes()],
+            WordOrder::Cdab => [low, high],
+            WordOrder::Dcba => [low.swap_bytes(), high.swap_bytes()],
+        }
+    }
+
+    // The reverse of to_registers
+    pub fn from_registers(self, registers: [u16; 2]) -> u32 {
+        let (high, low) = match self {
+            WordOrder::Abcd => (registers[0], registers[1]),
+            WordOrder::Badc => (registers[0].swap_bytes(), registers[1].swap_bytes()),
+            WordOrder::Cdab => (registers[1], registers[0]),
+            WordOrder::Dcba => (registers[1].swap_bytes(), registers[0].swap_bytes()),
+        };
+        (high as u32) << 16 | low as u32
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    // 0xAABBCCDD makes it easy to see where each byte ended up
+    const VALUE: u32 = 0xAABB_CCDD;
+
+    #[test]
+    fn abcd() {
+        assert_eq!(WordOrder::Abcd.to_registers(VALUE), [0xAABB, 0xCCDD]);
+    }
+
+    #[test]
+    fn badc() {
+        assert_eq!(WordOrder::Badc.to_registers(VALUE), [0xBBAA, 0xDDCC]);
+    }
+
+    #[test]
+    fn cdab() {
+        assert_eq!(WordOrder::Cdab.to_registers(VALUE), [0xCCDD, 0xAABB]);
+    }
+
+    #[test]
+    fn dcba() {
+        assert_eq!(WordOrder::Dcba.to_registers(VALUE), [0xDDCC, 0xBBAA]);
+    }
+
+    #[test]
+    fn round_trip_and_names() {
+        for name in &["abcd", "BADC", "cdab", "dcba"] {
+            let order = WordOrder::from_name(name).unwrap();
+            assert_eq!(order.from_registers(order.to_registers(VALUE)), VALUE);
+        }
+        assert_eq!(WordOrder::from_name("abdc"), None);
+    }
+}
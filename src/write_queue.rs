@@ -0,0 +1,217 @@
+use crate::modbus_client::{ModbusClient, Paced};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+// Where a write goes, a coil can have the same address as a register without being the same thing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Target {
+    Register(u16),
+    Coil(u16),
+}
+
+// A write that is waiting for the modbus to come back
+#[derive(Debug, Clone, PartialEq)]
+enum Pending {
+    Single(u16),
+    Multiple(Vec<u16>),
+    Coil(bool),
+}
+
+// Holds the register writes that couldn't be made while the modbus was down so that the PLC catches up
+// as soon as it is back, without waiting for the device to send every value again.
+// Only the latest write to each register is kept: the PLC cares about the current state, not the history
+pub struct WriteQueue {
+    // the most writes we hold on to, once we are full the oldest is dropped to make room for a new register
+    capacity: usize,
+    // keyed by the first register (or the coil) of the write, flushed in register order and then the coils.
+    // Each write is numbered in the order it was held, so that the oldest can be found
+    pending: BTreeMap<Target, (u64, Pending)>,
+    held: u64,
+    dropped: u64,
+}
+
+impl WriteQueue {
+    pub fn new(capacity: usize) -> WriteQueue {
+        WriteQueue {
+            capacity,
+            pending: BTreeMap::new(),
+            held: 0,
+            dropped: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn hold(&mut self, target: Target, write: Pending) {
+        if self.pending.len() >= self.capacity && !self.pending.contains_key(&target) {
+            self.dropped += 1;
+            // the newest values are the ones the PLC wants most
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, (held, _))| *held)
+                .map(|(target, _)| *target);
+            match oldest {
+                Some(oldest) => self.pending.remove(&oldest),
+                // a queue that holds nothing
+                None => return,
+            };
+        }
+        self.held += 1;
+        self.pending.insert(target, (self.held, write));
+    }
+
+    // Sends everything that is queued, spaced out by the delay.
+    // On an error the writes that haven't been made yet stay queued for next time
+    pub fn flush(
+        &mut self,
+        modbus_client: &mut dyn ModbusClient,
+        delay: Duration,
+    ) -> Result<(), modbus::Error> {
+        let mut paced = Paced::new(modbus_client, delay);
+        while let Some(target) = self.pending.keys().next().cloned() {
+            match (target, &self.pending[&target].1) {
+                (Target::Register(address), Pending::Single(value)) => {
+                    paced.write_single_register(address, *value)?
+                }
+                (Target::Register(address), Pending::Multiple(values)) => {
+                    paced.write_multiple_registers(address, values)?
+                }
+                (Target::Coil(address), Pending::Coil(value)) => {
+                    paced.write_single_coil(address, *value)?
+                }
+                // hold() never mixes them up
+                _ => {}
+            }
+            self.pending.remove(&target);
+        }
+        Ok(())
+    }
+
+    // The queue metrics in the Prometheus text format
+    pub fn metrics(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_write_queue_depth The number of register writes waiting for the modbus"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_write_queue_depth gauge");
+        let _ = writeln!(out, "modbusrouter_write_queue_depth {}", self.pending.len());
+        let _ = writeln!(
+            out,
+            "# HELP modbusrouter_write_queue_dropped_total Register writes dropped because the write queue was full"
+        );
+        let _ = writeln!(out, "# TYPE modbusrouter_write_queue_dropped_total counter");
+        let _ = writeln!(
+            out,
+            "modbusrouter_write_queue_dropped_total {}",
+            self.dropped
+        );
+        out
+    }
+}
+
+// Writing to the queue holds the write instead of sending it, so a message can be queued
+// with the same code that sends it
+impl ModbusClient for WriteQueue {
+    fn write_single_register(&mut self, address: u16, value: u16) -> Result<(), modbus::Error> {
+        self.hold(Target::Register(address), Pending::Single(value));
+        Ok(())
+    }
+
+    fn write_multiple_registers(
+        &mut self,
+        address: u16,
+        values: &[u16],
+    ) -> Result<(), modbus::Error> {
+        self.hold(
+            Target::Register(address),
+            Pending::Multiple(values.to_vec()),
+        );
+        Ok(())
+    }
+
+    fn write_single_coil(&mut self, address: u16, value: bool) -> Result<(), modbus::Error> {
+        self.hold(Target::Coil(address), Pending::Coil(value));
+        Ok(())
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::{FailingClient, RecordingClient, Write};
+
+    #[test]
+    fn only_the_latest_value_of_each_register_is_kept() {
+        let mut queue = WriteQueue::new(10);
+        queue.write_single_register(2, 84).unwrap();
+        queue.write_multiple_registers(3, &[1, 2, 3]).unwrap();
+        queue.write_single_register(2, 85).unwrap();
+        queue.write_multiple_registers(3, &[4, 5, 6]).unwrap();
+        // coil 2 isn't register 2
+        queue.write_single_coil(2, true).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        let mut client = RecordingClient::default();
+        queue.flush(&mut client, Duration::from_millis(0)).unwrap();
+        assert_eq!(
+            client.writes,
+            vec![
+                Write::Single(2, 85),
+                Write::Multiple(3, vec![4, 5, 6]),
+                Write::Coil(2, true)
+            ]
+        );
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn the_oldest_write_is_dropped_when_full() {
+        let mut queue = WriteQueue::new(2);
+        queue.write_single_register(1, 10).unwrap();
+        queue.write_single_register(2, 20).unwrap();
+        // a register that is already queued can still be updated, which makes it the newest
+        queue.write_single_register(1, 11).unwrap();
+        assert_eq!(queue.dropped, 0);
+        queue.write_single_register(3, 30).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.dropped, 1);
+
+        let metrics = queue.metrics();
+        assert!(metrics.contains("modbusrouter_write_queue_depth 2\n"));
+        assert!(metrics.contains("modbusrouter_write_queue_dropped_total 1\n"));
+
+        // once the modbus is back what is left goes out and the queue is empty again
+        let mut client = RecordingClient::default();
+        queue.flush(&mut client, Duration::from_millis(0)).unwrap();
+        assert_eq!(
+            client.writes,
+            vec![Write::Single(1, 11), Write::Single(3, 30)]
+        );
+        assert_eq!(queue.len(), 0);
+
+        let mut queue = WriteQueue::new(0);
+        queue.write_single_register(1, 10).unwrap();
+        assert_eq!((queue.len(), queue.dropped), (0, 1));
+    }
+
+    #[test]
+    fn failed_flush_keeps_what_was_not_sent() {
+        let mut queue = WriteQueue::new(10);
+        queue.write_single_register(1, 10).unwrap();
+        queue.write_single_register(2, 20).unwrap();
+        let mut client = FailingClient::after(1);
+        assert!(queue.flush(&mut client, Duration::from_millis(0)).is_err());
+        assert_eq!(client.inner.writes, vec![Write::Single(1, 10)]);
+        assert_eq!(queue.len(), 1);
+    }
+}
@@ -0,0 +1,163 @@
+use crate::frame::DeviceMessage;
+use crate::json_out::JsonLine;
+use crate::sink::{MessageSink, SinkError};
+use crate::units::Units;
+use log::{info, warn};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::sync::mpsc::{SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tungstenite::Message;
+
+// How many messages can be waiting for a slow client before new ones are dropped from it
+const PENDING_MESSAGES: usize = 64;
+
+// How long a browser gets to finish the WebSocket handshake before we give up on it
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Sends every decoded message as json to every browser connected to --ws-addr, for a live dashboard.
+// Each client has a thread and a queue of its own, so a slow client only loses its own messages and never
+// holds up the modbus or the other clients
+pub struct WsBroadcast {
+    clients: Arc<Mutex<Vec<SyncSender<String>>>>,
+    units: Units,
+}
+
+impl WsBroadcast {
+    // Binds straight away so that an address that is in use is reported at startup
+    pub fn start(addr: &str, units: Units) -> io::Result<WsBroadcast> {
+        let listener = TcpListener::bind(addr)?;
+        info!(
+            "Broadcasting the decoded messages over WebSocket on {}",
+            addr
+        );
+        Ok(WsBroadcast::serve(listener, units))
+    }
+
+    fn serve(listener: TcpListener, units: Units) -> WsBroadcast {
+        let clients = Arc::new(Mutex::new(Vec::new()));
+        let accepted = clients.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let clients = accepted.clone();
+                        thread::spawn(move || run(stream, &clients));
+                    }
+                    Err(e) => warn!("Unable to accept a WebSocket client: {:?}", e),
+                }
+            }
+        });
+        WsBroadcast { clients, units }
+    }
+}
+
+impl MessageSink for WsBroadcast {
+    fn name(&self) -> &str {
+        "WebSocket server"
+    }
+
+    // Queues the message for every client and returns straight away. The clients that have gone are dropped,
+    // a client that is behind misses the message without the others missing it too
+    fn send(&mut self, msg: &DeviceMessage) -> Result<(), SinkError> {
+        let mut clients = self.clients.lock().unwrap();
+        if clients.is_empty() {
+            return Ok(());
+        }
+        // the same json as a line of --json-out
+        let json = serde_json::to_string(&JsonLine::new(msg, &self.units))
+            .map_err(|e| SinkError::Encode(e.to_string()))?;
+        clients.retain(|client| match client.try_send(json.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                warn!("A WebSocket client is not keeping up, dropping message");
+                true
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+        Ok(())
+    }
+}
+
+// Does the handshake and then writes the client's messages until it goes away
+fn run(stream: TcpStream, clients: &Mutex<Vec<SyncSender<String>>>) {
+    let peer = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_string());
+    if let Err(e) = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+        warn!("Unable to set up the WebSocket client {}: {:?}", peer, e);
+        return;
+    }
+    let mut socket = match tungstenite::accept(stream) {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("WebSocket handshake with {} failed: {}", peer, e);
+            return;
+        }
+    };
+    info!("WebSocket client {} connected", peer);
+    let (sender, receiver) = mpsc::sync_channel(PENDING_MESSAGES);
+    clients.lock().unwrap().push(sender);
+    for json in receiver {
+        if let Err(e) = socket.send(Message::Text(json)) {
+            info!("WebSocket client {} went away: {}", peer, e);
+            // dropping the receiver tells send() to forget the client
+            return;
+        }
+    }
+}
+
+/****************************************************************************************************************/
+/*  ****************************************** Tests ************************************************************/
+/****************************************************************************************************************/
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::testing::sample_message;
+    use std::time::{Instant, UNIX_EPOCH};
+
+    #[test]
+    fn a_connected_client_gets_each_message_as_json() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut broadcast = WsBroadcast::serve(listener, Units::default());
+        // nobody is listening yet, so there is nothing to do
+        broadcast.send(&sample_message()).unwrap();
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let url = format!("ws://{}/", addr);
+        let (mut client, _) = tungstenite::client(url.as_str(), stream).unwrap();
+        let started = Instant::now();
+        while broadcast.clients.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let msg = DeviceMessage {
+            received_at: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123)),
+            ..sample_message()
+        };
+        broadcast.send(&msg).unwrap();
+        let json = match client.read().unwrap() {
+            Message::Text(json) => json,
+            other => panic!("Expected a text message but got {:?}", other),
+        };
+        let line = serde_json::to_string(&JsonLine::new(&msg, &Units::default())).unwrap();
+        assert_eq!(json, line);
+
+        // once the client has gone it is forgotten
+        drop(client);
+        let started = Instant::now();
+        while !broadcast.clients.lock().unwrap().is_empty() {
+            assert!(started.elapsed() < Duration::from_secs(5));
+            broadcast.send(&sample_message()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
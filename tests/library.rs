@@ -0,0 +1,37 @@
+// The router's parsing and writing used from outside the crate, the way another program would
+use modbusrouter::fields::FieldSet;
+use modbusrouter::frame::DEFAULT_MACS;
+use modbusrouter::register_map::RegisterMap;
+use modbusrouter::testing::{sample_message, RecordingClient, Write};
+use modbusrouter::{read_message, send_message_to_modbus, DeviceMessage};
+use std::io::Cursor;
+use std::time::Duration;
+
+const FRAME: [u8; 27] = [
+    0x19, 0x00, 0xD0, 0xCF, 0x5E, 0x82, 0x93, 0x7B, 0x12, 0x01, 0x00, 0x02, 0x54, 0x03, 0xFE, 0xF2,
+    0x5A, 0x02, 0x7A, 0x07, 0x05, 0x3A, 0x84, 0x0B, 0x02, 0x06, 0xBD,
+];
+
+#[test]
+fn read_a_frame_and_write_it_to_the_modbus() {
+    let msg = read_message(&mut Cursor::new(FRAME.to_vec()), &DEFAULT_MACS).unwrap();
+    // the sample message has no received time, the message that was read is stamped with when it arrived
+    assert!(msg.received_at.is_some());
+    let unstamped = DeviceMessage {
+        received_at: None,
+        ..msg.clone()
+    };
+    assert_eq!(unstamped, sample_message());
+
+    let mut client = RecordingClient::default();
+    send_message_to_modbus(
+        &msg,
+        FieldSet::all(),
+        &RegisterMap::default(),
+        Duration::from_millis(0),
+        &mut client,
+    )
+    .unwrap();
+    assert_eq!(client.writes.len(), 6);
+    assert_eq!(client.writes[0], Write::Single(1, 0));
+}
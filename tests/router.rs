@@ -0,0 +1,247 @@
+// The router itself end to end: a made up device and modbus server on localhost, with the real binary in between.
+// A connection on its own is tested with run_connection() in src/router.rs, these check that the binary puts it all
+// together: connecting to the device again, the command line and the modbus over tcp as it runs on site
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use modbusrouter::frame::encode_frame;
+use modbusrouter::testing::{sample_message, Write};
+use modbusrouter::DeviceMessage;
+use std::io;
+use std::io::{Read, Write as IoWrite};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// How long to wait for the router before the test fails
+const PATIENCE: Duration = Duration::from_secs(20);
+
+fn frame(msg_num: u16) -> Vec<u8> {
+    let msg = DeviceMessage {
+        msg_num_value: msg_num,
+        ..sample_message()
+    };
+    encode_frame(&msg).to_vec()
+}
+
+// Answers the writes of a modbus client the way a modbus server would and remembers them
+fn modbus_server() -> (u16, Arc<Mutex<Vec<Write>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let writes = Arc::new(Mutex::new(Vec::new()));
+    let recorded = writes.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let writes = recorded.clone();
+            thread::spawn(move || {
+                // the client going away ends the connection
+                let _ = serve_modbus(stream?, &writes);
+                Ok::<(), io::Error>(())
+            });
+        }
+    });
+    (port, writes)
+}
+
+fn serve_modbus(mut stream: TcpStream, writes: &Mutex<Vec<Write>>) -> io::Result<()> {
+    loop {
+        let mut header = [0; 7];
+        stream.read_exact(&mut header)?;
+        let length = (&header[4..6]).read_u16::<BigEndian>()? as usize;
+        let mut pdu = vec![0; length - 1];
+        stream.read_exact(&mut pdu)?;
+        let address = (&pdu[1..3]).read_u16::<BigEndian>()?;
+        let reply = match pdu[0] {
+            0x06 => {
+                let value = (&pdu[3..5]).read_u16::<BigEndian>()?;
+                writes.lock().unwrap().push(Write::Single(address, value));
+                pdu.clone()
+            }
+            0x10 => {
+                let values = pdu[6..]
+                    .chunks(2)
+                    .map(|mut value| value.read_u16::<BigEndian>())
+                    .collect::<io::Result<Vec<u16>>>()?;
+                writes
+                    .lock()
+                    .unwrap()
+                    .push(Write::Multiple(address, values));
+                pdu[..5].to_vec()
+            }
+            // illegal function
+            function => vec![function | 0x80, 0x01],
+        };
+        let mut frame = header[..4].to_vec();
+        frame.write_u16::<BigEndian>(reply.len() as u16 + 1)?;
+        frame.push(header[6]);
+        frame.extend_from_slice(&reply);
+        stream.write_all(&frame)?;
+    }
+}
+
+// Hands each connection the next lot of bytes and closes it, apart from the last one which is kept open
+// until the test is over. Returns the port and how many connections there have been
+fn device(connections: Vec<Vec<u8>>) -> (u16, Arc<AtomicUsize>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let counter = accepted.clone();
+    thread::spawn(move || {
+        let mut open = Vec::new();
+        for (n, stream) in listener.incoming().enumerate() {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(_) => continue,
+            };
+            if let Some(bytes) = connections.get(n) {
+                let _ = stream.write_all(bytes);
+            }
+            if n + 1 >= connections.len() {
+                open.push(stream);
+            }
+        }
+    });
+    (port, accepted)
+}
+
+// Kills the router when the test ends, pass or fail
+struct Router(Child);
+
+impl Drop for Router {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn start_router(device_port: u16, modbus_port: u16, options: &[&str]) -> Router {
+    let child = Command::new(env!("CARGO_BIN_EXE_modbusrouter"))
+        .arg(format!("127.0.0.1:{}", device_port))
+        .args(["--modbus-host", "127.0.0.1"].iter())
+        .arg("--modbus-port")
+        .arg(modbus_port.to_string())
+        .args(options)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Router(child)
+}
+
+// Polls until the condition holds, false if it never does
+fn eventually<F: Fn() -> bool>(condition: F) -> bool {
+    let started = Instant::now();
+    while started.elapsed() < PATIENCE {
+        if condition() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+    false
+}
+
+#[test]
+fn frames_are_written_and_a_broken_connection_is_made_again_once() {
+    let (modbus_port, writes) = modbus_server();
+    // the first connection ends part way through its second frame
+    let mut first = frame(100);
+    first.extend_from_slice(&frame(101)[..10]);
+    let (device_port, accepted) = device(vec![first, frame(102)]);
+    let _router = start_router(device_port, modbus_port, &[]);
+
+    let written = |msg_num: u16| writes.lock().unwrap().contains(&Write::Single(5, msg_num));
+    assert!(
+        eventually(|| written(102)),
+        "the writes: {:?}",
+        writes.lock().unwrap()
+    );
+
+    // every field of the first frame in the default register map, the partial frame never got that far
+    let writes = writes.lock().unwrap().clone();
+    let first = writes
+        .iter()
+        .position(|write| *write == Write::Single(1, 0))
+        .unwrap();
+    assert_eq!(
+        writes[first..first + 6],
+        [
+            Write::Single(1, 0),
+            Write::Single(2, 84),
+            Write::Multiple(3, vec![62206, 602, 1914]),
+            Write::Single(5, 100),
+            Write::Single(11, 2),
+            Write::Single(6, 189),
+        ]
+    );
+    assert!(!writes.contains(&Write::Single(5, 101)));
+
+    // the second connection stays up, so there is no call for a third
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn a_clamp_is_applied_before_the_message_is_written() {
+    let (modbus_port, writes) = modbus_server();
+    // the sample message has a battery of 0 and a temperature of 84
+    let (device_port, _) = device(vec![frame(100)]);
+    let _router = start_router(
+        device_port,
+        modbus_port,
+        &["--clamp", "battery=10:100", "--clamp", "temperature=0:80"],
+    );
+
+    let written = |msg_num: u16| writes.lock().unwrap().contains(&Write::Single(5, msg_num));
+    assert!(
+        eventually(|| written(100)),
+        "the writes: {:?}",
+        writes.lock().unwrap()
+    );
+    let writes = writes.lock().unwrap().clone();
+    assert!(writes.contains(&Write::Single(1, 10)), "{:?}", writes);
+    assert!(writes.contains(&Write::Single(2, 80)), "{:?}", writes);
+    assert!(!writes.contains(&Write::Single(1, 0)));
+}
+
+#[test]
+fn a_connection_is_made_again_after_max_frames_per_connection() {
+    let (modbus_port, writes) = modbus_server();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let device_port = listener.local_addr().unwrap().port();
+    let _router = start_router(
+        device_port,
+        modbus_port,
+        &["--max-frames-per-connection", "3"],
+    );
+    let written = |msg_num: u16| writes.lock().unwrap().contains(&Write::Single(5, msg_num));
+
+    // one frame at a time, each once the one before has been written, until the router hangs up
+    let (mut stream, _) = listener.accept().unwrap();
+    let mut sent = 0;
+    let mut closed = false;
+    while !closed && sent < 5 {
+        sent += 1;
+        stream.write_all(&frame(100 + sent)).unwrap();
+        assert!(
+            eventually(|| written(100 + sent)),
+            "frame {} wasn't written",
+            sent
+        );
+        stream
+            .set_read_timeout(Some(Duration::from_millis(500)))
+            .unwrap();
+        closed = match stream.read(&mut [0; 1]) {
+            Ok(0) => true,
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionReset => true,
+            _ => false,
+        };
+    }
+    assert!(closed, "the connection is still up after {} frames", sent);
+    assert_eq!(sent, 3);
+
+    // and then the router connects again
+    listener.set_nonblocking(true).unwrap();
+    assert!(eventually(|| listener.accept().is_ok()));
+}